@@ -0,0 +1,52 @@
+use std::time::{Duration, Instant};
+
+/// Limits the rate at which data flows in one direction (e.g. from the source doer to the boss),
+/// by sleeping after each chunk of data for long enough that the average rate stays below the limit.
+/// Because whole chunks are sent at once, the rate over short periods can exceed the limit.
+pub struct BandwidthLimiter {
+    bytes_per_sec: u64,
+    /// The time at which all the data so far would have finished transferring, if it had been sent at exactly
+    /// the limit.
+    next_free: Option<Instant>,
+}
+
+impl BandwidthLimiter {
+    pub fn new(bytes_per_sec: u64) -> BandwidthLimiter {
+        BandwidthLimiter { bytes_per_sec, next_free: None }
+    }
+
+    /// Records that the given number of bytes has been transferred, and blocks until the average rate is
+    /// back within the limit.
+    pub fn consume(&mut self, num_bytes: u64) {
+        let delay = self.delay_for(num_bytes, Instant::now());
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    fn delay_for(&mut self, num_bytes: u64, now: Instant) -> Duration {
+        // Don't let unused bandwidth from an idle period (e.g. while querying entries) build up, otherwise
+        // there would be a big burst afterwards.
+        let start = self.next_free.map_or(now, |n| n.max(now));
+        let next_free = start + Duration::from_secs_f64(num_bytes as f64 / self.bytes_per_sec as f64);
+        self.next_free = Some(next_free);
+        next_free - now
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_delay_for() {
+        let mut l = BandwidthLimiter::new(1000);
+        let t = Instant::now();
+        assert_eq!(l.delay_for(500, t), Duration::from_millis(500));
+        // The next chunk arrives before the previous one would have finished, so has to wait for both
+        assert_eq!(l.delay_for(500, t + Duration::from_millis(200)), Duration::from_millis(800));
+        // After being idle, the delay is just for the new chunk
+        assert_eq!(l.delay_for(1000, t + Duration::from_secs(10)), Duration::from_secs(1));
+        assert_eq!(l.delay_for(0, t + Duration::from_secs(20)), Duration::ZERO);
+    }
+}
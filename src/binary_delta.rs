@@ -0,0 +1,211 @@
+use std::{collections::HashMap, io::Write, process::ExitCode};
+
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+
+/// Size of the blocks in the base data that we look for in the new data.
+/// Smaller blocks find more matches but make the index bigger and the delta more fragmented.
+const BLOCK_SIZE: usize = 64;
+
+/// A compact description of how to turn one binary (the "base") into another (the "new" one),
+/// used to upgrade a previously deployed rjrssync without uploading the whole binary again.
+/// The base and new data are identified by their length and SHA-256 hash, so that a delta is never applied to
+/// the wrong base, and the result is checked before it replaces the existing binary.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Delta {
+    base_len: u64,
+    base_hash: [u8; 32],
+    new_len: u64,
+    new_hash: [u8; 32],
+    ops: Vec<DeltaOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum DeltaOp {
+    /// Copy a range of bytes from the base
+    Copy { offset: u64, len: u64 },
+    /// Insert bytes which aren't in the base
+    Insert(#[serde(with = "serde_bytes")] Vec<u8>),
+}
+
+/// SHA-256, so that it isn't feasible to make a different base or result which looks like the right one.
+fn hash(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Simple polynomial rolling hash over a window of BLOCK_SIZE bytes.
+const ROLLING_MULTIPLIER: u32 = 31;
+
+fn rolling_hash(window: &[u8]) -> u32 {
+    window.iter().fold(0u32, |h, b| h.wrapping_mul(ROLLING_MULTIPLIER).wrapping_add(*b as u32))
+}
+
+/// Creates a (compressed) delta which can be used by apply_delta() to reconstruct `new` from `base`.
+pub fn create_delta(base: &[u8], new: &[u8]) -> Result<Vec<u8>, String> {
+    // Index the (non-overlapping) blocks of the base by their rolling hash
+    let mut index: HashMap<u32, Vec<usize>> = HashMap::new();
+    for offset in (0..base.len().saturating_sub(BLOCK_SIZE - 1)).step_by(BLOCK_SIZE) {
+        index.entry(rolling_hash(&base[offset..offset + BLOCK_SIZE])).or_default().push(offset);
+    }
+
+    // The multiplier raised to the power of the window size, used to remove the oldest byte from the rolling hash
+    let out_factor = (0..BLOCK_SIZE).fold(1u32, |f, _| f.wrapping_mul(ROLLING_MULTIPLIER));
+
+    let mut ops = vec![];
+    let mut literal = vec![];
+    let mut pos = 0;
+    let mut h = if new.len() >= BLOCK_SIZE { rolling_hash(&new[..BLOCK_SIZE]) } else { 0 };
+    while pos + BLOCK_SIZE <= new.len() {
+        let window = &new[pos..pos + BLOCK_SIZE];
+        let matched = index.get(&h).and_then(|offsets| offsets.iter().find(|o| &base[**o..**o + BLOCK_SIZE] == window));
+        if let Some(&offset) = matched {
+            // Extend the match forwards as far as possible
+            let mut len = BLOCK_SIZE;
+            while pos + len < new.len() && offset + len < base.len() && new[pos + len] == base[offset + len] {
+                len += 1;
+            }
+            if !literal.is_empty() {
+                ops.push(DeltaOp::Insert(std::mem::take(&mut literal)));
+            }
+            ops.push(DeltaOp::Copy { offset: offset as u64, len: len as u64 });
+            pos += len;
+            if pos + BLOCK_SIZE <= new.len() {
+                h = rolling_hash(&new[pos..pos + BLOCK_SIZE]);
+            }
+        } else {
+            literal.push(new[pos]);
+            if pos + BLOCK_SIZE < new.len() {
+                h = h.wrapping_mul(ROLLING_MULTIPLIER).wrapping_add(new[pos + BLOCK_SIZE] as u32)
+                    .wrapping_sub((new[pos] as u32).wrapping_mul(out_factor));
+            }
+            pos += 1;
+        }
+    }
+    literal.extend_from_slice(&new[pos..]);
+    if !literal.is_empty() {
+        ops.push(DeltaOp::Insert(literal));
+    }
+
+    let delta = Delta {
+        base_len: base.len() as u64,
+        base_hash: hash(base),
+        new_len: new.len() as u64,
+        new_hash: hash(new),
+        ops,
+    };
+    let serialized = bincode::serialize(&delta).map_err(|e| format!("Error serializing delta: {e}"))?;
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&serialized).map_err(|e| format!("Error compressing delta: {e}"))?;
+    encoder.finish().map_err(|e| format!("Error compressing delta: {e}"))
+}
+
+/// Reconstructs the new data from the base data and a delta created by create_delta().
+pub fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>, String> {
+    let mut decoder = flate2::write::DeflateDecoder::new(Vec::new());
+    decoder.write_all(delta).map_err(|e| format!("Error decompressing delta: {e}"))?;
+    let serialized = decoder.finish().map_err(|e| format!("Error decompressing delta: {e}"))?;
+    let delta: Delta = bincode::deserialize(&serialized).map_err(|e| format!("Error deserializing delta: {e}"))?;
+
+    if delta.base_len != base.len() as u64 || delta.base_hash != hash(base) {
+        return Err("Delta was created against a different base".to_string());
+    }
+
+    let mut result = Vec::with_capacity(delta.new_len as usize);
+    for op in delta.ops {
+        match op {
+            DeltaOp::Copy { offset, len } => {
+                let range = offset as usize..(offset + len) as usize;
+                match base.get(range) {
+                    Some(b) => result.extend_from_slice(b),
+                    None => return Err("Delta refers to data outside of the base".to_string()),
+                }
+            }
+            DeltaOp::Insert(bytes) => result.extend_from_slice(&bytes),
+        }
+    }
+
+    if result.len() as u64 != delta.new_len || hash(&result) != delta.new_hash {
+        return Err("Result of applying delta doesn't match the expected data".to_string());
+    }
+    Ok(result)
+}
+
+fn apply_delta_to_files(base_path: &str, delta_path: &str, output_path: &str) -> Result<(), String> {
+    let base = std::fs::read(base_path).map_err(|e| format!("Error reading base '{base_path}': {e}"))?;
+    let delta = std::fs::read(delta_path).map_err(|e| format!("Error reading delta '{delta_path}': {e}"))?;
+    let new = apply_delta(&base, &delta)?;
+    std::fs::write(output_path, new).map_err(|e| format!("Error writing output '{output_path}': {e}"))
+}
+
+/// Entry point for `rjrssync --apply-delta <base> <delta> <output>`, which is run on a remote target during
+/// deployment to upgrade the rjrssync already there (see boss_deploy.rs).
+pub fn apply_delta_main() -> ExitCode {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 5 {
+        eprintln!("Usage: rjrssync --apply-delta <base> <delta> <output>");
+        return ExitCode::from(2);
+    }
+    let result = apply_delta_to_files(&args[2], &args[3], &args[4]);
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(1)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pseudo_random_data(len: usize, seed: u64) -> Vec<u8> {
+        let mut x = seed;
+        (0..len).map(|_| {
+            x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (x >> 56) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn test_round_trip_similar() {
+        let base = pseudo_random_data(100_000, 1);
+        let mut new = base.clone();
+        // Insert some bytes (shifting everything after), change some and delete some
+        new.splice(1000..1000, pseudo_random_data(100, 2));
+        new[50_000] ^= 0xff;
+        new.drain(70_000..70_500);
+
+        let delta = create_delta(&base, &new).unwrap();
+        assert!(delta.len() < new.len() / 10, "Delta should be much smaller than the new data, but was {} bytes", delta.len());
+        assert_eq!(apply_delta(&base, &delta), Ok(new));
+    }
+
+    #[test]
+    fn test_round_trip_different() {
+        let base = pseudo_random_data(1000, 1);
+        let new = pseudo_random_data(2000, 2);
+        let delta = create_delta(&base, &new).unwrap();
+        assert_eq!(apply_delta(&base, &delta), Ok(new));
+    }
+
+    #[test]
+    fn test_round_trip_small() {
+        assert_eq!(apply_delta(b"", &create_delta(b"", b"").unwrap()), Ok(vec![]));
+        assert_eq!(apply_delta(b"abc", &create_delta(b"abc", b"hello").unwrap()), Ok(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_hash() {
+        // Known SHA-256 test vector
+        assert_eq!(hash(b"abc")[..4], [0xba, 0x78, 0x16, 0xbf]);
+    }
+
+    #[test]
+    fn test_wrong_base() {
+        let base = pseudo_random_data(1000, 1);
+        let new = pseudo_random_data(1000, 2);
+        let delta = create_delta(&base, &new).unwrap();
+        assert_eq!(apply_delta(&new, &delta), Err("Delta was created against a different base".to_string()));
+    }
+}
@@ -0,0 +1,173 @@
+use std::{ops::RangeInclusive, path::Path, time::{Duration, Instant}};
+
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use log::info;
+
+use crate::{*, boss_doer_interface::{Command, Response, EntryDetails, ProgressMarker, ProgressPhase}, root_relative_path::RootRelativePath};
+
+/// How many ProfilingTimeSync commands are sent to measure the round-trip latency.
+const NUM_LATENCY_SAMPLES: u32 = 10;
+
+/// The size of each chunk of the test file that is uploaded (the same as the largest chunk that the doer sends back).
+const UPLOAD_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Checks that we can connect to the given host (deploying if necessary), then exercises the comms with
+/// a few commands and measures latency and (if transfer_size is non-zero) throughput, reporting the results.
+/// This doesn't touch anything apart from a temporary folder on the host, which is deleted afterwards.
+#[allow(clippy::too_many_arguments)]
+pub fn check_host(
+    hostname: &str,
+    username: &str,
+    data_hostname: Option<&str>,
+    ssh_tunnel: bool,
+    remote_ports_for_comms: Option<&RangeInclusive<u16>>,
+    verify_binary: bool,
+    deploy_behaviour: DeployBehaviour,
+    transfer_size: u64,
+    progress_bar: &ProgressBar,
+    relay: Option<(&str, &str)>,
+) -> Result<(), String> {
+    let description = if hostname.is_empty() { "the local computer".to_string() } else { format!("'{hostname}'") };
+    info!("Checking connection to {description}...");
+
+    // As when syncing, we can't use an animated spinner while connecting as it would clash with any ssh prompts
+    progress_bar.set_style(ProgressStyle::with_template("{wide_msg}").unwrap());
+    let start = Instant::now();
+    let comms = setup_comms(hostname, username, data_hostname, ssh_tunnel, remote_ports_for_comms, verify_binary,
+        "check".to_string(), deploy_behaviour, progress_bar, relay)?;
+    info!("Connected in {:.2}s, using transport: {}", start.elapsed().as_secs_f32(), comms.transport_description(false));
+
+    let result = check_comms(&comms, transfer_size, progress_bar);
+    progress_bar.set_message("");
+    comms.shutdown();
+    result
+}
+
+fn check_comms(comms: &Comms, transfer_size: u64, progress_bar: &ProgressBar) -> Result<(), String> {
+    // Round-trip some commands to measure the latency, which is dominated by the network for remote doers.
+    progress_bar.set_message("Measuring latency...");
+    let mut latencies = vec![];
+    for _ in 0..NUM_LATENCY_SAMPLES {
+        let start = Instant::now();
+        comms.send_command(Command::ProfilingTimeSync)?;
+        match comms.receive_response()? {
+            Response::ProfilingTimeSync(_) => latencies.push(start.elapsed()),
+            x => return Err(format!("Unexpected response (expected ProfilingTimeSync): {:?}", x)),
+        }
+    }
+    let min = latencies.iter().min().unwrap();
+    let avg = latencies.iter().sum::<Duration>() / NUM_LATENCY_SAMPLES;
+    info!("Round-trip latency: min {:.2}ms, average {:.2}ms (over {NUM_LATENCY_SAMPLES} commands)",
+        min.as_secs_f64() * 1000.0, avg.as_secs_f64() * 1000.0);
+
+    // Create and use a temporary folder, to check that the filesystem can be accessed
+    progress_bar.set_message("Checking filesystem access...");
+    comms.send_command(Command::CreateTempFolder)?;
+    let temp_folder = match comms.receive_response()? {
+        Response::TempFolderCreated(f) => f,
+        Response::Error(e) => return Err(e),
+        x => return Err(format!("Unexpected response (expected TempFolderCreated): {:?}", x)),
+    };
+    comms.send_command(Command::SetRoot { root: temp_folder.clone(), file_flags: false, fsync: false, bwlimit: None, resuming: false })?;
+    let result = match comms.receive_response()? {
+        Response::RootDetails { root_details: Some(EntryDetails::Folder), .. } => check_transfer(comms, transfer_size, progress_bar),
+        Response::Error(e) => Err(e),
+        x => Err(format!("Unexpected response (expected RootDetails for a folder): {:?}", x)),
+    };
+
+    // Clean up the temporary folder, even if something went wrong above
+    progress_bar.set_message("Cleaning up...");
+    let test_file = get_test_file_path();
+    if transfer_size > 0 {
+        comms.send_command(Command::DeleteFile { path: test_file })?;
+    }
+    comms.send_command(Command::DeleteFolder { path: RootRelativePath::root() })?;
+    let cleanup_result = wait_for_marker(comms);
+
+    // Report the original error in preference to any error from cleaning up, as that is probably the cause
+    result?;
+    cleanup_result.map_err(|e| format!("Failed to delete temporary folder '{temp_folder}': {e}"))?;
+    info!("Check successful!");
+    Ok(())
+}
+
+/// Uploads a test file of the given size into the root and downloads it again, reporting the throughput.
+fn check_transfer(comms: &Comms, transfer_size: u64, progress_bar: &ProgressBar) -> Result<(), String> {
+    if transfer_size == 0 {
+        return Ok(());
+    }
+    let test_file = get_test_file_path();
+
+    // Use data that doesn't compress, so the measurement is representative even if compression gets involved
+    let mut data = vec![0u8; transfer_size as usize];
+    let mut x: u32 = 0x12345678;
+    for b in &mut data {
+        // xorshift
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        *b = x as u8;
+    }
+
+    progress_bar.set_message(format!("Uploading {} test file...", HumanBytes(transfer_size)));
+    let start = Instant::now();
+    let mut chunks = data.chunks(UPLOAD_CHUNK_SIZE).peekable();
+    while let Some(c) = chunks.next() {
+        comms.send_command(Command::CreateOrUpdateFile {
+            path: test_file.clone(),
+            data: c.to_vec(),
+            uncompressed_size: None,
+            set_modified_time: None,
+            more_to_follow: chunks.peek().is_some(),
+        })?;
+    }
+    wait_for_marker(comms)?;
+    report_throughput("Upload", transfer_size, start.elapsed());
+
+    progress_bar.set_message(format!("Downloading {} test file...", HumanBytes(transfer_size)));
+    let start = Instant::now();
+    comms.send_command(Command::GetFileContent { path: test_file, compress: false })?;
+    let mut received = vec![];
+    loop {
+        match comms.receive_response()? {
+            Response::FileContent { data, more_to_follow, .. } => {
+                received.extend(data);
+                if !more_to_follow {
+                    break;
+                }
+            }
+            Response::Error(e) => return Err(e),
+            x => return Err(format!("Unexpected response (expected FileContent): {:?}", x)),
+        }
+    }
+    report_throughput("Download", transfer_size, start.elapsed());
+
+    if received != data {
+        return Err("Downloaded test file doesn't match what was uploaded".to_string());
+    }
+    Ok(())
+}
+
+fn get_test_file_path() -> RootRelativePath {
+    RootRelativePath::try_from(Path::new("rjrssync-check.bin")).expect("Valid path")
+}
+
+fn report_throughput(direction: &str, size: u64, elapsed: Duration) {
+    info!("{direction} throughput: {}/s ({} in {:.2}s)",
+        HumanBytes((size as f64 / elapsed.as_secs_f64()) as u64), HumanBytes(size), elapsed.as_secs_f32());
+}
+
+/// Sends a Marker and waits for it to be echoed back, so that we know the doer has processed all the commands
+/// before it. Any errors reported by the doer in the meantime are returned.
+fn wait_for_marker(comms: &Comms) -> Result<(), String> {
+    comms.send_command(Command::Marker(ProgressMarker { completed_work: 0, phase: ProgressPhase::Done }))?;
+    let mut first_error = None;
+    loop {
+        match comms.receive_response()? {
+            Response::Marker(_) => return first_error.map_or(Ok(()), Err),
+            // Keep going until the marker, so that it isn't left in the channel for any following commands
+            Response::Error(e) => { first_error.get_or_insert(e); }
+            x => return Err(format!("Unexpected response (expected Marker): {:?}", x)),
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::{fs::{File, OpenOptions}, io::{BufReader, BufWriter, Read, Write}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{boss_doer_interface::get_version_string, boss_sync::{Actions, ToCopy, ToDelete}};
+
+/// The start of a checkpoint file, identifying which sync the plan that follows it is for.
+/// The file then contains each entry to delete and copy (in the order that they will be done), followed by
+/// any number of progress records (see Checkpoint::record_progress), of which only the last is relevant.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// Checkpoints from other versions might not be compatible, so aren't used.
+    version: String,
+    src_root: String,
+    dest_root: String,
+    num_to_delete: u64,
+    num_to_copy: u64,
+}
+
+/// The size of each progress record at the end of the checkpoint file: the number of entries that have been
+/// deleted and then the number copied, each as a little-endian u32.
+const PROGRESS_RECORD_SIZE: usize = 8;
+
+/// Records the plan for a sync and how much of it has been completed, in a local file, so that the sync can be
+/// resumed if it gets interrupted, without needing to query and compare everything again (see --checkpoint).
+pub struct Checkpoint {
+    path: String,
+    /// The file is kept open so that progress records can be appended cheaply.
+    file: File,
+    last_recorded: (u32, u32),
+}
+impl Checkpoint {
+    /// Writes the plan to a new checkpoint file, replacing any existing one.
+    pub fn create(path: &str, src_root: &str, dest_root: &str, actions: &Actions) -> Result<Checkpoint, String> {
+        // Write to a temporary file first, so that if we're interrupted while writing it, any previous checkpoint
+        // is still intact rather than being left half-written
+        let temp_path = format!("{path}.tmp");
+        let write = || -> Result<(), Box<dyn std::error::Error>> {
+            let mut w = BufWriter::new(File::create(&temp_path)?);
+            let header = Header {
+                version: get_version_string(),
+                src_root: src_root.to_string(),
+                dest_root: dest_root.to_string(),
+                num_to_delete: actions.to_delete.len() as u64,
+                num_to_copy: actions.to_copy.len() as u64,
+            };
+            bincode::serialize_into(&mut w, &header)?;
+            for e in actions.to_delete.iter() {
+                bincode::serialize_into(&mut w, &e)?;
+            }
+            for e in actions.to_copy.iter() {
+                bincode::serialize_into(&mut w, &e)?;
+            }
+            actions.check_read_error()?;
+            w.flush()?;
+            Ok(())
+        };
+        write().map_err(|e| format!("Failed to write checkpoint file '{temp_path}': {e}"))?;
+        std::fs::rename(&temp_path, path).map_err(|e| format!("Failed to rename '{temp_path}' to '{path}': {e}"))?;
+
+        let file = OpenOptions::new().append(true).open(path)
+            .map_err(|e| format!("Failed to open checkpoint file '{path}': {e}"))?;
+        Ok(Checkpoint { path: path.to_string(), file, last_recorded: (0, 0) })
+    }
+
+    /// Records that the first `num_deleted` entries to be deleted and the first `num_copied` entries to be
+    /// copied have been completed.
+    pub fn record_progress(&mut self, num_deleted: u32, num_copied: u32) -> Result<(), String> {
+        if (num_deleted, num_copied) == self.last_recorded {
+            return Ok(());
+        }
+        let mut record = [0; PROGRESS_RECORD_SIZE];
+        record[..4].copy_from_slice(&num_deleted.to_le_bytes());
+        record[4..].copy_from_slice(&num_copied.to_le_bytes());
+        self.file.write_all(&record).map_err(|e| format!("Failed to update checkpoint file '{}': {e}", self.path))?;
+        self.last_recorded = (num_deleted, num_copied);
+        Ok(())
+    }
+}
+
+/// The result of looking for a checkpoint to resume from (see --resume).
+pub enum Resumable {
+    /// There's no checkpoint file, so there's nothing to resume.
+    Nothing,
+    /// The checkpoint file is for a different sync (e.g. another one in the same spec file), so should be left alone.
+    OtherSync,
+    /// The actions from the checkpoint which haven't yet been completed.
+    Remaining(Box<Actions>),
+}
+
+/// Reads the checkpoint file at the given path, if there is one, and gets the actions which were planned
+/// but not yet completed.
+pub fn load_checkpoint(path: &str, src_root: &str, dest_root: &str, max_entries_in_memory: Option<usize>) -> Result<Resumable, String> {
+    let file = match File::open(path) {
+        Ok(f) => f,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Resumable::Nothing),
+        Err(e) => return Err(format!("Failed to open checkpoint file '{path}': {e}")),
+    };
+    let mut r = BufReader::new(file);
+    let read = |r: &mut BufReader<File>| -> Result<Resumable, Box<dyn std::error::Error>> {
+        let header: Header = bincode::deserialize_from(&mut *r)?;
+        if header.version != get_version_string() {
+            return Err(format!("it was written by a different version of rjrssync ({})", header.version).into());
+        }
+        if header.src_root != src_root || header.dest_root != dest_root {
+            return Ok(Resumable::OtherSync);
+        }
+        let mut to_delete = vec![];
+        for _ in 0..header.num_to_delete {
+            to_delete.push(bincode::deserialize_from(&mut *r)?);
+        }
+        let mut to_copy = vec![];
+        for _ in 0..header.num_to_copy {
+            to_copy.push(bincode::deserialize_from(&mut *r)?);
+        }
+
+        // Only the last (complete) progress record matters
+        let mut records = vec![];
+        r.read_to_end(&mut records)?;
+        let (num_deleted, num_copied) = match records.len() / PROGRESS_RECORD_SIZE {
+            0 => (0, 0),
+            n => {
+                let last = &records[(n - 1) * PROGRESS_RECORD_SIZE..n * PROGRESS_RECORD_SIZE];
+                (u32::from_le_bytes(last[..4].try_into()?), u32::from_le_bytes(last[4..].try_into()?))
+            }
+        };
+
+        let mut actions = Actions {
+            to_delete: ToDelete::with_spill_threshold(max_entries_in_memory),
+            to_copy: ToCopy::with_spill_threshold(max_entries_in_memory),
+        };
+        for (p, v) in to_delete.into_iter().skip(num_deleted as usize) {
+            actions.to_delete.add(p, v)?;
+        }
+        for (p, v) in to_copy.into_iter().skip(num_copied as usize) {
+            actions.to_copy.add(p, v)?;
+        }
+        Ok(Resumable::Remaining(Box::new(actions)))
+    };
+    read(&mut r).map_err(|e| format!("Checkpoint file '{path}' can't be used: {e}. Delete it, or run without --resume."))
+}
+
+/// Deletes the checkpoint file once the sync that it's for has finished.
+pub fn remove_checkpoint(path: &str) -> Result<(), String> {
+    std::fs::remove_file(path).map_err(|e| format!("Failed to delete checkpoint file '{path}': {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{path::Path, time::SystemTime};
+    use crate::{boss_doer_interface::{EntryDetails, FileFlags}, boss_sync::{CopyReason, DeleteReason}, root_relative_path::RootRelativePath};
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+        let path = temp_folder.path().join("checkpoint").to_str().unwrap().to_string();
+        let p = |s: &str| RootRelativePath::try_from(Path::new(s)).unwrap();
+        let file = EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, size: 10, flags: FileFlags::default() };
+
+        let mut actions = Actions { to_delete: ToDelete::new(), to_copy: ToCopy::new() };
+        actions.to_delete.add(p("d1"), (file.clone(), DeleteReason::NotOnSource)).unwrap();
+        actions.to_delete.add(p("d2"), (EntryDetails::Folder, DeleteReason::Incompatible)).unwrap();
+        for c in ["c1", "c2", "c3"] {
+            actions.to_copy.add(p(c), (file.clone(), CopyReason::NotOnDest)).unwrap();
+        }
+
+        let remaining = |src_root: &str| match load_checkpoint(&path, src_root, "dest", None).unwrap() {
+            Resumable::Remaining(a) => Some((a.to_delete.iter().map(|(p, _)| p.to_string()).collect::<Vec<_>>(),
+                a.to_copy.iter().map(|(p, _)| p.to_string()).collect::<Vec<_>>())),
+            Resumable::OtherSync => None,
+            Resumable::Nothing => panic!("Checkpoint not found"),
+        };
+
+        assert!(matches!(load_checkpoint(&path, "src", "dest", None), Ok(Resumable::Nothing)));
+
+        let mut checkpoint = Checkpoint::create(&path, "src", "dest", &actions).unwrap();
+        assert_eq!(remaining("src"), Some((vec!["d1".to_string(), "d2".to_string()], vec!["c1".to_string(), "c2".to_string(), "c3".to_string()])));
+        assert_eq!(remaining("other src"), None);
+
+        checkpoint.record_progress(1, 0).unwrap();
+        assert_eq!(remaining("src"), Some((vec!["d2".to_string()], vec!["c1".to_string(), "c2".to_string(), "c3".to_string()])));
+        checkpoint.record_progress(2, 2).unwrap();
+        assert_eq!(remaining("src"), Some((vec![], vec!["c3".to_string()])));
+
+        // A partially-written progress record is ignored
+        checkpoint.file.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(remaining("src"), Some((vec![], vec!["c3".to_string()])));
+
+        remove_checkpoint(&path).unwrap();
+        assert!(matches!(load_checkpoint(&path, "src", "dest", None), Ok(Resumable::Nothing)));
+    }
+}
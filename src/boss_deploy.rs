@@ -2,10 +2,11 @@ use indicatif::{HumanBytes, ProgressBar};
 use log::{debug, info};
 use std::ffi::OsStr;
 use std::io::Write;
-use std::path::{Path};
+use std::path::{Path, PathBuf};
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
+use std::time::Instant;
 use std::{
     fmt::{self, Display},
     io::{BufRead, BufReader},
@@ -17,17 +18,24 @@ use crate::*;
 use embedded_binaries::EmbeddedBinaries;
 
 /// Deploys a pre-built binary of rjrssync to the given remote computer, ready to be executed.
-pub fn deploy_to_remote(remote_hostname: &str, remote_user: &str, reason: &str,
+/// If the remote already has a (different) version of rjrssync which we deployed previously, we attempt
+/// to upgrade it by uploading just the differences.
+pub fn deploy_to_remote(remote_hostname: &str, remote_user: &str, reason: &str, existing_remote_version: Option<&str>,
     deploy_behaviour: DeployBehaviour, progress_bar: &ProgressBar)
 -> Result<(), String>
 {
     profile_this!();
 
+    let start = Instant::now();
+
     // We're about to (potentially) some output from scp/ssh, so this log message may as well be the same severity,
     // so the user knows what's happening. We log a message as well as changing the progress bar message,
     // so that any messages from ssh that follow can be easily attributed to the deployment.
-    info!("Deploying onto '{}'...", &remote_hostname);
-    progress_bar.set_message("Deploying...");
+    info!("Deploying onto '{}' (reason: {})...", &remote_hostname, reason);
+    // Deploying can take a while, so show which stage we're at so that it doesn't look like we've hung.
+    // Note that we can't use an animated spinner here, as it would clash with any prompts from ssh/scp.
+    let set_stage = |stage: &str| progress_bar.set_message(format!("Deploying onto '{remote_hostname}': {stage}..."));
+    set_stage("checking remote platform");
 
     let user_prefix = if remote_user.is_empty() {
         "".to_string()
@@ -70,11 +78,19 @@ pub fn deploy_to_remote(remote_hostname: &str, remote_user: &str, reason: &str,
     let binary_filename = staging_dir.join("rjrssync").with_extension(binary_extension);
 
     // Generate a big binary for this platform into the staging folder, if we can.
+    set_stage("preparing binary");
     let binary_size = match create_binary_for_target(&os_test_output, &binary_filename) {
         Ok(s) => s,
         Err(e) => return Err(format!("Error generating binary to deploy: {}", e)),
     };
 
+    // If we have a copy of the binary that is already on the remote (from when we deployed it), then we can
+    // upload just the differences to the new binary, which is much quicker on a slow connection.
+    let target_key = get_compatible_target_triples(&os_test_output)?[0];
+    let delta = existing_remote_version.and_then(|v| get_deploy_cache_path(target_key, v))
+        .and_then(|base_path| create_deploy_delta(&base_path, &binary_filename))
+        .filter(|d| (d.len() as u64) < binary_size);
+
     let (remote_temp, remote_rjrssync_folder) = if is_windows {
         (REMOTE_TEMP_WINDOWS, format!("{REMOTE_TEMP_WINDOWS}\\rjrssync"))
     } else {
@@ -85,8 +101,12 @@ pub fn deploy_to_remote(remote_hostname: &str, remote_user: &str, reason: &str,
     // (we're copying something onto the device that wasn't explicitly requested,
     // so the user should probably be aware).
     let msg = format!("rjrssync needs to be deployed onto remote target {remote_hostname} because {reason}. \
-        A pre-built {} binary will be uploaded into the folder '{remote_rjrssync_folder}'",
-        HumanBytes(binary_size));
+        A pre-built {} binary will be uploaded into the folder '{remote_rjrssync_folder}'{}",
+        HumanBytes(binary_size),
+        match &delta {
+            Some(d) => format!(" (or just a {} upgrade to the existing binary, if possible)", HumanBytes(d.len() as u64)),
+            None => "".to_string(),
+        });
     let resolved_behaviour = match deploy_behaviour {
         DeployBehaviour::Prompt => {
             let prompt_result = resolve_prompt(format!("{msg}. What do?"),
@@ -105,22 +125,42 @@ pub fn deploy_to_remote(remote_hostname: &str, remote_user: &str, reason: &str,
         DeployBehaviour::Ok => (), // Continue with deployment
     };
 
-    // Deploy to remote target using scp
-    // Note we need to deal with the case where the the remote folder doesn't exist, and the case where it does, so
-    // we copy into /tmp (which should always exist), rather than directly to /tmp/rjrssync which may or may not
-    let source_spec = staging_dir;
-    let remote_spec = format!("{user_prefix}{remote_hostname}:{remote_temp}");
-    debug!("Copying {} to {}", source_spec.display(), remote_spec);
-    match run_process_with_live_output("scp", &[OsStr::new("-r"), source_spec.as_os_str(), OsStr::new(&remote_spec)]) {
-        Err(e) => return Err(format!("Error running scp: {}", e)),
-        Ok(s) if s.exit_status.success() => {
-            // Good!
-        },
-        Ok(s) => return Err(format!("Error copying pre-built binary. Exit status from scp: {}", s.exit_status)),
+    // Try upgrading the existing binary first, falling back to uploading the full binary if that doesn't work
+    // (e.g. the remote binary isn't the one we expected, or is too old to support upgrading).
+    let upgraded = match delta {
+        Some(d) => {
+            set_stage(&format!("uploading {} upgrade", HumanBytes(d.len() as u64)));
+            match deploy_delta(&user_prefix, remote_hostname, remote_temp, &remote_rjrssync_folder, is_windows, &d) {
+                Ok(()) => true,
+                Err(e) => {
+                    info!("Unable to upgrade existing binary ({e}). Uploading full binary instead.");
+                    false
+                }
+            }
+        }
+        None => false,
     };
 
+    if !upgraded {
+        set_stage(&format!("uploading {} binary", HumanBytes(binary_size)));
+        // Deploy to remote target using scp
+        // Note we need to deal with the case where the the remote folder doesn't exist, and the case where it does, so
+        // we copy into /tmp (which should always exist), rather than directly to /tmp/rjrssync which may or may not
+        let source_spec = &staging_dir;
+        let remote_spec = format!("{user_prefix}{remote_hostname}:{remote_temp}");
+        debug!("Copying {} to {}", source_spec.display(), remote_spec);
+        match run_process_with_live_output("scp", &[OsStr::new("-r"), source_spec.as_os_str(), OsStr::new(&remote_spec)]) {
+            Err(e) => return Err(format!("Error running scp: {}", e)),
+            Ok(s) if s.exit_status.success() => {
+                // Good!
+            },
+            Ok(s) => return Err(format!("Error copying pre-built binary. Exit status from scp: {}", s.exit_status)),
+        };
+    }
+
     // Make sure the remote exe is executable (on Linux this is required)
     if !is_windows {
+        set_stage("setting executable permission");
         // Note that we could merge this ssh command with the one to run the program once it's built (in launch_doer_via_ssh),
         // but this would make error reporting slightly more difficult as the command in launch_doer_via_ssh is more tricky as
         // we are parsing the stdout, but for the command here we can wait for it to finish easily.
@@ -135,8 +175,16 @@ pub fn deploy_to_remote(remote_hostname: &str, remote_user: &str, reason: &str,
         };
     }
 
+    // Remember what we deployed, so that it can be upgraded more efficiently next time.
+    // This isn't essential, so errors are ignored.
+    if let Some(cache_path) = get_deploy_cache_path(target_key, &boss_doer_interface::get_version_string()) {
+        if let Err(e) = create_private_dir(cache_path.parent().unwrap()).and_then(|_| std::fs::copy(&binary_filename, &cache_path)) {
+            debug!("Unable to save deployed binary to {}: {e}", cache_path.display());
+        }
+    }
+
     // Log message here to delineate any ssh messages printed above
-    info!("Deploy successful!");
+    info!("Deploy successful! Deployed onto '{}' in {:.1}s", remote_hostname, start.elapsed().as_secs_f32());
 
     Ok(())
 }
@@ -276,6 +324,109 @@ where S : std::io::Read {
     }
 }
 
+/// Gets the target triples of binaries that would run on the remote target, based on the output
+/// of our remote OS test command. The first is used to identify the platform.
+fn get_compatible_target_triples(os_test_output: &str) -> Result<Vec<&'static str>, String> {
+    // The embedded binaries might have different target triples depending on how it was build,
+    // e.g. -gnu vs -msvc suffixes, so we need to be somewhat flexible here.
+    if os_test_output.contains("Windows") && os_test_output.contains("AMD64") {
+        Ok(vec!["x86_64-pc-windows-msvc", "x86_64-pc-windows-gnu"])
+    } else if os_test_output.contains("Linux") && os_test_output.contains("x86_64") {
+        Ok(vec!["x86_64-unknown-linux-musl", "x86_64-unknown-linux-gnu"])
+    } else if os_test_output.contains("Linux") && os_test_output.contains("aarch64") {
+        Ok(vec!["aarch64-unknown-linux-musl", "aarch64-unknown-linux-gnu"])
+    } else {
+        Err(format!("Unknown target platform: {os_test_output}"))
+    }
+}
+
+/// Computes the digest (see compute_binary_digest) of the binary that we would deploy to a target with the given
+/// target triple, so that we can check that a previously deployed binary hasn't been corrupted.
+pub fn get_expected_binary_digest(target_triple: &str, challenge: &[u8; 16]) -> Result<Vec<u8>, String> {
+    let staging_dir = TempDir::new("rjrssync-verify-staging").map_err(|e| format!("Error creating temp dir: {e}"))?;
+    let binary_filename = staging_dir.path().join("rjrssync");
+    create_binary_for_target_triples(&[target_triple], &binary_filename)?;
+    let binary = std::fs::read(&binary_filename).map_err(|e| format!("Error reading {}: {e}", binary_filename.display()))?;
+    Ok(boss_doer_interface::compute_binary_digest(challenge, &binary))
+}
+
+/// Where we keep a copy of each binary that we deploy, so that it can be used as the base
+/// for an upgrade next time.
+/// This is in the user's own cache folder rather than the shared temp folder, so that other users can't
+/// plant a different base binary there. None if we can't find the user's cache folder.
+fn get_deploy_cache_path(target_key: &str, version: &str) -> Option<PathBuf> {
+    let user_cache_dir = if cfg!(windows) {
+        std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+    } else {
+        std::env::var_os("XDG_CACHE_HOME").map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|h| PathBuf::from(h).join(".cache")))
+    };
+    user_cache_dir.map(|d| d.join("rjrssync").join("deploy-cache").join(format!("{target_key}-{version}")))
+}
+
+/// Creates the given folder (and any ancestors) so that only the current user can access it.
+/// On Windows, the user's local app data folder is already private, so there's nothing extra to do.
+fn create_private_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+        std::fs::DirBuilder::new().recursive(true).mode(0o700).create(path)?;
+        // In case it already existed with different permissions
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o700))
+    }
+    #[cfg(not(unix))]
+    std::fs::create_dir_all(path)
+}
+
+/// Creates a delta between a previously deployed binary (if we still have it) and the new one.
+fn create_deploy_delta(base_path: &Path, new_path: &Path) -> Option<Vec<u8>> {
+    let base = std::fs::read(base_path).ok()?;
+    let new = std::fs::read(new_path).ok()?;
+    match binary_delta::create_delta(&base, &new) {
+        Ok(d) => Some(d),
+        Err(e) => {
+            debug!("Unable to create delta against {}: {e}", base_path.display());
+            None
+        }
+    }
+}
+
+/// Upgrades the existing rjrssync on the remote target by uploading a delta and getting the
+/// existing rjrssync to apply it to itself.
+fn deploy_delta(user_prefix: &str, remote_hostname: &str, remote_temp: &str, remote_rjrssync_folder: &str,
+    is_windows: bool, delta: &[u8]) -> Result<(), String>
+{
+    // Use the same staging folder structure as for a full deploy, so that the delta ends up in the same remote folder
+    let staging_dir = TempDir::new("rjrssync-deploy-delta-staging").map_err(|e| format!("Error creating temp dir: {e}"))?;
+    let staging_dir = staging_dir.path().join("rjrssync");
+    std::fs::create_dir_all(&staging_dir).map_err(|e| format!("Error creating staging dir {}: {e}", staging_dir.display()))?;
+    std::fs::write(staging_dir.join("rjrssync.delta"), delta).map_err(|e| format!("Error saving delta: {e}"))?;
+
+    let remote_spec = format!("{user_prefix}{remote_hostname}:{remote_temp}");
+    debug!("Copying {} to {}", staging_dir.display(), remote_spec);
+    match run_process_with_live_output("scp", [OsStr::new("-r"), staging_dir.as_os_str(), OsStr::new(&remote_spec)]) {
+        Err(e) => return Err(format!("Error running scp: {e}")),
+        Ok(s) if s.exit_status.success() => (),
+        Ok(s) => return Err(format!("Error copying delta. Exit status from scp: {}", s.exit_status)),
+    };
+
+    // The new binary is written to a separate file and then moved into place, so that we don't
+    // break the existing binary if something goes wrong
+    let remote_command = if is_windows {
+        format!("cd /d {remote_rjrssync_folder} && rjrssync.exe --apply-delta rjrssync.exe rjrssync.delta rjrssync.new \
+            && move /Y rjrssync.new rjrssync.exe >nul && del rjrssync.delta")
+    } else {
+        format!("cd {remote_rjrssync_folder} && ./rjrssync --apply-delta rjrssync rjrssync.delta rjrssync.new \
+            && mv rjrssync.new rjrssync && rm rjrssync.delta")
+    };
+    debug!("Running remote command: {}", remote_command);
+    match run_process_with_live_output("ssh", &[user_prefix.to_string() + remote_hostname, remote_command]) {
+        Err(e) => Err(format!("Error running ssh: {e}")),
+        Ok(s) if s.exit_status.success() => Ok(()),
+        Ok(s) => Err(format!("Error applying delta. Exit status from ssh: {}", s.exit_status)),
+    }
+}
+
 /// Attempts to create an rjrssync binary that can be deployed to a target platform.
 ///
 /// This is quite confusing because of the recursive resource embedding.
@@ -299,18 +450,11 @@ where S : std::io::Read {
 /// copy ourselves directly - no need to recreate what we already have. This means that even
 /// a lite binary can be deployed to remote targets as long as they are the same platform.
 fn create_binary_for_target(os_test_output: &str, output_binary_filename: &Path) -> Result<u64, String> {
-    // The embedded binaries might have different target triples depending on how it was build,
-    // e.g. -gnu vs -msvc suffixes, so we need to be somewhat flexible here.
-    let compatible_target_triples = if os_test_output.contains("Windows") && os_test_output.contains("AMD64") {
-        vec!["x86_64-pc-windows-msvc", "x86_64-pc-windows-gnu"]
-    } else if os_test_output.contains("Linux") && os_test_output.contains("x86_64") {
-        vec!["x86_64-unknown-linux-musl", "x86_64-unknown-linux-gnu"]
-    } else if os_test_output.contains("Linux") && os_test_output.contains("aarch64") {
-        vec!["aarch64-unknown-linux-musl", "aarch64-unknown-linux-gnu"]
-    } else {
-        return Err(format!("Unknown target platform: {os_test_output}"));
-    };
+    create_binary_for_target_triples(&get_compatible_target_triples(os_test_output)?, output_binary_filename)
+}
 
+/// Creates an rjrssync binary for the first available of the given target triples (see create_binary_for_target).
+fn create_binary_for_target_triples(compatible_target_triples: &[&str], output_binary_filename: &Path) -> Result<u64, String> {
     // If the target is simply the same as what we are already running on, we can use our current
     // binary - no need to recreate what we already have.
     // Note that the env var TARGET is set (forwarded) by us in build.rs
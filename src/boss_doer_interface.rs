@@ -1,8 +1,10 @@
+use aes_gcm::{Aes128Gcm, KeyInit, Nonce, aead::{Aead, Payload}, aead::generic_array::GenericArray};
 use indicatif::HumanBytes;
 use regex::{RegexSet};
 use serde::{Deserialize, Serialize, Serializer, Deserializer, de::Error};
 use std::{
     fmt::{self},
+    ops::RangeInclusive,
     time::{SystemTime}
 };
 
@@ -34,6 +36,28 @@ pub const HANDSHAKE_STARTED_MSG: &str = "rjrssync doer v"; // Version number wil
 // is listening on a network port for a connection.
 pub const HANDSHAKE_COMPLETED_MSG: &str = "Waiting for incoming network connection on port "; // Port number will be appended.
 
+/// Any files or folders that rjrssync creates inside a sync root for its own bookkeeping (e.g. lock, state or
+/// partially-received files) have names starting with this. The doer never reports these as entries when listing,
+/// so they are never synced or deleted, and rjrssync doesn't end up fighting with its own files. They are listed
+/// separately though, so that the user is warned about any of their own files which happen to use this prefix.
+pub const INTERNAL_ENTRY_PREFIX: &str = ".rjrssync-";
+
+/// Checks if the given file/folder name (the final component of a path only) is one of rjrssync's own
+/// bookkeeping entries (see INTERNAL_ENTRY_PREFIX).
+pub fn is_internal_entry_name(name: &str) -> bool {
+    name.starts_with(INTERNAL_ENTRY_PREFIX)
+}
+
+/// Computes a digest of an rjrssync binary, keyed with a random challenge from the boss so that it can't
+/// simply be recorded and replayed (see --verify-remote-binary).
+/// This is the AES-GCM authentication tag for the binary's data (i.e. GMAC), as we already have AES-GCM
+/// available for the encrypted comms.
+pub fn compute_binary_digest(challenge: &[u8; 16], binary: &[u8]) -> Vec<u8> {
+    let cipher = Aes128Gcm::new(GenericArray::from_slice(challenge));
+    // Each challenge is only used once, so a fixed nonce is fine
+    cipher.encrypt(&Nonce::default(), Payload { msg: &[], aad: binary }).expect("Encrypting nothing shouldn't fail")
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Filters {
     /// Use a RegexSet rather than separate Regex objects for better performance.
@@ -46,6 +70,8 @@ pub struct Filters {
     pub regex_set: RegexSet,
     /// For each regex in the RegexSet above, is it an include filter or an exclude filter.
     pub kinds: Vec<FilterKind>,
+    /// For each regex in the RegexSet above, whether it only applies to folders (written with a trailing slash).
+    pub folders_only: Vec<bool>,
 }
 
 /// Serializes a RegexSet by serializing the patterns (strings) that it was originally created from.
@@ -99,18 +125,41 @@ pub enum Command {
     // as the boss may need to do something before we send it all the rest of the entries
     SetRoot {
         root: String, // Note this doesn't use a RootRelativePath as it isn't relative to the root - it _is_ the root!
+        /// Whether to get the flags of files, and clear them from files that need overwriting or deleting (see --flags).
+        file_flags: bool,
+        /// Whether to flush files and folders to disk after changing them (see --fsync).
+        fsync: bool,
+        /// Limit the rate (in bytes per second) at which file contents are received (see --bwlimit-up).
+        /// This is done by the doer, so that it happens alongside the boss fetching more data from the source.
+        bwlimit: Option<u64>,
+        /// Whether this is continuing an interrupted sync (see --resume). The checkpoint might be slightly behind
+        /// what was actually done, so entries that are already deleted or created aren't treated as errors.
+        resuming: bool,
     },
     GetEntries {
         filters: Filters,
+        /// If set, entries whose details can't be read are reported with UnreadableEntry and skipped,
+        /// rather than stopping with an error (see --ignore-read-errors).
+        skip_unreadable: bool,
+        /// What to do with symlinks whose targets point outside the root. If not set, they are reported as normal.
+        safe_links: Option<SafeLinksBehaviour>,
     },
     CreateRootAncestors,
     GetFileContent {
         path: RootRelativePath,
+        /// Whether to compress the data we send back (unless it turns out to not compress well).
+        compress: bool,
+    },
+    /// Asks for the SHA-256 checksum of a file's contents, for the manifest (see --manifest-out).
+    GetFileChecksum {
+        path: RootRelativePath,
     },
     CreateOrUpdateFile {
         path: RootRelativePath,
         #[serde(with = "serde_bytes")] // Make serde fast
         data: Vec<u8>,
+        /// See FileContent::uncompressed_size.
+        uncompressed_size: Option<u64>,
         // Note that SystemTime is safe to serialize across platforms, because Serde serializes this
         // as the elapsed time since UNIX_EPOCH, so it is platform-independent.
         set_modified_time: Option<SystemTime>,
@@ -126,10 +175,31 @@ pub enum Command {
     },
     CreateFolder {
         path: RootRelativePath,
+        /// If set, an existing folder at this path is left in place rather than being an error.
+        /// This is used when the boss doesn't know whether the folder exists, because it was excluded
+        /// from the dest entries (see --dest-filter), or because a sync is being resumed (see --resume).
+        allow_existing: bool,
+    },
+    /// Creates a file as a hard link to an existing file, rather than copying its contents
+    /// (see --backup-snapshots).
+    CreateHardLink {
+        path: RootRelativePath,
+        target: String, // Note this is a full path on the doer's platform, as it is outside the root
     },
     DeleteFile {
         path: RootRelativePath,
     },
+    /// Sets the flags of an existing file (see --flags). This is done after any new contents have been written
+    /// (and the modified time set), as it might make the file immutable.
+    SetFileFlags {
+        path: RootRelativePath,
+        flags: FileFlags,
+    },
+    /// Removes all the folders inside the root which are empty (including those which only contained
+    /// other empty folders), apart from those excluded by the filters (see --prune-empty-dirs).
+    PruneEmptyFolders {
+        filters: Filters,
+    },
     DeleteFolder {
         path: RootRelativePath,
     },
@@ -138,6 +208,29 @@ pub enum Command {
         kind: SymlinkKind,
     },
 
+    /// Creates a new empty folder in the doer's temporary folder, to be used as the root
+    /// when checking the connection to a host (see --check).
+    CreateTempFolder,
+
+    /// Asks the doer for a digest of its own executable, combined with the given random challenge
+    /// (see compute_binary_digest), so that the boss can check it hasn't been corrupted.
+    GetBinaryDigest {
+        challenge: [u8; 16],
+    },
+
+    /// Asks the doer to act as a relay (see --relay), by listening for a new network connection
+    /// (on the given address and one of the given ports, if specified) and forwarding everything received on it
+    /// to the given target address, and vice versa. The data is forwarded as-is, so the doer doesn't need (or get)
+    /// the key to decrypt it.
+    /// The connection must start with the given secret, otherwise it is dropped, so that only the boss can use the relay.
+    StartRelay {
+        target_host: String,
+        target_port: u16,
+        bind: String,
+        ports: Option<RangeInclusive<u16>>,
+        secret: [u8; 16],
+    },
+
     ProfilingTimeSync,
 
     /// Used to mark a position in the sequence of commands, which the doer will echo back
@@ -163,16 +256,23 @@ impl std::fmt::Debug for Command {
         // Note that rust-analyzer can auto-generate the complete version of this for us (delete the function, then Ctrl+Space),
         // then we can make the tweaks that we need.
         match self {
-            Self::SetRoot { root } => f.debug_struct("SetRoot").field("root", root).finish(),
-            Self::GetEntries { filters } => f.debug_struct("GetEntries").field("filters", filters).finish(),
+            Self::SetRoot { root, file_flags, fsync, bwlimit, resuming } => f.debug_struct("SetRoot").field("root", root).field("file_flags", file_flags).field("fsync", fsync).field("bwlimit", bwlimit).field("resuming", resuming).finish(),
+            Self::GetEntries { filters, skip_unreadable, safe_links } => f.debug_struct("GetEntries").field("filters", filters).field("skip_unreadable", skip_unreadable).field("safe_links", safe_links).finish(),
             Self::CreateRootAncestors => write!(f, "CreateRootAncestors"),
-            Self::GetFileContent { path } => f.debug_struct("GetFileContent").field("path", path).finish(),
-            Self::CreateOrUpdateFile { path, data, set_modified_time, more_to_follow } => f.debug_struct("CreateOrUpdateFile").field("path", path).field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("set_modified_time", set_modified_time).field("more_to_follow", more_to_follow).finish(),
+            Self::GetFileContent { path, compress } => f.debug_struct("GetFileContent").field("path", path).field("compress", compress).finish(),
+            Self::GetFileChecksum { path } => f.debug_struct("GetFileChecksum").field("path", path).finish(),
+            Self::CreateOrUpdateFile { path, data, uncompressed_size, set_modified_time, more_to_follow } => f.debug_struct("CreateOrUpdateFile").field("path", path).field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("uncompressed_size", uncompressed_size).field("set_modified_time", set_modified_time).field("more_to_follow", more_to_follow).finish(),
             Self::CreateSymlink { path, kind, target } => f.debug_struct("CreateSymlink").field("path", path).field("kind", kind).field("target", target).finish(),
-            Self::CreateFolder { path } => f.debug_struct("CreateFolder").field("path", path).finish(),
+            Self::CreateFolder { path, allow_existing } => f.debug_struct("CreateFolder").field("path", path).field("allow_existing", allow_existing).finish(),
+            Self::CreateHardLink { path, target } => f.debug_struct("CreateHardLink").field("path", path).field("target", target).finish(),
             Self::DeleteFile { path } => f.debug_struct("DeleteFile").field("path", path).finish(),
+            Self::SetFileFlags { path, flags } => f.debug_struct("SetFileFlags").field("path", path).field("flags", flags).finish(),
+            Self::PruneEmptyFolders { filters } => f.debug_struct("PruneEmptyFolders").field("filters", filters).finish(),
             Self::DeleteFolder { path } => f.debug_struct("DeleteFolder").field("path", path).finish(),
             Self::DeleteSymlink { path, kind } => f.debug_struct("DeleteSymlink").field("path", path).field("kind", kind).finish(),
+            Self::CreateTempFolder => write!(f, "CreateTempFolder"),
+            Self::GetBinaryDigest { challenge } => f.debug_struct("GetBinaryDigest").field("challenge", challenge).finish(),
+            Self::StartRelay { target_host, target_port, bind, ports, secret: _ } => f.debug_struct("StartRelay").field("target_host", target_host).field("target_port", target_port).field("bind", bind).field("ports", ports).field("secret", &"...").finish(),
             Self::ProfilingTimeSync => write!(f, "ProfilingTimeSync"),
             Self::Marker(arg0) => f.debug_tuple("Marker").field(arg0).finish(),
             Self::Shutdown => write!(f, "Shutdown"),
@@ -189,6 +289,32 @@ pub enum SymlinkKind {
     Unknown, // Unix-only - a symlink that we couldn't determine the target type for, e.g. if it is broken.
 }
 
+/// File flags which are transferred with --flags, on platforms which support them
+/// (Linux via chattr/lsattr, BSD/macOS via chflags). These are stored in a platform-independent way
+/// so that they can be transferred between the different platforms.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileFlags {
+    /// The file can't be modified, deleted or renamed.
+    pub immutable: bool,
+    /// The file isn't included in backups made by dump.
+    pub nodump: bool,
+}
+
+/// What to do with source symlinks whose targets point outside the root (see --safe-links).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum SafeLinksBehaviour {
+    /// The symlink is skipped, as if it wasn't on the source.
+    Skip,
+    /// The symlink is copied, but with its target prefixed with "/rjrssync-munged/" so that it doesn't point anywhere.
+    Munge,
+    /// An error is raised and the sync will stop.
+    Error,
+}
+
+/// Prefixed to the target of symlinks which point outside the root, for --safe-links=munge.
+/// This is an absolute path which won't exist, so the symlink can't be followed.
+pub const MUNGED_SYMLINK_PREFIX: &str = "/rjrssync-munged/";
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum SymlinkTarget {
     /// A symlink target which we identified as a relative path and converted the slashes to
@@ -208,7 +334,9 @@ pub enum EntryDetails {
         // Note that SystemTime is safe to serialize across platforms, because Serde serializes this
         // as the elapsed time since UNIX_EPOCH, so it is platform-independent.
         modified_time: SystemTime,
-        size: u64
+        size: u64,
+        /// Only retrieved if requested (see --flags), otherwise always the default (no flags).
+        flags: FileFlags,
     },
     Folder,
     Symlink {
@@ -228,16 +356,37 @@ pub enum Response {
         platform_differentiates_symlinks: bool,
         /// Forward vs backwards slash.
         platform_dir_separator: char,
+        /// The root path with all symlinks and relative components resolved, so that the boss
+        /// can check whether the source and dest overlap. If the root doesn't exist then this
+        /// is based on its parent instead. None if this couldn't be determined.
+        canonical_root: Option<String>,
     },
 
     // The result of GetEntries is split into lots of individual messages (rather than one big list)
     // so that the boss can start doing stuff before receiving the full list.
     Entry((RootRelativePath, EntryDetails)),
-    EndOfEntries,
+    /// An entry whose details couldn't be read (or a folder whose contents couldn't be listed),
+    /// when GetEntries was asked to skip these.
+    UnreadableEntry {
+        path: RootRelativePath,
+        error: String,
+    },
+    EndOfEntries {
+        /// The number of entries that were excluded by the filters. Excluded folders count as one entry,
+        /// as their contents aren't walked.
+        num_filtered: u32,
+        /// The number of symlinks that were skipped because they point outside the root (see --safe-links).
+        num_unsafe_links: u32,
+        /// Entries that were skipped because their names are reserved for rjrssync's own files (see INTERNAL_ENTRY_PREFIX).
+        /// Folders are included but not their contents, as these aren't walked.
+        internal_entries: Vec<RootRelativePath>,
+    },
 
     FileContent {
         #[serde(with = "serde_bytes")] // Make serde fast
         data: Vec<u8>,
+        /// If set, the data has been compressed (with Deflate) and this is the size of the original data.
+        uncompressed_size: Option<u64>,
         /// If set, there is more data for this same file being sent in a following Response.
         /// This is used to split up large files so that we don't send them all in one huge message:
         ///   - better memory usage
@@ -246,6 +395,27 @@ pub enum Response {
         more_to_follow: bool,
     },
 
+    /// The result of GetFileChecksum.
+    FileChecksum([u8; 32]),
+
+    /// The result of PruneEmptyFolders - the folders which were removed.
+    EmptyFoldersPruned(Vec<RootRelativePath>),
+
+    /// The result of CreateTempFolder - the full path of the new folder on the doer's platform.
+    TempFolderCreated(String),
+
+    /// The result of GetBinaryDigest. The doer also reports the target triple it was built for,
+    /// so that the boss knows which binary to compare against.
+    BinaryDigest {
+        target_triple: String,
+        digest: Vec<u8>,
+    },
+
+    /// The result of StartRelay - the port that the doer is listening on for the connection to be relayed.
+    RelayStarted {
+        port: u16,
+    },
+
     ProfilingTimeSync(std::time::Duration),
     ProfilingData(ProcessProfilingData),
 
@@ -268,10 +438,16 @@ impl std::fmt::Debug for Response {
         // Note that rust-analyzer can auto-generate the complete version of this for us (delete the function, then Ctrl+Space),
         // then we can make the tweaks that we need.
         match self {
-            Self::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator } => f.debug_struct("RootDetails").field("root_details", root_details).field("platform_differentiates_symlinks", platform_differentiates_symlinks).field("platform_dir_separator", platform_dir_separator).finish(),
+            Self::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator, canonical_root } => f.debug_struct("RootDetails").field("root_details", root_details).field("platform_differentiates_symlinks", platform_differentiates_symlinks).field("platform_dir_separator", platform_dir_separator).field("canonical_root", canonical_root).finish(),
             Self::Entry(arg0) => f.debug_tuple("Entry").field(arg0).finish(),
-            Self::EndOfEntries => write!(f, "EndOfEntries"),
-            Self::FileContent { data, more_to_follow } => f.debug_struct("FileContent").field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("more_to_follow", more_to_follow).finish(),
+            Self::UnreadableEntry { path, error } => f.debug_struct("UnreadableEntry").field("path", path).field("error", error).finish(),
+            Self::EndOfEntries { num_filtered, num_unsafe_links, internal_entries } => f.debug_struct("EndOfEntries").field("num_filtered", num_filtered).field("num_unsafe_links", num_unsafe_links).field("internal_entries", internal_entries).finish(),
+            Self::FileContent { data, uncompressed_size, more_to_follow } => f.debug_struct("FileContent").field("data", &format!("... ({})", HumanBytes(data.len() as u64))).field("uncompressed_size", uncompressed_size).field("more_to_follow", more_to_follow).finish(),
+            Self::FileChecksum(arg0) => f.debug_tuple("FileChecksum").field(arg0).finish(),
+            Self::EmptyFoldersPruned(arg0) => f.debug_tuple("EmptyFoldersPruned").field(arg0).finish(),
+            Self::TempFolderCreated(arg0) => f.debug_tuple("TempFolderCreated").field(arg0).finish(),
+            Self::BinaryDigest { target_triple, digest } => f.debug_struct("BinaryDigest").field("target_triple", target_triple).field("digest", digest).finish(),
+            Self::RelayStarted { port } => f.debug_struct("RelayStarted").field("port", port).finish(),
             Self::ProfilingTimeSync(arg0) => f.debug_tuple("ProfilingTimeSync").field(arg0).finish(),
             Self::ProfilingData(_) => f.debug_tuple("ProfilingData").finish(),
             Self::Marker(arg0) => f.debug_tuple("Marker").field(arg0).finish(),
@@ -279,3 +455,29 @@ impl std::fmt::Debug for Response {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compute_binary_digest() {
+        let challenge = [1; 16];
+        let digest = compute_binary_digest(&challenge, b"binary");
+        assert_eq!(digest.len(), 16);
+        // Deterministic for the same inputs
+        assert_eq!(compute_binary_digest(&challenge, b"binary"), digest);
+        // But changes with either the binary or the challenge
+        assert_ne!(compute_binary_digest(&challenge, b"binarY"), digest);
+        assert_ne!(compute_binary_digest(&[2; 16], b"binary"), digest);
+    }
+
+    #[test]
+    fn test_is_internal_entry_name() {
+        assert!(is_internal_entry_name(".rjrssync-lock"));
+        assert!(is_internal_entry_name(".rjrssync-state.json"));
+        assert!(!is_internal_entry_name("rjrssync-lock"));
+        assert!(!is_internal_entry_name(".rjrssync"));
+        assert!(!is_internal_entry_name("notes.rjrssync-lock"));
+    }
+}
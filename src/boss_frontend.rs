@@ -1,20 +1,25 @@
+use std::ops::RangeInclusive;
 use std::path::Path;
 use std::process::ExitCode;
 use std::io::Write;
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use clap::{Parser, ValueEnum, CommandFactory};
 use env_logger::{Env, fmt::Color};
 use indicatif::{ProgressBar, HumanBytes, ProgressStyle};
 use log::info;
-use log::{debug, error};
+use log::{debug, error, log};
 use regex::Regex;
 use yaml_rust::{YamlLoader, Yaml};
 use lazy_static::{lazy_static};
 
 use crate::profiling::{dump_all_profiling, start_timer, stop_timer, self};
 use crate::logger_and_progress::LoggerAndProgress;
-use crate::{boss_launch::*, profile_this, function_name, boss_deploy};
+use crate::{boss_launch::*, profile_this, function_name, boss_check, boss_deploy, gitignore, doer::parse_port_range};
+use crate::boss_doer_interface::SafeLinksBehaviour;
+use crate::histogram::FileSizeHistogram;
 use crate::boss_sync::*;
 
 /// Fast rsync-like tool for incrementally copying files.
@@ -31,7 +36,7 @@ pub struct BossCliArgs {
     ///
     /// If a file or symlink is provided, only that single item will be copied (symlinks are not followed).
     /// If a folder is provided, all its contents will be copied as well, recursively. Symlinks inside the folder are never followed.
-    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries"], conflicts_with="spec")]
+    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries", "check"], conflicts_with="spec")]
     src: Option<RemotePathDesc>,
     /// The destination path. Can be existent or non-existent, local or remote. Format: [[username@]hostname:]path
     ///
@@ -51,9 +56,36 @@ pub struct BossCliArgs {
     ///
     ///   * Syncing a file to a symlink will delete the destination symlink and copy the source file its place
     ///
-    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries"], conflicts_with="spec")]
+    #[arg(required_unless_present_any=["spec", "generate_auto_complete_script", "list_embedded_binaries", "check"], conflicts_with="spec")]
     dest: Option<RemotePathDesc>,
 
+    /// Additional destinations to sync the source to, after DEST.
+    ///
+    /// Can be specified multiple times, using the same format as DEST.
+    /// The source is only queried once, and then each destination is synced in turn, which is quicker
+    /// than running rjrssync separately for each destination.
+    #[arg(long, conflicts_with="spec")]
+    extra_dest: Vec<RemotePathDesc>,
+
+    /// Preserve the folders in the source path on the destination, rather than just the final component
+    /// (like rsync's --relative).
+    ///
+    /// For example, syncing "src/deep/subdir" to "dest" will sync to "dest/src/deep/subdir".
+    /// To only preserve some of the folders, insert a "." folder in the source path to mark where the preserved
+    /// part starts, e.g. syncing "src/./deep/subdir" to "dest" will sync to "dest/deep/subdir".
+    /// For an absolute source path, the root (e.g. "/" or "C:\") isn't preserved. This applies to all syncs,
+    /// including those from a --spec file.
+    #[arg(long)]
+    relative: bool,
+
+    /// Swap the source and destination, so that e.g. "rjrssync --reverse a b" syncs from b to a.
+    ///
+    /// This is convenient for pulling back changes instead of pushing them, without re-typing the paths.
+    /// With a --spec file, this applies to every sync in the file, including the hostnames/usernames and
+    /// any per-side filters. Other command-line options (e.g. --src-filter) apply to the reversed sync.
+    #[arg(long, conflicts_with="extra_dest")]
+    reverse: bool,
+
     /// Instead of providing SRC and DEST, a YAML file can be used to define the sync.
     ///
     /// The file has the following structure:
@@ -68,6 +100,14 @@ pub struct BossCliArgs {
     ///         dest: /home/myuser/dest
     ///         # See description of the --filter parameter
     ///         filters: [ "+.*\.txt", "-garbage\.txt" ]
+    ///         # See description of the --src-filter and --dest-filter parameters
+    ///         src_filters: [ "+build/.*" ]
+    ///         dest_filters: [ "-cache" ]
+    ///         # See description of the --no-delete-pattern parameter
+    ///         no_delete_patterns: [ "notes\.txt" ]
+    ///         # See description of the --extra-dest parameter. Note that these include the hostname
+    ///         # (if not local), rather than using dest_hostname/dest_username.
+    ///         extra_dests: [ "mirror.domain.com:/home/myuser/dest", "/local/copy" ]
     ///         dest_file_newer_behaviour: error
     ///         dest_file_older_behaviour: skip
     ///         dest_entry_needs_deleting_behaviour: prompt
@@ -78,8 +118,17 @@ pub struct BossCliArgs {
     ///
     /// If the same argument is given in both the spec file and on the command-line,
     /// the command-line value will take precedence.
+    ///
+    /// Can be specified multiple times, e.g. a base spec followed by smaller per-environment overrides,
+    /// with later files taking precedence. Each setting (e.g. dest_hostname) in a later file replaces
+    /// that from earlier files. The 'syncs' lists are combined: each sync is added to the list, unless
+    /// it has the same src and dest as an earlier sync, in which case its settings replace just those
+    /// settings of that sync.
+    ///
+    /// Use '-' to read the spec from stdin. As stdin can't then be used to answer prompts,
+    /// they behave as in a non-interactive environment.
     #[arg(long, verbatim_doc_comment)]
-    spec: Option<String>,
+    spec: Vec<String>,
 
     /// Ignore or include matching entries inside a folder being synced
     ///
@@ -102,6 +151,9 @@ pub struct BossCliArgs {
     /// If a folder is excluded, then the contents of the folder will not be inspected,
     /// even if they would otherwise be included by the filters.
     ///
+    /// A filter ending with a slash (e.g. '-build/') only applies to folders, with the slash not being
+    /// part of the regex.
+    ///
     /// For example:
     ///
     ///     * --filter '+.*\.txt' --filter '-subfolder'  Syncs all files with the extension .txt, but not inside `subfolder`
@@ -109,9 +161,184 @@ pub struct BossCliArgs {
     #[arg(name="filter", long, allow_hyphen_values(true))]
     filter: Vec<String>,
 
-    /// Show which files/folders will be copied or deleted, without making any real changes.
+    /// How filters given with --filter are combined with any filters defined for each sync in a --spec file.
+    ///
+    /// This also applies to --src-filter, --dest-filter, --gitignore and --no-delete-pattern.
+    /// With 'replace', the command-line filters are used instead of the spec file filters.
+    /// With 'append', the command-line filters are added after the spec file filters, and so
+    /// can override their decisions.
+    #[arg(long, default_value="replace")]
+    filter_mode: FilterMode,
+
+    /// Read filters from a file in .gitignore syntax, including negation (!), anchoring (leading /),
+    /// folder-only patterns (trailing /) and '**'.
+    ///
+    /// The patterns are relative to the root of the sync, as if the file was in the root folder.
+    /// They are added after any --filter filters (and so can override their decisions), and follow
+    /// --filter-mode in the same way. Can be specified multiple times.
+    #[arg(long, value_name="FILE")]
+    gitignore: Vec<String>,
+
+    /// Like --filter, but only applied when listing entries on the source.
+    ///
+    /// These are added after any --filter filters (and so can override their decisions),
+    /// allowing different sets of entries to be considered on each side.
+    /// Entries excluded on the source but not on the dest look like they have been deleted from
+    /// the source, so will be deleted from the dest (subject to --dest-entry-needs-deleting and --no-delete-pattern).
+    #[arg(name="src-filter", long, allow_hyphen_values(true))]
+    src_filter: Vec<String>,
+
+    /// Like --filter, but only applied when listing entries on the dest.
+    ///
+    /// These are added after any --filter filters (and so can override their decisions).
+    /// Entries excluded on the dest are never considered for deletion, e.g. '-cache' would leave a
+    /// dest-only 'cache' folder alone (there is no equivalent of rsync's --delete-excluded).
+    /// Use --no-delete-pattern instead if you want an entry to be synced but never deleted.
+    /// Note that if the source includes an entry which is excluded on the dest, it will be copied as if it
+    /// didn't exist on the dest: files are overwritten and folders that do already exist there are kept.
+    #[arg(name="dest-filter", long, allow_hyphen_values(true))]
+    dest_filter: Vec<String>,
+
+    /// Never delete dest entries matching this regex because they don't exist on the source.
+    ///
+    /// Can be specified multiple times. Matching entries are still overwritten if they exist on
+    /// the source (unlike filters, which would skip them entirely).
+    /// The regexes are matched against the same normalized path as for --filter, and a pattern
+    /// matching a folder also applies to everything inside that folder.
+    #[arg(long, allow_hyphen_values(true))]
+    no_delete_pattern: Vec<String>,
+
+    /// When syncing multiple paths from a --spec file, carry on with the remaining syncs if one fails.
+    ///
+    /// All the failures are reported at the end. The exit code is 12 if every sync failed,
+    /// or 13 if only some of them did.
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Compress file contents when transferring them.
+    ///
+    /// This can speed up syncing to/from remote targets over a slow network, but will likely slow down
+    /// local syncs and those over a fast network.
+    #[arg(long)]
+    compress: bool,
+
+    /// File extensions which are not compressed even when --compress is given, as they are usually already compressed.
+    ///
+    /// Files are also sent uncompressed if the start of the file doesn't compress well.
+    #[arg(long, value_delimiter=',', default_value="7z,avi,bz2,deb,flac,gif,gz,jpeg,jpg,lz4,lzma,mkv,mov,mp3,mp4,ogg,png,rar,rpm,webm,webp,xz,zip,zst")]
+    skip_compress: Vec<String>,
+
+    /// Limit the rate (in bytes per second) at which file contents are downloaded from the source.
+    ///
+    /// This is the src -> boss leg of the transfer, which can be limited separately to the boss -> dest leg
+    /// (see --bwlimit-up), e.g. if the source is on a slow uplink. The limit applies to the data actually
+    /// transferred (so after any --compress) and is averaged over each chunk of a file, so short bursts may exceed it.
+    #[arg(long, value_name="BYTES_PER_SEC", value_parser=clap::value_parser!(u64).range(1..))]
+    bwlimit_down: Option<u64>,
+
+    /// Limit the rate (in bytes per second) at which file contents are uploaded to the dest.
+    ///
+    /// This is the boss -> dest leg of the transfer (see --bwlimit-down). The two legs run at the same time,
+    /// so limiting both to the same rate is no slower than limiting just one of them.
+    #[arg(long, value_name="BYTES_PER_SEC", value_parser=clap::value_parser!(u64).range(1..))]
+    bwlimit_up: Option<u64>,
+
+    /// Limit memory usage when syncing huge numbers of files, by moving the lists of entries to temporary files on disk.
+    ///
+    /// Once a list of source or destination entries (or of entries to copy or delete) holds more than this
+    /// many entries, it is moved to disk. This uses far less memory per entry, but makes the sync slower.
+    #[arg(long, value_name="NUM_ENTRIES")]
+    max_entries_in_memory: Option<usize>,
+
+    /// Transfer file flags (immutable and nodump), on platforms which support them (Linux and BSD/macOS).
+    ///
+    /// Files whose flags differ but are otherwise up-to-date just have their flags updated.
+    /// Dest files which are immutable have this flag cleared so that they can be overwritten or deleted.
+    /// Note that setting or clearing the immutable flag usually needs extra privileges (e.g. root).
+    #[arg(long)]
+    flags: bool,
+
+    /// Make sure that everything written to the destination is actually on disk before the sync finishes,
+    /// so that it survives e.g. a power cut straight afterwards.
+    ///
+    /// Each file is flushed to disk once it has been written, and each folder with entries that were
+    /// created or deleted is flushed at the end. This makes the sync slower, especially for lots of small files.
+    #[arg(long)]
+    fsync: bool,
+
+    /// Skip source files/folders that can't be read (e.g. due to permissions), rather than failing the sync.
+    ///
+    /// This covers both entries whose details can't be read while finding what needs syncing (including folders
+    /// whose contents can't be listed), and files whose contents can't be read while copying them.
+    /// Anything on the dest at these paths is left alone, and the skipped entries are listed at the end of the sync.
     #[arg(long)]
-    dry_run: bool,
+    ignore_read_errors: bool,
+
+    /// Don't sync source symlinks whose targets point outside the source root, e.g. absolute paths or relative
+    /// paths with too many ".." components, which would otherwise allow the dest to refer to anything.
+    ///
+    /// By default (--safe-links or --safe-links=skip) these symlinks are skipped, as if they weren't on the source.
+    /// With --safe-links=munge, they are copied but with "/rjrssync-munged/" prefixed to their target
+    /// (and any ".." components removed), so that they can't be followed. Targets which were already munged
+    /// are munged again. With --safe-links=error, the sync stops with an error.
+    /// Only the target path itself is checked, so any other symlinks along the way aren't followed.
+    #[arg(long, value_enum, num_args=0..=1, require_equals=true, default_missing_value="skip")]
+    safe_links: Option<SafeLinksBehaviour>,
+
+    /// Record the plan for the sync, and how much of it has been done, in this local file, so that an
+    /// interrupted sync can be continued with --resume. The file is deleted once the sync has finished.
+    ///
+    /// It's updated after each entry is copied or deleted, so it may be a little behind what was actually done
+    /// when interrupted. Redoing these few entries when resuming is harmless.
+    #[arg(long, value_name="FILE", conflicts_with_all=["backup_snapshots", "diff"])]
+    checkpoint: Option<String>,
+
+    /// If the file given by --checkpoint exists and is for this sync, carry on with the remainder of the plan
+    /// recorded in it, rather than querying and comparing everything again.
+    ///
+    /// Any changes made to the source or dest since the plan was made are not noticed, so run another sync
+    /// afterwards if that matters. Without a checkpoint file, this is a normal sync.
+    #[arg(long, requires="checkpoint")]
+    resume: bool,
+
+    /// Before making any changes, show a summary of everything that will be copied and deleted,
+    /// and ask once whether to proceed (with the option of showing the full list of entries first).
+    ///
+    /// This is instead of needing to answer a prompt for every file (although any prompts from e.g.
+    /// --dest-file-newer=prompt are still shown first). In a non-interactive environment, the sync is cancelled.
+    #[arg(long, conflicts_with_all=["dry_run", "diff"])]
+    confirm: bool,
+
+    /// Show which files/folders will be copied or deleted, without making any real changes.
+    ///
+    /// With --dry-run=detailed, the size of each file and the reason that each entry would be copied or deleted
+    /// are also shown.
+    #[arg(long, value_enum, num_args=0..=1, require_equals=true, default_missing_value="basic")]
+    dry_run: Option<DryRunLevel>,
+
+    /// Compare the source and destination and report the differences, without making any changes.
+    ///
+    /// Entries are listed as being only on the source, only on the destination or different
+    /// (according to the same comparison used for syncing, i.e. file modified times and entry types),
+    /// followed by a count of each along with the number of identical entries.
+    #[arg(long, conflicts_with_all=["backup_snapshots", "existing"])]
+    diff: bool,
+
+    /// Show the reason for deciding whether or not each file/folder/symlink needs copying or deleting.
+    ///
+    /// This can help to understand why rjrssync thinks that something is (or isn't) up-to-date.
+    /// To avoid flooding the output, only the first 100 entries are explained, unless a different
+    /// limit is given (e.g. --explain=1000).
+    #[arg(long, num_args=0..=1, require_equals=true, default_missing_value="100")]
+    explain: Option<usize>,
+
+    /// Give up if no files/folders are found for this many seconds while querying the source or dest.
+    ///
+    /// This helps to diagnose cases where listing the files hangs (e.g. an unresponsive network drive),
+    /// by reporting which side stopped responding and the last path that it found.
+    /// By default there is no timeout.
+    #[arg(long, value_name="SECONDS")]
+    query_timeout: Option<u64>,
 
     /// Hide the progress bar.
     ///
@@ -119,12 +346,48 @@ pub struct BossCliArgs {
     #[arg(long)]
     no_progress: bool,
 
+    /// What the progress bar measures while syncing.
+    ///
+    /// By default ('work'), the bar estimates the overall work remaining, counting both the size and number of files.
+    /// With 'bytes', it shows how much of the file data has been copied, which suits a few large files.
+    /// With 'files', it shows how many entries have been copied or deleted, which suits lots of small files.
+    /// With 'auto', one of 'bytes' or 'files' is picked based on the average size of the files being copied.
+    #[arg(long, default_value="work")]
+    progress_unit: ProgressUnit,
+
     /// Show additional statistics about the files and folders copied.
     //
     // This is a separate flag to --verbose, because that is more for debugging, but this is useful for normal users
     #[arg(long)]
     stats: bool,
 
+    /// Custom boundaries (in bytes) between the buckets of the file size histograms, e.g. 1024,1048576.
+    ///
+    /// By default, the buckets are powers of ten. These histograms are shown with --stats, and exported with --histogram-out.
+    #[arg(long, value_name="EDGES", value_parser=parse_histogram_buckets)]
+    histogram_buckets: Option<FileSizeHistogram>,
+
+    /// Write the source and copied file size histograms to this file, for analysis with other tools.
+    ///
+    /// If the file has a .csv extension, it is written as CSV, otherwise it is written as JSON
+    /// (one object per line, for each sync). An existing file is overwritten.
+    #[arg(long)]
+    histogram_out: Option<String>,
+
+    /// After syncing, write a manifest of the source files and their SHA-256 checksums to this file,
+    /// so that the dest can be verified independently later. An existing file is overwritten.
+    ///
+    /// The checksums are calculated by reading each source file again once the sync is done, so this adds to the time taken.
+    /// The lines are sorted by path, which is relative to the dest (or is the dest's name, if it's a file),
+    /// so by default `sha256sum -c` can be run on the manifest from inside the dest folder (or the folder containing the dest file).
+    /// See --manifest-format for other formats. With multiple syncs (e.g. from a spec file), the manifest of each is appended in turn.
+    #[arg(long, value_name="FILE", conflicts_with_all=["dry_run", "diff", "resume"])]
+    manifest_out: Option<String>,
+
+    /// The format of the manifest written with --manifest-out.
+    #[arg(long, default_value="sha256sum", requires="manifest_out")]
+    manifest_format: ManifestFormat,
+
     /// Hide all output except warnings, errors and prompts.
     #[arg(short, long, group="verbosity")]
     quiet: bool,
@@ -139,6 +402,51 @@ pub struct BossCliArgs {
     #[arg(long)]
     remote_port: Option<u16>,
 
+    /// Restrict the TCP port for the remote rjrssync to listen on to a range, e.g. 40000-40100.
+    ///
+    /// This is an alternative to --remote-port for when only a range of ports is open (e.g. in a firewall).
+    /// The first free port in the range is used.
+    #[arg(long, value_parser=parse_port_range, value_name="FIRST-LAST", conflicts_with="remote_port")]
+    remote_port_range: Option<RangeInclusive<u16>>,
+
+    /// Override the hostname/address used for the TCP connection to a remote target, in the form
+    /// SSH_HOSTNAME=DATA_HOSTNAME.
+    ///
+    /// By default the TCP connection is made to the same hostname as used for ssh, but this might not be
+    /// reachable directly, e.g. when ssh goes via a bastion or the remote target is behind NAT.
+    /// Can be specified multiple times, for different remote targets.
+    #[arg(long, value_parser=parse_data_host, value_name="SSH_HOSTNAME=DATA_HOSTNAME")]
+    data_host: Vec<(String, String)>,
+
+    /// Make the TCP connection to remote targets through an ssh port forward (ssh -L), so that the remote
+    /// rjrssync only needs to listen on its loopback interface rather than on all interfaces.
+    ///
+    /// This runs a second ssh process for each remote target, so works best with key-based authentication
+    /// (otherwise you may be prompted for a password twice).
+    #[arg(long, conflicts_with="data_host")]
+    ssh_tunnel: bool,
+
+    /// Make the TCP connection to a remote target through another host, in the form
+    /// TARGET_HOSTNAME=[RELAY_USERNAME@]RELAY_HOSTNAME.
+    ///
+    /// This is for when the remote target can't be reached directly from here, but can be from the relay host
+    /// (and the relay host can be reached from here). rjrssync is launched on the relay host too (deploying it if necessary),
+    /// and it forwards the encrypted data between here and the remote target without being able to decrypt it.
+    /// Note that ssh is still used to launch rjrssync on the remote target, so may need configuring to reach it
+    /// (e.g. with ProxyJump). Any --data-host for the remote target is the address used by the relay host.
+    /// Can be specified multiple times, for different remote targets.
+    #[arg(long, value_parser=parse_relay, value_name="TARGET_HOSTNAME=[RELAY_USERNAME@]RELAY_HOSTNAME", conflicts_with="ssh_tunnel")]
+    relay: Vec<(String, String, String)>,
+
+    /// The maximum number of remote targets to connect to (and deploy to, if necessary) at the same time.
+    ///
+    /// By default, remote targets are connected to one at a time. When syncing to many remote targets
+    /// (see --extra-dest), connecting to several at once can be quicker, but too many simultaneous ssh
+    /// connections might be throttled or refused (e.g. by a bastion host, or the ssh server's MaxStartups).
+    /// Any prompts (e.g. from --deploy=prompt) are still shown one at a time.
+    #[arg(long, default_value_t=1, value_parser=clap::value_parser!(u16).range(1..))]
+    max_connections: u16,
+
     /// Behaviour for deploying rjrssync to remote targets.
     ///
     /// If a remote target doesn't have rjrssync, or the version it has is incompatible with this version,
@@ -150,6 +458,20 @@ pub struct BossCliArgs {
     #[arg(long)]
     deploy: Option<DeployBehaviour>,
 
+    /// Check that a previously deployed copy of rjrssync on a remote target is exactly the one that would be deployed
+    /// now, rather than just checking that its version is compatible.
+    ///
+    /// This detects a remote binary that has been corrupted, in which case it is deployed again (subject to --deploy).
+    /// It is not a defence against a binary that has been deliberately modified, as that could still pass the check.
+    /// The remote rjrssync responds to a random challenge by reading its entire binary each time,
+    /// so this makes connecting a little slower.
+    ///
+    /// Note that a binary deployed by a different build of the same version (e.g. built on another computer)
+    /// also fails the check. If several builds connect to the same remote target, each will re-deploy
+    /// its own binary every time it connects.
+    #[arg(long)]
+    verify_remote_binary: bool,
+
     /// Behaviour when a file exists on both source and destination sides, but the destination file has a newer modified timestamp.
     ///
     /// This might indicate that data is about to be unintentionally lost.
@@ -191,6 +513,50 @@ pub struct BossCliArgs {
     #[arg(long)]
     dest_root_needs_deleting: Option<DestRootNeedsDeletingBehaviour>,
 
+    /// If the destination root is a symlink to a folder and the source is a folder, sync into the folder
+    /// that the symlink points to, rather than replacing the symlink.
+    ///
+    /// This is useful if the destination is deliberately a symlink, e.g. to redirect it to another volume.
+    #[arg(long)]
+    keep_dest_dirlink: bool,
+
+    /// If the destination root already exists but is incompatible with the source, merge the source into it
+    /// rather than replacing it, where this makes sense.
+    ///
+    /// If the source is a file (or symlink) and the destination is a folder, the file is synced into the folder
+    /// (as if the destination had a trailing slash). If the source is a folder and the destination is a symlink to
+    /// a folder, the source is synced into the folder that it points to (as for --keep-dest-dirlink).
+    /// Other combinations (e.g. a source folder and a destination file) can't be merged, so the destination root
+    /// still needs deleting, which is controlled by --dest-root-needs-deleting.
+    #[arg(long)]
+    merge_root: bool,
+
+    /// Treat the destination as a folder of dated backup snapshots. Each run syncs into a new
+    /// subfolder named after the current (UTC) time, e.g. "2023-11-14_221320.123". Files which are unchanged
+    /// since the most recent existing snapshot are hard-linked to it rather than being copied again,
+    /// so each snapshot is a complete copy of the source but only changed files take up extra space.
+    ///
+    /// Older snapshots are never modified or deleted. The destination filesystem must support hard links.
+    #[arg(long)]
+    backup_snapshots: bool,
+
+    /// Only update entries which already exist on the destination, never creating new ones.
+    /// This is useful to refresh a set of files without adding any extras.
+    ///
+    /// Entries on the destination which aren't on the source are still deleted as normal
+    /// (see --dest-entry-needs-deleting).
+    #[arg(long)]
+    existing: bool,
+
+    /// After syncing, remove any folders on the destination which are empty, for example because the
+    /// files in them were deleted but the folder itself still exists on the source.
+    /// Folders excluded by a filter (and their contents) are left alone.
+    ///
+    /// This includes folders which are empty on the source too, so these aren't created on the destination.
+    /// With --dry-run, the folders which would be removed are listed.
+    #[arg(long)]
+    prune_empty_dirs: bool,
+
     /// Behaviour when a file exists on both source and destination sides,
     /// and both files have the same modified timestamp.
     ///
@@ -223,10 +589,34 @@ pub struct BossCliArgs {
     #[arg(long)]
     all_destructive_behaviour: Option<AllDestructiveBehaviour>,
 
+    /// Show the configuration that would be used for each sync, after combining the --spec file (if any)
+    /// with the other command-line arguments, instead of performing a sync.
+    ///
+    /// The output is in the same format as a --spec file, so it includes the hosts, --deploy and, for each sync,
+    /// the paths, filters, --no-delete-pattern and the --dest-*/--files-same-time behaviours
+    /// (after applying --all-destructive-behaviour).
+    /// Options which can't be set in a spec file aren't shown, e.g. --dry-run, --merge-root, --existing,
+    /// --compress, --bwlimit-down/--bwlimit-up or the connection options like --remote-port and --ssh-tunnel.
+    #[arg(long)]
+    show_config: bool,
+
     /// List the binaries embedded inside this program ready for deployment to remote targets, instead of performing a sync.
     #[arg(long)]
     list_embedded_binaries: bool,
 
+    /// Check that rjrssync can connect to the given target, instead of performing a sync. Format: [username@]hostname
+    ///
+    /// This launches (and deploys, if necessary) rjrssync on the target, measures the round-trip latency,
+    /// and checks the filesystem can be accessed by transferring a test file to and from a temporary folder
+    /// (which is deleted afterwards) to measure the throughput. This is useful before relying on rjrssync
+    /// for a new target in an automated setup.
+    #[arg(long, value_name="TARGET", conflicts_with_all=["src", "dest", "spec"])]
+    check: Option<String>,
+
+    /// The size (in bytes) of the test file transferred by --check. Set to 0 to skip the transfer.
+    #[arg(long, default_value_t=4*1024*1024, requires="check")]
+    check_transfer_size: u64,
+
     /// Output an auto-complete script for the provided shell, instead of performing a sync.
     ///
     /// For example, to configure auto-complete for bash:
@@ -248,6 +638,44 @@ pub struct BossCliArgs {
     doer: bool,
 }
 
+/// The port(s) for remote doers to listen on, from either --remote-port or --remote-port-range.
+fn get_remote_ports(args: &BossCliArgs) -> Option<RangeInclusive<u16>> {
+    args.remote_port.map(|p| p..=p).or_else(|| args.remote_port_range.clone())
+}
+
+fn parse_data_host(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((ssh_hostname, data_hostname)) if !ssh_hostname.is_empty() && !data_hostname.is_empty() =>
+            Ok((ssh_hostname.to_string(), data_hostname.to_string())),
+        _ => Err("Expected SSH_HOSTNAME=DATA_HOSTNAME".to_string()),
+    }
+}
+
+/// Parses a comma-separated list of bucket edges (see --histogram-buckets) into an empty histogram with those buckets,
+/// so that invalid edges are reported along with any other invalid command-line arguments.
+fn parse_histogram_buckets(s: &str) -> Result<FileSizeHistogram, String> {
+    let edges = s.split(',').map(|e| e.trim().parse::<u64>().map_err(|err| format!("Invalid bucket edge '{e}': {err}")))
+        .collect::<Result<Vec<u64>, String>>()?;
+    FileSizeHistogram::with_edges(edges)
+}
+
+/// Parses TARGET_HOSTNAME=[RELAY_USERNAME@]RELAY_HOSTNAME (see --relay) into the target hostname, relay username
+/// (empty if not specified) and relay hostname.
+fn parse_relay(s: &str) -> Result<(String, String, String), String> {
+    let err = || "Expected TARGET_HOSTNAME=[RELAY_USERNAME@]RELAY_HOSTNAME".to_string();
+    let (target, relay) = s.split_once('=').ok_or_else(err)?;
+    let (user, host) = relay.split_once('@').unwrap_or(("", relay));
+    if target.is_empty() || host.is_empty() || (relay.contains('@') && user.is_empty()) {
+        return Err(err());
+    }
+    Ok((target.to_string(), user.to_string(), host.to_string()))
+}
+
+/// Looks up the relay (hostname and username) to use for the given remote target, if any (see --relay).
+fn relay_for<'a>(args: &'a BossCliArgs, hostname: &str) -> Option<(&'a str, &'a str)> {
+    args.relay.iter().find(|(h, _, _)| h == hostname).map(|(_, u, r)| (r.as_str(), u.as_str()))
+}
+
 /// Describes a local or remote path, parsed from the `src` or `dest` command-line arguments.
 #[derive(Clone, Default, PartialEq, Eq, Debug)]
 pub struct RemotePathDesc {
@@ -301,6 +729,53 @@ impl std::str::FromStr for RemotePathDesc {
         Ok(r)
     }
 }
+impl std::fmt::Display for RemotePathDesc {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if !self.username.is_empty() {
+            write!(f, "{}@", self.username)?;
+        }
+        if !self.hostname.is_empty() {
+            write!(f, "{}:", self.hostname)?;
+        }
+        write!(f, "{}", self.path)
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum FilterMode {
+    /// Filters from the command-line replace any filters from the spec file.
+    Replace,
+    /// Filters from the command-line are appended to any filters from the spec file.
+    Append,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum DryRunLevel {
+    /// Shows each entry that would be copied or deleted.
+    Basic,
+    /// As well as each entry, shows the size of each file and why each entry would be copied or deleted.
+    Detailed,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ManifestFormat {
+    /// Each line is the checksum and then the path, as written by `sha256sum`.
+    Sha256sum,
+    /// Each line is the path, size in bytes, modified time (in seconds since the Unix epoch) and checksum.
+    Detailed,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
+pub enum ProgressUnit {
+    /// The bar estimates the overall work done, accounting for both the size and number of entries.
+    Work,
+    /// The bar measures the number of bytes of file data copied.
+    Bytes,
+    /// The bar measures the number of entries copied or deleted.
+    Files,
+    /// Bytes if the files being copied are large on average, otherwise files.
+    Auto,
+}
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum, Debug)]
 pub enum DeployBehaviour {
@@ -393,11 +868,16 @@ impl Default for Spec {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct SyncSpec {
     pub src: String,
     pub dest: String,
     pub filters: Vec<String>,
+    pub src_filters: Vec<String>,
+    pub dest_filters: Vec<String>,
+    pub no_delete_patterns: Vec<String>,
+    /// Additional destinations (which may be on different hosts to `dest`) to sync the same source to.
+    pub extra_dests: Vec<RemotePathDesc>,
     pub dest_file_newer_behaviour: DestFileUpdateBehaviour,
     pub dest_file_older_behaviour: DestFileUpdateBehaviour,
     pub files_same_time_behaviour: DestFileUpdateBehaviour,
@@ -410,6 +890,10 @@ impl Default for SyncSpec {
             src: String::new(),
             dest: String::new(),
             filters: vec![],
+            src_filters: vec![],
+            dest_filters: vec![],
+            no_delete_patterns: vec![],
+            extra_dests: vec![],
             dest_file_newer_behaviour: DestFileUpdateBehaviour::Prompt,
             dest_file_older_behaviour: DestFileUpdateBehaviour::Overwrite,
             files_same_time_behaviour: DestFileUpdateBehaviour::Skip,
@@ -426,25 +910,37 @@ fn parse_string(yaml: &Yaml, key_name: &str) -> Result<String, String> {
     }
 }
 
+fn parse_string_array(yaml: &Yaml, key_name: &str) -> Result<Vec<String>, String> {
+    match yaml {
+        Yaml::Array(array_yaml) => {
+            let mut result = vec![];
+            for element_yaml in array_yaml {
+                match element_yaml {
+                    Yaml::String(x) => result.push(x.to_string()),
+                    x => return Err(format!("Unexpected value in '{}' array. Expected string, but got {:?}", key_name, x)),
+                }
+            }
+            Ok(result)
+        }
+        x => Err(format!("Unexpected value for '{}'. Expected an array, but got {:?}", key_name, x)),
+    }
+}
+
 fn parse_sync_spec(yaml: &Yaml) -> Result<SyncSpec, String> {
     let mut result = SyncSpec::default();
     for (root_key, root_value) in yaml.as_hash().ok_or("Sync value must be a dictionary")? {
         match root_key {
             Yaml::String(x) if x == "src" => result.src = parse_string(root_value, "src")?,
             Yaml::String(x) if x == "dest" => result.dest = parse_string(root_value, "dest")?,
-            Yaml::String(x) if x == "filters" => {
-                match root_value {
-                    Yaml::Array(array_yaml) => {
-                        for element_yaml in array_yaml {
-                            match element_yaml {
-                                Yaml::String(x) => result.filters.push(x.to_string()),
-                                x => return Err(format!("Unexpected value in 'filters' array. Expected string, but got {:?}", x)),
-                            }
-                        }
-                    }
-                    x => return Err(format!("Unexpected value for 'filters'. Expected an array, but got {:?}", x)),
+            Yaml::String(x) if x == "filters" => result.filters = parse_string_array(root_value, "filters")?,
+            Yaml::String(x) if x == "src_filters" => result.src_filters = parse_string_array(root_value, "src_filters")?,
+            Yaml::String(x) if x == "dest_filters" => result.dest_filters = parse_string_array(root_value, "dest_filters")?,
+            Yaml::String(x) if x == "no_delete_patterns" => result.no_delete_patterns = parse_string_array(root_value, "no_delete_patterns")?,
+            Yaml::String(x) if x == "extra_dests" => {
+                for d in parse_string_array(root_value, "extra_dests")? {
+                    result.extra_dests.push(d.parse::<RemotePathDesc>().map_err(|e| format!("Invalid value in 'extra_dests' array '{d}': {e}"))?);
                 }
-            },
+            }
             Yaml::String(x) if x == "dest_file_newer_behaviour" =>
                 result.dest_file_newer_behaviour = DestFileUpdateBehaviour::from_str(&parse_string(root_value, "dest_file_newer_behaviour")?, true)?,
             Yaml::String(x) if x == "dest_file_older_behaviour" =>
@@ -469,19 +965,59 @@ fn parse_sync_spec(yaml: &Yaml) -> Result<SyncSpec, String> {
     Ok(result)
 }
 
-fn parse_spec_file(path: &Path) -> Result<Spec, String> {
-    profile_this!();
-    let mut result = Spec::default();
+/// Parses and merges several spec files (see --spec), with later files taking precedence over earlier ones.
+/// The root keys of each file replace those from earlier files, except for 'syncs'. Each sync is added to the list
+/// from the earlier files, unless it has the same src and dest as an earlier sync, in which case its keys replace those
+/// of that sync instead (so that only some of its settings can be overridden, e.g. the filters).
+fn parse_spec_files(paths: &[String]) -> Result<Spec, String> {
+    let mut merged = yaml_rust::yaml::Hash::new();
+    for path in paths {
+        let spec_yaml = load_spec_yaml(Path::new(path)).map_err(|e| format!("Failed to parse spec file at '{path}': {e}"))?;
+        for (key, value) in spec_yaml {
+            match (key.as_str(), merged.get_mut(&key), value) {
+                (Some("syncs"), Some(Yaml::Array(merged_syncs)), Yaml::Array(syncs)) => {
+                    for sync in syncs {
+                        let same_paths = |s: &&mut Yaml| s["src"] == sync["src"] && s["dest"] == sync["dest"];
+                        match (merged_syncs.iter_mut().find(same_paths), sync) {
+                            (Some(Yaml::Hash(merged_sync)), Yaml::Hash(sync)) => merged_sync.extend(sync),
+                            (_, sync) => merged_syncs.push(sync),
+                        }
+                    }
+                }
+                (_, _, value) => { merged.insert(key, value); }
+            }
+        }
+    }
+    parse_spec_yaml(&merged).map_err(|e| match paths {
+        [path] => format!("Failed to parse spec file at '{path}': {e}"),
+        _ => format!("Failed to parse the combination of spec files {}: {e}",
+            paths.iter().map(|p| format!("'{p}'")).collect::<Vec<_>>().join(", ")),
+    })
+}
 
-    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+/// Reads the root dictionary of a spec file, or from stdin if the path is "-".
+fn load_spec_yaml(path: &Path) -> Result<yaml_rust::yaml::Hash, String> {
+    let contents = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin()).map_err(|e| format!("Error reading from stdin: {e}"))?
+    } else {
+        std::fs::read_to_string(path).map_err(|e| e.to_string())?
+    };
     let docs = YamlLoader::load_from_str(&contents).map_err(|e| e.to_string())?;
     if docs.len() < 1 {
         // We allow >1 doc, but just ignore the rest, this might be useful for users, to use like a comments or versions
         return Err("Expected at least one YAML document".to_string());
     }
-    let doc = &docs[0];
+    match docs.into_iter().next() {
+        Some(Yaml::Hash(h)) => Ok(h),
+        _ => Err("Document root must be a dictionary".to_string()),
+    }
+}
+
+fn parse_spec_yaml(spec_yaml: &yaml_rust::yaml::Hash) -> Result<Spec, String> {
+    profile_this!();
+    let mut result = Spec::default();
 
-    for (root_key, root_value) in doc.as_hash().ok_or("Document root must be a dictionary")? {
+    for (root_key, root_value) in spec_yaml {
         match root_key {
             Yaml::String(x) if x == "src_hostname" => result.src_hostname = parse_string(root_value, "src_hostname")?,
             Yaml::String(x) if x == "src_username" => result.src_username = parse_string(root_value, "src_username")?,
@@ -618,6 +1154,25 @@ fn boss_main_impl(args: BossCliArgs, progress_bar: &ProgressBar) -> ExitCode {
         }
     }
 
+    if let Some(target) = &args.check {
+        let (username, hostname) = target.split_once('@').unwrap_or(("", target));
+        let data_hostname = args.data_host.iter().find(|(h, _)| h == hostname).map(|(_, d)| d.as_str());
+        return match boss_check::check_host(hostname, username, data_hostname, args.ssh_tunnel, get_remote_ports(&args).as_ref(), args.verify_remote_binary,
+            args.deploy.unwrap_or(Spec::default().deploy_behaviour), args.check_transfer_size, progress_bar, relay_for(&args, hostname))
+        {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(e) => {
+                error!("Check failed: {e}");
+                ExitCode::from(14)
+            }
+        };
+    }
+
+    // If the spec is coming from stdin, then stdin can't be used for prompts as well
+    if args.spec.iter().any(|s| s == "-") {
+        PROMPTS_DISABLED.store(true, Ordering::Relaxed);
+    }
+
     // Decide what to sync - defined either on the command line or in a spec file if provided
     let spec = match resolve_spec(&args) {
         Ok(s) => s,
@@ -627,6 +1182,11 @@ fn boss_main_impl(args: BossCliArgs, progress_bar: &ProgressBar) -> ExitCode {
         }
     };
 
+    if args.show_config {
+        print!("{}", format_spec(&spec));
+        return ExitCode::SUCCESS;
+    }
+
     let exit_code = execute_spec(spec, &args, progress_bar);
 
     stop_timer(timer);
@@ -642,21 +1202,54 @@ fn boss_main_impl(args: BossCliArgs, progress_bar: &ProgressBar) -> ExitCode {
     exit_code
 }
 
+/// Formats a Spec in the same YAML format as a spec file, so that it can be shown to the user
+/// (and potentially saved as a spec file).
+fn format_spec(spec: &Spec) -> String {
+    // Single-quoted YAML strings don't have any escape sequences except for quotes themselves
+    fn quote(s: &str) -> String {
+        format!("'{}'", s.replace('\'', "''"))
+    }
+    fn quote_list(l: &[String]) -> String {
+        if l.is_empty() {
+            return "[]".to_string();
+        }
+        format!("[ {} ]", l.iter().map(|s| quote(s)).collect::<Vec<String>>().join(", "))
+    }
+    fn value<T: ValueEnum>(v: T) -> String {
+        v.to_possible_value().expect("All values should be visible").get_name().to_string()
+    }
+
+    let mut result = String::new();
+    result += "# Options which can't be set in a spec file (e.g. --dry-run or --compress) aren't shown\n";
+    result += &format!("src_hostname: {}\n", quote(&spec.src_hostname));
+    result += &format!("src_username: {}\n", quote(&spec.src_username));
+    result += &format!("dest_hostname: {}\n", quote(&spec.dest_hostname));
+    result += &format!("dest_username: {}\n", quote(&spec.dest_username));
+    result += &format!("deploy_behaviour: {}\n", value(spec.deploy_behaviour));
+    result += "syncs:\n";
+    for sync in &spec.syncs {
+        result += &format!("  - src: {}\n", quote(&sync.src));
+        result += &format!("    dest: {}\n", quote(&sync.dest));
+        result += &format!("    filters: {}\n", quote_list(&sync.filters));
+        result += &format!("    src_filters: {}\n", quote_list(&sync.src_filters));
+        result += &format!("    dest_filters: {}\n", quote_list(&sync.dest_filters));
+        result += &format!("    no_delete_patterns: {}\n", quote_list(&sync.no_delete_patterns));
+        result += &format!("    extra_dests: {}\n", quote_list(&sync.extra_dests.iter().map(|d| d.to_string()).collect::<Vec<_>>()));
+        result += &format!("    dest_file_newer_behaviour: {}\n", value(sync.dest_file_newer_behaviour));
+        result += &format!("    dest_file_older_behaviour: {}\n", value(sync.dest_file_older_behaviour));
+        result += &format!("    files_same_time_behaviour: {}\n", value(sync.files_same_time_behaviour));
+        result += &format!("    dest_entry_needs_deleting_behaviour: {}\n", value(sync.dest_entry_needs_deleting_behaviour));
+        result += &format!("    dest_root_needs_deleting_behaviour: {}\n", value(sync.dest_root_needs_deleting_behaviour));
+    }
+    result
+}
+
 /// Figures out the Spec that we should execute, from a combination of the command-line args
-/// and a --spec file (if provided)
+/// and any --spec files
 fn resolve_spec(args: &BossCliArgs) -> Result<Spec, String> {
     let mut spec = Spec::default();
-    match &args.spec {
-        Some(s) => {
-            // If --spec was provided, use that as the starting point
-            spec = match parse_spec_file(Path::new(&s)) {
-                Ok(s) => s,
-                Err(e) => return Err(format!("Failed to parse spec file at '{}': {}", s, e)),
-            }
-            // Some things in the spec file are overridable by command line equivalents (behaviours, filters etc.)
-            // which is done below
-        },
-        None => {
+    match args.spec.as_slice() {
+        [] => {
             // No spec - the command-line must have the src and dest specified
             let src = args.src.as_ref().unwrap(); // Command-line parsing rules means these must be valid, if spec is not provided
             let dest = args.dest.as_ref().unwrap();
@@ -667,20 +1260,67 @@ fn resolve_spec(args: &BossCliArgs) -> Result<Spec, String> {
             spec.syncs.push(SyncSpec {
                 src: src.path.clone(),
                 dest: dest.path.clone(),
+                extra_dests: args.extra_dest.clone(),
                 ..Default::default()
             });
             // The rest of the command-line arguments are applied below (as they are also relevant
             // when a spec file is used).
         }
+        paths => {
+            // If --spec was provided, use that as the starting point
+            spec = parse_spec_files(paths)?;
+            // Some things in the spec file are overridable by command line equivalents (behaviours, filters etc.)
+            // which is done below
+        }
+    }
+
+    // Swap the sides before applying the other command-line args, so that those refer to the sync as it will be done
+    if args.reverse {
+        reverse_spec(&mut spec)?;
     }
 
     // Apply additional command-line args, which may override/augment what's in the spec file.
     if let Some(b) = args.deploy {
         spec.deploy_behaviour = b;
     }
+    let mut gitignore_texts = vec![];
+    for g in &args.gitignore {
+        let text = std::fs::read_to_string(g).map_err(|e| format!("Failed to read gitignore file '{g}': {e}"))?;
+        gitignore_texts.push(text);
+    }
     for mut sync in &mut spec.syncs {
-        if !args.filter.is_empty() {
-            sync.filters = args.filter.clone();
+        // The --gitignore filters go after the --filter filters, and follow the same --filter-mode
+        if !args.filter.is_empty() || !gitignore_texts.is_empty() {
+            let mut filters = match args.filter_mode {
+                FilterMode::Replace => vec![],
+                // Later filters take precedence, so the command-line filters go last
+                FilterMode::Append => std::mem::take(&mut sync.filters),
+            };
+            filters.extend(args.filter.iter().cloned());
+            for text in &gitignore_texts {
+                // The filters before these are needed to know whether a leading negation does anything
+                gitignore::append_filters(text, &mut filters)?;
+            }
+            sync.filters = filters;
+        }
+        // The per-side filters follow the same --filter-mode as --filter
+        if !args.src_filter.is_empty() {
+            match args.filter_mode {
+                FilterMode::Replace => sync.src_filters = args.src_filter.clone(),
+                FilterMode::Append => sync.src_filters.extend(args.src_filter.iter().cloned()),
+            }
+        }
+        if !args.dest_filter.is_empty() {
+            match args.filter_mode {
+                FilterMode::Replace => sync.dest_filters = args.dest_filter.clone(),
+                FilterMode::Append => sync.dest_filters.extend(args.dest_filter.iter().cloned()),
+            }
+        }
+        if !args.no_delete_pattern.is_empty() {
+            match args.filter_mode {
+                FilterMode::Replace => sync.no_delete_patterns = args.no_delete_pattern.clone(),
+                FilterMode::Append => sync.no_delete_patterns.extend(args.no_delete_pattern.iter().cloned()),
+            }
         }
 
         if let Some(b) = args.all_destructive_behaviour {
@@ -750,11 +1390,72 @@ fn resolve_spec(args: &BossCliArgs) -> Result<Spec, String> {
         if let Some(b) = args.dest_root_needs_deleting {
             sync.dest_root_needs_deleting_behaviour = b;
         }
+
+        if args.relative {
+            let (src, preserved) = split_relative_src_path(&sync.src)?;
+            sync.src = src;
+            sync.dest = append_relative_path(&sync.dest, &preserved);
+            for d in &mut sync.extra_dests {
+                d.path = append_relative_path(&d.path, &preserved);
+            }
+        }
     }
 
     Ok(spec)
 }
 
+/// For --reverse, swaps the source and dest of every sync in the spec, including everything that is specific to one side.
+fn reverse_spec(spec: &mut Spec) -> Result<(), String> {
+    std::mem::swap(&mut spec.src_hostname, &mut spec.dest_hostname);
+    std::mem::swap(&mut spec.src_username, &mut spec.dest_username);
+    for sync in &mut spec.syncs {
+        if !sync.extra_dests.is_empty() {
+            return Err(format!("Can't use --reverse for the sync from '{}', as it has extra dests, which would mean syncing from multiple sources", sync.src));
+        }
+        std::mem::swap(&mut sync.src, &mut sync.dest);
+        std::mem::swap(&mut sync.src_filters, &mut sync.dest_filters);
+    }
+    Ok(())
+}
+
+/// For --relative, splits a source path into the path to sync from (with any "." marker removed),
+/// and the folders which should be preserved on the dest (with forward slashes).
+/// We don't know what platform the source is on yet, so both forward and backward slashes are treated as separators.
+fn split_relative_src_path(src: &str) -> Result<(String, String), String> {
+    lazy_static! {
+        static ref MARKER_REGEX: Regex = Regex::new(r"[/\\]\.([/\\]|$)").unwrap();
+    }
+    let (src_path, preserved) = match MARKER_REGEX.find(src) {
+        // Remove the "." marker, but keep the separator before it
+        Some(m) => (src[..m.start() + 1].to_string() + &src[m.end()..], &src[m.end()..]),
+        // The whole path is preserved, apart from the root of an absolute path
+        None => {
+            let without_drive = match src.split_once(':') {
+                Some((drive, rest)) if drive.len() == 1 => rest,
+                _ => src,
+            };
+            (src.to_string(), without_drive)
+        }
+    };
+    let components: Vec<&str> = preserved.split(['/', '\\']).filter(|c| !c.is_empty() && *c != ".").collect();
+    if components.contains(&"..") {
+        return Err(format!("Can't use --relative with source path '{src}' as the preserved part contains '..'. \
+            Insert a '.' folder to mark where the preserved part starts (e.g. 'a/../b/./c')."));
+    }
+    Ok((src_path, components.join("/")))
+}
+
+/// For --relative, appends the preserved folders from the source path (see split_relative_src_path) to a dest path.
+fn append_relative_path(dest: &str, preserved: &str) -> String {
+    if preserved.is_empty() {
+        dest.to_string()
+    } else if dest.ends_with(['/', '\\']) {
+        format!("{dest}{preserved}")
+    } else {
+        format!("{dest}/{preserved}")
+    }
+}
+
 fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> ExitCode {
     // The src and/or dest may be on another computer. We need to run a copy of rjrssync on the remote
     // computer(s) and set up network commmunication.
@@ -778,64 +1479,184 @@ fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> E
     // this will clash with potential ssh output/prompts
 
     // Launch doers on remote hosts or threads on local targets and estabilish communication (check version etc.)
-    let mut src_comms = match setup_comms(
-        &spec.src_hostname,
-        &spec.src_username,
-        args.remote_port,
-        "src".to_string(),
-        spec.deploy_behaviour,
-        &progress_bar,
-    ) {
-        Ok(c) => c,
-        Err(e) => {
-            error!("Error connecting to {}: {}", spec.src_hostname, e);
-            return ExitCode::from(10);
+    let data_host_for = |hostname: &str| args.data_host.iter().find(|(h, _)| h == hostname).map(|(_, d)| d.as_str());
+    let remote_ports = get_remote_ports(args);
+    // There may be several dests (see --extra-dest), possibly on different hosts. We only need one
+    // doer for each host/user, which is re-used for all the dests on it. The first one is for the main dest.
+    let mut dest_targets: Vec<(&String, &String)> = vec![];
+    let extra_dests = spec.syncs.iter().flat_map(|s| s.extra_dests.iter().map(|d| (&d.hostname, &d.username)));
+    for target in std::iter::once((&spec.dest_hostname, &spec.dest_username)).chain(extra_dests) {
+        if !dest_targets.contains(&target) {
+            dest_targets.push(target);
         }
-    };
-    let mut dest_comms = match setup_comms(
-        &spec.dest_hostname,
-        &spec.dest_username,
-        args.remote_port,
-        "dest".to_string(),
+    }
+    // The src is always the first target
+    let targets: Vec<(&String, &String, &str)> = std::iter::once((&spec.src_hostname, &spec.src_username, "src"))
+        .chain(dest_targets.iter().map(|(h, u)| (*h, *u, "dest"))).collect();
+    let mut results = setup_comms_concurrently(&targets, args.max_connections, |(hostname, username, debug_name)| setup_comms(
+        hostname,
+        username,
+        data_host_for(hostname),
+        args.ssh_tunnel,
+        remote_ports.as_ref(),
+        args.verify_remote_binary,
+        debug_name.to_string(),
         spec.deploy_behaviour,
-        &progress_bar,
-    ) {
-        Ok(c) => c,
-        Err(e) => {
-            error!("Error connecting to {}: {}", spec.dest_hostname, e);
-            src_comms.shutdown(); // Clean shutdown
-            return ExitCode::from(11);
+        progress_bar,
+        relay_for(args, hostname),
+    )).into_iter();
+    let src_result = results.next().expect("Src is always first");
+    let dest_results: Vec<Option<Result<Comms, String>>> = results.collect();
+
+    // If anything failed, report the (first) error and cleanly shutdown any doers that we did connect to
+    let first_error = std::iter::once(&src_result).chain(dest_results.iter()).zip(targets.iter())
+        .find_map(|(r, (h, _, d))| match r { Some(Err(e)) => Some((h.to_string(), *d, e.clone())), _ => None });
+    if let Some((hostname, debug_name, e)) = first_error {
+        error!("Error connecting to {}: {}", hostname, e);
+        for c in std::iter::once(src_result).chain(dest_results).flatten().flatten() {
+            c.shutdown();
+        }
+        return ExitCode::from(if debug_name == "src" { 10 } else { 11 });
+    }
+    let mut src_comms = src_result.expect("No errors so all were attempted").expect("No errors");
+    let mut dest_comms_list: Vec<(String, String, Comms)> = dest_targets.iter().zip(dest_results)
+        .map(|((h, u), r)| (h.to_string(), u.to_string(), r.expect("No errors so all were attempted").expect("No errors")))
+        .collect();
+    let shutdown_all = |src_comms: Comms, dest_comms_list: Vec<(String, String, Comms)>| {
+        src_comms.shutdown();
+        for (_, _, c) in dest_comms_list {
+            c.shutdown();
         }
     };
 
-    // Perform the actual file sync(s)
-    for sync_spec in &spec.syncs {
-        // Indicate which sync this is, if there are many
-        if spec.syncs.len() > 1 {
-            info!("{} => {}:", sync_spec.src, sync_spec.dest);
+    // Show how we're communicating with each doer, as this can make a big difference to performance
+    // (shown at debug level normally, so it's still visible with --verbose)
+    let level = if args.stats { log::Level::Info } else { log::Level::Debug };
+    for comms in std::iter::once(&src_comms).chain(dest_comms_list.iter().map(|(_, _, c)| c)) {
+        log!(level, "{comms} transport: {}", comms.transport_description(args.compress));
+    }
+
+    // Each sync appends to the histogram file, so clear any previous contents first
+    if let Some(h) = &args.histogram_out {
+        if let Err(e) = std::fs::write(h, "") {
+            error!("Failed to create histogram file '{}': {}", h, e);
+            shutdown_all(src_comms, dest_comms_list);
+            return ExitCode::from(12);
+        }
+    }
+    // Similarly for the manifest
+    if let Some(m) = &args.manifest_out {
+        if let Err(e) = std::fs::write(m, "") {
+            error!("Failed to create manifest file '{}': {}", m, e);
+            shutdown_all(src_comms, dest_comms_list);
+            return ExitCode::from(12);
         }
+    }
+
+    let sync_options = SyncOptions {
+        dry_run: args.dry_run.is_some(),
+        dry_run_detailed: args.dry_run == Some(DryRunLevel::Detailed),
+        diff: args.diff,
+        confirm: args.confirm,
+        // No point showing progress when doing a dry run or diff
+        show_progress: !args.no_progress && args.dry_run.is_none() && !args.diff,
+        progress_unit: args.progress_unit,
+        show_stats: args.stats,
+        same_host: spec.src_hostname == spec.dest_hostname,
+        keep_dest_dirlink: args.keep_dest_dirlink,
+        merge_root: args.merge_root,
+        backup_snapshots: args.backup_snapshots,
+        existing_only: args.existing,
+        prune_empty_dirs: args.prune_empty_dirs,
+        explain_limit: args.explain,
+        file_size_histogram: args.histogram_buckets.clone().unwrap_or_default(),
+        histogram_out: args.histogram_out.clone(),
+        manifest_out: args.manifest_out.clone(),
+        manifest_format: args.manifest_format,
+        query_timeout: args.query_timeout.map(Duration::from_secs),
+        compress: args.compress,
+        skip_compress: args.skip_compress.iter().map(|e| e.trim_start_matches('.').to_lowercase()).collect(),
+        bwlimit_down: args.bwlimit_down,
+        bwlimit_up: args.bwlimit_up,
+        max_entries_in_memory: args.max_entries_in_memory,
+        ignore_read_errors: args.ignore_read_errors,
+        file_flags: args.flags,
+        safe_links: args.safe_links,
+        fsync: args.fsync,
+        checkpoint: args.checkpoint.clone(),
+        resume: args.resume,
+    };
 
-        // No point showing progress when doing a dry run
-        let show_progress = !args.no_progress && !args.dry_run;
-        let sync_result = sync(&sync_spec, args.dry_run, &progress_bar, show_progress,
-            args.stats, &mut src_comms, &mut dest_comms);
+    // Perform the actual file sync(s). Each extra dest counts as a separate sync.
+    let num_syncs: usize = spec.syncs.iter().map(|s| 1 + s.extra_dests.len()).sum();
+    let mut failures = vec![];
+    for sync_spec in &spec.syncs {
+        // The source entries are remembered from the first dest, so that they can be re-used for the extra dests
+        let mut src_entries_cache = SrcEntriesCache::default();
+        let main_dest = RemotePathDesc {
+            hostname: spec.dest_hostname.clone(),
+            username: spec.dest_username.clone(),
+            path: sync_spec.dest.clone(),
+        };
+        for dest in std::iter::once(&main_dest).chain(&sync_spec.extra_dests) {
+            // Indicate which sync this is, if there are many
+            if num_syncs > 1 {
+                info!("{} => {}:", sync_spec.src, dest);
+            }
 
-        match sync_result {
-            Ok(()) => (),
-            Err(e) => {
+            let dest_sync_spec = SyncSpec {
+                dest: dest.path.clone(),
+                extra_dests: vec![],
+                ..sync_spec.clone()
+            };
+            let dest_sync_options = SyncOptions {
+                same_host: spec.src_hostname == dest.hostname,
+                ..sync_options.clone()
+            };
+            let dest_comms = &mut dest_comms_list.iter_mut()
+                .find(|(h, u, _)| *h == dest.hostname && *u == dest.username)
+                .expect("Should have connected to all dests").2;
+            let cache = if sync_spec.extra_dests.is_empty() { None } else { Some(&mut src_entries_cache) };
+
+            let sync_result = sync(&dest_sync_spec, &dest_sync_options, &progress_bar, &mut src_comms, dest_comms, cache);
+
+            if let Err(e) = sync_result {
                 error!("Sync error: {}", e);
-                 // Clean shutdown
-                src_comms.shutdown();
-                dest_comms.shutdown();
-                return ExitCode::from(12);
+                if matches!(e, SyncError::QueryTimeout(_)) {
+                    // A doer might be stuck, in which case a clean shutdown would hang too, and we can't carry on
+                    src_comms.abandon();
+                    for (_, _, c) in dest_comms_list {
+                        c.abandon();
+                    }
+                    return ExitCode::from(12);
+                }
+                if !args.keep_going {
+                    // Clean shutdown
+                    shutdown_all(src_comms, dest_comms_list);
+                    return ExitCode::from(12);
+                }
+                // Make sure that the doers are in a good state for the next sync
+                if let Err(e) = wait_for_doers_idle(&mut src_comms, dest_comms) {
+                    error!("Unable to continue with remaining syncs: {}", e);
+                    shutdown_all(src_comms, dest_comms_list);
+                    return ExitCode::from(12);
+                }
+                failures.push(format!("{} => {}: {}", sync_spec.src, dest, e));
             }
         }
     }
 
     // Shutdown the comms before dumping profiling, so that any doer threads and comms threads have cleanly exited,
     // and their profiling data is saved, and we have received profiling data from any remote doer processes.
-    src_comms.shutdown();
-    dest_comms.shutdown();
+    shutdown_all(src_comms, dest_comms_list);
+
+    if !failures.is_empty() {
+        error!("{} of {} sync(s) failed:", failures.len(), num_syncs);
+        for f in &failures {
+            error!("  {}", f);
+        }
+        return ExitCode::from(if failures.len() == num_syncs { 12 } else { 13 });
+    }
 
     ExitCode::SUCCESS
 }
@@ -845,10 +1666,17 @@ fn execute_spec(spec: Spec, args: &BossCliArgs, progress_bar: &ProgressBar) -> E
 /// to respond.
 const TEST_PROMPT_RESPONSE_ENV_VAR: &str = "RJRSSYNC_TEST_PROMPT_RESPONSE";
 
+/// Set when stdin isn't available for the user to respond to prompts, in which case they behave as if the
+/// terminal is unattended.
+static PROMPTS_DISABLED: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     // We're only accessing this on one thread, but the compiler doesn't know that so we need a mutex.
     // It's only used for the prompt code, so performance should not be a concern.
     static ref TEST_PROMPT_RESPONSES: Mutex<TestPromptResponses> = Mutex::new(TestPromptResponses::from_env());
+    // Prompts can come from several threads when connecting to several remote targets at once
+    // (see --max-connections), so we make sure only one is shown at a time.
+    static ref PROMPT_LOCK: Mutex<()> = Mutex::new(());
 }
 
 struct TestPromptResponses {
@@ -895,16 +1723,25 @@ pub struct ResolvePromptResult<B> {
     pub immediate_behaviour: B,
     /// The decision that was made to be remembered for future occurences (if any)
     pub remembered_behaviour: Option<B>,
+    /// Whether the remembered decision should also apply to other categories of prompt (e.g. both deleting
+    /// and overwriting), where the caller decides what that means.
+    pub remembered_for_all_categories: bool,
 }
 impl<B: Copy> ResolvePromptResult<B> {
     fn once(b: B) -> Self {
-        Self { immediate_behaviour: b, remembered_behaviour: None }
+        Self { immediate_behaviour: b, remembered_behaviour: None, remembered_for_all_categories: false }
     }
     fn always(b: B) -> Self {
-        Self { immediate_behaviour: b, remembered_behaviour: Some(b) }
+        Self { immediate_behaviour: b, remembered_behaviour: Some(b), remembered_for_all_categories: false }
+    }
+    fn always_all_categories(b: B) -> Self {
+        Self { immediate_behaviour: b, remembered_behaviour: Some(b), remembered_for_all_categories: true }
     }
 }
 
+/// Prompts the user to choose one of the given options (or to cancel).
+/// If include_always_versions is set, each option can also be remembered for future occurences of this prompt,
+/// or for all categories of prompt (see ResolvePromptResult::remembered_for_all_categories).
 pub fn resolve_prompt<B: Copy>(prompt: String, progress_bar: Option<&ProgressBar>,
     options: &[(&str, B)], include_always_versions: bool, cancel_behaviour: B) -> ResolvePromptResult<B> {
 
@@ -913,6 +1750,7 @@ pub fn resolve_prompt<B: Copy>(prompt: String, progress_bar: Option<&ProgressBar
         if include_always_versions {
             items.push((format!("{} (just this occurence)", o.0), ResolvePromptResult::once(o.1)));
             items.push((format!("{} (all occurences)", o.0), ResolvePromptResult::always(o.1)));
+            items.push((format!("{} (all occurences and prompt categories)", o.0), ResolvePromptResult::always_all_categories(o.1)));
         } else {
             items.push((String::from(o.0), ResolvePromptResult::once(o.1)));
         }
@@ -929,8 +1767,8 @@ pub fn resolve_prompt<B: Copy>(prompt: String, progress_bar: Option<&ProgressBar
     let response_idx = match response_idx {
         Some(r) => r,
         None => {
-            if !dialoguer::console::user_attended() {
-                debug!("Unattended terminal, behaving as if prompt cancelled");
+            if !dialoguer::console::user_attended() || PROMPTS_DISABLED.load(Ordering::Relaxed) {
+                debug!("Unattended terminal or prompts disabled, behaving as if prompt cancelled");
                 items.len() - 1 // Last entry is always cancel
             } else {
                 // The prompt message provided as input to this function may have styling applied
@@ -945,6 +1783,7 @@ pub fn resolve_prompt<B: Copy>(prompt: String, progress_bar: Option<&ProgressBar
                 let prompt = prompt.replace("\x1b[0m", &format!("\x1b[0m{style_begin}"));
 
                 let f = || {
+                    let _lock = PROMPT_LOCK.lock().expect("Mutex problem");
                     let r = dialoguer::Select::with_theme(&theme)
                         .with_prompt(prompt)
                         .items(&items.iter().map(|i| &i.0).collect::<Vec<&String>>())
@@ -974,6 +1813,59 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_parse_histogram_buckets() {
+        assert_eq!(parse_histogram_buckets("1024,1048576").unwrap().bucket_ranges(),
+            vec![(0, Some(1024)), (1024, Some(1048576)), (1048576, None)]);
+        assert!(parse_histogram_buckets("1048576,1024").unwrap_err().contains("ascending order"));
+        assert!(parse_histogram_buckets("1024,1M").unwrap_err().contains("Invalid bucket edge '1M'"));
+    }
+
+    #[test]
+    fn test_parse_relay() {
+        assert_eq!(parse_relay("target=relay"), Ok(("target".to_string(), "".to_string(), "relay".to_string())));
+        assert_eq!(parse_relay("target=user@relay"), Ok(("target".to_string(), "user".to_string(), "relay".to_string())));
+        assert!(parse_relay("target").is_err());
+        assert!(parse_relay("=relay").is_err());
+        assert!(parse_relay("target=").is_err());
+        assert!(parse_relay("target=user@").is_err());
+        assert!(parse_relay("target=@relay").is_err());
+    }
+
+    #[test]
+    fn test_parse_data_host() {
+        assert_eq!(parse_data_host("bastion.example.com=10.0.0.5"), Ok(("bastion.example.com".to_string(), "10.0.0.5".to_string())));
+        assert_eq!(parse_data_host("a=b=c"), Ok(("a".to_string(), "b=c".to_string())));
+        assert!(parse_data_host("host").is_err());
+        assert!(parse_data_host("=host").is_err());
+        assert!(parse_data_host("host=").is_err());
+    }
+
+    #[test]
+    fn test_split_relative_src_path() {
+        let split = |s| split_relative_src_path(s).map(|(a, b)| (a, b.to_string()));
+        assert_eq!(split("src/deep/subdir"), Ok(("src/deep/subdir".to_string(), "src/deep/subdir".to_string())));
+        assert_eq!(split("src/./deep/subdir"), Ok(("src/deep/subdir".to_string(), "deep/subdir".to_string())));
+        assert_eq!(split(r"src\.\deep\subdir"), Ok((r"src\deep\subdir".to_string(), "deep/subdir".to_string())));
+        assert_eq!(split("/abs/./deep/"), Ok(("/abs/deep/".to_string(), "deep".to_string())));
+        assert_eq!(split("src/."), Ok(("src/".to_string(), "".to_string())));
+        assert_eq!(split("./src/deep"), Ok(("./src/deep".to_string(), "src/deep".to_string())));
+        // The root of an absolute path isn't preserved
+        assert_eq!(split("/abs/path"), Ok(("/abs/path".to_string(), "abs/path".to_string())));
+        assert_eq!(split(r"C:\abs\path"), Ok((r"C:\abs\path".to_string(), "abs/path".to_string())));
+        // '..' can't be preserved, but it's fine before the marker
+        assert!(split("../src").is_err());
+        assert_eq!(split("../src/./deep"), Ok(("../src/deep".to_string(), "deep".to_string())));
+    }
+
+    #[test]
+    fn test_append_relative_path() {
+        assert_eq!(append_relative_path("dest", "deep/subdir"), "dest/deep/subdir");
+        assert_eq!(append_relative_path("dest/", "deep/subdir"), "dest/deep/subdir");
+        assert_eq!(append_relative_path(r"C:\", "deep"), r"C:\deep");
+        assert_eq!(append_relative_path("dest", ""), "dest");
+    }
+
     #[test]
     fn parse_remote_path_desc() {
         // There's some quirks here with windows paths containing colons for drive letters
@@ -1175,6 +2067,10 @@ mod tests {
         );
     }
 
+    fn parse_spec_file(path: &Path) -> Result<Spec, String> {
+        parse_spec_files(&[path.to_str().unwrap().to_string()])
+    }
+
     #[test]
     fn test_parse_spec_file_missing() {
         let err = parse_spec_file(Path::new("does/not/exist")).unwrap_err();
@@ -1208,6 +2104,10 @@ mod tests {
             - src: T:\Source1
               dest: T:\Dest1
               filters: [ "-exclude1", "-exclude2" ]
+              src_filters: [ "+src1" ]
+              dest_filters: [ "-dest1" ]
+              no_delete_patterns: [ "keep1" ]
+              extra_dests: [ 'T:\Dest1b', "user3@computer3:/dest1c" ]
               dest_file_newer_behaviour: error
               dest_file_older_behaviour: skip
               files_same_time_behaviour: overwrite
@@ -1216,6 +2116,7 @@ mod tests {
             - src: T:\Source2
               dest: T:\Dest2
               filters: [ "-exclude3", "-exclude4" ]
+              no_delete_patterns: [ "keep2", "keep3" ]
               dest_file_newer_behaviour: prompt
               dest_file_older_behaviour: overwrite
               files_same_time_behaviour: error
@@ -1234,6 +2135,13 @@ mod tests {
                     src: "T:\\Source1".to_string(),
                     dest: "T:\\Dest1".to_string(),
                     filters: vec![ "-exclude1".to_string(), "-exclude2".to_string() ],
+                    src_filters: vec![ "+src1".to_string() ],
+                    dest_filters: vec![ "-dest1".to_string() ],
+                    no_delete_patterns: vec![ "keep1".to_string() ],
+                    extra_dests: vec![
+                        RemotePathDesc { path: "T:\\Dest1b".to_string(), ..Default::default() },
+                        RemotePathDesc { username: "user3".to_string(), hostname: "computer3".to_string(), path: "/dest1c".to_string() },
+                    ],
                     dest_file_newer_behaviour: DestFileUpdateBehaviour::Error,
                     dest_file_older_behaviour: DestFileUpdateBehaviour::Skip,
                     files_same_time_behaviour: DestFileUpdateBehaviour::Overwrite,
@@ -1244,6 +2152,10 @@ mod tests {
                     src: "T:\\Source2".to_string(),
                     dest: "T:\\Dest2".to_string(),
                     filters: vec![ "-exclude3".to_string(), "-exclude4".to_string() ],
+                    src_filters: vec![],
+                    dest_filters: vec![],
+                    no_delete_patterns: vec![ "keep2".to_string(), "keep3".to_string() ],
+                    extra_dests: vec![],
                     dest_file_newer_behaviour: DestFileUpdateBehaviour::Prompt,
                     dest_file_older_behaviour: DestFileUpdateBehaviour::Overwrite,
                     files_same_time_behaviour: DestFileUpdateBehaviour::Error,
@@ -1436,6 +2348,238 @@ mod tests {
         });
     }
 
+    /// Tests that --filter-mode=append adds the command-line filters after those in the spec file,
+    /// rather than replacing them.
+    #[test]
+    fn resolve_spec_filter_mode_append() {
+        let mut spec_file = NamedTempFile::new().unwrap();
+        write!(spec_file, r#"
+            syncs:
+            - src: a
+              dest: b
+              filters: [ +hello ]
+              no_delete_patterns: [ keep1 ]
+            - src: c
+              dest: d
+        "#).unwrap();
+
+        let args = BossCliArgs::try_parse_from(&["rjrssync",
+            "--spec", spec_file.path().to_str().unwrap(),
+            "--filter", "-meow",
+            "--no-delete-pattern", "keep2",
+            "--filter-mode=append",
+        ]).unwrap();
+        let spec = resolve_spec(&args).unwrap();
+        assert_eq!(spec.syncs[0].filters, vec!["+hello".to_string(), "-meow".to_string()]); // Appended to the spec file filters
+        assert_eq!(spec.syncs[1].filters, vec!["-meow".to_string()]); // No spec file filters to append to
+        assert_eq!(spec.syncs[0].no_delete_patterns, vec!["keep1".to_string(), "keep2".to_string()]);
+        assert_eq!(spec.syncs[1].no_delete_patterns, vec!["keep2".to_string()]);
+    }
+
+    /// Tests that --gitignore filters follow --filter-mode like --filter, and that a leading negation
+    /// is kept when there are filters before it to override.
+    #[test]
+    fn resolve_spec_gitignore_filter_mode() {
+        let mut spec_file = NamedTempFile::new().unwrap();
+        write!(spec_file, r#"
+            syncs:
+            - src: a
+              dest: b
+              filters: [ "-.*\\.log" ]
+            - src: c
+              dest: d
+        "#).unwrap();
+        let mut gitignore_file = NamedTempFile::new().unwrap();
+        write!(gitignore_file, "!keep.log\n*.o\n").unwrap();
+
+        let resolve = |mode: &str| {
+            let args = BossCliArgs::try_parse_from(["rjrssync",
+                "--spec", spec_file.path().to_str().unwrap(),
+                "--gitignore", gitignore_file.path().to_str().unwrap(),
+                &format!("--filter-mode={mode}"),
+            ]).unwrap();
+            resolve_spec(&args).unwrap()
+        };
+
+        let spec = resolve("append");
+        assert_eq!(spec.syncs[0].filters, vec!["-.*\\.log", "+(.*/)?keep\\.log", "-(.*/)?[^/]*\\.o"]);
+        assert_eq!(spec.syncs[1].filters, vec!["-(.*/)?[^/]*\\.o"]); // Nothing before the negation for it to override
+
+        let spec = resolve("replace");
+        assert_eq!(spec.syncs[0].filters, vec!["-(.*/)?[^/]*\\.o"]); // Spec file filters replaced
+        assert_eq!(spec.syncs[1].filters, vec!["-(.*/)?[^/]*\\.o"]);
+    }
+
+    /// Tests that --reverse swaps the sides of every sync in a spec file, and that the other command-line
+    /// args then apply to the reversed syncs.
+    #[test]
+    fn resolve_spec_reverse() {
+        let mut spec_file = NamedTempFile::new().unwrap();
+        write!(spec_file, r#"
+            src_hostname: computer1
+            src_username: user1
+            dest_hostname: computer2
+            syncs:
+            - src: a
+              dest: b
+              src_filters: [ +a ]
+              dest_filters: [ +b ]
+            - src: c
+              dest: d
+        "#).unwrap();
+
+        let args = BossCliArgs::try_parse_from(["rjrssync",
+            "--spec", spec_file.path().to_str().unwrap(),
+            "--reverse",
+            "--src-filter", "-meow",
+        ]).unwrap();
+        let spec = resolve_spec(&args).unwrap();
+        assert_eq!(spec, Spec {
+            src_hostname: "computer2".to_string(),
+            src_username: "".to_string(),
+            dest_hostname: "computer1".to_string(),
+            dest_username: "user1".to_string(),
+            syncs: vec![
+                SyncSpec {
+                    src: "b".to_string(),
+                    dest: "a".to_string(),
+                    src_filters: vec!["-meow".to_string()], // Replaced the swapped filters, as this applies to the new source
+                    dest_filters: vec!["+a".to_string()],
+                    ..Default::default()
+                },
+                SyncSpec {
+                    src: "d".to_string(),
+                    dest: "c".to_string(),
+                    src_filters: vec!["-meow".to_string()],
+                    ..Default::default()
+                }
+            ],
+            ..Default::default()
+        });
+
+        // Reversing a sync with extra dests would mean syncing from several sources
+        let mut spec_file = NamedTempFile::new().unwrap();
+        write!(spec_file, r#"
+            syncs:
+            - src: a
+              dest: b
+              extra_dests: [ c ]
+        "#).unwrap();
+        let args = BossCliArgs::try_parse_from(["rjrssync", "--spec", spec_file.path().to_str().unwrap(), "--reverse"]).unwrap();
+        assert!(resolve_spec(&args).unwrap_err().contains("extra dests"));
+    }
+
+    /// Tests that multiple --spec files are merged, with later files taking precedence and syncs with the
+    /// same src and dest being merged together.
+    #[test]
+    fn resolve_spec_multiple_files() {
+        let mut base_spec_file = NamedTempFile::new().unwrap();
+        write!(base_spec_file, r#"
+            src_hostname: computer1
+            dest_hostname: computer2
+            dest_username: user2
+            syncs:
+            - src: a
+              dest: b
+              filters: [ +hello ]
+              dest_file_newer_behaviour: skip
+            - src: c
+              dest: d
+        "#).unwrap();
+        let mut override_spec_file = NamedTempFile::new().unwrap();
+        write!(override_spec_file, r#"
+            dest_hostname: computer3
+            syncs:
+            - src: a
+              dest: b
+              filters: [ +goodbye ]
+            - src: a
+              dest: e
+        "#).unwrap();
+
+        let args = BossCliArgs::try_parse_from(["rjrssync",
+            "--spec", base_spec_file.path().to_str().unwrap(),
+            "--spec", override_spec_file.path().to_str().unwrap(),
+        ]).unwrap();
+        let spec = resolve_spec(&args).unwrap();
+        assert_eq!(spec, Spec {
+            src_hostname: "computer1".to_string(),
+            dest_hostname: "computer3".to_string(), // Overridden by the later spec file
+            dest_username: "user2".to_string(), // Not in the later spec file, so kept
+            syncs: vec![
+                SyncSpec {
+                    src: "a".to_string(),
+                    dest: "b".to_string(),
+                    filters: vec!["+goodbye".into()], // Overridden by the later spec file
+                    dest_file_newer_behaviour: DestFileUpdateBehaviour::Skip, // Not in the later spec file, so kept
+                    ..Default::default()
+                },
+                SyncSpec {
+                    src: "c".to_string(),
+                    dest: "d".to_string(),
+                    ..Default::default()
+                },
+                SyncSpec {
+                    src: "a".to_string(),
+                    dest: "e".to_string(), // Different dest, so a separate sync
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        });
+
+        // Errors from the merged spec mention all the files, as it could be due to any of them
+        let mut invalid_spec_file = NamedTempFile::new().unwrap();
+        write!(invalid_spec_file, "deploy_behaviour: meow").unwrap();
+        let args = BossCliArgs::try_parse_from(["rjrssync",
+            "--spec", base_spec_file.path().to_str().unwrap(),
+            "--spec", invalid_spec_file.path().to_str().unwrap(),
+        ]).unwrap();
+        assert!(resolve_spec(&args).unwrap_err().contains("Failed to parse the combination of spec files"));
+    }
+
+    /// Tests that format_spec() (used for --show-config) produces output that can be parsed back
+    /// into the same Spec.
+    #[test]
+    fn format_spec_round_trip() {
+        let spec = Spec {
+            src_hostname: "computer1".to_string(),
+            src_username: "".to_string(),
+            dest_hostname: "computer2".to_string(),
+            dest_username: "user's name".to_string(),
+            deploy_behaviour: DeployBehaviour::Force,
+            syncs: vec![
+                SyncSpec {
+                    src: "T:\\Source1".to_string(),
+                    dest: "/dest: 1".to_string(),
+                    filters: vec![ "-exclude1".to_string(), "+.*\\.txt".to_string() ],
+                    src_filters: vec![ "+src".to_string() ],
+                    dest_filters: vec![ "-dest".to_string() ],
+                    no_delete_patterns: vec![ "keep".to_string() ],
+                    extra_dests: vec![
+                        RemotePathDesc { path: "C:\\dest2".to_string(), ..Default::default() },
+                        RemotePathDesc { hostname: "computer3".to_string(), path: "/dest 3".to_string(), ..Default::default() },
+                        RemotePathDesc { username: "u".to_string(), hostname: "computer4".to_string(), path: "dest4".to_string() },
+                    ],
+                    dest_file_newer_behaviour: DestFileUpdateBehaviour::Error,
+                    dest_file_older_behaviour: DestFileUpdateBehaviour::Skip,
+                    files_same_time_behaviour: DestFileUpdateBehaviour::Overwrite,
+                    dest_entry_needs_deleting_behaviour: DestEntryNeedsDeletingBehaviour::Prompt,
+                    dest_root_needs_deleting_behaviour: DestRootNeedsDeletingBehaviour::Delete,
+                },
+                SyncSpec {
+                    src: "a".to_string(),
+                    dest: "b".to_string(),
+                    ..Default::default()
+                },
+            ]
+        };
+
+        let mut s = NamedTempFile::new().unwrap();
+        write!(s, "{}", format_spec(&spec)).unwrap();
+        assert_eq!(parse_spec_file(s.path()), Ok(spec));
+    }
+
     /// Tests that --all-destructive-behaviour overrides things set in the spec file,
     /// but can itself be overridden by individual behaviours set on the command-line.
     #[test]
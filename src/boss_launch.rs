@@ -4,16 +4,19 @@ use indicatif::ProgressBar;
 use log::{debug, error, info, log, trace};
 use std::io::LineWriter;
 use std::net::{TcpStream};
+use std::ops::RangeInclusive;
 use std::str::FromStr;
 use std::sync::mpsc;
 use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{
     fmt::{self, Display},
     io::{BufRead, BufReader, Write},
     process::{ChildStderr, ChildStdin, ChildStdout, Stdio},
     sync::mpsc::{RecvError, SendError},
+    sync::Mutex,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
     thread::JoinHandle,
 };
 
@@ -52,6 +55,12 @@ pub enum Comms {
         stderr_reading_thread: JoinHandle<()>,
 
         encrypted_comms: AsyncEncryptedComms<Command, Response>,
+        /// If the network connection goes through an ssh port forward (see --ssh-tunnel), this is
+        /// the separate ssh process providing it.
+        ssh_tunnel_process: Option<std::process::Child>,
+        /// If the network connection goes through a relay (see --relay), this is the doer on the relay host
+        /// which is forwarding it.
+        relay: Option<Box<Comms>>,
     },
 }
 impl Comms {
@@ -69,6 +78,19 @@ impl Comms {
         }
     }
 
+    /// Describes how we are communicating with the doer, including whether file contents are compressed
+    /// (see --compress), to help explain performance differences (see --stats).
+    pub fn transport_description(&self, compress: bool) -> String {
+        let channel = match self {
+            Comms::Local { .. } => "local thread (in-process channel, not encrypted)".to_string(),
+            Comms::Remote { encrypted_comms, ssh_tunnel_process, relay, .. } => format!("remote doer launched via ssh, over TCP to {}{} (encrypted with AES-128-GCM)",
+                encrypted_comms.peer_addr().map_or("unknown address".to_string(), |a| a.to_string()),
+                if ssh_tunnel_process.is_some() { " through an ssh port forward" }
+                else if relay.is_some() { " (a relay, which forwards it to the doer without decrypting it)" } else { "" }),
+        };
+        format!("{channel}, file contents {}", if compress { "compressed with deflate" } else { "not compressed" })
+    }
+
     /// This will block if there is not enough capacity in the channel, so
     /// that we don't use up infinite memory if the doer is being slow.
     pub fn send_command(&self, c: Command) -> Result<(), String> {
@@ -143,7 +165,7 @@ impl Comms {
                 let _ = self.send_command(Command::Shutdown);
 
                 // Shutdown the comms cleanly, potentially getting profiling data at the same time
-                if let Comms::Remote { encrypted_comms, mut ssh_process, stdin, stdout, stderr_reading_thread, .. } = self { // This is always true, we just need a way of getting the fields
+                if let Comms::Remote { encrypted_comms, mut ssh_process, stdin, stdout, stderr_reading_thread, ssh_tunnel_process, relay, .. } = self { // This is always true, we just need a way of getting the fields
                     // Wait for remote doers to send back any profiling data, if enabled
                     match encrypted_comms.receiver.recv() {
                         Ok(Response::ProfilingData(x)) => add_remote_profiling(x, _debug_name, profiling_offset),
@@ -152,6 +174,11 @@ impl Comms {
 
                     encrypted_comms.shutdown();
 
+                    // The port forward isn't needed any more, and won't exit by itself
+                    if let Some(p) = ssh_tunnel_process {
+                        stop_ssh_tunnel(p);
+                    }
+
                     // Wait for the ssh process to cleanly shutdown.
                     // We don't strictly need to do this for most cases, but it's nice to have a clean shutdown.
                     // We do however need to do this when the doer is printing its memory usage, to make sure that we receive it
@@ -163,6 +190,32 @@ impl Comms {
                     debug!("Waiting for ssh child process");
                     let result = ssh_process.wait();
                     debug!("ssh child process wait result = {:?}", result);
+
+                    // The relay is only needed for as long as the connection it's relaying
+                    if let Some(r) = relay {
+                        r.shutdown();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Disconnects from the doer without waiting for it to shut down, for when it might be stuck
+    /// (e.g. blocked on a slow filesystem) and so a clean shutdown would hang.
+    pub fn abandon(self) {
+        match self {
+            // Dropping the thread handle detaches the thread, and dropping the channels will make it exit
+            // as soon as it gets unstuck (or when our process exits).
+            Comms::Local { .. } => (),
+            Comms::Remote { mut ssh_process, ssh_tunnel_process, relay, .. } => {
+                if let Err(e) = ssh_process.kill() {
+                    debug!("Failed to kill ssh child process: {e}");
+                }
+                if let Some(p) = ssh_tunnel_process {
+                    stop_ssh_tunnel(p);
+                }
+                if let Some(r) = relay {
+                    r.abandon();
                 }
             }
         }
@@ -178,13 +231,25 @@ impl Display for Comms {
 }
 
 // Sets up communications with the given computer, which may be either remote or local (if remote_hostname is empty).
+// For remote computers, the network connection is made to data_hostname if provided, otherwise remote_hostname
+// (which is always used for ssh). If ssh_tunnel is set, the remote doer only listens on its loopback interface
+// and the network connection is instead made through an ssh port forward.
+// If relay is set (hostname and username), a doer is also launched on that host and the network connection
+// is made through it instead (see --relay).
+// If verify_binary is set, the remote binary is checked against the one we would deploy (see verify_remote_binary),
+// and re-deployed if it doesn't match.
+#[allow(clippy::too_many_arguments)]
 pub fn setup_comms(
     remote_hostname: &str,
     remote_user: &str,
-    remote_port_for_comms: Option<u16>,
+    data_hostname: Option<&str>,
+    ssh_tunnel: bool,
+    remote_ports_for_comms: Option<&RangeInclusive<u16>>,
+    verify_binary: bool,
     debug_name: String,
     deploy_behaviour: DeployBehaviour,
     progress_bar: &ProgressBar,
+    relay: Option<(&str, &str)>,
 ) -> Result<Comms, String> {
     profile_this!(format!("setup_comms {}", debug_name));
     debug!(
@@ -212,14 +277,23 @@ pub fn setup_comms(
         });
     }
 
+    // The relay doer is set up in the same way as any other remote doer (deploying if necessary etc.),
+    // but only once we've launched the doer that it will be relaying to.
+    let relay_debug_name = format!("{debug_name} relay");
+    let setup_relay = relay.map(|(relay_hostname, relay_user)| move || setup_comms(relay_hostname, relay_user, None, false,
+        remote_ports_for_comms, verify_binary, relay_debug_name.clone(), deploy_behaviour, progress_bar, None));
+    let setup_relay = setup_relay.as_ref().map(|f| f as &dyn Fn() -> Result<Comms, String>);
+
     // We first attempt to run a previously-deployed copy of the program on the remote, to save time.
     // If it exists and is a compatible version, we can use that. Otherwise we deploy a new version
     // and try again
-    let deploy_reason = if deploy_behaviour == DeployBehaviour::Force {
-        format!("--deploy=force was set")
+    // We also keep track of the version already on the remote (if any), so that we might be able to upgrade it
+    // rather than deploying from scratch.
+    let (deploy_reason, existing_remote_version) = if deploy_behaviour == DeployBehaviour::Force {
+        (format!("--deploy=force was set"), None)
     }
     else {
-        match launch_doer_via_ssh(remote_hostname, remote_user, remote_port_for_comms, progress_bar) {
+        match launch_doer_via_ssh(remote_hostname, remote_user, remote_ports_for_comms, ssh_tunnel, progress_bar) {
             SshDoerLaunchResult::FailedToRunSsh(e) |
             SshDoerLaunchResult::CommunicationError(e) |
             SshDoerLaunchResult::ExitedUnexpectedly(e) => {
@@ -227,28 +301,42 @@ pub fn setup_comms(
                 return Err(format!("{e}"));
             }
             SshDoerLaunchResult::NotPresentOnRemote => {
-                format!("rjrssync is not present on the remote target") // Attempt to deploy
+                (format!("rjrssync is not present on the remote target"), None) // Attempt to deploy
             }
             SshDoerLaunchResult::HandshakeIncompatibleVersion { expected, actual } => {
-                format!("the rjrssync version present on the remote target ({actual}) is not compatible with this version ({expected})") // Will attempt to deploy
+                (format!("the rjrssync version present on the remote target ({actual}) is not compatible with this version ({expected})"),
+                    Some(actual)) // Will attempt to deploy
             }
-            SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } =>
-                match connect_to_remote_doer(remote_hostname, debug_name, ssh_process, stdin, stdout, stderr, secret_key, actual_port) {
-                    Ok(c) => return Ok(c),
+            SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } => {
+                let comms = match connect_to_remote_doer(data_hostname.unwrap_or(remote_hostname), ssh_tunnel.then_some((remote_hostname, remote_user)), setup_relay, remote_ports_for_comms, debug_name.clone(), ssh_process, stdin, stdout, stderr, secret_key, actual_port) {
+                    Ok(c) => c,
                     Err(e) => return Err(format!("Failed to connect to remote: {e}")),
+                };
+                if !verify_binary {
+                    return Ok(comms);
+                }
+                match verify_remote_binary(&comms) {
+                    Ok(()) => return Ok(comms),
+                    Err(e) => {
+                        comms.shutdown();
+                        // Don't attempt to upgrade the existing binary, as it isn't what we think it is
+                        (format!("the rjrssync binary on the remote target failed verification ({e})"), None) // Will attempt to deploy
+                    }
                 }
+            }
         }
     };
 
     // New version is needed
-    if let Err(e) = deploy_to_remote(remote_hostname, remote_user, &deploy_reason, deploy_behaviour, progress_bar) {
+    if let Err(e) = deploy_to_remote(remote_hostname, remote_user, &deploy_reason, existing_remote_version.as_deref(),
+        deploy_behaviour, progress_bar) {
         return Err(format!("Failed to deploy to remote: {e}"));
     }
 
     debug!("Successfully deployed, attempting to run again");
 
     // Check again
-    match launch_doer_via_ssh(remote_hostname, remote_user, remote_port_for_comms, progress_bar) {
+    match launch_doer_via_ssh(remote_hostname, remote_user, remote_ports_for_comms, ssh_tunnel, progress_bar) {
         SshDoerLaunchResult::FailedToRunSsh(e) |
         SshDoerLaunchResult::CommunicationError(e) |
         SshDoerLaunchResult::ExitedUnexpectedly(e) => {
@@ -258,16 +346,88 @@ pub fn setup_comms(
         SshDoerLaunchResult::HandshakeIncompatibleVersion { .. }) => {
             return Err(format!("Failed to launch, even after deployment: {:?}", x));
         }
-        SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } =>
-            match connect_to_remote_doer(remote_hostname, debug_name, ssh_process, stdin, stdout, stderr, secret_key, actual_port) {
-                Ok(c) => return Ok(c),
+        SshDoerLaunchResult::Success { ssh_process, stdin, stdout, stderr, secret_key, actual_port } => {
+            let comms = match connect_to_remote_doer(data_hostname.unwrap_or(remote_hostname), ssh_tunnel.then_some((remote_hostname, remote_user)), setup_relay, remote_ports_for_comms, debug_name, ssh_process, stdin, stdout, stderr, secret_key, actual_port) {
+                Ok(c) => c,
                 Err(e) => return Err(format!("Failed to connect to remote: {e}")),
+            };
+            if verify_binary {
+                if let Err(e) = verify_remote_binary(&comms) {
+                    comms.shutdown();
+                    return Err(format!("Remote binary failed verification, even after deployment: {e}"));
+                }
             }
+            return Ok(comms);
+        }
     };
 }
 
+/// Checks that the binary that the remote doer is running is exactly the one that we would deploy,
+/// to detect a binary that has been corrupted (but still has a compatible version). This doesn't protect
+/// against a deliberately modified doer, which could compute the digest from a copy of the expected binary.
+/// A random challenge is combined with the binary, so that the doer has to actually compute the digest
+/// rather than reporting a known value.
+fn verify_remote_binary(comms: &Comms) -> Result<(), String> {
+    profile_this!();
+    let challenge: [u8; 16] = Aes128Gcm::generate_key(&mut OsRng).into();
+    comms.send_command(Command::GetBinaryDigest { challenge })?;
+    match comms.receive_response()? {
+        Response::BinaryDigest { target_triple, digest } => {
+            let expected = boss_deploy::get_expected_binary_digest(&target_triple, &challenge)?;
+            if digest != expected {
+                return Err(format!("the {target_triple} binary doesn't match the one that would be deployed"));
+            }
+            debug!("Verified {} binary on {}", target_triple, comms);
+            Ok(())
+        }
+        Response::Error(e) => Err(e),
+        x => Err(format!("Unexpected response (expected BinaryDigest): {:?}", x)),
+    }
+}
+
+/// Runs setup_comms (via the given function) for each of the given targets, with up to max_concurrent
+/// of these running at the same time (see --max-connections). Once one fails, no more are started.
+/// The results are in the same order as the targets, with None for any which weren't attempted.
+pub fn setup_comms_concurrently<T: Sync>(targets: &[T], max_concurrent: u16,
+    setup: impl Fn(&T) -> Result<Comms, String> + Sync) -> Vec<Option<Result<Comms, String>>>
+{
+    let next_idx = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let results = Mutex::new((0..targets.len()).map(|_| None).collect::<Vec<_>>());
+    let worker = || {
+        while !failed.load(Ordering::Relaxed) {
+            let idx = next_idx.fetch_add(1, Ordering::Relaxed);
+            if idx >= targets.len() {
+                break;
+            }
+            let r = setup(&targets[idx]);
+            if r.is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+            results.lock().expect("Mutex problem")[idx] = Some(r);
+        }
+    };
+
+    let num_threads = std::cmp::min(max_concurrent as usize, targets.len());
+    if num_threads <= 1 {
+        // Don't bother with any extra threads in the common case
+        worker();
+    } else {
+        thread::scope(|s| {
+            for _ in 0..num_threads {
+                s.spawn(worker);
+            }
+        });
+    }
+    results.into_inner().expect("Mutex problem")
+}
+
+#[allow(clippy::too_many_arguments)]
 fn connect_to_remote_doer(
-    remote_hostname: &str,
+    data_hostname: &str,
+    ssh_tunnel: Option<(&str, &str)>,
+    setup_relay: Option<&dyn Fn() -> Result<Comms, String>>,
+    remote_ports_for_comms: Option<&RangeInclusive<u16>>,
     debug_name: String,
     ssh_process: std::process::Child,
     stdin: LineWriter<ChildStdin>,
@@ -281,17 +441,68 @@ fn connect_to_remote_doer(
     let debug_name_clone = debug_name.clone();
     let stderr_reading_thread = std::thread::spawn(move || remote_doer_logging_thread(stderr, debug_name_clone));
 
-    // Connect to the network port that the doer should be listening on
-    let addr = (remote_hostname, actual_port);
+    // Connect to the network port that the doer should be listening on, either directly, through a port forward
+    // or through a relay
+    let mut relay = None;
+    let mut relay_secret = None;
+    let (mut ssh_tunnel_process, addr) = match (ssh_tunnel, setup_relay) {
+        (Some((remote_hostname, remote_user)), _) => {
+            let (p, local_port) = start_ssh_tunnel(remote_hostname, remote_user, actual_port)?;
+            (Some(p), ("127.0.0.1".to_string(), local_port))
+        }
+        (None, Some(setup_relay)) => {
+            let r = setup_relay().map_err(|e| format!("Failed to set up relay: {e}"))?;
+            match start_relay(&r, data_hostname, actual_port, remote_ports_for_comms) {
+                Ok((addr, secret)) => {
+                    relay = Some(Box::new(r));
+                    relay_secret = Some(secret);
+                    (None, addr)
+                }
+                Err(e) => {
+                    r.shutdown();
+                    return Err(format!("Failed to start relay: {e}"));
+                }
+            }
+        }
+        (None, None) => (None, (data_hostname.to_string(), actual_port)),
+    };
     debug!("Connecting to doer over network at {:?}", addr);
     let tcp_connection = {
         profile_this!("Connecting");
-        match TcpStream::connect(addr) {
-            Ok(t) => {
-                debug!("Connected! {:?}", t);
-                t
+        // The port forward won't be ready until ssh has connected, so keep trying until then
+        let deadline = Instant::now() + SSH_TUNNEL_TIMEOUT;
+        loop {
+            // The relay needs to know that it's us before it will relay anything
+            let connect = || -> std::io::Result<TcpStream> {
+                let mut t = TcpStream::connect((addr.0.as_str(), addr.1))?;
+                if let Some(s) = &relay_secret {
+                    t.write_all(s)?;
+                }
+                Ok(t)
+            };
+            match connect() {
+                Ok(t) => {
+                    debug!("Connected! {:?}", t);
+                    break t;
+                }
+                Err(e) => {
+                    if let Some(p) = &mut ssh_tunnel_process {
+                        let exit_status = p.try_wait().ok().flatten();
+                        if exit_status.is_none() && Instant::now() < deadline {
+                            std::thread::sleep(Duration::from_millis(50));
+                            continue;
+                        }
+                        stop_ssh_tunnel(ssh_tunnel_process.take().unwrap());
+                        if let Some(s) = exit_status {
+                            return Err(format!("ssh port forward exited unexpectedly with {s}"));
+                        }
+                    }
+                    if let Some(r) = relay {
+                        r.shutdown();
+                    }
+                    return Err(format!("Failed to connect to network address {:?}: {}", addr, e));
+                }
             }
-            Err(e) => return Err(format!("Failed to connect to network address {:?}: {}", addr, e)),
         }
     };
 
@@ -308,10 +519,66 @@ fn connect_to_remote_doer(
             0, // Nonce counters must be different, so sender and receiver don't reuse
             1,
             ("boss", &debug_comms_name)
-        )
+        ),
+        ssh_tunnel_process,
+        relay,
     });
 }
 
+/// Asks the given doer to relay a new network connection to the given address (see Command::StartRelay),
+/// and returns the address that we should connect to for this, along with the secret that we need to send first.
+fn start_relay(relay: &Comms, target_host: &str, target_port: u16, ports: Option<&RangeInclusive<u16>>) -> Result<((String, u16), [u8; 16]), String> {
+    // Connect to the relay using the same address that we're already connected to it on, so it only needs to
+    // listen on that interface
+    let relay_ip = match relay {
+        Comms::Remote { encrypted_comms, .. } => encrypted_comms.peer_addr().map(|a| a.ip().to_string()),
+        Comms::Local { .. } => None,
+    };
+    let relay_ip = relay_ip.ok_or_else(|| format!("Unknown network address for {relay}"))?;
+    // This is sent over our existing (encrypted) connection to the relay, so nobody else can know it
+    let secret: [u8; 16] = Aes128Gcm::generate_key(&mut OsRng).into();
+    relay.send_command(Command::StartRelay { target_host: target_host.to_string(), target_port, bind: relay_ip.clone(),
+        ports: ports.cloned(), secret })?;
+    match relay.receive_response()? {
+        Response::RelayStarted { port } => Ok(((relay_ip, port), secret)),
+        Response::Error(e) => Err(e),
+        x => Err(format!("Unexpected response (expected RelayStarted): {:?}", x)),
+    }
+}
+
+/// How long to wait for an ssh port forward to be ready (which may include the user entering a password).
+const SSH_TUNNEL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Launches a separate ssh process to forward a local port to the given port on the remote's loopback interface.
+/// Returns the ssh process and the local port.
+fn start_ssh_tunnel(remote_hostname: &str, remote_user: &str, remote_port: u16) -> Result<(std::process::Child, u16), String> {
+    // Find a free local port. Something else could take it before ssh does, in which case ssh will
+    // fail (because of ExitOnForwardFailure) and we'll report that.
+    let local_port = std::net::TcpListener::bind(("127.0.0.1", 0)).and_then(|l| l.local_addr())
+        .map_err(|e| format!("Failed to find a free local port: {e}"))?.port();
+
+    let user_prefix = if remote_user.is_empty() { "".to_string() } else { remote_user.to_string() + "@" };
+    let forward = format!("{local_port}:127.0.0.1:{remote_port}");
+    debug!("Starting ssh port forward {} to {}", forward, remote_hostname);
+    let p = std::process::Command::new("ssh")
+        .arg("-N") // Don't run a command, just forward the port
+        .arg("-o").arg("ExitOnForwardFailure=yes")
+        .arg("-L").arg(forward)
+        .arg(user_prefix + remote_hostname)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Error launching ssh for port forward: {e}"))?;
+    Ok((p, local_port))
+}
+
+fn stop_ssh_tunnel(mut p: std::process::Child) {
+    if let Err(e) = p.kill() {
+        debug!("Failed to kill ssh port forward process: {e}");
+    }
+    let _ = p.wait();
+}
+
 fn remote_doer_logging_thread(mut stderr: BufReader<ChildStderr>, debug_name: String) {
     loop {
         let mut l: String = "".to_string();
@@ -467,11 +734,11 @@ fn output_reader_thread_main(
 
 /// Attempts to launch a remote copy of rjrssync on the given remote computer using ssh.
 /// Additionally checks that the remote doer is a compatible version, and is now
-/// listening for an incoming network connection on the requested port. It is also provided
+/// listening for an incoming network connection on the requested port(s). It is also provided
 /// with a randomly generated secret shared key for encryption, which is returned to the caller
 /// for setting up encrypted communication over the network connection.
 fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
-    remote_port_for_comms: Option<u16>, progress_bar: &ProgressBar,
+    remote_ports_for_comms: Option<&RangeInclusive<u16>>, bind_loopback: bool, progress_bar: &ProgressBar,
 ) -> SshDoerLaunchResult
 {
     profile_this!();
@@ -499,10 +766,13 @@ fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
     };
 
     // Forward any specific port request to the remote doer
-    let port_arg = match remote_port_for_comms {
-        Some(p) => format!(" --port {p}"),
+    let port_arg = match remote_ports_for_comms {
+        Some(r) if r.start() == r.end() => format!(" --port {}", r.start()),
+        Some(r) => format!(" --port-range {}-{}", r.start(), r.end()),
         None => "".to_string()
     };
+    // If we'll be connecting through an ssh port forward, there's no need for the doer to be reachable from elsewhere
+    let bind_arg = if bind_loopback { " --bind 127.0.0.1" } else { "" };
 
     // Forward memory dumping flag to the remote doer
     let memory_dump_arg = match std::env::var("RJRSSYNC_TEST_DUMP_MEMORY_USAGE") {
@@ -512,7 +782,7 @@ fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
 
     // Note we don't cd, so that relative paths for the path specified by the user on the remote
     // will be correct (relative to their ssh default dir, e.g. home dir)
-    let doer_args = format!("--doer {} {} {}", log_arg, port_arg, memory_dump_arg);
+    let doer_args = format!("--doer {} {}{} {}", log_arg, port_arg, bind_arg, memory_dump_arg);
     // Try launching using both Unix and Windows paths, as we don't know what the remote system is
     // We run a command that doesn't print out anything on both Windows and Linux, so we don't pollute the output
     // (we show all output from ssh, in case it contains prompts etc. that are useful/required for the user to see).
@@ -579,11 +849,16 @@ fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
     }
     let mut handshook_data = HandshookStdoutAndStderr::default();
     let mut _handshaking_timer = None;
+    // Remember what ssh printed on stderr, so that if it fails we can report the reason in the error message
+    let mut stderr_lines = vec![];
     loop {
         match receiver.recv() {
             Ok((stream_type, OutputReaderThreadMsg::Line(l))) => {
                 // Show ssh output to the user, as this might be useful/necessary
                 info!("ssh {}: {}", stream_type, l);
+                if stream_type == OutputReaderStreamType::Stderr {
+                    stderr_lines.push(l.clone());
+                }
                 // Check for both the Linux (bash) and Windows (cmd) errors
                 if l.contains("No such file or directory") ||
                     l.contains("The system cannot find the path specified") ||
@@ -659,9 +934,64 @@ fn launch_doer_via_ssh(remote_hostname: &str, remote_user: &str,
                 // why it exited.
                 debug!("Both reader threads done, ssh must have exited. Waiting for process.");
                 // Wait for the process to exit, for tidyness
-                let result = ssh_process.wait();
-                return SshDoerLaunchResult::ExitedUnexpectedly(format!("ssh exited unexpectedly with {:?}. Please check above output from ssh.", result));
+                let status = match ssh_process.wait() {
+                    Ok(s) => s.to_string(),
+                    Err(e) => format!("unknown status ({e})"),
+                };
+                return SshDoerLaunchResult::ExitedUnexpectedly(match find_ssh_error_line(&stderr_lines) {
+                    Some(l) => format!("ssh exited unexpectedly with {status}: {l}"),
+                    None => format!("ssh exited unexpectedly with {status}. Please check above output from ssh."),
+                });
             }
         }
     }
 }
+
+/// Picks out the line from ssh's stderr which best explains why it failed, e.g. "Permission denied (publickey).",
+/// so that this can be shown in the error message rather than being lost among the other output.
+fn find_ssh_error_line(stderr_lines: &[String]) -> Option<&str> {
+    // Well-known errors from ssh. The host key warning is a large banner, so we pick out the most relevant part.
+    const KNOWN_ERRORS: &[&str] = &[
+        "REMOTE HOST IDENTIFICATION HAS CHANGED",
+        "Host key verification failed",
+        "Permission denied",
+        "Connection refused",
+        "Could not resolve hostname",
+        "Connection timed out",
+        "No route to host",
+        "Connection closed",
+        "Connection reset",
+    ];
+    for e in KNOWN_ERRORS {
+        if let Some(l) = stderr_lines.iter().find(|l| l.contains(e)) {
+            // Strip the border of the host key banner
+            return Some(l.trim_matches(|c: char| c == '@' || c.is_whitespace()));
+        }
+    }
+    // Otherwise the last thing ssh said is most likely to be relevant
+    stderr_lines.iter().rev().map(|l| l.trim()).find(|l| !l.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(l: &[&str]) -> Vec<String> {
+        l.iter().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_find_ssh_error_line() {
+        assert_eq!(find_ssh_error_line(&[]), None);
+        assert_eq!(find_ssh_error_line(&lines(&["", "  "])), None);
+        assert_eq!(find_ssh_error_line(&lines(&["Warning: something", "user@host: Permission denied (publickey).", ""])),
+            Some("user@host: Permission denied (publickey)."));
+        assert_eq!(find_ssh_error_line(&lines(&[
+            "@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@",
+            "@    WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!     @",
+            "@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@@",
+            "Host key verification failed.",
+        ])), Some("WARNING: REMOTE HOST IDENTIFICATION HAS CHANGED!"));
+        assert_eq!(find_ssh_error_line(&lines(&["first", "something unusual", ""])), Some("something unusual"));
+    }
+}
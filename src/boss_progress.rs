@@ -1,9 +1,10 @@
-use std::{ops::{AddAssign, SubAssign}, time::{Instant, Duration}, thread, sync::{Arc}};
+use std::{ops::{AddAssign, SubAssign}, time::{Instant, Duration}, thread, sync::{Arc}, borrow::Cow};
 
 use crossbeam::atomic::AtomicCell;
 use indicatif::{ProgressBar, HumanCount, HumanBytes, ProgressStyle, WeakProgressBar};
+use log::warn;
 
-use crate::{boss_doer_interface::{EntryDetails, ProgressPhase, ProgressMarker}, root_relative_path::RootRelativePath, boss_sync::Actions};
+use crate::{boss_doer_interface::{EntryDetails, ProgressPhase, ProgressMarker}, root_relative_path::RootRelativePath, boss_sync::Actions, boss_frontend::ProgressUnit, boss_checkpoint::Checkpoint};
 
 /// FPS of progress bar update.
 // This has a surprisingly significant effect on performance, seen especially when
@@ -118,6 +119,59 @@ struct BarState {
     total: ProgressValues,
     current_entry: Option<RootRelativePath>,
 }
+impl BarState {
+    /// Gets the position and length of the progress bar, measured in the given unit (which must already have been
+    /// resolved, see resolve_progress_unit).
+    fn get_position_and_length(&self, unit: ProgressUnit) -> (u64, u64) {
+        match unit {
+            ProgressUnit::Work => (self.completed.work, self.total.work),
+            ProgressUnit::Bytes => (self.completed.copy_bytes, self.total.copy_bytes),
+            _ => {
+                // Once the doer has moved on to copying, all the deletes are done (the completed count might not
+                // have been updated with the final delete, as we don't send a marker after every entry)
+                let deleted = if self.is_deleting { self.completed.delete } else { self.total.delete };
+                ((deleted + self.completed.copy) as u64, (self.total.delete + self.total.copy) as u64)
+            }
+        }
+    }
+}
+
+/// Decides what the progress bar should measure, for --progress-unit.
+fn resolve_progress_unit(unit: ProgressUnit, num_files_to_copy: u64, num_bytes_to_copy: u64) -> ProgressUnit {
+    match unit {
+        // A few large files are best measured in bytes, as each file takes a while. Lots of small files
+        // are best measured in files, as the overhead of each file dominates.
+        ProgressUnit::Auto if num_files_to_copy > 0 && num_bytes_to_copy / num_files_to_copy >= MIN_FILE_SIZE => ProgressUnit::Bytes,
+        ProgressUnit::Auto => ProgressUnit::Files,
+        // If no file data is being copied then there's nothing to measure in bytes
+        ProgressUnit::Bytes if num_bytes_to_copy == 0 => ProgressUnit::Work,
+        x => x,
+    }
+}
+
+/// Steps through the paths of one of the lists of actions as the doer works through them, so that we can show
+/// the current one on the progress bar. This avoids needing our own copy of all the paths, which could be huge.
+struct PathCursor<'a> {
+    iter: Box<dyn Iterator<Item = Cow<'a, RootRelativePath>> + 'a>,
+    /// The number of paths taken from the iterator so far, the last of which is `current`.
+    num_taken: usize,
+    current: Option<RootRelativePath>,
+}
+impl<'a> PathCursor<'a> {
+    fn new(iter: impl Iterator<Item = Cow<'a, RootRelativePath>> + 'a) -> Self {
+        PathCursor { iter: Box::new(iter), num_taken: 0, current: None }
+    }
+
+    /// Gets the path at the given position in the list. As the iterator can only go forwards,
+    /// asking for an earlier position than last time just gives the same path as last time.
+    fn get(&mut self, idx: usize) -> Option<RootRelativePath> {
+        if idx >= self.num_taken {
+            self.current = self.iter.nth(idx - self.num_taken).map(Cow::into_owned);
+            self.num_taken = idx + 1;
+        }
+        self.current.clone()
+    }
+}
 
 /// Wrapper around progress-bar related logic, which is a little tricky.
 ///
@@ -167,31 +221,35 @@ pub struct Progress<'a> {
     /// the deletes and had moved on to the copies.
     first_copy_time: Option<Instant>,
 
-    /// Lists of source and dest paths, so that we can match up progress markers
+    /// The source and dest paths, so that we can match up progress markers
     /// to filenames to display on the progress bar.
-    to_copy_paths: Vec<RootRelativePath>,
-    to_delete_paths: Vec<RootRelativePath>,
+    to_copy_paths: PathCursor<'a>,
+    to_delete_paths: PathCursor<'a>,
+
+    /// If set, the progress markers from the doer are also used to record which entries have been completed,
+    /// so that the sync can be resumed (see --checkpoint).
+    checkpoint: Option<Checkpoint>,
 }
 impl<'a> Progress<'a> {
-    pub fn new(actions: &Actions, progress_bar: &'a ProgressBar, mut detailed: bool) -> Self {
+    pub fn new(actions: &'a Actions, progress_bar: &'a ProgressBar, mut detailed: bool, unit: ProgressUnit) -> Self {
         if progress_bar.is_hidden() {
             // No point doing extra work if the progress bar isn't visible anyway (e.g. unattended terminal)
             detailed = false;
         }
 
-        // Sum up the total amount of work that needs doing, and store a copy of the
-        // paths so that we can display these on the progress bar as we go
-        let mut to_delete_paths = vec![];
+        // Sum up the total amount of work that needs doing
         let mut total = ProgressValues::default();
-        for (p, (d, _)) in actions.to_delete.iter() {
-            total += ProgressValues::for_delete(d);
-            to_delete_paths.push(p.clone());
+        for (_, v) in actions.to_delete.iter() {
+            total += ProgressValues::for_delete(&v.0);
         }
-        let mut to_copy_paths = vec![];
-        for (p, (c, _)) in actions.to_copy.iter() {
-            total += ProgressValues::for_copy(c);
-            to_copy_paths.push(p.clone());
+        let mut num_files_to_copy = 0;
+        for (_, v) in actions.to_copy.iter() {
+            total += ProgressValues::for_copy(&v.0);
+            if matches!(v.0, EntryDetails::File { .. }) {
+                num_files_to_copy += 1;
+            }
         }
+        let unit = resolve_progress_unit(unit, num_files_to_copy, total.copy_bytes);
 
         // Set up the UI element
         // Note that we don't render the pos or length in the template, as the 'work' values are pretty meaningless
@@ -200,7 +258,8 @@ impl<'a> Progress<'a> {
         // when the filename is long, which causes problems.
         progress_bar.reset();
         if detailed {
-            progress_bar.set_length(total.work);
+            let initial_state = BarState { is_deleting: true, completed: ProgressValues::default(), total: total.clone(), current_entry: None };
+            progress_bar.set_length(initial_state.get_position_and_length(unit).1);
             progress_bar.set_style(ProgressStyle::with_template("{percent}% {bar:40.green/black} {wide_msg}").unwrap());
         } else {
             progress_bar.set_style(ProgressStyle::default_spinner());
@@ -214,7 +273,7 @@ impl<'a> Progress<'a> {
             let bar2 = progress_bar.downgrade(); // Weak reference for the background thread
             let new_bar_state2 = new_bar_state.clone();
             thread::Builder::new().name("Progress bar".to_string()).spawn(
-                move || Self::background_updater(bar2, new_bar_state2)).expect("Failed to spawn thread");
+                move || Self::background_updater(bar2, new_bar_state2, unit)).expect("Failed to spawn thread");
         }
 
         Progress {
@@ -226,17 +285,30 @@ impl<'a> Progress<'a> {
             new_bar_state,
             last_progress_marker: 0,
             first_copy_time: None,
-            to_delete_paths,
-            to_copy_paths,
+            to_delete_paths: PathCursor::new(actions.to_delete.iter().map(|(p, _)| p)),
+            to_copy_paths: PathCursor::new(actions.to_copy.iter().map(|(p, _)| p)),
+            checkpoint: None,
         }
     }
 
+    /// Records the entries completed by the dest doer in the given checkpoint as we go.
+    /// Progress markers are always sent when there's a checkpoint, even if the progress bar isn't being shown.
+    pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    /// Stops updating the checkpoint, leaving it recording the progress so far, e.g. because an entry failed
+    /// and so will need redoing when the sync is resumed.
+    pub fn stop_checkpoint(&mut self) {
+        self.checkpoint = None;
+    }
+
     /// Gets a ProgressMarker to be sent to the dest doer to mark the amount of work
     /// that has been already sent.
     /// This might return None if the last update was sent too recently, to avoid too much overhead
     /// from the progress markers.
     pub fn get_progress_marker_limited(&mut self) -> Option<ProgressMarker> {
-        if !self.detailed {
+        if !self.detailed && self.checkpoint.is_none() {
             return None;
         }
         // Don't send progress markers too often, to avoid overhead
@@ -308,6 +380,7 @@ impl<'a> Progress<'a> {
         match marker.phase {
             ProgressPhase::Deleting { num_entries_deleted } => {
                 self.completed.delete = num_entries_deleted;
+                self.update_checkpoint(num_entries_deleted, 0);
 
                 // Update the progress bar based on the progress that the dest doer has made.
                 self.update_bar_limited();
@@ -321,6 +394,8 @@ impl<'a> Progress<'a> {
 
                 self.completed.copy = num_entries_copied;
                 self.completed.copy_bytes = num_bytes_copied;
+                // All the deletes are done before any copies are started
+                self.update_checkpoint(self.total.delete, num_entries_copied);
 
                 // Update the progress bar based on the progress that the dest doer has made.
                 self.update_bar_limited();
@@ -331,6 +406,16 @@ impl<'a> Progress<'a> {
         }
     }
 
+    fn update_checkpoint(&mut self, num_deleted: u32, num_copied: u32) {
+        if let Some(c) = &mut self.checkpoint {
+            // Failing to update the checkpoint doesn't affect the sync itself, so just stop updating it
+            if let Err(e) = c.record_progress(num_deleted, num_copied) {
+                warn!("{e}. The sync will continue, but resuming it would redo everything since the last update.");
+                self.checkpoint = None;
+            }
+        }
+    }
+
     // Doesn't directly update the bar, because we might do this too quickly and cause too much overhead
     // (see comment on background_updater).
     fn update_bar_limited(&mut self) {
@@ -345,9 +430,9 @@ impl<'a> Progress<'a> {
         // name alongside the progress bar.
         let current_entry =
             if self.first_copy_time.is_none() {
-                self.to_delete_paths.get(self.completed.delete as usize).cloned()
+                self.to_delete_paths.get(self.completed.delete as usize)
             } else {
-                self.to_copy_paths.get(self.completed.copy as usize).cloned()
+                self.to_copy_paths.get(self.completed.copy as usize)
             };
 
         let new_state = Box::new(BarState {
@@ -372,7 +457,7 @@ impl<'a> Progress<'a> {
     /// so the overhead might now not be a problem any more.)
     /// To avoid this, we run our own background thread (instead of using enable_steady_tick) which
     /// limits calls to any APIs on the ProgressBar.
-    fn background_updater(bar: WeakProgressBar, new_bar_state: Arc<AtomicCell<Option<Box<BarState>>>>) {
+    fn background_updater(bar: WeakProgressBar, new_bar_state: Arc<AtomicCell<Option<Box<BarState>>>>, unit: ProgressUnit) {
         loop {
             thread::sleep(Duration::from_secs_f32(1.0 / BAR_UPDATE_RATE));
 
@@ -409,12 +494,13 @@ impl<'a> Progress<'a> {
                         HumanCount(new_state.completed.copy as u64).to_string(), HumanCount(new_state.total.copy as u64).to_string(),
                         HumanBytes(new_state.completed.copy_bytes as u64).to_string(), HumanBytes(new_state.total.copy_bytes as u64).to_string())
                 };
+                let (position, length) = new_state.get_position_and_length(unit);
                 if let Some(e) = new_state.current_entry {
                     message += &format!("   {}", e);
                 }
 
-                bar.set_length(new_state.total.work);
-                bar.set_position(new_state.completed.work);
+                bar.set_length(length);
+                bar.set_position(position);
                 bar.set_message(message);
             }
             bar.tick(); // Make the spinner spin, regardless of any other updates
@@ -426,20 +512,22 @@ impl<'a> Progress<'a> {
 mod tests {
     use std::time::SystemTime;
 
+    use crate::boss_doer_interface::FileFlags;
+
     use super::*;
 
     #[test]
     fn progress_values() {
         // Small files of different sizes still have the same work
         assert_eq!(
-            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, size: 1 }).work,
-            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, size: 100 }).work
+            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, flags: FileFlags::default(), size: 1 }).work,
+            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, flags: FileFlags::default(), size: 100 }).work
         );
 
         // But big files scale linearly
         assert_eq!(
-            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, size: 10_000_000_000 }).work,
-            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, size: 1_000_000_000 }).work * 10
+            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, flags: FileFlags::default(), size: 10_000_000_000 }).work,
+            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, flags: FileFlags::default(), size: 1_000_000_000 }).work * 10
         );
 
         // Several partial copies add up to the same total as the whole file - small file
@@ -447,7 +535,7 @@ mod tests {
         p += ProgressValues::for_copy_partial(100, 100, 1000);
         p += ProgressValues::for_copy_partial(200, 800, 1000);
         assert_eq!(p,
-            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, size: 1000 })
+            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, flags: FileFlags::default(), size: 1000 })
         );
 
         // Several partial copies add up to the same total as the whole file - large file
@@ -456,7 +544,38 @@ mod tests {
         p += ProgressValues::for_copy_partial(200, 800, 1_000_000_000);
         p += ProgressValues::for_copy_partial(1000, 999_999_000, 1_000_000_000);
         assert_eq!(p,
-            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, size: 1_000_000_000 })
+            ProgressValues::for_copy(&EntryDetails::File { modified_time: SystemTime::UNIX_EPOCH, flags: FileFlags::default(), size: 1_000_000_000 })
         );
     }
+
+    #[test]
+    fn test_resolve_progress_unit() {
+        // A few large files
+        assert_eq!(resolve_progress_unit(ProgressUnit::Auto, 2, 100 * MIN_FILE_SIZE), ProgressUnit::Bytes);
+        // Lots of small files
+        assert_eq!(resolve_progress_unit(ProgressUnit::Auto, 1000, 1000 * 10), ProgressUnit::Files);
+        // No files at all (e.g. just deleting)
+        assert_eq!(resolve_progress_unit(ProgressUnit::Auto, 0, 0), ProgressUnit::Files);
+        // Explicit choices are respected, unless there's nothing to measure
+        assert_eq!(resolve_progress_unit(ProgressUnit::Work, 1000, 1000 * 10), ProgressUnit::Work);
+        assert_eq!(resolve_progress_unit(ProgressUnit::Work, 0, 0), ProgressUnit::Work);
+        assert_eq!(resolve_progress_unit(ProgressUnit::Bytes, 1000, 1000 * 10), ProgressUnit::Bytes);
+        assert_eq!(resolve_progress_unit(ProgressUnit::Files, 2, 100 * MIN_FILE_SIZE), ProgressUnit::Files);
+        // If no file data is being copied (e.g. just deleting) then there's nothing to measure in bytes
+        assert_eq!(resolve_progress_unit(ProgressUnit::Bytes, 3, 0), ProgressUnit::Work);
+    }
+
+    #[test]
+    fn test_bar_position_and_length() {
+        let total = ProgressValues { work: 5000, delete: 4, copy: 6, copy_bytes: 1000 };
+        let state = |is_deleting, delete, copy, copy_bytes| BarState {
+            is_deleting, completed: ProgressValues { work: delete as u64 * 100 + copy_bytes, delete, copy, copy_bytes }, total: total.clone(), current_entry: None };
+        assert_eq!(state(true, 2, 0, 0).get_position_and_length(ProgressUnit::Work), (200, 5000));
+        assert_eq!(state(true, 2, 0, 0).get_position_and_length(ProgressUnit::Files), (2, 10));
+        assert_eq!(state(true, 2, 0, 0).get_position_and_length(ProgressUnit::Bytes), (0, 1000));
+        // The completed delete count may be out of date once copying has started
+        assert_eq!(state(false, 3, 1, 300).get_position_and_length(ProgressUnit::Files), (5, 10));
+        assert_eq!(state(false, 3, 1, 300).get_position_and_length(ProgressUnit::Bytes), (300, 1000));
+        assert_eq!(state(false, 3, 1, 300).get_position_and_length(ProgressUnit::Work), (600, 5000));
+    }
 }
\ No newline at end of file
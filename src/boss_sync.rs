@@ -1,12 +1,14 @@
 use std::{
-    cmp::Ordering, time::{Instant, SystemTime, Duration},
+    cmp::Ordering, cell::Cell, collections::{BTreeMap, HashSet}, io::Write, time::{Instant, SystemTime, Duration},
 };
 
 use indicatif::{HumanCount, HumanBytes, ProgressBar, ProgressStyle};
-use log::{debug, info, trace};
-use regex::{RegexSet};
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use lazy_static::{lazy_static};
+use regex::{Regex, RegexSet};
 
-use crate::{*, boss_progress::{Progress}, histogram::FileSizeHistogram, root_relative_path::{RootRelativePath, PrettyPath, Side}, boss_doer_interface::{ProgressPhase, EntryDetails, Response, Command, Filters, FilterKind}, ordered_map::OrderedMap};
+use crate::{*, boss_progress::{Progress}, boss_checkpoint::{Checkpoint, Resumable, load_checkpoint, remove_checkpoint}, histogram::FileSizeHistogram, bandwidth_limiter::BandwidthLimiter, root_relative_path::{RootRelativePath, PrettyPath, Side}, boss_doer_interface::{ProgressPhase, ProgressMarker, EntryDetails, FileFlags, Response, Command, Filters, FilterKind, SymlinkKind, SafeLinksBehaviour, INTERNAL_ENTRY_PREFIX}, ordered_map::OrderedMap};
 
 #[derive(Default)]
 struct Stats {
@@ -37,6 +39,71 @@ struct Stats {
     pub num_symlinks_copied: u32,
     pub copied_file_size_hist: FileSizeHistogram,
     pub copy_end_time: Option<Instant>,
+    /// How much file content was actually sent to the dest, which is less than num_bytes_copied if it was compressed
+    /// (see --compress).
+    pub num_bytes_transferred: u64,
+
+    /// Files which were hard-linked to the previous snapshot rather than copied (see --backup-snapshots).
+    pub num_files_linked: u32,
+    pub num_bytes_linked: u64,
+
+    /// Files which were up-to-date apart from their flags, so just had their flags updated (see --flags).
+    pub num_files_flags_updated: u32,
+
+    pub num_folders_pruned: u32,
+
+    /// Entries which weren't transferred (or, for dest entries, weren't deleted), grouped by the reason.
+    pub num_skipped: BTreeMap<SkipReason, u32>,
+}
+
+impl Stats {
+    fn add_skipped(&mut self, reason: SkipReason, count: u32) {
+        if count > 0 {
+            *self.num_skipped.entry(reason).or_default() += count;
+        }
+    }
+}
+
+/// Why an entry was skipped, for the summary at the end of the sync.
+/// The order here is the order that they are listed in the summary.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum SkipReason {
+    /// Excluded by the filters (on the source).
+    Filter,
+    /// Symlink on the source pointing outside the root (see --safe-links).
+    UnsafeLink,
+    /// Not on the dest, and --existing was given.
+    NotOnDest,
+    /// Dest file was newer and --dest-file-newer (or the prompt) said to skip.
+    DestNewer,
+    /// Dest file was older and --dest-file-older (or the prompt) said to skip.
+    DestOlder,
+    /// Files had the same modified time and the prompt said to skip.
+    SameTime,
+    /// Dest entry needed deleting and --dest-entry-needs-deleting (or the prompt) said to skip.
+    NeedsDeleting,
+    /// Dest entry matched a --no-delete-pattern.
+    NoDeletePattern,
+    /// Source entry couldn't be read (see --ignore-read-errors).
+    ReadError,
+    /// Source folder would be empty on the dest, so would just be removed again (see --prune-empty-dirs).
+    WouldBeEmpty,
+}
+impl SkipReason {
+    fn describe(&self) -> &'static str {
+        match self {
+            SkipReason::Filter => "by filter",
+            SkipReason::UnsafeLink => "because symlink points outside root",
+            SkipReason::NotOnDest => "because not on dest (--existing)",
+            SkipReason::DestNewer => "because dest newer",
+            SkipReason::DestOlder => "because dest older",
+            SkipReason::SameTime => "because same modified time",
+            SkipReason::NeedsDeleting => "because dest entry needs deleting",
+            SkipReason::NoDeletePattern => "by --no-delete-pattern",
+            SkipReason::ReadError => "due to read errors",
+            SkipReason::WouldBeEmpty => "because would be empty (--prune-empty-dirs)",
+        }
+    }
 }
 
 /// Validates if a trailing slash was provided incorrectly on the given entry.
@@ -67,7 +134,7 @@ fn validate_trailing_slash(root_path: &str, entry_details: &EntryDetails) -> Res
 /// until it finds a response with a progress marker that shows the doer is finished.
 /// If not set, this function won't block and will return once it's processed all pending responses from the doer.
 /// If an error is encountered though, it will return rather than blocking.
-fn process_dest_responses(dest_comms: &mut Comms, progress: &mut Progress,
+fn process_dest_responses(dest_comms: &mut Comms, dest_dir_separator: Option<char>, progress: &mut Progress,
     mut block_until_done: bool) -> Result<(), String>
 {
     // To make the rest of this function consistent for both cases of block_until_done,
@@ -89,7 +156,9 @@ fn process_dest_responses(dest_comms: &mut Comms, progress: &mut Progress,
     while let Some(x) = next_fn(block_until_done) {
         match x {
             Ok(Response::Error(e)) => {
-                errors.push(e);
+                errors.push(doer_error(dest_comms, dest_dir_separator, &e));
+                // Later progress markers would count the failed entry as completed, so the checkpoint must not include them
+                progress.stop_checkpoint();
                 // If an error was encountered, don't block - just process the remaining messages to see if there
                 // were any other errors to report, then return the error(s)
                 block_until_done = false;
@@ -117,14 +186,36 @@ fn process_dest_responses(dest_comms: &mut Comms, progress: &mut Progress,
     }
 }
 
+/// Describes an error reported by a doer, for showing to the user. Any paths in it are in the format of
+/// the doer's platform (which might not be the same as ours), so this says which side and platform it came from.
+/// The dir_separator is None if we haven't yet found out the platform.
+fn doer_error(comms: &Comms, dir_separator: Option<char>, e: &str) -> String {
+    match dir_separator {
+        Some('\\') => format!("{comms} (Windows): {e}"),
+        Some(_) => format!("{comms} (Unix): {e}"),
+        None => format!("{comms}: {e}"),
+    }
+}
+
 /// A bunch of fields related to the current sync that would otherwise need to be passed
 /// around as individual variables.
 struct SyncContext<'a> {
     src_comms: &'a mut Comms,
     dest_comms: &'a mut Comms,
-    filters: Filters,
+    /// Filters used when listing entries on each side. These are the common filters from the spec,
+    /// followed by any side-specific ones.
+    src_filters: Filters,
+    dest_filters: Filters,
+    /// Dest entries matching these are never deleted because they don't exist on the source
+    /// (but can still be overwritten).
+    no_delete_patterns: RegexSet,
     stats: Stats,
     dry_run: bool,
+    /// Show the size and reason for each entry in the dry-run output (see --dry-run=detailed).
+    dry_run_detailed: bool,
+    diff: bool,
+    /// Ask once whether to proceed with the whole plan, before making any changes (see --confirm).
+    confirm: bool,
     dest_file_newer_behaviour: DestFileUpdateBehaviour,
     dest_file_older_behaviour: DestFileUpdateBehaviour,
     files_same_time_behaviour: DestFileUpdateBehaviour,
@@ -132,15 +223,79 @@ struct SyncContext<'a> {
     dest_root_needs_deleting_behaviour: DestRootNeedsDeletingBehaviour,
     progress_bar: &'a ProgressBar,
     show_progress: bool,
+    progress_unit: ProgressUnit,
     show_stats: bool,
     src_root: String,
     dest_root: String,
+    /// Whether the source and dest doers are running on the same computer, in which case
+    /// the source and dest roots might overlap.
+    same_host: bool,
+    keep_dest_dirlink: bool,
+    merge_root: bool,
+    /// If set, the dest root is a folder of backup snapshots, and we sync into a new snapshot inside it.
+    backup_snapshots: bool,
+    /// Only update entries which already exist on the dest, never creating new ones (see --existing).
+    existing_only: bool,
+    /// After syncing, remove any folders on the dest which are empty (see --prune-empty-dirs).
+    prune_empty_dirs: bool,
+    /// The existing dest folders which will be empty after the sync, so that these can be reported for a dry run.
+    folders_left_empty: Vec<RootRelativePath>,
+    /// Dest entries which were skipped as their names are reserved for rjrssync's own files (see INTERNAL_ENTRY_PREFIX).
+    /// Their parent folders aren't empty, even though no entries were reported inside them.
+    dest_internal_entries: Vec<RootRelativePath>,
+    /// The root and entries of the previous backup snapshot (if any), which unchanged files are hard-linked to.
+    link_dest: Option<(String, EntriesList)>,
+    /// If set, the reasons for deciding whether to copy/delete each entry are shown to the user,
+    /// up to this many entries (see --explain).
+    explain_limit: Option<usize>,
+    num_explained: Cell<usize>,
+    /// If no entries are received while querying for this long, give up (see --query-timeout).
+    query_timeout: Option<Duration>,
+    compress: bool,
+    skip_compress: Vec<String>,
+    /// Limit the rate of file contents from the source (see --bwlimit-down).
+    src_bwlimit: Option<BandwidthLimiter>,
+    /// Limit the rate of file contents to the dest (see --bwlimit-up). This is done by the dest doer.
+    dest_bwlimit: Option<u64>,
+    /// Lists of entries larger than this are moved to disk (see --max-entries-in-memory).
+    max_entries_in_memory: Option<usize>,
+    /// Skip source entries that can't be read rather than failing the sync (see --ignore-read-errors).
+    ignore_read_errors: bool,
+    /// Transfer file flags such as immutable (see --flags).
+    file_flags: bool,
+    /// What to do with source symlinks that point outside the source root (see --safe-links).
+    safe_links: Option<SafeLinksBehaviour>,
+    /// Make sure everything written to the dest is on disk before finishing (see --fsync).
+    fsync: bool,
+    /// Record the plan and progress in this local file, so that the sync can be resumed (see --checkpoint).
+    checkpoint: Option<String>,
+    /// Continue the sync recorded in the checkpoint file, if there is one (see --resume).
+    resume: bool,
+    /// Set once we're actually continuing from a checkpoint, which is passed on to the dest doer (see SetRoot).
+    resuming: bool,
+    /// The source entries that were skipped because they couldn't be read, reported at the end of the sync.
+    read_errors: Vec<ReadError>,
+    /// File to append the file size histograms to, after the sync (see --histogram-out).
+    histogram_out: Option<String>,
+    /// File to append the manifest of source files and their checksums to, after the sync (see --manifest-out).
+    manifest_out: Option<String>,
+    manifest_format: ManifestFormat,
+    /// The path, size and modified time of each source file, remembered while querying if they're needed for the manifest.
+    manifest_files: Vec<(RootRelativePath, u64, SystemTime)>,
+    /// If set, the source entries are taken from here rather than querying the source doer (if available),
+    /// and are stored here after querying (if not), so that they can be re-used for the next dest.
+    src_entries_cache: Option<&'a mut SrcEntriesCache>,
 
     // Used for debugging/display only, shouldn't be needed for any syncing logic
     src_dir_separator: Option<char>,
     dest_dir_separator: Option<char>,
 }
 impl<'a> SyncContext<'a> {
+    /// The command for setting the dest doer's root, which also passes on the settings for changing the dest.
+    fn set_dest_root_command(&self, root: String) -> Command {
+        Command::SetRoot { root, file_flags: self.file_flags, fsync: self.fsync, bwlimit: self.dest_bwlimit, resuming: self.resuming }
+    }
+
     fn pretty_src<'b>(&'b self, path: &'b RootRelativePath, details: &'b EntryDetails) -> PrettyPath {
         let kind = match details {
             EntryDetails::File { .. } => "file",
@@ -164,6 +319,50 @@ impl<'a> SyncContext<'a> {
         PrettyPath { side: Side::Dest, dir_separator: self.dest_dir_separator.unwrap_or('/'), root: &self.dest_root, path, kind }
     }
 
+    /// Applies a choice made at a prompt to all the other categories of prompt (newer/older/same-time files and
+    /// deletes) which would otherwise prompt too. Categories that the user set explicitly are left alone.
+    /// `proceed` means to overwrite/delete, otherwise the entries are skipped.
+    fn remember_for_all_prompt_categories(&mut self, proceed: bool) {
+        let file_behaviour = if proceed { DestFileUpdateBehaviour::Overwrite } else { DestFileUpdateBehaviour::Skip };
+        for b in [&mut self.dest_file_newer_behaviour, &mut self.dest_file_older_behaviour, &mut self.files_same_time_behaviour] {
+            if *b == DestFileUpdateBehaviour::Prompt {
+                *b = file_behaviour;
+            }
+        }
+        if self.dest_entry_needs_deleting_behaviour == DestEntryNeedsDeletingBehaviour::Prompt {
+            self.dest_entry_needs_deleting_behaviour =
+                if proceed { DestEntryNeedsDeletingBehaviour::Delete } else { DestEntryNeedsDeletingBehaviour::Skip };
+        }
+    }
+
+    /// Logs the reason for a decision about whether to copy/delete an entry.
+    /// Normally these are only visible at trace level, but --explain shows them to the user,
+    /// (up to a limit, so that the output isn't flooded when there are lots of entries).
+    fn explain(&self, msg: std::fmt::Arguments) {
+        match self.explain_limit {
+            None => trace!("{msg}"),
+            Some(limit) => {
+                let n = self.num_explained.get();
+                if n < limit {
+                    info!("{msg}");
+                } else if n == limit {
+                    info!("(further explanations omitted - limit of {limit} reached)");
+                }
+                self.num_explained.set(n + 1);
+            }
+        }
+    }
+
+    /// The extra details shown for each entry in the dry-run output for --dry-run=detailed (the file size, if a file,
+    /// and the reason for the copy/delete), or nothing otherwise.
+    fn dry_run_details(&self, size: Option<u64>, reason: &str) -> String {
+        match (self.dry_run_detailed, size) {
+            (false, _) => String::new(),
+            (true, Some(size)) => format!(" ({}, {reason})", HumanBytes(size)),
+            (true, None) => format!(" ({reason})"),
+        }
+    }
+
     fn send_progress_marker_limited(&self, progress: &mut Progress) -> Result<(), String> {
         if let Some(m) = progress.get_progress_marker_limited() {
             self.dest_comms.send_command(Command::Marker(m))
@@ -173,31 +372,130 @@ impl<'a> SyncContext<'a> {
     }
 }
 
+/// Options that apply to every sync being performed, as opposed to those in SyncSpec which
+/// can differ for each sync.
+#[derive(Clone)]
+pub struct SyncOptions {
+    pub dry_run: bool,
+    /// Show the size and reason for each entry in the dry-run output (see --dry-run=detailed).
+    pub dry_run_detailed: bool,
+    /// Report the differences between the source and dest rather than syncing (see --diff).
+    pub diff: bool,
+    /// Ask once whether to proceed with the whole plan, before making any changes (see --confirm).
+    pub confirm: bool,
+    pub show_progress: bool,
+    pub progress_unit: ProgressUnit,
+    pub show_stats: bool,
+    /// Whether the source and dest doers are running on the same computer.
+    pub same_host: bool,
+    /// If the dest root is a symlink to a folder (and the source is a folder), sync into that folder
+    /// rather than replacing the symlink.
+    pub keep_dest_dirlink: bool,
+    /// If the dest root exists but is incompatible with the source, merge into it rather than replacing it,
+    /// where possible (see --merge-root).
+    pub merge_root: bool,
+    /// Treat the dest root as a folder of dated backup snapshots, creating a new one for this sync and
+    /// hard-linking files which are unchanged since the previous one.
+    pub backup_snapshots: bool,
+    /// Only update entries which already exist on the dest, never creating new ones.
+    pub existing_only: bool,
+    /// After syncing, remove any folders on the dest which are empty.
+    pub prune_empty_dirs: bool,
+    pub explain_limit: Option<usize>,
+    /// An empty histogram with the buckets to use for the file size histograms (see --histogram-buckets).
+    pub file_size_histogram: FileSizeHistogram,
+    pub histogram_out: Option<String>,
+    pub manifest_out: Option<String>,
+    pub manifest_format: ManifestFormat,
+    pub query_timeout: Option<Duration>,
+    /// Whether to compress file contents when transferring them.
+    pub compress: bool,
+    /// Extensions (without the dot, lowercase) of files that shouldn't be compressed, even if compress is set.
+    pub skip_compress: Vec<String>,
+    /// Maximum rate (bytes per second) of file contents transferred from the source doer to the boss.
+    pub bwlimit_down: Option<u64>,
+    /// Maximum rate (bytes per second) of file contents transferred from the boss to the dest doer.
+    pub bwlimit_up: Option<u64>,
+    /// If set, each list of entries built up while querying is moved to a temporary file on disk
+    /// once it holds more than this many entries, to limit memory usage.
+    pub max_entries_in_memory: Option<usize>,
+    /// Skip source entries whose details or contents can't be read, rather than failing the sync.
+    pub ignore_read_errors: bool,
+    /// Transfer file flags such as immutable (see --flags).
+    pub file_flags: bool,
+    /// What to do with source symlinks that point outside the source root (see --safe-links).
+    pub safe_links: Option<SafeLinksBehaviour>,
+    /// Flush each file written to the dest (and the folders containing them) to disk, so the sync survives a power loss.
+    pub fsync: bool,
+    /// Record the plan and progress of the sync in this local file, so that it can be resumed if interrupted.
+    pub checkpoint: Option<String>,
+    /// Continue from the plan in the checkpoint file (if there is one for this sync), rather than querying again.
+    pub resume: bool,
+}
+
+/// The entries found on the source by a previous sync, which can be re-used when syncing the same source
+/// to another dest (see --extra-dest), to save querying the source again.
+#[derive(Default)]
+pub struct SrcEntriesCache {
+    entries: Option<EntriesList>,
+    /// Source entries that couldn't be read, so need to be left alone on the other dests too.
+    read_errors: Vec<ReadError>,
+    /// The number of source entries excluded by the filters, for the summary.
+    num_filtered: u32,
+    /// The number of source symlinks skipped because they point outside the root (see --safe-links), for the summary.
+    num_unsafe_links: u32,
+}
+
+/// Which stage of reading a source entry failed (see --ignore-read-errors).
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ReadErrorKind {
+    /// Couldn't get the details (e.g. type, size) of the entry, or list the contents of a folder.
+    Details,
+    /// The details were fine, but the file's contents couldn't be read when copying it.
+    Contents,
+}
+
+/// A source entry that was skipped because it couldn't be read (see --ignore-read-errors).
+#[derive(Clone)]
+struct ReadError {
+    path: RootRelativePath,
+    kind: ReadErrorKind,
+    error: String,
+}
+
 pub fn sync(
     sync_spec: &SyncSpec,
-    dry_run: bool,
+    options: &SyncOptions,
     progress_bar: &ProgressBar,
-    show_progress: bool,
-    show_stats: bool,
     src_comms: &mut Comms,
     dest_comms: &mut Comms,
-) -> Result<(), String> {
+    src_entries_cache: Option<&mut SrcEntriesCache>,
+) -> Result<(), SyncError> {
     // Parse and compile the filter strings
-    let filters = match compile_filters(sync_spec) {
-        Ok(f) => f,
-        Err(e) => return Err(e),
-    };
+    let src_filters = compile_filters(sync_spec.filters.iter().chain(&sync_spec.src_filters))?;
+    let dest_filters = compile_filters(sync_spec.filters.iter().chain(&sync_spec.dest_filters))?;
+    let no_delete_patterns = compile_no_delete_patterns(sync_spec)?;
 
     // Make context object, to avoid having to pass around a bunch of individual variables everywhere
     let context = SyncContext {
         src_comms,
         dest_comms,
-        filters,
-        stats: Stats::default(),
-        dry_run,
+        src_filters,
+        dest_filters,
+        no_delete_patterns,
+        stats: Stats {
+            src_file_size_hist: options.file_size_histogram.clone(),
+            copied_file_size_hist: options.file_size_histogram.clone(),
+            ..Default::default()
+        },
+        dry_run: options.dry_run,
+        dry_run_detailed: options.dry_run_detailed,
+        diff: options.diff,
+        confirm: options.confirm,
         progress_bar,
-        show_progress,
-        show_stats,
+        show_progress: options.show_progress,
+        progress_unit: options.progress_unit,
+        show_stats: options.show_stats,
         dest_file_newer_behaviour: sync_spec.dest_file_newer_behaviour,
         dest_file_older_behaviour: sync_spec.dest_file_older_behaviour,
         files_same_time_behaviour: sync_spec.files_same_time_behaviour,
@@ -205,6 +503,36 @@ pub fn sync(
         dest_root_needs_deleting_behaviour: sync_spec.dest_root_needs_deleting_behaviour,
         src_root: sync_spec.src.clone(),
         dest_root: sync_spec.dest.clone(),
+        same_host: options.same_host,
+        keep_dest_dirlink: options.keep_dest_dirlink,
+        merge_root: options.merge_root,
+        backup_snapshots: options.backup_snapshots,
+        existing_only: options.existing_only,
+        prune_empty_dirs: options.prune_empty_dirs,
+        folders_left_empty: vec![],
+        dest_internal_entries: vec![],
+        link_dest: None,
+        explain_limit: options.explain_limit,
+        num_explained: Cell::new(0),
+        histogram_out: options.histogram_out.clone(),
+        manifest_out: options.manifest_out.clone(),
+        manifest_format: options.manifest_format,
+        manifest_files: vec![],
+        query_timeout: options.query_timeout,
+        compress: options.compress,
+        skip_compress: options.skip_compress.clone(),
+        src_bwlimit: options.bwlimit_down.map(BandwidthLimiter::new),
+        dest_bwlimit: options.bwlimit_up,
+        max_entries_in_memory: options.max_entries_in_memory,
+        ignore_read_errors: options.ignore_read_errors,
+        file_flags: options.file_flags,
+        safe_links: options.safe_links,
+        fsync: options.fsync,
+        checkpoint: options.checkpoint.clone(),
+        resume: options.resume,
+        resuming: false,
+        read_errors: vec![],
+        src_entries_cache,
         src_dir_separator: None,
         dest_dir_separator: None,
     };
@@ -213,10 +541,11 @@ pub fn sync(
     sync_impl(context)
 }
 
-fn compile_filters(sync_spec: &SyncSpec) -> Result<Filters, String> {
+fn compile_filters<'a>(filter_strings: impl Iterator<Item=&'a String>) -> Result<Filters, String> {
     let mut patterns = vec![];
     let mut kinds = vec![];
-    for f in &sync_spec.filters {
+    let mut folders_only = vec![];
+    for f in filter_strings {
         // Check if starts with a + (include) or a - (exclude)
         match f.chars().nth(0) {
             Some('+') => kinds.push(FilterKind::Include),
@@ -224,6 +553,13 @@ fn compile_filters(sync_spec: &SyncSpec) -> Result<Filters, String> {
             _ => return Err(format!("Invalid filter '{}': Must start with a '+' or '-'", f)),
         }
         let pattern = f.split_at(1).1.to_string();
+        // Paths never have a trailing slash, so we use this to indicate a filter that only applies to folders
+        // (similar to .gitignore)
+        let (pattern, folder_only) = match pattern.strip_suffix('/') {
+            Some(p) if !p.ends_with('\\') => (p.to_string(), true),
+            _ => (pattern, false),
+        };
+        folders_only.push(folder_only);
         // Wrap in ^...$ to make it match the whole string, otherwise it's too easy
         // to make a mistake with filters that unintentionally match something else
         let pattern = format!("^{pattern}$");
@@ -236,10 +572,16 @@ fn compile_filters(sync_spec: &SyncSpec) -> Result<Filters, String> {
             return Err(format!("Invalid filter: {e}"));
         }
     };
-    Ok(Filters { regex_set, kinds })
+    Ok(Filters { regex_set, kinds, folders_only })
+}
+
+fn compile_no_delete_patterns(sync_spec: &SyncSpec) -> Result<RegexSet, String> {
+    // Wrap in ^...$ to make it match the whole string, the same as for filters
+    let patterns = sync_spec.no_delete_patterns.iter().map(|p| format!("^{p}$"));
+    RegexSet::new(patterns).map_err(|e| format!("Invalid no-delete pattern: {e}"))
 }
 
-fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
+fn sync_impl(mut ctx: SyncContext) -> Result<(), SyncError> {
     profile_this!();
 
     let sync_start = Instant::now();
@@ -250,6 +592,10 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
     ctx.progress_bar.set_message("Querying...");
     ctx.progress_bar.enable_steady_tick(Duration::from_millis(100));
 
+    if ctx.backup_snapshots {
+        prepare_backup_snapshot(&mut ctx)?;
+    }
+
     // First get details of the root file/folder etc. of each side, as this might affect the sync
     // before we start it (e.g. errors, or changing the dest root)
     let (src_root_details, dest_root_details, dest_platform_differentiates_symlinks) = get_root_details(&mut ctx)?;
@@ -257,7 +603,8 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
     // Check if the dest root will need deleting, and potentially prompt the user.
     // We do this before we start querying everything to show this prompt as the first one
     // (otherwise it would be the last prompt, as we delete in reverse order)
-    if let Some(d) = &dest_root_details {
+    // (for --diff, nothing will be deleted so there's no need to check, and the incompatibility will be reported as a difference)
+    if let (Some(d), false) = (&dest_root_details, ctx.diff) {
         if needs_delete(&src_root_details, d, dest_platform_differentiates_symlinks) {
             if !check_dest_root_delete_ok(&mut ctx, &src_root_details, d)? {
                 // Don't raise an error if we've been told to skip, but we can't continue as it will fail, so skip the entire sync
@@ -266,42 +613,96 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
         }
     }
 
+    // If resuming, the remaining actions come from the checkpoint file instead of querying and comparing everything again.
+    // The user already confirmed these actions (if needed) when the checkpoint was made.
+    let resumable = match (&ctx.checkpoint, ctx.resume) {
+        (Some(c), true) => load_checkpoint(c, &ctx.src_root, &ctx.dest_root, ctx.max_entries_in_memory)?,
+        _ => Resumable::Nothing,
+    };
+    if let Resumable::Remaining(_) = resumable {
+        // Some of the remaining actions might have already been done, so let the dest doer know to allow this
+        ctx.resuming = true;
+        ctx.dest_comms.send_command(ctx.set_dest_root_command(ctx.dest_root.clone()))?;
+        match ctx.dest_comms.receive_response()? {
+            Response::RootDetails { .. } => (),
+            Response::Error(e) => return Err(doer_error(ctx.dest_comms, None, &e).into()),
+            r => return Err(format!("Unexpected response getting root details from dest: {:?}", r).into()),
+        }
+    }
+
     // If the dest doesn't yet exist, make sure that all its ancestors are created, so that
     // when we come to create the dest path itself, it can succeed
-    if dest_root_details.is_none() {
-        if !ctx.dry_run {
+    // (unless we're not going to be creating anything, because of --existing)
+    if dest_root_details.is_none() && !ctx.existing_only {
+        if !ctx.dry_run && !ctx.diff {
             ctx.dest_comms.send_command(Command::CreateRootAncestors)?;
         }
     }
 
-    // Get the lists of entries to delete and copy, by querying both source and dest
-    // for what they have and checking for differences.
-    let mut actions = query_entries(&mut ctx, src_root_details, dest_root_details, dest_platform_differentiates_symlinks)?;
+    let src_root_is_folder = matches!(src_root_details, EntryDetails::Folder);
+
+    let actions = match resumable {
+        Resumable::Remaining(actions) => {
+            ctx.progress_bar.finish_and_clear();
+            info!("Resuming from checkpoint: {} entries left to delete and {} left to copy",
+                HumanCount(actions.to_delete.len() as u64), HumanCount(actions.to_copy.len() as u64));
+            *actions
+        }
+        Resumable::Nothing | Resumable::OtherSync => {
+            // Get the lists of entries to delete and copy, by querying both source and dest
+            // for what they have and checking for differences.
+            let (mut actions, dest_entries) = query_entries(&mut ctx, src_root_details, dest_root_details, dest_platform_differentiates_symlinks)?;
+
+            // Stop the progress bar before we (potentially) prompt the user, so the progress bar
+            // redrawing doesn't interfere with the prompts
+            ctx.progress_bar.finish_and_clear();
+
+            show_post_query_stats(&ctx, sync_start.elapsed());
 
-    // Stop the progress bar before we (potentially) prompt the user, so the progress bar
-    // redrawing doesn't interfere with the prompts
-    ctx.progress_bar.finish_and_clear();
+            if ctx.diff {
+                show_diff(&ctx, &actions);
+                actions.check_read_error()?;
+                return Ok(());
+            }
 
-    let query_elapsed_secs = sync_start.elapsed().as_secs_f32();
-    show_post_query_stats(&ctx, query_elapsed_secs);
+            // Confirm that the user is happy to take these actions
+            confirm_actions(&mut ctx, &mut actions)?;
 
-    // Confirm that the user is happy to take these actions
-    confirm_actions(&mut ctx, &mut actions)?;
+            // This is done after confirming, as skipping a delete could mean that a folder is no longer left empty
+            if ctx.prune_empty_dirs && src_root_is_folder {
+                skip_folders_left_empty(&mut ctx, &dest_entries, &mut actions)?;
+            }
+            actions
+        }
+    };
+    if ctx.confirm && !confirm_plan(&ctx, &actions) {
+        return Err("Sync cancelled at the confirmation prompt. See --confirm.".to_string().into());
+    }
+    actions.check_read_error()?;
 
     // Start the proper progress bar. We still need this even for --no-progress, because we use
     // some of the features for tracking the timings for --stats, for example. We just put it into
     // a simpler 'mode'.
-    let mut progress = Progress::new(&actions, ctx.progress_bar, ctx.show_progress);
+    let mut progress = Progress::new(&actions, ctx.progress_bar, ctx.show_progress, ctx.progress_unit);
+
+    // Record the plan before making any changes, so that if we get interrupted, we can pick up where we left off.
+    // A checkpoint for a different sync (e.g. a later one in the same spec file) is replaced, as only one can be resumed.
+    let checkpoint_path = if ctx.dry_run { None } else { ctx.checkpoint.clone() };
+    if let Some(c) = &checkpoint_path {
+        progress.set_checkpoint(Checkpoint::create(c, &ctx.src_root, &ctx.dest_root, &actions)?);
+    }
 
     // Delete dest entries that don't exist on the source. This needs to be done first in case there
     // are entries with the same name but incompatible (e.g. files vs folders).
     {
         profile_this!("Sending delete commands");
         ctx.stats.delete_start_time = Some(Instant::now());
-        for (dest_path, (dest_details, _reason)) in actions.to_delete.iter() {
-            delete_dest_entry(&mut ctx, &mut progress, dest_path, dest_details)?;
-            process_dest_responses(ctx.dest_comms, &mut progress, false)?;
+        for (dest_path, v) in actions.to_delete.iter() {
+            let (dest_details, reason) = &*v;
+            delete_dest_entry(&mut ctx, &mut progress, &dest_path, dest_details, reason)?;
+            process_dest_responses(ctx.dest_comms, ctx.dest_dir_separator, &mut progress, false)?;
         }
+        actions.to_delete.check_read_error()?;
     }
 
     // Copy entries that don't exist, or do exist but are out-of-date.
@@ -309,9 +710,14 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
         profile_this!("Sending copy commands");
         // Mark the exact start of copying, to make sure our timing stats are split accurately between copying and deleting
         ctx.dest_comms.send_command(Command::Marker(progress.get_progress_marker()))?;
-        for (src_path, (src_details, _reason)) in actions.to_copy.iter() {
-            copy_entry(&mut ctx, &mut progress, &src_path, &src_details)?;
-            process_dest_responses(ctx.dest_comms, &mut progress, false)?;
+        for (src_path, v) in actions.to_copy.iter() {
+            let (src_details, reason) = &*v;
+            copy_entry(&mut ctx, &mut progress, &src_path, src_details, reason)?;
+            process_dest_responses(ctx.dest_comms, ctx.dest_dir_separator, &mut progress, false)?;
+        }
+        actions.to_copy.check_read_error()?;
+        if let Some((_, prev_entries)) = &ctx.link_dest {
+            prev_entries.check_read_error()?;
         }
     }
 
@@ -321,24 +727,144 @@ fn sync_impl(mut ctx: SyncContext) -> Result<(), String> {
     ctx.dest_comms.send_command(Command::Marker(m))?;
     {
         profile_this!("Waiting for dest to finish");
-        process_dest_responses(ctx.dest_comms, &mut progress, true)?;
+        process_dest_responses(ctx.dest_comms, ctx.dest_dir_separator, &mut progress, true)?;
+    }
+
+    // Everything in the plan has been done, so there's nothing left to resume
+    if let Some(c) = &checkpoint_path {
+        remove_checkpoint(c)?;
     }
 
     ctx.stats.delete_end_time = progress.get_first_copy_time();
     ctx.stats.copy_start_time = progress.get_first_copy_time();
     ctx.stats.copy_end_time = Some(Instant::now());
 
+    // Now that everything else is done on the dest, we can see which folders have been left empty.
+    // If the source root is a file, then the dest root will now be a file too and there's nothing to prune.
+    if ctx.prune_empty_dirs && src_root_is_folder {
+        prune_empty_dest_folders(&mut ctx)?;
+    }
+
     show_post_sync_stats(&ctx);
+    show_read_errors(&ctx);
+    ctx.stats.add_skipped(SkipReason::ReadError, ctx.read_errors.len() as u32);
+    info!("{}", format_summary(&ctx.stats, ctx.dry_run, sync_start.elapsed()));
+
+    if let Some(h) = &ctx.histogram_out {
+        export_histograms(&ctx, h).map_err(|e| format!("Failed to write histograms to '{h}': {e}"))?;
+    }
+    if let Some(m) = ctx.manifest_out.clone() {
+        export_manifest(&mut ctx, &m)?;
+    }
+
+    Ok(())
+}
+
+/// Changes the dest root to a new snapshot folder inside the given one, named after the current (UTC) time,
+/// and queries the entries of the most recent existing snapshot (if any) so that files which haven't changed
+/// since then can be hard-linked to it, rather than being copied again.
+fn prepare_backup_snapshot(ctx: &mut SyncContext) -> Result<(), String> {
+    let new_snapshot = format_snapshot_name(SystemTime::now());
+
+    // Find the existing snapshots, which are the folders directly inside the dest root with names in the same format
+    ctx.dest_comms.send_command(ctx.set_dest_root_command(ctx.dest_root.clone()))?;
+    let (snapshots_root_details, dir_separator) = match ctx.dest_comms.receive_response()? {
+        Response::RootDetails { root_details, platform_dir_separator, .. } => (root_details, platform_dir_separator),
+        r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
+    };
+    let mut snapshots = vec![];
+    match snapshots_root_details {
+        None => (), // No snapshots yet - the folder will be created along with the new snapshot
+        Some(EntryDetails::Folder) => {
+            let top_level_only = compile_filters(["+[^/]*".to_string()].iter())?;
+            let entries = receive_all_dest_entries(ctx, top_level_only)?;
+            for (p, d) in entries.iter() {
+                let name = p.to_string();
+                if matches!(*d, EntryDetails::Folder) && is_snapshot_name(&name) {
+                    snapshots.push(name);
+                }
+            }
+            entries.check_read_error()?;
+        }
+        Some(_) => return Err(format!("dest path '{}' must be a folder to contain backup snapshots", ctx.dest_root)),
+    }
+    if snapshots.contains(&new_snapshot) {
+        return Err(format!("Backup snapshot '{new_snapshot}' already exists in '{}'", ctx.dest_root));
+    }
+
+    let trimmed_root = ctx.dest_root.trim_end_matches(['/', '\\']).to_string();
+    let snapshot_path = |name: &str| format!("{trimmed_root}{dir_separator}{name}");
+
+    // The names sort chronologically, so the most recent snapshot is the last one
+    if let Some(prev) = snapshots.iter().max() {
+        let prev_root = snapshot_path(prev);
+        ctx.dest_comms.send_command(Command::SetRoot { root: prev_root.clone(), file_flags: ctx.file_flags, fsync: false, bwlimit: None, resuming: false })?;
+        match ctx.dest_comms.receive_response()? {
+            Response::RootDetails { .. } => (),
+            r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
+        }
+        let prev_entries = receive_all_dest_entries(ctx, ctx.dest_filters.clone())?;
+        info!("Creating backup snapshot '{}', linking unchanged files to '{}'", snapshot_path(&new_snapshot), prev_root);
+        ctx.link_dest = Some((prev_root, prev_entries));
+    } else {
+        info!("Creating backup snapshot '{}'", snapshot_path(&new_snapshot));
+    }
 
+    ctx.dest_root = snapshot_path(&new_snapshot);
     Ok(())
 }
 
+/// Gets all the entries (apart from the root) from the dest doer's current root.
+fn receive_all_dest_entries(ctx: &mut SyncContext, filters: Filters) -> Result<EntriesList, String> {
+    ctx.dest_comms.send_command(Command::GetEntries { filters, skip_unreadable: false, safe_links: None })?;
+    let mut entries = EntriesList::with_spill_threshold(ctx.max_entries_in_memory);
+    loop {
+        match ctx.dest_comms.receive_response()? {
+            Response::Entry((p, d)) => entries.add(p, d)?,
+            Response::EndOfEntries { .. } => break,
+            r => return Err(format!("Unexpected response getting entries from dest: {:?}", r)),
+        }
+    }
+    Ok(entries)
+}
+
+/// Backup snapshot folders are named after the UTC time they were created, e.g. "2023-11-14_221320.123".
+/// This format sorts chronologically and is valid on all platforms. The milliseconds mean that syncing twice
+/// within the same second doesn't fail because the snapshot already exists.
+fn format_snapshot_name(t: SystemTime) -> String {
+    let since_epoch = t.duration_since(SystemTime::UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a calendar date (see http://howardhinnant.github.io/date_algorithms.html#civil_from_days)
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{year:04}-{month:02}-{day:02}_{:02}{:02}{:02}.{:03}", secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60,
+        since_epoch.subsec_millis())
+}
+
+lazy_static! {
+    static ref SNAPSHOT_NAME_REGEX: Regex = Regex::new("^[0-9]{4}-[0-9]{2}-[0-9]{2}_[0-9]{6}\\.[0-9]{3}$").unwrap();
+}
+
+fn is_snapshot_name(name: &str) -> bool {
+    SNAPSHOT_NAME_REGEX.is_match(name)
+}
+
 fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<EntryDetails>, bool), String> {
     // Source SetRoot
     let timer = start_timer("SetRoot src");
-    ctx.src_comms.send_command(Command::SetRoot { root: ctx.src_root.to_string() })?;
-    let src_root_details = match ctx.src_comms.receive_response()? {
-        Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator } => {
+    ctx.src_comms.send_command(Command::SetRoot { root: ctx.src_root.to_string(), file_flags: ctx.file_flags, fsync: false, bwlimit: None, resuming: false })?;
+    let (src_root_details, src_canonical_root) = match ctx.src_comms.receive_response()? {
+        Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator, canonical_root } => {
             match &root_details {
                 None => return Err(format!("src path '{}' doesn't exist!", ctx.src_root)),
                 Some(d) => if let Err(e) = validate_trailing_slash(&ctx.src_root, &d) {
@@ -346,8 +872,9 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
                 }
             };
             ctx.src_dir_separator = Some(platform_dir_separator);
-            root_details
+            (root_details, canonical_root)
         }
+        Response::Error(e) => return Err(doer_error(ctx.src_comms, None, &e)),
         r => return Err(format!("Unexpected response getting root details from src: {:?}", r)),
     };
     let src_root_details = src_root_details.unwrap();
@@ -355,9 +882,9 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
 
     // Dest SetRoot
     let timer = start_timer("SetRoot dest");
-    ctx.dest_comms.send_command(Command::SetRoot { root: ctx.dest_root.clone() })?;
-    let (mut dest_root_details, dest_platform_differentiates_symlinks) = match ctx.dest_comms.receive_response()? {
-        Response::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator } => {
+    ctx.dest_comms.send_command(ctx.set_dest_root_command(ctx.dest_root.clone()))?;
+    let (mut dest_root_details, dest_platform_differentiates_symlinks, mut dest_canonical_root) = match ctx.dest_comms.receive_response()? {
+        Response::RootDetails { root_details, platform_differentiates_symlinks, platform_dir_separator, canonical_root } => {
             match &root_details {
                 None => (), // Dest root doesn't exist, but that's fine (we will create it later)
                 Some(d) => if let Err(e) = validate_trailing_slash(&ctx.dest_root, &d) {
@@ -365,8 +892,9 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
                 }
             }
             ctx.dest_dir_separator = Some(platform_dir_separator);
-            (root_details, platform_differentiates_symlinks)
+            (root_details, platform_differentiates_symlinks, canonical_root)
         }
+        Response::Error(e) => return Err(doer_error(ctx.dest_comms, None, &e)),
         r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
     };
     stop_timer(timer);
@@ -380,28 +908,123 @@ fn get_root_details(ctx: &mut SyncContext) -> Result<(EntryDetails, Option<Entry
     // Note that we can't use std::path::is_separator (or similar) because this might be a remote path, so the current platform
     // isn't appropriate.
     let dest_trailing_slash = last_dest_char == Some('/') || last_dest_char == Some('\\');
-    if matches!(src_root_details, EntryDetails::File {..} | EntryDetails::Symlink { .. }) && dest_trailing_slash {
+    // With --merge-root, the same applies if the dest is an existing folder, even without a trailing slash
+    let merge_into_dest_folder = ctx.merge_root && matches!(dest_root_details, Some(EntryDetails::Folder));
+    if matches!(src_root_details, EntryDetails::File {..} | EntryDetails::Symlink { .. }) && (dest_trailing_slash || merge_into_dest_folder) {
         let src_filename = ctx.src_root.split(|c| c == '/' || c == '\\').last();
         if let Some(c) = src_filename {
-            ctx.dest_root = ctx.dest_root.clone() + c;
+            let sep = if dest_trailing_slash { String::new() } else { ctx.dest_dir_separator.unwrap_or('/').to_string() };
+            ctx.dest_root = ctx.dest_root.clone() + &sep + c;
             debug!("Modified dest path to {}", ctx.dest_root);
 
-            ctx.dest_comms.send_command(Command::SetRoot { root: ctx.dest_root.clone() })?;
-            dest_root_details = match ctx.dest_comms.receive_response()? {
-                Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator: _ } => root_details,
+            ctx.dest_comms.send_command(ctx.set_dest_root_command(ctx.dest_root.clone()))?;
+            (dest_root_details, dest_canonical_root) = match ctx.dest_comms.receive_response()? {
+                Response::RootDetails { root_details, platform_differentiates_symlinks: _, platform_dir_separator: _, canonical_root } => (root_details, canonical_root),
                 r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
             }
         }
     }
 
+    // If the dest root is a symlink to a folder, the user may have set this up deliberately (e.g. to put the dest on
+    // another volume), in which case we can sync into the folder that it points to, rather than replacing it.
+    // The canonical root has the symlink resolved, so we can use that as the new root if it turns out to be a folder.
+    // --merge-root implies this too.
+    if (ctx.keep_dest_dirlink || ctx.merge_root) && matches!(src_root_details, EntryDetails::Folder) &&
+        matches!(dest_root_details, Some(EntryDetails::Symlink { .. }))
+    {
+        if let Some(target) = dest_canonical_root.clone() {
+            ctx.dest_comms.send_command(ctx.set_dest_root_command(target.clone()))?;
+            match ctx.dest_comms.receive_response()? {
+                Response::RootDetails { root_details: Some(EntryDetails::Folder), canonical_root, .. } => {
+                    debug!("Following dest root symlink '{}' to folder '{}'", ctx.dest_root, target);
+                    ctx.dest_root = target;
+                    dest_root_details = Some(EntryDetails::Folder);
+                    dest_canonical_root = canonical_root;
+                }
+                Response::RootDetails { .. } => {
+                    // Not a folder (e.g. a broken symlink), so go back to the symlink itself, which will be replaced as normal
+                    debug!("Not following dest root symlink '{}' as it doesn't point to a folder", ctx.dest_root);
+                    ctx.dest_comms.send_command(ctx.set_dest_root_command(ctx.dest_root.clone()))?;
+                    match ctx.dest_comms.receive_response()? {
+                        Response::RootDetails { .. } => (),
+                        r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
+                    }
+                }
+                r => return Err(format!("Unexpected response getting root details from dest: {:?}", r)),
+            }
+        }
+    }
+
+    if let (Some(s), Some(d)) = (&src_canonical_root, &dest_canonical_root) {
+        check_dest_not_inside_src(ctx, &src_root_details, s, d)?;
+    }
+
     Ok((src_root_details, dest_root_details, dest_platform_differentiates_symlinks))
 }
 
+/// If the source and dest are on the same computer and the dest is inside the source folder, then
+/// syncing would copy the dest into itself, and each subsequent sync would copy that copy again, and so on.
+/// We raise an error for this, unless the filters exclude the dest from the source (which is a sensible
+/// way of setting this up, e.g. syncing a folder into a 'backup' subfolder which is excluded).
+fn check_dest_not_inside_src(ctx: &SyncContext, src_root_details: &EntryDetails,
+    src_canonical_root: &str, dest_canonical_root: &str) -> Result<(), String>
+{
+    // If the source is a file/symlink, then we never look inside it so there can't be a problem
+    if !ctx.same_host || !matches!(src_root_details, EntryDetails::Folder) {
+        return Ok(());
+    }
+
+    // Both doers are on the same computer, so will be using the same dir separator
+    let sep = ctx.src_dir_separator.unwrap_or('/');
+    let src_prefix = src_canonical_root.trim_end_matches(sep).to_string() + &sep.to_string();
+    let dest_relative_to_src = match strip_path_prefix(dest_canonical_root, &src_prefix, sep) {
+        Some(r) => r,
+        None => return Ok(()), // Dest is not inside the source
+    };
+
+    // The dest is inside the source, but this is fine if the filters mean that it (or one of its
+    // ancestors) won't be seen when walking the source.
+    if let Ok(p) = RootRelativePath::from_platform_path(dest_relative_to_src, sep) {
+        // The ancestors are all folders, and if the dest itself is a file then it's about to be replaced by a folder anyway
+        if p.self_and_ancestors().iter().any(|a| apply_filters(a, true, &ctx.src_filters) == FilterResult::Exclude) {
+            debug!("dest root '{}' is inside src root '{}', but is excluded by the filters", dest_canonical_root, src_canonical_root);
+            return Ok(());
+        }
+    }
+
+    Err(format!("dest path '{}' is inside src path '{}', so would be copied into itself. \
+        Use a dest path outside of the src, or use --filter to exclude it.", ctx.dest_root, ctx.src_root))
+}
+
+/// Checks if the given folder (or one of its ancestors) is excluded by the dest filters, in which case
+/// the dest entries won't tell us whether or not it exists there (see --dest-filter).
+fn is_excluded_on_dest(ctx: &SyncContext, folder: &RootRelativePath) -> bool {
+    folder.self_and_ancestors().iter().any(|a| apply_filters(a, true, &ctx.dest_filters) == FilterResult::Exclude)
+}
+
+/// Removes the given prefix from a path, if the path starts with it. Windows paths are compared case-insensitively,
+/// as the canonical path of something which doesn't exist yet keeps the user's casing (e.g. C:\Src vs c:\src\out).
+fn strip_path_prefix<'a>(path: &'a str, prefix: &str, sep: char) -> Option<&'a str> {
+    if sep == '\\' {
+        // get() returns None if the prefix length isn't on a char boundary, in which case it can't match anyway
+        path.get(..prefix.len()).filter(|p| p.to_lowercase() == prefix.to_lowercase()).map(|_| &path[prefix.len()..])
+    } else {
+        path.strip_prefix(prefix)
+    }
+}
+
 fn check_dest_root_delete_ok(ctx: &mut SyncContext,
     src_root_details: &EntryDetails, dest_root_details: &EntryDetails) -> Result<bool, String>
 {
+    // Syncing a file to an existing folder replaces the whole folder, but it's easy to do this by mistake when the
+    // intention was to put the file inside the folder, so point out how to do that instead
+    let into_folder_hint = match (src_root_details, dest_root_details) {
+        (EntryDetails::File { .. } | EntryDetails::Symlink { .. }, EntryDetails::Folder) =>
+            ". To sync into the folder instead, add a trailing slash to the dest path or use --merge-root",
+        _ => "",
+    };
     let msg = format!(
-        "{} needs deleting as it is incompatible with {}",
+        "{} needs deleting as it is incompatible with {}{into_folder_hint}",
         ctx.pretty_dest(&RootRelativePath::root(), dest_root_details),
         ctx.pretty_src(&RootRelativePath::root(), &src_root_details));
     let resolved_behaviour = match ctx.dest_root_needs_deleting_behaviour {
@@ -418,93 +1041,237 @@ fn check_dest_root_delete_ok(ctx: &mut SyncContext,
     };
     match resolved_behaviour {
         DestRootNeedsDeletingBehaviour::Prompt => panic!("Should have been alredy resolved!"),
-        DestRootNeedsDeletingBehaviour::Error => return Err(format!("{msg}. Will not delete. See --dest-root-needs-deleting")),
+        DestRootNeedsDeletingBehaviour::Error => {
+            let hint = match (src_root_details, dest_root_details) {
+                (EntryDetails::Folder, EntryDetails::Symlink { .. }) =>
+                    " (or --keep-dest-dirlink to sync into the folder that the symlink points to)",
+                _ => "",
+            };
+            return Err(format!("{msg}. Will not delete. See --dest-root-needs-deleting{hint}"));
+        }
         DestRootNeedsDeletingBehaviour::Skip => return Ok(false), // Don't raise an error, but we can't continue as it will fail, so skip the entire sync
         DestRootNeedsDeletingBehaviour::Delete => return Ok(true), // We will delete it anyway later on
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum DeleteReason {
     NotOnSource,
     Incompatible,
 }
+impl DeleteReason {
+    /// A short description for showing to the user (e.g. in the --dry-run=detailed output).
+    fn describe(&self) -> &'static str {
+        match self {
+            DeleteReason::NotOnSource => "not on source",
+            DeleteReason::Incompatible => "different type on source",
+        }
+    }
+}
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum CopyReason {
     NotOnDest,
     DestNewer,
     DestOlder,
     SameTimeAndNotSkipped,
+    /// The file is otherwise up-to-date, so only its flags need updating (see --flags).
+    FlagsDiffer,
+}
+impl CopyReason {
+    /// A short description for showing to the user (e.g. in the --dry-run=detailed output).
+    fn describe(&self) -> &'static str {
+        match self {
+            CopyReason::NotOnDest => "not on dest",
+            CopyReason::DestNewer => "dest newer",
+            CopyReason::DestOlder => "dest older",
+            CopyReason::SameTimeAndNotSkipped => "same modified time",
+            CopyReason::FlagsDiffer => "flags differ",
+        }
+    }
 }
 
 type EntriesList = OrderedMap<RootRelativePath, EntryDetails>;
-type ToDelete = OrderedMap<RootRelativePath, (EntryDetails, DeleteReason)>;
-type ToCopy = OrderedMap<RootRelativePath, (EntryDetails, CopyReason)>;
+pub type ToDelete = OrderedMap<RootRelativePath, (EntryDetails, DeleteReason)>;
+pub type ToCopy = OrderedMap<RootRelativePath, (EntryDetails, CopyReason)>;
 
 pub struct Actions {
     pub to_delete: ToDelete,
     pub to_copy: ToCopy,
 }
+impl Actions {
+    /// Checks that nothing went wrong reading either list back from disk (see OrderedMap::check_read_error).
+    pub fn check_read_error(&self) -> Result<(), String> {
+        self.to_delete.check_read_error()?;
+        self.to_copy.check_read_error()
+    }
+}
+
+/// After a sync has failed, there may still be responses to come from the doers for commands that were
+/// sent before the error. This waits until both doers have caught up, discarding those responses,
+/// so that they are ready to be used for another sync.
+pub fn wait_for_doers_idle(src_comms: &mut Comms, dest_comms: &mut Comms) -> Result<(), String> {
+    for comms in [src_comms, dest_comms] {
+        comms.send_command(Command::Marker(ProgressMarker { completed_work: 0, phase: ProgressPhase::Done }))?;
+        loop {
+            match comms.receive_response()? {
+                Response::Marker(ProgressMarker { phase: ProgressPhase::Done, .. }) => break,
+                r => debug!("Discarding response from {} after failed sync: {:?}", comms, r),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// An error which caused a sync to fail.
+#[derive(Debug)]
+pub enum SyncError {
+    /// A doer stopped responding while querying (see --query-timeout), and so might be stuck and unable
+    /// to shut down cleanly.
+    QueryTimeout(String),
+    Other(String),
+}
+impl From<String> for SyncError {
+    fn from(e: String) -> Self {
+        SyncError::Other(e)
+    }
+}
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::QueryTimeout(e) | SyncError::Other(e) => write!(f, "{e}"),
+        }
+    }
+}
 
 fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_root_details: Option<EntryDetails>,
     dest_platform_differentiates_symlinks: bool)
  ->
-    Result<Actions, String>
+    Result<(Actions, EntriesList), SyncError>
 {
     profile_this!();
 
     // As we receive entry details from the source and dest, we will build up lists of which entries
     // need copying and which need deleting. We will be both adding and removing entries from these
     // lists as a decision might need changing once we receive details from both source and dest.
-    let mut to_delete = ToDelete::new();
-    let mut to_copy = ToCopy::new();
+    let mut to_delete = ToDelete::with_spill_threshold(ctx.max_entries_in_memory);
+    let mut to_copy = ToCopy::with_spill_threshold(ctx.max_entries_in_memory);
 
-    let mut src_entries = EntriesList::new();
+    let mut src_entries = EntriesList::with_spill_threshold(ctx.max_entries_in_memory);
     let mut src_done = true;
 
-    let mut dest_entries = EntriesList::new();
+    let mut dest_entries = EntriesList::with_spill_threshold(ctx.max_entries_in_memory);
     let mut dest_done = true;
 
-    // Add the source root entry
-    process_src_entry(ctx, RootRelativePath::root(), src_root_details.clone(),
-        &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
-        &mut to_delete, &mut to_copy);
-
-    if matches!(src_root_details, EntryDetails::Folder) {
-        ctx.src_comms.send_command(Command::GetEntries { filters: ctx.filters.clone() })?;
-        src_done = false;
+    let cached_src_entries = ctx.src_entries_cache.as_mut().and_then(|c| c.entries.take());
+    if let Some(cached_src_entries) = cached_src_entries {
+        // We've already queried the source for a previous dest, so use those entries (including the root)
+        // rather than asking again. There are no dest entries yet, so this is the same as if the source
+        // entries had all arrived first.
+        debug!("Using {} cached source entries", cached_src_entries.len());
+        let cached_read_errors = ctx.src_entries_cache.as_ref().map_or(vec![], |c| c.read_errors.clone());
+        ctx.read_errors.extend(cached_read_errors);
+        let cached_num_filtered = ctx.src_entries_cache.as_ref().map_or(0, |c| c.num_filtered);
+        ctx.stats.add_skipped(SkipReason::Filter, cached_num_filtered);
+        let cached_num_unsafe_links = ctx.src_entries_cache.as_ref().map_or(0, |c| c.num_unsafe_links);
+        ctx.stats.add_skipped(SkipReason::UnsafeLink, cached_num_unsafe_links);
+        for (p, src_entry) in cached_src_entries.iter() {
+            process_src_entry(ctx, p.into_owned(), src_entry.into_owned(),
+                &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
+                &mut to_delete, &mut to_copy)?;
+        }
+    } else {
+        // Add the source root entry
+        process_src_entry(ctx, RootRelativePath::root(), src_root_details.clone(),
+            &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
+            &mut to_delete, &mut to_copy)?;
+
+        if matches!(src_root_details, EntryDetails::Folder) {
+            ctx.src_comms.send_command(Command::GetEntries { filters: ctx.src_filters.clone(), skip_unreadable: ctx.ignore_read_errors,
+                safe_links: ctx.safe_links })?;
+            src_done = false;
+        }
     }
 
     if let Some(d) = &dest_root_details {
         // Add the dest root entry
         process_dest_entry(ctx, RootRelativePath::root(), d.clone(), &src_entries,
-            &mut dest_entries, dest_platform_differentiates_symlinks, &mut to_delete, &mut to_copy);
+            &mut dest_entries, dest_platform_differentiates_symlinks, &mut to_delete, &mut to_copy)?;
 
         if let EntryDetails::Folder = d {
-            ctx.dest_comms.send_command(Command::GetEntries { filters: ctx.filters.clone() })?;
+            ctx.dest_comms.send_command(Command::GetEntries { filters: ctx.dest_filters.clone(), skip_unreadable: false, safe_links: None })?;
             dest_done = false;
         }
     }
 
+    // The most recent entry received from each side, to help diagnose which path a doer is stuck on
+    let mut last_src_path = RootRelativePath::root();
+    let mut last_dest_path = RootRelativePath::root();
+
     while !src_done || !dest_done {
         // Wait for either src or dest to send us a response with an entry
-        match memory_bound_channel::select_ready(ctx.src_comms.get_receiver(), ctx.dest_comms.get_receiver()) {
+        let ready = match ctx.query_timeout {
+            None => Some(memory_bound_channel::select_ready(ctx.src_comms.get_receiver(), ctx.dest_comms.get_receiver())),
+            Some(t) => memory_bound_channel::select_ready_timeout(ctx.src_comms.get_receiver(), ctx.dest_comms.get_receiver(), t),
+        };
+        match ready {
+            None => {
+                let mut stalled = vec![];
+                if !src_done {
+                    stalled.push(format!("{} (last entry received was {})", ctx.src_comms, ctx.pretty_src_kind(&last_src_path, "entry")));
+                }
+                if !dest_done {
+                    stalled.push(format!("{} (last entry received was {})", ctx.dest_comms, ctx.pretty_dest_kind(&last_dest_path, "entry")));
+                }
+                return Err(SyncError::QueryTimeout(format!("Timed out querying entries: nothing received for {} seconds from {}. See --query-timeout.",
+                    ctx.query_timeout.unwrap().as_secs(), stalled.join(" or "))));
+            }
             // Source entry
-            0 => match ctx.src_comms.receive_response()? {
-                Response::Entry((p, src_entry)) => process_src_entry(ctx, p, src_entry,
-                    &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
-                    &mut to_delete, &mut to_copy),
-                Response::EndOfEntries => src_done = true,
-                r => return Err(format!("Unexpected response getting entries from src: {:?}", r)),
+            Some(0) => match ctx.src_comms.receive_response()? {
+                Response::Entry((p, src_entry)) => {
+                    if ctx.query_timeout.is_some() {
+                        last_src_path = p.clone();
+                    }
+                    process_src_entry(ctx, p, src_entry,
+                        &mut src_entries, &dest_entries, dest_platform_differentiates_symlinks,
+                        &mut to_delete, &mut to_copy)?
+                }
+                Response::UnreadableEntry { path, error } => {
+                    debug!("Skipping {} as it couldn't be read: {error}", ctx.pretty_src_kind(&path, "entry"));
+                    ctx.read_errors.push(ReadError { path, kind: ReadErrorKind::Details, error });
+                }
+                Response::EndOfEntries { num_filtered, num_unsafe_links, internal_entries } => {
+                    ctx.stats.add_skipped(SkipReason::Filter, num_filtered);
+                    ctx.stats.add_skipped(SkipReason::UnsafeLink, num_unsafe_links);
+                    for p in &internal_entries {
+                        warn!("Ignoring {} as names starting with '{INTERNAL_ENTRY_PREFIX}' are reserved for rjrssync's own files",
+                            ctx.pretty_src_kind(p, "entry"));
+                    }
+                    src_done = true;
+                }
+                Response::Error(e) => return Err(doer_error(ctx.src_comms, ctx.src_dir_separator, &e).into()),
+                r => return Err(format!("Unexpected response getting entries from src: {:?}", r).into()),
             },
             // Dest entry
-            1 => match ctx.dest_comms.receive_response()? {
-                Response::Entry((p, dest_entry)) => process_dest_entry(ctx, p, dest_entry,
-                    &src_entries, &mut dest_entries, dest_platform_differentiates_symlinks,
-                    &mut to_delete, &mut to_copy),
-                Response::EndOfEntries => dest_done = true,
-                r => return Err(format!("Unexpected response getting entries from dest: {:?}", r)),
+            Some(1) => match ctx.dest_comms.receive_response()? {
+                Response::Entry((p, dest_entry)) => {
+                    if ctx.query_timeout.is_some() {
+                        last_dest_path = p.clone();
+                    }
+                    process_dest_entry(ctx, p, dest_entry,
+                        &src_entries, &mut dest_entries, dest_platform_differentiates_symlinks,
+                        &mut to_delete, &mut to_copy)?
+                }
+                Response::EndOfEntries { internal_entries, .. } => {
+                    for p in &internal_entries {
+                        warn!("Ignoring {} as names starting with '{INTERNAL_ENTRY_PREFIX}' are reserved for rjrssync's own files",
+                            ctx.pretty_dest_kind(p, "entry"));
+                    }
+                    ctx.dest_internal_entries = internal_entries;
+                    dest_done = true;
+                }
+                Response::Error(e) => return Err(doer_error(ctx.dest_comms, ctx.dest_dir_separator, &e).into()),
+                r => return Err(format!("Unexpected response getting entries from dest: {:?}", r).into()),
             },
             _ => panic!("Invalid index"),
         }
@@ -513,6 +1280,56 @@ fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_roo
     ctx.stats.num_src_entries = src_entries.len() as u32;
     ctx.stats.num_dest_entries = dest_entries.len() as u32;
 
+    // We don't know what the source has at (or, for a folder, inside) an unreadable path, so leave the dest alone there.
+    // Otherwise we might delete dest entries just because we couldn't see them on the source.
+    if !ctx.read_errors.is_empty() {
+        let unreadable = |p: &RootRelativePath| ctx.read_errors.iter().any(|e| p.starts_with(&e.path));
+        let copies: Vec<RootRelativePath> = to_copy.iter().map(|(p, _)| p.into_owned()).filter(unreadable).collect();
+        let deletes: Vec<RootRelativePath> = to_delete.iter().map(|(p, _)| p.into_owned()).filter(unreadable).collect();
+        for p in copies {
+            to_copy.remove(&p)?;
+        }
+        for p in deletes {
+            to_delete.remove(&p)?;
+        }
+    }
+
+    // We can only tell which entries are new once we've heard from both sides (the dest entry might arrive after
+    // the source entry), so for --existing we remove them now, rather than when processing each entry.
+    if ctx.existing_only {
+        let new_entries: Vec<RootRelativePath> = to_copy.iter()
+            .filter(|(p, _)| dest_entries.lookup(p).is_none())
+            .map(|(p, _)| p.into_owned()).collect();
+        for p in new_entries {
+            ctx.explain(format_args!("{} doesn't exist on the dest. Will not create it (--existing).",
+                ctx.pretty_dest_kind(&p, "entry")));
+            to_copy.remove(&p)?;
+            ctx.stats.add_skipped(SkipReason::NotOnDest, 1);
+        }
+    }
+
+    // Any problems reading the lists back from disk would mean that the plan is wrong, so must stop now
+    src_entries.check_read_error()?;
+    dest_entries.check_read_error()?;
+    to_delete.check_read_error()?;
+    to_copy.check_read_error()?;
+
+    // The checksums for the manifest are calculated after the sync, so only the source files' details are needed for now
+    if ctx.manifest_out.is_some() {
+        ctx.manifest_files = src_entries.iter().filter_map(|(p, d)| match &*d {
+            EntryDetails::File { modified_time, size, .. } => Some((p.into_owned(), *size, *modified_time)),
+            _ => None,
+        }).collect();
+        src_entries.check_read_error()?;
+    }
+
+    if let Some(c) = ctx.src_entries_cache.as_mut() {
+        c.entries = Some(src_entries);
+        c.read_errors = ctx.read_errors.clone();
+        c.num_filtered = ctx.stats.num_skipped.get(&SkipReason::Filter).copied().unwrap_or(0);
+        c.num_unsafe_links = ctx.stats.num_skipped.get(&SkipReason::UnsafeLink).copied().unwrap_or(0);
+    }
+
     // Reverse the order of to_delete, so that entries are deleted from last to first.
     // We do this to make sure that files are deleted before their parent folder
     // (otherwise deleting the parent is harder/more risky - possibly would also have problems with
@@ -520,15 +1337,27 @@ fn query_entries(ctx: &mut SyncContext, src_root_details: EntryDetails, dest_roo
     // see test_remove_dest_folder_with_excluded_files())
     to_delete.reverse_order();
 
-    Ok(Actions { to_delete, to_copy })
+    Ok((Actions { to_delete, to_copy }, dest_entries))
+}
+
+/// Two different names on the source could end up as the same RootRelativePath (e.g. if the source filesystem
+/// does some kind of name normalization), in which case one would silently replace the other in our lists,
+/// and so wouldn't be synced. Raise an error rather than risk silently losing data.
+fn check_src_entry_not_duplicate(src_entries: &EntriesList, p: &RootRelativePath, pretty: PrettyPath) -> Result<(), String> {
+    if src_entries.lookup(p).is_some() {
+        return Err(format!("{pretty} was found more than once on the source (possibly two names which differ only by case or \
+            Unicode normalization). Will not continue, as only one of them could be synced."));
+    }
+    Ok(())
 }
 
 fn process_src_entry(ctx: &mut SyncContext, p: RootRelativePath, src_entry: EntryDetails,
     src_entries: &mut EntriesList, dest_entries: &EntriesList,
     dest_platform_differentiates_symlinks: bool,
     to_delete: &mut ToDelete, to_copy: &mut ToCopy,
-) {
+) -> Result<(), String> {
     trace!("Source entry '{}': {:?}", p, src_entry);
+    check_src_entry_not_duplicate(src_entries, &p, ctx.pretty_src(&p, &src_entry))?;
     match src_entry {
         EntryDetails::File { size, .. } => {
             ctx.stats.num_src_files += 1;
@@ -540,32 +1369,34 @@ fn process_src_entry(ctx: &mut SyncContext, p: RootRelativePath, src_entry: Entr
     }
     // Check if we've already seen an equivalent entry on the dest side, and decide
     // whether or not we need to copy this entry over
-    match dest_entries.lookup(&p) {
-        None => to_copy.add(p.clone(), (src_entry.clone(), CopyReason::NotOnDest)),
+    match dest_entries.lookup(&p).as_deref() {
+        None => to_copy.add(p.clone(), (src_entry.clone(), CopyReason::NotOnDest))?,
         Some(dest_entry) => {
             // This entry will already be in to_delete, but we might need to remove it now
             if needs_delete(&src_entry, dest_entry, dest_platform_differentiates_symlinks) {
+                ctx.explain(format_args!("{} is incompatible with {}. Will delete and replace.",
+                    ctx.pretty_dest(&p, dest_entry), ctx.pretty_src(&p, &src_entry)));
                 // Even though the entry is already in to_delete, the *reason* needs updating
-                to_delete.update(&p, (dest_entry.clone(), DeleteReason::Incompatible));
+                to_delete.update(&p, (dest_entry.clone(), DeleteReason::Incompatible))?;
                 // Dest is going to be deleted, so we will definitely be copying the source
-                to_copy.add(p.clone(), (src_entry.clone(), CopyReason::NotOnDest));
+                to_copy.add(p.clone(), (src_entry.clone(), CopyReason::NotOnDest))?;
             } else {
-                to_delete.remove(&p);
+                to_delete.remove(&p)?;
                 if let Some(r) = needs_copy(ctx, &p, &src_entry, dest_entry) {
-                    to_copy.add(p.clone(), (src_entry.clone(), r));
+                    to_copy.add(p.clone(), (src_entry.clone(), r))?;
                 }
             }
         }
     }
 
-    src_entries.add(p, src_entry);
+    src_entries.add(p, src_entry)
 }
 
 fn process_dest_entry(ctx: &mut SyncContext, p: RootRelativePath, dest_entry: EntryDetails,
     src_entries: &EntriesList, dest_entries: &mut EntriesList,
     dest_platform_differentiates_symlinks: bool,
     to_delete: &mut ToDelete, to_copy: &mut ToCopy,
-) {
+) -> Result<(), String> {
     trace!("Dest entry '{}': {:?}", p, dest_entry);
     match dest_entry {
         EntryDetails::File { size, .. } => {
@@ -576,22 +1407,24 @@ fn process_dest_entry(ctx: &mut SyncContext, p: RootRelativePath, dest_entry: En
         EntryDetails::Symlink { .. } => ctx.stats.num_dest_symlinks += 1,
     }
 
-    dest_entries.add(p.clone(), dest_entry.clone());
+    dest_entries.add(p.clone(), dest_entry.clone())?;
 
     // Check if we've already seen an equivalent entry on the source side, and decide
     // whether or not we need to delete this entry
-    match src_entries.lookup(&p) {
+    match src_entries.lookup(&p).as_deref() {
         None => to_delete.add(p, (dest_entry, DeleteReason::NotOnSource)),
         Some(src_entry) => {
             // This entry will already be in to_copy, but we might need to remove it now
             if needs_delete(src_entry, &dest_entry, dest_platform_differentiates_symlinks) {
-                to_delete.add(p, (dest_entry, DeleteReason::Incompatible));
+                ctx.explain(format_args!("{} is incompatible with {}. Will delete and replace.",
+                    ctx.pretty_dest(&p, &dest_entry), ctx.pretty_src(&p, src_entry)));
+                to_delete.add(p, (dest_entry, DeleteReason::Incompatible))
             } else {
                 if let Some(r) = needs_copy(ctx, &p, src_entry, &dest_entry) {
                     // Even though the entry is already in to_copy, the *reason* needs updating
-                    to_copy.update(&p, (src_entry.clone(), r));
+                    to_copy.update(&p, (src_entry.clone(), r))
                 } else {
-                    to_copy.remove(&p);
+                    to_copy.remove(&p)
                 }
             }
         }
@@ -617,7 +1450,10 @@ fn needs_delete(src: &EntryDetails, dest: &EntryDetails, dest_platform_different
             EntryDetails::Symlink { kind: dest_kind, target: dest_target } => {
                 if src_target != dest_target {
                     true
-                } else if src_kind != dest_kind && dest_platform_differentiates_symlinks {
+                // A source symlink of Unknown kind (e.g. a broken symlink on Linux) is considered to match a dest symlink
+                // of either kind. Otherwise a symlink whose target comes and goes on the source would be deleted and
+                // recreated on every sync (or fail, as an Unknown symlink can't be created on a platform which differentiates them).
+                } else if src_kind != dest_kind && dest_platform_differentiates_symlinks && *src_kind != SymlinkKind::Unknown {
                     true
                 } else {
                     false
@@ -635,47 +1471,63 @@ fn needs_copy(ctx: &SyncContext, path: &RootRelativePath, src_details: &EntryDet
 {
     // Dest already has this entry - check if it is up-to-date
     match src_details {
-        EntryDetails::File { modified_time: src_modified_time, .. } => {
-            let dest_modified_time = match dest_details {
-                EntryDetails::File { modified_time, .. } => modified_time,
+        EntryDetails::File { modified_time: src_modified_time, flags: src_flags, .. } => {
+            let (dest_modified_time, dest_flags) = match dest_details {
+                EntryDetails::File { modified_time, flags, .. } => (modified_time, flags),
                 _ => panic!("Wrong entry type"), // This should never happen as we check the type in should_delete
             };
-            match src_modified_time.cmp(&dest_modified_time) {
+            let dest_pretty = ctx.pretty_dest_kind(path, "file");
+            let src_pretty = ctx.pretty_src_kind(path, "file");
+            match src_modified_time.cmp(dest_modified_time) {
                 Ordering::Equal => {
                     // This option is unlikely to be changed from the default, so we don't bother
                     // adding all the files to the list be later removed. We could perhaps do the same
                     // for newer/older, but haven't refactored that.
                     if ctx.files_same_time_behaviour == DestFileUpdateBehaviour::Skip {
-                        trace!("{} has same modified time as {}. Will not update.",
-                            ctx.pretty_dest_kind(&path, "file"),
-                            ctx.pretty_src_kind(&path, "file"));
-                        None
+                        // Note that flags are only retrieved for --flags, otherwise they're always the same
+                        if src_flags != dest_flags {
+                            ctx.explain(format_args!("{dest_pretty} has same modified time as {src_pretty}, but different flags. Will update flags."));
+                            Some(CopyReason::FlagsDiffer)
+                        } else {
+                            ctx.explain(format_args!("{dest_pretty} has same modified time as {src_pretty}. Will not update."));
+                            None
+                        }
                     } else {
+                        ctx.explain(format_args!("{dest_pretty} has same modified time as {src_pretty}. Will update, subject to --files-same-time."));
                         Some(CopyReason::SameTimeAndNotSkipped)
                     }
                 },
-                Ordering::Greater => Some(CopyReason::DestOlder),
-                Ordering::Less => Some(CopyReason::DestNewer),
+                Ordering::Greater => {
+                    ctx.explain(format_args!("{dest_pretty} is older than {src_pretty}. Will update, subject to --dest-file-older."));
+                    Some(CopyReason::DestOlder)
+                },
+                Ordering::Less => {
+                    ctx.explain(format_args!("{dest_pretty} is newer than {src_pretty}. Will update, subject to --dest-file-newer."));
+                    Some(CopyReason::DestNewer)
+                },
             }
         },
         EntryDetails::Folder |  // Folders are always up-to-date
         EntryDetails::Symlink { .. }  // Symlinks are always up-to-date, if should_delete indicated that we shouldn't delete it
         => {
-            trace!("{} already exists at {} - nothing to do",
-                ctx.pretty_src(&path, &src_details),
-                ctx.pretty_dest(&path, dest_details));
+            ctx.explain(format_args!("{} already exists at {} - nothing to do",
+                ctx.pretty_src(path, src_details),
+                ctx.pretty_dest(path, dest_details)));
             None
         },
     }
 }
 
 fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), String> {
+    apply_no_delete_patterns(ctx, actions)?;
+
     // Confirm deletes
     let mut to_remove = vec![]; // Rather than removing things as we go, we remove them at the end
-    for (path, (entry_to_delete, reason)) in actions.to_delete.iter() {
+    for (path, v) in actions.to_delete.iter() {
+        let (entry_to_delete, reason) = &*v;
         let msg = format!(
             "{} needs deleting {}",
-            ctx.pretty_dest(path, entry_to_delete),
+            ctx.pretty_dest(&path, entry_to_delete),
             match reason {
                 DeleteReason::NotOnSource => "as it doesn't exist on the src",
                 DeleteReason::Incompatible => "to allow the source entry to be copied",
@@ -692,6 +1544,9 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
                     ], true, DestEntryNeedsDeletingBehaviour::Error);
                 if let Some(b) = prompt_result.remembered_behaviour {
                     ctx.dest_entry_needs_deleting_behaviour = b;
+                    if prompt_result.remembered_for_all_categories {
+                        ctx.remember_for_all_prompt_categories(b == DestEntryNeedsDeletingBehaviour::Delete);
+                    }
                 }
                 prompt_result.immediate_behaviour
             },
@@ -704,20 +1559,23 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
             )),
             DestEntryNeedsDeletingBehaviour::Skip => {
                 trace!("{msg}. Skipping.");
-                to_remove.push(path.clone());
+                to_remove.push(path.clone().into_owned());
+                ctx.stats.add_skipped(SkipReason::NeedsDeleting, 1);
             }
             DestEntryNeedsDeletingBehaviour::Delete => (), // Carry on
         }
     }
     for p in to_remove {
-        actions.to_delete.remove(&p);
+        actions.to_delete.remove(&p)?;
     }
 
     // Confirm copies
     let mut to_remove = vec![]; // Rather than removing things as we go, we remove them at the end
-    for (path, (_entry_to_copy, reason)) in actions.to_copy.iter() {
+    for (path, v) in actions.to_copy.iter() {
+        let (_entry_to_copy, reason) = &*v;
         match reason {
             CopyReason::NotOnDest => (), // Nothing to confirm
+            CopyReason::FlagsDiffer => (), // Nothing to confirm, as the contents won't be changed
             CopyReason::DestNewer => {
                 let msg = format!(
                     "{} is newer than {}",
@@ -734,6 +1592,9 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
                             ], true, DestFileUpdateBehaviour::Error);
                         if let Some(b) = prompt_result.remembered_behaviour {
                             ctx.dest_file_newer_behaviour = b;
+                            if prompt_result.remembered_for_all_categories {
+                                ctx.remember_for_all_prompt_categories(b == DestFileUpdateBehaviour::Overwrite);
+                            }
                         }
                         prompt_result.immediate_behaviour
                     },
@@ -746,7 +1607,8 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
                     )),
                     DestFileUpdateBehaviour::Skip => {
                         trace!("{msg}. Skipping.");
-                        to_remove.push(path.clone());
+                        to_remove.push(path.clone().into_owned());
+                        ctx.stats.add_skipped(SkipReason::DestNewer, 1);
                     }
                     DestFileUpdateBehaviour::Overwrite => {
                         trace!("{msg}. Overwriting anyway.");
@@ -769,6 +1631,9 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
                             ], true, DestFileUpdateBehaviour::Error);
                         if let Some(b) = prompt_result.remembered_behaviour {
                             ctx.dest_file_older_behaviour = b;
+                            if prompt_result.remembered_for_all_categories {
+                                ctx.remember_for_all_prompt_categories(b == DestFileUpdateBehaviour::Overwrite);
+                            }
                         }
                         prompt_result.immediate_behaviour
                     },
@@ -781,7 +1646,8 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
                     )),
                     DestFileUpdateBehaviour::Skip => {
                         trace!("{msg}. Skipping.");
-                        to_remove.push(path.clone());
+                        to_remove.push(path.clone().into_owned());
+                        ctx.stats.add_skipped(SkipReason::DestOlder, 1);
                     }
                     DestFileUpdateBehaviour::Overwrite => {
                         trace!("{msg}. Overwriting.");
@@ -804,6 +1670,9 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
                             ], true, DestFileUpdateBehaviour::Error);
                         if let Some(b) = prompt_result.remembered_behaviour {
                             ctx.files_same_time_behaviour = b;
+                            if prompt_result.remembered_for_all_categories {
+                                ctx.remember_for_all_prompt_categories(b == DestFileUpdateBehaviour::Overwrite);
+                            }
                         }
                         prompt_result.immediate_behaviour
                     },
@@ -816,7 +1685,8 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
                     )),
                     DestFileUpdateBehaviour::Skip => {
                         trace!("{msg}. Skipping.");
-                        to_remove.push(path.clone());
+                        to_remove.push(path.clone().into_owned());
+                        ctx.stats.add_skipped(SkipReason::SameTime, 1);
                     }
                     DestFileUpdateBehaviour::Overwrite => {
                         trace!("{msg}. Overwriting anyway.");
@@ -826,13 +1696,119 @@ fn confirm_actions(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), S
         }
     }
     for p in to_remove {
-        actions.to_copy.remove(&p);
+        actions.to_copy.remove(&p)?;
     }
 
     Ok(())
 }
 
-fn show_post_query_stats(ctx: &SyncContext, query_elapsed_secs: f32) {
+#[derive(Clone, Copy)]
+enum ConfirmPlanResponse {
+    Proceed,
+    ShowPlan,
+    Cancel,
+}
+
+/// Shows a summary of all the actions that will be taken and asks the user whether to proceed (see --confirm).
+/// The user can also choose to see the full list of actions, before being asked again.
+fn confirm_plan(ctx: &SyncContext, actions: &Actions) -> bool {
+    let mut num_files_to_copy = 0;
+    let mut num_bytes_to_copy = 0;
+    let mut num_folders_to_create = 0;
+    let mut num_symlinks_to_copy = 0;
+    for (_, v) in actions.to_copy.iter() {
+        let (details, _) = &*v;
+        match details {
+            EntryDetails::File { size, .. } => {
+                num_files_to_copy += 1;
+                num_bytes_to_copy += size;
+            }
+            EntryDetails::Folder => num_folders_to_create += 1,
+            EntryDetails::Symlink { .. } => num_symlinks_to_copy += 1,
+        }
+    }
+    let mut num_to_delete = 0;
+    let mut num_bytes_to_delete = 0;
+    for (_, v) in actions.to_delete.iter() {
+        let (details, _) = &*v;
+        num_to_delete += 1;
+        if let EntryDetails::File { size, .. } = details {
+            num_bytes_to_delete += size;
+        }
+    }
+    if num_files_to_copy + num_folders_to_create + num_symlinks_to_copy + num_to_delete == 0 {
+        return true; // Nothing will be changed, so nothing to confirm
+    }
+
+    let msg = format!("Will copy {} file(s) totalling {}, create {} folder(s) and copy {} symlink(s), and delete {} entry(s) totalling {}. Proceed?",
+        HumanCount(num_files_to_copy), HumanBytes(num_bytes_to_copy), HumanCount(num_folders_to_create),
+        HumanCount(num_symlinks_to_copy), HumanCount(num_to_delete), HumanBytes(num_bytes_to_delete));
+    loop {
+        let prompt_result = resolve_prompt(msg.clone(),
+            None,
+            &[
+                ("Proceed", ConfirmPlanResponse::Proceed),
+                ("Show plan", ConfirmPlanResponse::ShowPlan),
+            ], false, ConfirmPlanResponse::Cancel);
+        match prompt_result.immediate_behaviour {
+            ConfirmPlanResponse::Proceed => return true,
+            ConfirmPlanResponse::Cancel => return false,
+            ConfirmPlanResponse::ShowPlan => {
+                for (path, v) in actions.to_delete.iter() {
+                    let (details, reason) = &*v;
+                    info!("Will delete ({}) {}", reason.describe(), ctx.pretty_dest(&path, details));
+                }
+                for (path, v) in actions.to_copy.iter() {
+                    let (details, reason) = &*v;
+                    match details {
+                        EntryDetails::File { size, .. } => info!("Will copy ({}, {}) {} => {}", HumanBytes(*size), reason.describe(),
+                            ctx.pretty_src_kind(&path, "file"), ctx.pretty_dest_kind(&path, "file")),
+                        _ => info!("Will create ({}) {}", reason.describe(), ctx.pretty_dest(&path, details)),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Removes dest entries matching a --no-delete-pattern from the list of things to delete, if they are only
+/// being deleted because they aren't on the source. This doesn't affect entries that will be
+/// overwritten by the source (they are in to_copy rather than to_delete).
+fn apply_no_delete_patterns(ctx: &mut SyncContext, actions: &mut Actions) -> Result<(), String> {
+    if ctx.no_delete_patterns.is_empty() {
+        return Ok(());
+    }
+
+    let mut to_keep = HashSet::new();
+    for (path, v) in actions.to_delete.iter() {
+        let (entry, reason) = &*v;
+        if !matches!(reason, DeleteReason::NotOnSource) {
+            continue;
+        }
+        let ancestors = path.self_and_ancestors();
+        // Patterns that match a folder also protect its contents, similar to filters
+        if !ancestors.iter().any(|a| a.regex_set_matches(&ctx.no_delete_patterns).matched_any()) {
+            continue;
+        }
+        // If an ancestor is being deleted to make way for a source entry, then we can't keep this
+        if ancestors.iter().any(|a| matches!(actions.to_delete.lookup(a).as_deref(), Some((_, DeleteReason::Incompatible)))) {
+            continue;
+        }
+        ctx.explain(format_args!("{} matches a --no-delete-pattern. Will not delete.", ctx.pretty_dest(&path, entry)));
+        // Any ancestor folders also need keeping, otherwise they couldn't be deleted as they wouldn't be empty
+        to_keep.extend(ancestors);
+    }
+
+    for p in to_keep {
+        if actions.to_delete.lookup(&p).is_some() {
+            actions.to_delete.remove(&p)?;
+            ctx.stats.add_skipped(SkipReason::NoDeletePattern, 1);
+        }
+    }
+    Ok(())
+}
+
+fn show_post_query_stats(ctx: &SyncContext, query_elapsed: Duration) {
     if ctx.show_stats {
         info!("Source: {} file(s) totalling {}, {} folder(s) and {} symlink(s)",
             HumanCount(ctx.stats.num_src_files as u64),
@@ -849,12 +1825,49 @@ fn show_post_query_stats(ctx: &SyncContext, query_elapsed_secs: f32) {
         );
         info!("Source file size distribution:");
         info!("{}", ctx.stats.src_file_size_hist);
-        info!("Queried in {:.2} seconds", query_elapsed_secs);
+        info!("Queried in {}", format_duration(query_elapsed));
+    }
+}
+
+/// Reports the differences between the source and dest (for --diff), based on the actions that a sync would take.
+fn show_diff(ctx: &SyncContext, actions: &Actions) {
+    let mut num_only_on_src = 0;
+    let mut num_different = 0;
+    for (path, v) in actions.to_copy.iter() {
+        let (src_details, reason) = &*v;
+        let difference = match reason {
+            // Incompatible entries are in to_delete as well, and count as different rather than only on the source
+            CopyReason::NotOnDest => match actions.to_delete.lookup(&path) {
+                Some(_) => "different types",
+                None => {
+                    info!("Only on source: {}", ctx.pretty_src(&path, src_details));
+                    num_only_on_src += 1;
+                    continue;
+                }
+            },
+            CopyReason::DestNewer => "dest is newer",
+            CopyReason::DestOlder => "dest is older",
+            CopyReason::SameTimeAndNotSkipped => "same modified time",
+            CopyReason::FlagsDiffer => "different flags",
+        };
+        info!("Different ({difference}): {}", ctx.pretty_src(&path, src_details));
+        num_different += 1;
     }
+
+    // to_delete is in reverse order (ready for deleting), so put it back in the order that entries were found
+    let only_on_dest: Vec<_> = actions.to_delete.iter().filter(|(_, v)| v.1 == DeleteReason::NotOnSource).collect();
+    for (path, v) in only_on_dest.iter().rev() {
+        info!("Only on dest: {}", ctx.pretty_dest(path, &v.0));
+    }
+
+    let num_identical = ctx.stats.num_src_entries as usize - num_only_on_src - num_different;
+    info!("{} only on source, {} only on dest, {} different, {} identical",
+        HumanCount(num_only_on_src as u64), HumanCount(only_on_dest.len() as u64),
+        HumanCount(num_different as u64), HumanCount(num_identical as u64));
 }
 
 fn delete_dest_entry(ctx: &mut SyncContext, progress: &mut Progress,
-    dest_path: &RootRelativePath, dest_details: &EntryDetails)
+    dest_path: &RootRelativePath, dest_details: &EntryDetails, reason: &DeleteReason)
     -> Result<(), String>
 {
     trace!("Deleting {dest_path}");
@@ -886,7 +1899,11 @@ fn delete_dest_entry(ctx: &mut SyncContext, progress: &mut Progress,
         ctx.dest_comms.send_command(c)?;
     } else {
         // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be deleted
-        info!("Would delete {}", ctx.pretty_dest(dest_path, dest_details));
+        let size = match dest_details {
+            EntryDetails::File { size, .. } => Some(*size),
+            _ => None,
+        };
+        info!("Would delete{} {}", ctx.dry_run_details(size, reason.describe()), ctx.pretty_dest(dest_path, dest_details));
     });
 
     progress.delete_sent(&dest_details);
@@ -895,30 +1912,50 @@ fn delete_dest_entry(ctx: &mut SyncContext, progress: &mut Progress,
 }
 
 fn copy_entry(ctx: &mut SyncContext, progress: &mut Progress,
-    path: &RootRelativePath, src_details: &EntryDetails) -> Result<(), String>
+    path: &RootRelativePath, src_details: &EntryDetails, reason: &CopyReason) -> Result<(), String>
 {
     match src_details {
-        EntryDetails::File { size, modified_time: src_modified_time } => {
-            debug!("Copying {}", ctx.pretty_src(&path, &src_details));
-            copy_file(&path, *size, *src_modified_time, ctx, progress)?
+        EntryDetails::File { size, flags, .. } if *reason == CopyReason::FlagsDiffer => {
+            debug!("Updating flags of {}", ctx.pretty_dest(path, src_details));
+            ctx.send_progress_marker_limited(progress)?;
+            if !ctx.dry_run {
+                ctx.dest_comms.send_command(Command::SetFileFlags { path: path.clone(), flags: *flags })?;
+            } else {
+                info!("Would update flags of {} to {:?}", ctx.pretty_dest(path, src_details), flags);
+            }
+            progress.copy_sent_partial(0, *size, *size);
+            ctx.stats.num_files_flags_updated += 1;
+        }
+        EntryDetails::File { size, modified_time: src_modified_time, flags } => {
+            debug!("Copying {}", ctx.pretty_src(&path, src_details));
+            let linked = copy_file(&path, *size, *src_modified_time, *flags, reason, ctx, progress)?;
+            // The flags are set after the contents, as they might make the file immutable. If the dest file
+            // already existed, it might have had flags that need clearing. A hard-linked file shares its flags
+            // with the previous snapshot (which already has the right ones), so mustn't be changed.
+            if ctx.file_flags && !ctx.dry_run && !linked && (*flags != FileFlags::default() || *reason != CopyReason::NotOnDest) {
+                ctx.dest_comms.send_command(Command::SetFileFlags { path: path.clone(), flags: *flags })?;
+            }
         }
         EntryDetails::Folder => {
-            debug!("Creating {}", ctx.pretty_src(&path, &src_details));
+            debug!("Creating {}", ctx.pretty_src(&path, src_details));
             ctx.send_progress_marker_limited(progress)?;
             ctx.stats.num_folders_created += 1;
             if !ctx.dry_run {
                 ctx.dest_comms
                     .send_command(Command::CreateFolder {
                         path: path.clone(),
+                        // If the folder is excluded on the dest, then we don't know if it exists there.
+                        // When resuming, the dest doer allows it to exist anyway (see SetRoot).
+                        allow_existing: is_excluded_on_dest(ctx, path),
                     })?;
             } else {
                 // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be copied
-                info!("Would create {}", ctx.pretty_dest_kind(&path, "folder"));
+                info!("Would create{} {}", ctx.dry_run_details(None, reason.describe()), ctx.pretty_dest_kind(&path, "folder"));
             }
             progress.copy_sent(&src_details);
         },
         EntryDetails::Symlink { ref kind, ref target } => {
-            debug!("Copying {}", ctx.pretty_src(&path, &src_details));
+            debug!("Copying {}", ctx.pretty_src(&path, src_details));
             ctx.send_progress_marker_limited(progress)?;
             ctx.stats.num_symlinks_copied += 1;
             if !ctx.dry_run {
@@ -930,7 +1967,7 @@ fn copy_entry(ctx: &mut SyncContext, progress: &mut Progress,
                     })?;
             } else {
                 // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be copied
-                info!("Would create {}", ctx.pretty_dest_kind(&path, "symlink"));
+                info!("Would create{} {}", ctx.dry_run_details(None, reason.describe()), ctx.pretty_dest_kind(&path, "symlink"));
             }
             progress.copy_sent(&src_details);
         }
@@ -938,47 +1975,85 @@ fn copy_entry(ctx: &mut SyncContext, progress: &mut Progress,
     Ok(())
 }
 
+/// Returns true if the file was hard linked to the previous snapshot (see --link-dest), rather than being copied.
 fn copy_file(
     path: &RootRelativePath,
     size: u64,
     modified_time: SystemTime,
+    flags: FileFlags,
+    reason: &CopyReason,
     ctx: &mut SyncContext,
-    progress: &mut Progress) -> Result<(), String>
+    progress: &mut Progress) -> Result<bool, String>
 {
     ctx.send_progress_marker_limited(progress)?;
 
+    // When making a backup snapshot, files which are unchanged since the previous snapshot are hard-linked
+    // to it rather than being copied again.
+    let sep = ctx.dest_dir_separator.unwrap_or('/');
+    let link_target = ctx.link_dest.as_ref().and_then(|(prev_root, prev_entries)| match prev_entries.lookup(path).as_deref() {
+        Some(EntryDetails::File { size: s, modified_time: m, flags: f }) if *s == size && *m == modified_time
+            && (!ctx.file_flags || *f == flags) =>
+            Some(if path.is_root() { prev_root.clone() } else { format!("{prev_root}{sep}{}", path.to_platform_path(sep)) }),
+        _ => None,
+    });
+    if let Some(target) = link_target {
+        if !ctx.dry_run {
+            ctx.dest_comms.send_command(Command::CreateHardLink { path: path.clone(), target })?;
+        } else {
+            info!("Would hard link{} {} to '{}'", ctx.dry_run_details(Some(size), reason.describe()), ctx.pretty_dest_kind(path, "file"), target);
+        }
+        progress.copy_sent_partial(0, size, size);
+        ctx.stats.num_files_linked += 1;
+        ctx.stats.num_bytes_linked += size;
+        return Ok(true);
+    }
+
     if !ctx.dry_run {
         trace!("Fetching from {}", ctx.pretty_src_kind(&path, "file"));
         ctx.src_comms
             .send_command(Command::GetFileContent {
                 path: path.clone(),
+                compress: ctx.compress && !path.extension().map_or(false, |e| ctx.skip_compress.contains(&e.to_lowercase())),
             })?;
         // Large files are split into chunks, loop until all chunks are transferred.
+        // Empty files are sent as a single empty chunk, which still creates (or truncates) the file on the dest.
         let mut chunk_offset: u64 = 0;
         loop {
             // Add progress markers during copies of large files, so we can see the progress (in bytes)
             ctx.send_progress_marker_limited(progress)?;
 
-            let (data, more_to_follow) = match ctx.src_comms.receive_response()? {
-                Response::FileContent { data, more_to_follow } => (data, more_to_follow),
+            let (data, uncompressed_size, more_to_follow) = match ctx.src_comms.receive_response()? {
+                Response::FileContent { data, uncompressed_size, more_to_follow } => (data, uncompressed_size, more_to_follow),
+                Response::Error(error) if ctx.ignore_read_errors => {
+                    skip_unreadable_file(ctx, progress, path, chunk_offset, size, error)?;
+                    return Ok(false);
+                }
+                Response::Error(e) => return Err(doer_error(ctx.src_comms, ctx.src_dir_separator, &e)),
                 x => return Err(format!(
                     "Unexpected response fetching {}: {:?}", ctx.pretty_src_kind(&path, "file"), x
                 )),
             };
             trace!("Create/update {}", ctx.pretty_dest_kind(&path, "file"));
-            let chunk_size = data.len();
+            if let Some(l) = &mut ctx.src_bwlimit {
+                l.consume(data.len() as u64);
+            }
+            // If the data is compressed, it's the original size that matters, not how much we're sending
+            let chunk_size = uncompressed_size.map_or(data.len(), |s| s as usize);
+            ctx.stats.num_bytes_transferred += data.len() as u64;
 
             if chunk_offset + chunk_size as u64 > size {
-                // We'll check the expected vs. actual size of the file anyway after this loop,
-                // but this will catch issues earlier, so we don't spend ages copying a big file
-                // only to report the error afterwards anyway.
-                break;
+                // The file has grown since the querying phase. We'd check the expected vs. actual size after this loop
+                // anyway, but this catches it earlier, so we don't spend ages copying a big file only to report the error afterwards.
+                // Note that we can't just stop receiving and rely on that check though, as a file that was empty when
+                // queried would then look like it had been copied successfully, without anything being written.
+                return Err(format!("Size of {} has changed during the sync.", ctx.pretty_src_kind(path, "file")));
             }
 
             ctx.dest_comms
                 .send_command(Command::CreateOrUpdateFile {
                     path: path.clone(),
                     data,
+                    uncompressed_size,
                     set_modified_time: if more_to_follow { None } else { Some(modified_time) }, // Only set the modified time after the final chunk
                     more_to_follow,
                 })?;
@@ -989,7 +2064,7 @@ fn copy_file(
 
             // For large files, it might be a while before process_dest_responses is called in the main sync function,
             // so check it periodically here too.
-            process_dest_responses(ctx.dest_comms, progress, false)?;
+            process_dest_responses(ctx.dest_comms, ctx.dest_dir_separator, progress, false)?;
 
             if !more_to_follow {
                 break;
@@ -1000,12 +2075,13 @@ fn copy_file(
             // The file has changed size since the querying phase. This will cause problems for boss_progress
             // because of asserts/assumptions it makes about work sent vs. completed, but also might indicate
             // something fishy is going on and so we err on the side of caution and raise an error.
-            return Err(format!("Size of {} has changed during the sync.", ctx.pretty_src_kind(&path, "file")));
+            return Err(format!("Size of {} has changed during the sync.", ctx.pretty_src_kind(path, "file")));
         }
     } else {
         progress.copy_sent_partial(0, size, size);
         // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be copied
-        info!("Would copy {} => {}",
+        info!("Would copy{} {} => {}",
+            ctx.dry_run_details(Some(size), reason.describe()),
             ctx.pretty_src_kind(&path, "file"),
             ctx.pretty_dest_kind(&path, "file"));
     }
@@ -1014,9 +2090,218 @@ fn copy_file(
     ctx.stats.num_bytes_copied += size;
     ctx.stats.copied_file_size_hist.add(size);
 
+    Ok(false)
+}
+
+/// Gives up on copying a file whose contents couldn't be read from the source (see --ignore-read-errors),
+/// after the given number of bytes were already sent to the dest.
+fn skip_unreadable_file(ctx: &mut SyncContext, progress: &mut Progress, path: &RootRelativePath,
+    chunk_offset: u64, size: u64, error: String) -> Result<(), String>
+{
+    debug!("Skipping {} as its contents couldn't be read: {error}", ctx.pretty_src_kind(path, "file"));
+    if chunk_offset > 0 {
+        // The dest file has already been (partially) overwritten, so rather than leaving it incomplete,
+        // finish it off and delete it. It will be copied again by the next sync.
+        ctx.dest_comms.send_command(Command::CreateOrUpdateFile {
+            path: path.clone(), data: vec![], uncompressed_size: None, set_modified_time: None, more_to_follow: false })?;
+        ctx.dest_comms.send_command(Command::DeleteFile { path: path.clone() })?;
+    }
+    // None of the rest of the file will be sent, but the progress bar needs to know that it's done
+    progress.copy_sent_partial(chunk_offset, size - chunk_offset, size);
+    ctx.read_errors.push(ReadError { path: path.clone(), kind: ReadErrorKind::Contents, error });
     Ok(())
 }
 
+/// Reports the source entries that were skipped because they couldn't be read (see --ignore-read-errors).
+fn show_read_errors(ctx: &SyncContext) {
+    if ctx.read_errors.is_empty() {
+        return;
+    }
+    let num_details = ctx.read_errors.iter().filter(|e| e.kind == ReadErrorKind::Details).count();
+    warn!("Skipped {} source entry(s) which couldn't be read: {} whose details couldn't be read and {} file(s) whose contents couldn't be read",
+        HumanCount(ctx.read_errors.len() as u64), HumanCount(num_details as u64),
+        HumanCount((ctx.read_errors.len() - num_details) as u64));
+    for e in &ctx.read_errors {
+        let what = match e.kind {
+            ReadErrorKind::Details => "Couldn't read details of",
+            ReadErrorKind::Contents => "Couldn't read contents of",
+        };
+        warn!("  {what} {}: {}", ctx.pretty_src_kind(&e.path, "entry"), e.error);
+    }
+}
+
+/// With --prune-empty-dirs, any dest folders which are empty once everything else is done are removed at the end.
+/// Rather than creating new folders only to remove them again, this stops them from being created in the first place.
+/// It also records which existing dest folders will be left empty, so that these can be reported for a dry run.
+/// Folders excluded on the dest are left alone, as we can't see what's inside them.
+fn skip_folders_left_empty(ctx: &mut SyncContext, dest_entries: &EntriesList, actions: &mut Actions) -> Result<(), String> {
+    // Find the folders that will have something other than empty folders inside them after the sync.
+    // rjrssync's own entries are never removed, so count as something.
+    let mut not_empty: HashSet<RootRelativePath> = ctx.dest_internal_entries.iter().flat_map(|p| p.self_and_ancestors()).collect();
+    let mut folders = vec![];
+    let remaining_dest_entries = dest_entries.iter().filter(|(p, _)| actions.to_delete.lookup(p).is_none())
+        .map(|(p, d)| (p, matches!(*d, EntryDetails::Folder)));
+    let copies = actions.to_copy.iter().map(|(p, v)| (p, matches!(v.0, EntryDetails::Folder)));
+    for (p, is_folder) in remaining_dest_entries.chain(copies) {
+        if is_folder {
+            folders.push(p.into_owned());
+        } else {
+            not_empty.extend(p.self_and_ancestors());
+        }
+    }
+
+    for f in folders {
+        if not_empty.contains(&f) || f.is_root() || is_excluded_on_dest(ctx, &f) {
+            continue;
+        }
+        if actions.to_copy.lookup(&f).is_some() {
+            ctx.explain(format_args!("{} would be empty. Will not create it (--prune-empty-dirs).",
+                ctx.pretty_dest_kind(&f, "folder")));
+            actions.to_copy.remove(&f)?;
+            ctx.stats.add_skipped(SkipReason::WouldBeEmpty, 1);
+        } else {
+            ctx.folders_left_empty.push(f);
+        }
+    }
+    dest_entries.check_read_error()?;
+    actions.check_read_error()
+}
+
+fn prune_empty_dest_folders(ctx: &mut SyncContext) -> Result<(), String> {
+    if ctx.dry_run {
+        // Print dry-run as info level, as presumably the user is interested in exactly _what_ will be removed.
+        // Note that this also includes folders which only contain entries excluded on the dest (which we can't see),
+        // even though these won't actually be removed.
+        for p in &ctx.folders_left_empty {
+            info!("Would remove empty {}", ctx.pretty_dest_kind(p, "folder"));
+        }
+        ctx.stats.num_folders_pruned = ctx.folders_left_empty.len() as u32;
+        return Ok(());
+    }
+    ctx.dest_comms.send_command(Command::PruneEmptyFolders { filters: ctx.dest_filters.clone() })?;
+    match ctx.dest_comms.receive_response()? {
+        Response::EmptyFoldersPruned(pruned) => {
+            for p in &pruned {
+                debug!("Removed empty {}", ctx.pretty_dest_kind(p, "folder"));
+            }
+            ctx.stats.num_folders_pruned = pruned.len() as u32;
+            Ok(())
+        }
+        Response::Error(e) => Err(format!("Error removing empty folders from dest: {e}")),
+        r => Err(format!("Unexpected response removing empty folders from dest: {:?}", r)),
+    }
+}
+
+/// One bucket of a file size histogram, as exported to a CSV file with --histogram-out.
+#[derive(Serialize)]
+struct HistogramBucketRecord<'a> {
+    src: &'a str,
+    dest: &'a str,
+    histogram: &'a str,
+    min_bytes: u64,
+    max_bytes: Option<u64>,
+    count: u32,
+}
+
+/// Appends the source and copied file size histograms to the given file, so that they can be analysed
+/// externally. The file is CSV if it has a .csv extension, otherwise JSON (one object per line, per sync).
+fn export_histograms(ctx: &SyncContext, path: &str) -> Result<(), String> {
+    let mut f = std::fs::OpenOptions::new().create(true).append(true).open(path).map_err(|e| e.to_string())?;
+    if path.to_lowercase().ends_with(".csv") {
+        // Only write the header once, in case there are multiple syncs appending to the same file
+        let is_empty = f.metadata().map_err(|e| e.to_string())?.len() == 0;
+        let mut writer = csv::WriterBuilder::new().has_headers(is_empty).from_writer(f);
+        for (name, hist) in [("source", &ctx.stats.src_file_size_hist), ("copied", &ctx.stats.copied_file_size_hist)] {
+            for ((min_bytes, max_bytes), count) in hist.bucket_ranges().into_iter().zip(hist.buckets.iter().copied()) {
+                writer.serialize(HistogramBucketRecord {
+                    src: &ctx.src_root, dest: &ctx.dest_root, histogram: name, min_bytes, max_bytes, count
+                }).map_err(|e| e.to_string())?;
+            }
+        }
+        writer.flush().map_err(|e| e.to_string())
+    } else {
+        let buckets = |hist: &FileSizeHistogram| hist.bucket_ranges().into_iter().zip(hist.buckets.iter()).map(|((min, max), count)|
+            serde_json::json!({ "min_bytes": min, "max_bytes": max, "count": count })).collect::<Vec<_>>();
+        let line = serde_json::json!({
+            "src": ctx.src_root,
+            "dest": ctx.dest_root,
+            "source": buckets(&ctx.stats.src_file_size_hist),
+            "copied": buckets(&ctx.stats.copied_file_size_hist),
+        });
+        writeln!(f, "{line}").map_err(|e| e.to_string())
+    }
+}
+
+/// Calculates the checksum of each source file (on the source doer) and appends them to the given file, sorted by path
+/// (see --manifest-out). Files which couldn't be read during the sync are left out, as they've already been reported.
+fn export_manifest(ctx: &mut SyncContext, path: &str) -> Result<(), String> {
+    let mut files = std::mem::take(&mut ctx.manifest_files);
+    files.retain(|(p, _, _)| !ctx.read_errors.iter().any(|e| p.starts_with(&e.path)));
+    files.sort_by_key(|(p, _, _)| p.to_platform_path('/'));
+
+    // Keep several requests in flight to hide the latency of a remote source, but not so many that
+    // the doer's responses back up while we're still sending
+    const MAX_CHECKSUMS_IN_FLIGHT: usize = 100;
+    let mut checksums = Vec::with_capacity(files.len());
+    let mut num_sent = 0;
+    while checksums.len() < files.len() {
+        while num_sent < files.len() && num_sent - checksums.len() < MAX_CHECKSUMS_IN_FLIGHT {
+            ctx.src_comms.send_command(Command::GetFileChecksum { path: files[num_sent].0.clone() })?;
+            num_sent += 1;
+        }
+        match ctx.src_comms.receive_response()? {
+            Response::FileChecksum(c) => checksums.push(c),
+            Response::Error(e) => return Err(doer_error(ctx.src_comms, ctx.src_dir_separator, &e)),
+            r => return Err(format!("Unexpected response getting file checksum from source: {:?}", r)),
+        }
+    }
+
+    // If the source is a single file, then so is the dest, so the only line is named after that
+    let root_name = ctx.dest_root.trim_end_matches(['/', '\\']).rsplit(['/', '\\']).next().unwrap_or_default().to_string();
+    let write = || -> std::io::Result<()> {
+        let mut w = std::io::BufWriter::new(std::fs::OpenOptions::new().create(true).append(true).open(path)?);
+        for ((p, size, modified_time), checksum) in files.iter().zip(checksums.iter()) {
+            let name = if p.is_root() { root_name.clone() } else { p.to_platform_path('/') };
+            writeln!(w, "{}", format_manifest_line(ctx.manifest_format, &name, *size, *modified_time, checksum))?;
+        }
+        w.flush()
+    };
+    write().map_err(|e| format!("Failed to write manifest to '{path}': {e}"))
+}
+
+/// Formats one line of the manifest (see --manifest-out). Backslashes and line breaks in the name are escaped
+/// in the same way as `sha256sum` does, which also marks these lines by starting them with a backslash.
+fn format_manifest_line(format: ManifestFormat, name: &str, size: u64, modified_time: SystemTime, checksum: &[u8; 32]) -> String {
+    let escaped = name.replace('\\', "\\\\").replace('\n', "\\n").replace('\r', "\\r");
+    let hex: String = checksum.iter().map(|b| format!("{b:02x}")).collect();
+    match format {
+        ManifestFormat::Sha256sum => {
+            let prefix = if escaped != name { "\\" } else { "" };
+            format!("{prefix}{hex}  {escaped}")
+        }
+        ManifestFormat::Detailed => {
+            let mtime = match modified_time.duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(d) => format!("{}.{:09}", d.as_secs(), d.subsec_nanos()),
+                Err(e) => format!("-{}.{:09}", e.duration().as_secs(), e.duration().subsec_nanos()),
+            };
+            format!("{escaped}  {size}  {mtime}  {hex}")
+        }
+    }
+}
+
+/// Formats a duration for showing to the user, e.g. "0.25s", "42.10s", "2m 5s" or "1h 2m 5s".
+/// Long durations are much easier to read this way than as a large number of seconds.
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs < 60 {
+        format!("{:.2}s", d.as_secs_f32())
+    } else if secs < 60 * 60 {
+        format!("{}m {}s", secs / 60, secs % 60)
+    } else {
+        format!("{}h {}m {}s", secs / (60 * 60), (secs / 60) % 60, secs % 60)
+    }
+}
+
 fn show_post_sync_stats(ctx: &SyncContext) {
     // Note that we print all the stats at the end (even though we could print the delete stats earlier),
     // so that they are together in the output (e.g. for dry run or --verbose, they could be a lot of other
@@ -1031,7 +2316,7 @@ fn show_post_sync_stats(ctx: &SyncContext) {
             HumanCount(ctx.stats.num_folders_deleted as u64),
             HumanCount(ctx.stats.num_symlinks_deleted as u64),
             if !ctx.dry_run && ctx.show_stats {
-                format!(", in {:.2} seconds", delete_elapsed.as_secs_f32())
+                format!(", in {}", format_duration(delete_elapsed))
             } else { "".to_string() },
         );
     }
@@ -1047,8 +2332,8 @@ fn show_post_sync_stats(ctx: &SyncContext) {
             if !ctx.dry_run { "copied" } else { "would copy" },
             HumanCount(ctx.stats.num_symlinks_copied as u64),
             if !ctx.dry_run && ctx.show_stats {
-                format!(", in {:.2} seconds ({}/s)",
-                    copy_elapsed.as_secs_f32(), HumanBytes((ctx.stats.num_bytes_copied as f32 / copy_elapsed.as_secs_f32()).round() as u64))
+                format!(", in {} ({}/s)",
+                    format_duration(copy_elapsed), HumanBytes((ctx.stats.num_bytes_copied as f32 / copy_elapsed.as_secs_f32()).round() as u64))
             } else { "".to_string() },
         );
         if ctx.show_stats {
@@ -1056,16 +2341,196 @@ fn show_post_sync_stats(ctx: &SyncContext) {
                 if !ctx.dry_run { "Copied" } else { "Would copy" },
             );
             info!("{}", ctx.stats.copied_file_size_hist);
+            if ctx.compress && !ctx.dry_run {
+                info!("Transferred {} of file contents after compression", HumanBytes(ctx.stats.num_bytes_transferred));
+            }
         }
     }
+    if ctx.stats.num_folders_pruned > 0 {
+        info!("{} {} empty folder(s)", if !ctx.dry_run { "Removed" } else { "Would remove" },
+            HumanCount(ctx.stats.num_folders_pruned as u64));
+    }
+    if ctx.stats.num_files_linked > 0 {
+        info!(
+            "{} {} unchanged file(s) totalling {} to the previous snapshot",
+            if !ctx.dry_run { "Hard linked" } else { "Would hard link" },
+            HumanCount(ctx.stats.num_files_linked as u64),
+            HumanBytes(ctx.stats.num_bytes_linked),
+        );
+    }
+    if ctx.stats.num_files_flags_updated > 0 {
+        info!("{} flags of {} file(s)", if !ctx.dry_run { "Updated" } else { "Would update" },
+            HumanCount(ctx.stats.num_files_flags_updated as u64));
+    }
     if ctx.stats.num_files_deleted
         + ctx.stats.num_folders_deleted
         + ctx.stats.num_symlinks_deleted
         + ctx.stats.num_files_copied
         + ctx.stats.num_folders_created
         + ctx.stats.num_symlinks_copied
+        + ctx.stats.num_files_linked
+        + ctx.stats.num_files_flags_updated
+        + ctx.stats.num_folders_pruned
         == 0
     {
         info!("Nothing to do!");
     }
-}
\ No newline at end of file
+}
+
+/// A concise one-line summary of what a sync did, which is always shown at the end of a successful sync
+/// (unlike the more detailed lines from show_post_sync_stats, which are only shown for --stats or when there's something to report).
+fn format_summary(stats: &Stats, dry_run: bool, elapsed: Duration) -> String {
+    let num_copied = stats.num_files_copied + stats.num_folders_created + stats.num_symlinks_copied;
+    let num_deleted = stats.num_files_deleted + stats.num_folders_deleted + stats.num_symlinks_deleted + stats.num_folders_pruned;
+    let mut parts = vec![];
+    if num_copied > 0 {
+        parts.push(format!("{} {} entry(s) ({})", if !dry_run { "copied" } else { "would copy" },
+            HumanCount(num_copied as u64), HumanBytes(stats.num_bytes_copied)));
+    }
+    if num_deleted > 0 {
+        parts.push(format!("{} {} entry(s)", if !dry_run { "deleted" } else { "would delete" }, HumanCount(num_deleted as u64)));
+    }
+    if stats.num_files_linked > 0 {
+        parts.push(format!("{} {} unchanged file(s)", if !dry_run { "hard linked" } else { "would hard link" },
+            HumanCount(stats.num_files_linked as u64)));
+    }
+    if stats.num_files_flags_updated > 0 {
+        parts.push(format!("{} flags of {} file(s)", if !dry_run { "updated" } else { "would update" },
+            HumanCount(stats.num_files_flags_updated as u64)));
+    }
+    if parts.is_empty() {
+        parts.push("already up to date".to_string());
+    }
+    if !stats.num_skipped.is_empty() {
+        let groups: Vec<String> = stats.num_skipped.iter()
+            .map(|(r, n)| format!("{} {}", HumanCount(*n as u64), r.describe())).collect();
+        parts.push(format!("skipped {}", groups.join("; ")));
+    }
+    if !dry_run {
+        format!("Synced in {}: {}", format_duration(elapsed), parts.join(", "))
+    } else {
+        format!("Dry run: {}", parts.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boss_doer_interface::SymlinkTarget;
+
+    #[test]
+    fn test_strip_path_prefix() {
+        assert_eq!(strip_path_prefix("/src/out", "/src/", '/'), Some("out"));
+        assert_eq!(strip_path_prefix("/Src/out", "/src/", '/'), None);
+        assert_eq!(strip_path_prefix("c:\\src\\out", "C:\\Src\\", '\\'), Some("out"));
+        assert_eq!(strip_path_prefix("C:\\other\\out", "C:\\Src\\", '\\'), None);
+        assert_eq!(strip_path_prefix("C:\\", "C:\\Src\\", '\\'), None);
+    }
+
+    #[test]
+    fn test_format_summary() {
+        let stats = Stats {
+            num_files_copied: 40,
+            num_folders_created: 1,
+            num_symlinks_copied: 1,
+            num_bytes_copied: 3_100_000,
+            num_files_deleted: 3,
+            num_folders_deleted: 1,
+            num_folders_pruned: 1,
+            ..Default::default()
+        };
+        assert_eq!(format_summary(&stats, false, Duration::from_millis(1500)),
+            "Synced in 1.50s: copied 42 entry(s) (2.96 MiB), deleted 5 entry(s)");
+        assert_eq!(format_summary(&stats, true, Duration::from_millis(1500)),
+            "Dry run: would copy 42 entry(s) (2.96 MiB), would delete 5 entry(s)");
+        let mut stats = Stats { num_files_linked: 7, ..Default::default() };
+        stats.add_skipped(SkipReason::ReadError, 2);
+        assert_eq!(format_summary(&stats, false, Duration::from_secs(0)),
+            "Synced in 0.00s: hard linked 7 unchanged file(s), skipped 2 due to read errors");
+        assert_eq!(format_summary(&Stats::default(), false, Duration::from_secs(0)), "Synced in 0.00s: already up to date");
+
+        // Skipped entries are grouped by reason, in a consistent order
+        let mut stats = Stats::default();
+        stats.add_skipped(SkipReason::ReadError, 2);
+        stats.add_skipped(SkipReason::Filter, 1203);
+        stats.add_skipped(SkipReason::DestNewer, 3);
+        stats.add_skipped(SkipReason::DestNewer, 1);
+        stats.add_skipped(SkipReason::DestOlder, 0);
+        assert_eq!(format_summary(&stats, false, Duration::from_secs(0)),
+            "Synced in 0.00s: already up to date, skipped 1,203 by filter; 4 because dest newer; 2 due to read errors");
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_millis(0)), "0.00s");
+        assert_eq!(format_duration(Duration::from_millis(1234)), "1.23s");
+        assert_eq!(format_duration(Duration::from_millis(59_990)), "59.99s");
+        assert_eq!(format_duration(Duration::from_secs(60)), "1m 0s");
+        assert_eq!(format_duration(Duration::from_secs(125)), "2m 5s");
+        assert_eq!(format_duration(Duration::from_secs(3725)), "1h 2m 5s");
+        assert_eq!(format_duration(Duration::from_secs(100 * 3600)), "100h 0m 0s");
+    }
+
+    #[test]
+    fn test_needs_delete_symlink_kinds() {
+        let symlink = |kind, target: &str| EntryDetails::Symlink { kind, target: SymlinkTarget::Normalized(target.to_string()) };
+        // Different kinds only matter if the dest platform differentiates them
+        assert!(needs_delete(&symlink(SymlinkKind::File, "t"), &symlink(SymlinkKind::Folder, "t"), true));
+        assert!(!needs_delete(&symlink(SymlinkKind::File, "t"), &symlink(SymlinkKind::Folder, "t"), false));
+        // An unknown kind on the source matches either kind on the dest
+        assert!(!needs_delete(&symlink(SymlinkKind::Unknown, "t"), &symlink(SymlinkKind::File, "t"), true));
+        assert!(!needs_delete(&symlink(SymlinkKind::Unknown, "t"), &symlink(SymlinkKind::Folder, "t"), true));
+        // But not if the target has changed
+        assert!(needs_delete(&symlink(SymlinkKind::Unknown, "t"), &symlink(SymlinkKind::Folder, "t2"), true));
+    }
+
+    #[test]
+    fn test_check_src_entry_not_duplicate() {
+        let mut src_entries = EntriesList::new();
+        let file = RootRelativePath::from_platform_path("file", '/').unwrap();
+        let pretty = |p| PrettyPath { side: Side::Source, dir_separator: '/', root: "src", path: p, kind: "file" };
+        let details = EntryDetails::File { size: 1, modified_time: SystemTime::UNIX_EPOCH, flags: FileFlags::default() };
+
+        assert_eq!(check_src_entry_not_duplicate(&src_entries, &file, pretty(&file)), Ok(()));
+        src_entries.add(file.clone(), details).unwrap();
+        // e.g. a second name which normalizes to the same path
+        let err = check_src_entry_not_duplicate(&src_entries, &file, pretty(&file)).unwrap_err();
+        assert!(err.contains("file 'src/file' was found more than once on the source"), "{err}");
+        // Other paths are fine
+        let other = RootRelativePath::from_platform_path("other", '/').unwrap();
+        assert_eq!(check_src_entry_not_duplicate(&src_entries, &other, pretty(&other)), Ok(()));
+    }
+
+    #[test]
+    fn test_format_manifest_line() {
+        let mut checksum = [0; 32];
+        checksum[0] = 0x0f;
+        checksum[31] = 0xa0;
+        let hex = format!("0f{}a0", "00".repeat(30));
+        let t = SystemTime::UNIX_EPOCH + Duration::from_millis(1700000000250);
+        assert_eq!(format_manifest_line(ManifestFormat::Sha256sum, "folder/file", 12, t, &checksum), format!("{hex}  folder/file"));
+        assert_eq!(format_manifest_line(ManifestFormat::Detailed, "folder/file", 12, t, &checksum),
+            format!("folder/file  12  1700000000.250000000  {hex}"));
+        // Before the epoch
+        let t = SystemTime::UNIX_EPOCH - Duration::from_millis(1500);
+        assert_eq!(format_manifest_line(ManifestFormat::Detailed, "file", 0, t, &checksum), format!("file  0  -1.500000000  {hex}"));
+        // Names which need escaping, which sha256sum marks with a leading backslash
+        assert_eq!(format_manifest_line(ManifestFormat::Sha256sum, "a\\b\nc", 12, t, &checksum), format!("\\{hex}  a\\\\b\\nc"));
+        assert_eq!(format_manifest_line(ManifestFormat::Detailed, "a\\b\nc", 0, t, &checksum), format!("a\\\\b\\nc  0  -1.500000000  {hex}"));
+    }
+
+    #[test]
+    fn test_format_snapshot_name() {
+        let t = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(format_snapshot_name(t(0)), "1970-01-01_000000.000");
+        assert_eq!(format_snapshot_name(t(951782400)), "2000-02-29_000000.000");
+        assert_eq!(format_snapshot_name(t(1700000000)), "2023-11-14_221320.000");
+        assert_eq!(format_snapshot_name(t(1709251199)), "2024-02-29_235959.000");
+        assert_eq!(format_snapshot_name(t(1700000000) + Duration::from_millis(1234)), "2023-11-14_221321.234");
+        assert!(is_snapshot_name(&format_snapshot_name(SystemTime::now())));
+        assert!(!is_snapshot_name("2023-11-14_221320"));
+        assert!(!is_snapshot_name("2023-11-14"));
+        // Names sort chronologically
+        assert!(format_snapshot_name(t(1700000000) + Duration::from_millis(999)) < format_snapshot_name(t(1700000001)));
+    }
+}
@@ -1,19 +1,23 @@
 use aes_gcm::aead::generic_array::GenericArray;
 
 use clap::Parser;
+use sha2::{Digest, Sha256};
 use env_logger::Env;
-use log::{debug, error, trace, info};
+use log::{debug, error, trace, info, warn};
 use std::io::{ErrorKind, Read};
 use std::path;
 use std::{
     fmt::{self, Display},
     io::{Write},
     path::{Path, PathBuf},
-    time::{Instant}, net::{TcpListener},
+    time::{Instant}, net::{TcpListener, TcpStream}, ops::RangeInclusive,
+    sync::{Arc, Mutex, atomic::{AtomicU32, Ordering}}, collections::{BTreeSet, HashSet},
 };
 
 use crate::*;
-use crate::boss_doer_interface::{EntryDetails, SymlinkTarget, Response, Command, SymlinkKind, Filters, FilterKind, HANDSHAKE_STARTED_MSG, HANDSHAKE_COMPLETED_MSG};
+use crate::boss_doer_interface::{ProgressPhase, EntryDetails, SymlinkTarget, Response, Command, SymlinkKind, Filters, FilterKind, FileFlags, SafeLinksBehaviour, MUNGED_SYMLINK_PREFIX, HANDSHAKE_STARTED_MSG, HANDSHAKE_COMPLETED_MSG, is_internal_entry_name};
+use crate::bandwidth_limiter::BandwidthLimiter;
+use crate::file_flags::{get_file_flags, set_file_flags};
 use crate::encrypted_comms::AsyncEncryptedComms;
 use crate::memory_bound_channel::{Sender, Receiver};
 use crate::parallel_walk_dir::parallel_walk_dir;
@@ -29,6 +33,14 @@ struct DoerCliArgs {
     /// If not specified, a free port is chosen.
     #[arg(long)]
     port: Option<u16>,
+    /// A range of network ports to listen on, e.g. 40000-40100, as an alternative to --port.
+    /// Each port in the range is tried in turn until one is free.
+    #[arg(long, value_parser=parse_port_range, conflicts_with="port")]
+    port_range: Option<RangeInclusive<u16>>,
+    /// The network address to listen on for a connection from the boss, e.g. 127.0.0.1 when the boss
+    /// connects through an ssh port forward. By default, all interfaces are listened on.
+    #[arg(long, default_value="0.0.0.0")]
+    bind: String,
     /// Logging configuration.
     #[arg(long, default_value="info")]
     log_filter: String,
@@ -36,7 +48,20 @@ struct DoerCliArgs {
     dump_memory_usage: bool,
 }
 
-fn entry_details_from_metadata(m: std::fs::Metadata, path: &Path) -> Result<EntryDetails, String> {
+/// Parses a range of network ports in the form FIRST-LAST, e.g. 40000-40100 (inclusive).
+pub fn parse_port_range(s: &str) -> Result<RangeInclusive<u16>, String> {
+    let (first, last) = s.split_once('-').ok_or("Expected FIRST-LAST".to_string())?;
+    let first = first.trim().parse::<u16>().map_err(|e| format!("Invalid first port '{first}': {e}"))?;
+    let last = last.trim().parse::<u16>().map_err(|e| format!("Invalid last port '{last}': {e}"))?;
+    if first > last {
+        return Err(format!("First port ({first}) must not be greater than the last port ({last})"));
+    }
+    Ok(first..=last)
+}
+
+/// Gets the details of an entry from its metadata. The flags of files are only retrieved if requested
+/// (see --flags), as this needs extra calls to the filesystem on some platforms.
+fn entry_details_from_metadata(m: std::fs::Metadata, path: &Path, get_flags: bool) -> Result<EntryDetails, String> {
     if m.is_dir() {
         Ok(EntryDetails::Folder)
     } else if m.is_file() {
@@ -45,9 +70,12 @@ fn entry_details_from_metadata(m: std::fs::Metadata, path: &Path) -> Result<Entr
             Err(err) => return Err(format!("Unknown modified time for '{}': {err}", path.display())),
         };
 
+        let flags = if get_flags { get_file_flags(path)? } else { FileFlags::default() };
+
         Ok(EntryDetails::File {
             modified_time,
             size: m.len(),
+            flags,
         })
     } else if m.is_symlink() {
         let target = match std::fs::read_link(path) {
@@ -210,15 +238,17 @@ pub fn doer_main() -> ExitCode {
     // running on the same device, which would then need different ports.
     // It also reduces issues if we ever leave behind orphaned doer instances which would otherwise block us
     // from using that port.
-    // Listen on all interfaces as we don't know which one is needed.
-    let addr = ("0.0.0.0", args.port.unwrap_or(0));
-    let listener = match TcpListener::bind(addr) {
-        Ok(l) => {
-            debug!("Listening on {:?}", l.local_addr()); // This will include the actual port chosen, if we bound to 0
-            l
-        }
+    // Listen on all interfaces by default, as we don't know which one is needed.
+    // If we've been given a range of ports (e.g. only these are open in a firewall), we use the first free one.
+    let ports = match (args.port_range, args.port) {
+        (Some(r), _) => r,
+        (None, Some(p)) => p..=p,
+        (None, None) => 0..=0,
+    };
+    let listener = match bind_listener(&args.bind, ports) {
+        Ok(l) => l,
         Err(e) => {
-            error!("Failed to bind to {:?}: {}", addr, e);
+            error!("{e}");
             return ExitCode::from(24);
         }
     };
@@ -290,6 +320,86 @@ pub fn doer_main() -> ExitCode {
     ExitCode::SUCCESS
 }
 
+/// Listens on the first free port in the given range (which may be just 0, to choose one automatically).
+fn bind_listener(bind: &str, ports: RangeInclusive<u16>) -> Result<TcpListener, String> {
+    for port in ports.clone() {
+        let addr = (bind, port);
+        match TcpListener::bind(addr) {
+            Ok(l) => {
+                debug!("Listening on {:?}", l.local_addr()); // This will include the actual port chosen, if we bound to 0
+                return Ok(l);
+            }
+            // Only report the failure if there's nowhere else to try
+            Err(e) if port == *ports.end() => return Err(format!("Failed to bind to {:?}: {}", addr, e)),
+            Err(e) => debug!("Failed to bind to {:?}: {}. Trying next port.", addr, e),
+        }
+    }
+    unreachable!("The loop always returns on the last port");
+}
+
+/// Waits for a connection on the given listener, then forwards everything between it and a new connection
+/// to the given target, in both directions, until either side disconnects (see Command::StartRelay).
+fn relay_thread(listener: TcpListener, target: (String, u16), secret: [u8; 16]) {
+    // Anyone could connect to our port, so only relay a connection which starts with the secret that the boss
+    // gave us. Anything else is dropped, and we carry on waiting for the boss.
+    let incoming = loop {
+        match listener.accept() {
+            Ok((mut socket, addr)) => {
+                debug!("Relay client connected: {addr:?}");
+                match check_relay_secret(&mut socket, &secret) {
+                    Ok(()) => break socket,
+                    Err(e) => warn!("Relay rejected connection from {addr:?}: {e}"),
+                }
+            }
+            Err(e) => {
+                error!("Relay failed to accept: {}", e);
+                return;
+            }
+        }
+    };
+    let outgoing = match TcpStream::connect((target.0.as_str(), target.1)) {
+        Ok(s) => s,
+        Err(e) => {
+            // Dropping the incoming connection will let the boss know that something went wrong
+            error!("Relay failed to connect to {:?}: {}", target, e);
+            return;
+        }
+    };
+    debug!("Relaying to {:?}", target);
+    let forward = |mut from: TcpStream, mut to: TcpStream, name: &'static str| {
+        std::thread::spawn(move || {
+            if let Err(e) = std::io::copy(&mut from, &mut to) {
+                debug!("Relay {name} stopped: {e}");
+            }
+            // Let the other side know that nothing more is coming
+            let _ = to.shutdown(std::net::Shutdown::Write);
+        })
+    };
+    match (incoming.try_clone(), outgoing.try_clone()) {
+        (Ok(incoming2), Ok(outgoing2)) => {
+            let t = forward(incoming, outgoing, "to target");
+            forward(outgoing2, incoming2, "from target");
+            let _ = t.join();
+        }
+        (Err(e), _) | (_, Err(e)) => error!("Relay failed to clone connection: {e}"),
+    }
+}
+
+/// Checks that the first thing received on a new connection to the relay is the given secret.
+fn check_relay_secret(socket: &mut TcpStream, secret: &[u8; 16]) -> Result<(), String> {
+    // Don't let a connection that never sends anything stop the boss from connecting
+    socket.set_read_timeout(Some(RELAY_SECRET_TIMEOUT)).map_err(|e| format!("Error setting timeout: {e}"))?;
+    let mut received = [0; 16];
+    socket.read_exact(&mut received).map_err(|e| format!("Error receiving secret: {e}"))?;
+    if &received != secret {
+        return Err("Wrong secret".to_string());
+    }
+    socket.set_read_timeout(None).map_err(|e| format!("Error clearing timeout: {e}"))
+}
+
+/// How long the relay waits for the secret on a new connection (see check_relay_secret).
+const RELAY_SECRET_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
 fn stdin_reading_thread() {
     loop {
         let mut l: String = "".to_string();
@@ -328,6 +438,67 @@ struct DoerContext {
     root: PathBuf,
     /// Stores details of a file we're partway through receiving.
     in_progress_file_receive: Option<(RootRelativePath, std::fs::File)>,
+    /// Folders that we failed to create (e.g. due to permissions). Anything inside these can't be created either,
+    /// so we don't try, which avoids reporting a cascade of errors for everything inside them.
+    failed_folders: HashSet<RootRelativePath>,
+    /// Whether to get the flags of files, and clear them from files that need overwriting or deleting (see --flags).
+    file_flags: bool,
+    /// Whether to flush files to disk once they've been written, and the folders containing any changed entries
+    /// at the end of the sync (see --fsync).
+    fsync: bool,
+    folders_to_fsync: BTreeSet<PathBuf>,
+    /// Limits the rate at which file contents are received (see --bwlimit-up).
+    bwlimit: Option<BandwidthLimiter>,
+    /// Whether we're continuing an interrupted sync, so entries might already be deleted or created (see SetRoot).
+    resuming: bool,
+    /// For testing --resume, this simulates the connection to the doer being lost just before creating the given folder.
+    /// This is only in debug builds, so that it can't affect a real sync.
+    #[cfg(debug_assertions)]
+    test_stop_before_folder: Option<String>,
+}
+impl DoerContext {
+    /// Checks if the given path is inside a folder that we failed to create. The error for that folder has
+    /// already been reported, so the caller should silently skip creating anything at this path.
+    fn is_inside_failed_folder(&self, path: &RootRelativePath) -> bool {
+        // This is checked for every entry created, so avoid any work in the common case that nothing has failed
+        if self.failed_folders.is_empty() {
+            return false;
+        }
+        let mut folders = path.self_and_ancestors();
+        folders.push(RootRelativePath::root());
+        match folders.iter().find(|f| self.failed_folders.contains(f)) {
+            Some(f) => {
+                trace!("Skipping '{path}' as its folder '{f}' couldn't be created");
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Records that an entry has been created or deleted, so the folder containing it needs flushing to disk
+    /// (if --fsync is being used). This is done once at the end, rather than after every entry.
+    fn entry_changed(&mut self, full_path: &Path) {
+        if let (true, Some(p)) = (self.fsync, full_path.parent()) {
+            // The parent of a relative path with a single component (e.g. the root) is empty, meaning the current folder
+            let p = if p.as_os_str().is_empty() { Path::new(".") } else { p };
+            self.folders_to_fsync.insert(p.to_path_buf());
+        }
+    }
+
+    /// Flushes all the folders with changed entries to disk, returning any errors.
+    fn fsync_folders(&mut self) -> Vec<String> {
+        let mut errors = vec![];
+        for f in std::mem::take(&mut self.folders_to_fsync) {
+            trace!("Flushing folder '{}' to disk", f.display());
+            // Folders can't be opened like this on Windows, but NTFS journals changes to folders anyway
+            if cfg!(unix) {
+                if let Err(e) = std::fs::File::open(&f).and_then(|h| h.sync_all()) {
+                    errors.push(format!("Error flushing folder '{}' to disk: {e}", f.display()));
+                }
+            }
+        }
+        errors
+    }
 }
 
 // Repeatedly waits for Commands from the boss and processes them (possibly sending back Responses).
@@ -368,14 +539,20 @@ fn message_loop(comms: &mut Comms) -> Result<(), ()> {
 /// error, like a communication failure.
 fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerContext>) -> Result<bool, String> {
     match command {
-        Command::SetRoot { root } => {
-            if let Err(e) = handle_set_root(comms, context, root) {
+        Command::SetRoot { root, file_flags, fsync, bwlimit, resuming } => {
+            if let Err(e) = handle_set_root(comms, context, root, file_flags, fsync, bwlimit, resuming) {
                 comms.send_response(Response::Error(e))?;
             }
         }
-        Command::GetEntries { filters } => {
+        Command::GetEntries { filters, skip_unreadable, safe_links } => {
             profile_this!("GetEntries");
-            if let Err(e) = handle_get_entries(comms, context.as_mut().unwrap(), filters) {
+            // For testing --query-timeout, this simulates a doer which is stuck listing its entries.
+            // This is only in debug builds, so that it can't affect a real sync.
+            #[cfg(debug_assertions)]
+            if let Some(secs) = std::env::var("RJRSSYNC_TEST_QUERY_STALL_SECS").ok().and_then(|s| s.parse().ok()) {
+                std::thread::sleep(std::time::Duration::from_secs(secs));
+            }
+            if let Err(e) = handle_get_entries(comms, context.as_mut().unwrap(), filters, skip_unreadable, safe_links) {
                 comms.send_response(Response::Error(e))?;
             }
         }
@@ -386,28 +563,63 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
                 profile_this!(format!("CreateRootAncestors {}", p.to_str().unwrap().to_string()));
                 if let Err(e) = std::fs::create_dir_all(p) {
                     comms.send_response(Response::Error(format!("Error creating folder and ancestors for '{}': {e}", p.display())))?;
+                    // Nothing can be created inside the root, so don't report errors for each of those too
+                    context.as_mut().unwrap().failed_folders.insert(RootRelativePath::root());
                 }
             }
         }
-        Command::GetFileContent { path } => {
+        Command::GetFileContent { path, compress } => {
             let full_path = path.get_full_path(&context.as_ref().unwrap().root);
             profile_this!(format!("GetFileContent {}", path.to_string()));
-            if let Err(e) = handle_get_file_contents(comms, &full_path) {
+            if let Err(e) = handle_get_file_contents(comms, &full_path, compress) {
                 comms.send_response(Response::Error(e))?;
             }
         }
+        Command::GetFileChecksum { path } => {
+            let full_path = path.get_full_path(&context.as_ref().unwrap().root);
+            profile_this!(format!("GetFileChecksum {}", path.to_string()));
+            match compute_file_checksum(&full_path) {
+                Ok(c) => comms.send_response(Response::FileChecksum(c))?,
+                Err(e) => comms.send_response(Response::Error(format!("Error calculating checksum of '{}': {e}", full_path.display())))?,
+            }
+        }
         Command::CreateOrUpdateFile {
             path,
             data,
+            uncompressed_size,
             set_modified_time,
             more_to_follow
         } => {
+            if context.as_ref().unwrap().is_inside_failed_folder(&path) {
+                return Ok(true);
+            }
             let full_path = path.get_full_path(&context.as_ref().unwrap().root);
             trace!("Creating/updating content of '{}'", full_path.display());
             profile_this!(format!("CreateOrUpdateFile {}", path.to_string()));
         //    std::thread::sleep(std::time::Duration::from_nanos(1));
 
-            // Check if this is the continuation of an existing file
+            // The limit applies to the data actually transferred, so before decompressing
+            if let Some(l) = &mut context.as_mut().unwrap().bwlimit {
+                l.consume(data.len() as u64);
+            }
+
+            let data = match uncompressed_size {
+                None => data,
+                Some(size) => match decompress_chunk(&data, size) {
+                    Ok(d) => d,
+                    Err(e) => {
+                        comms.send_response(Response::Error(format!("Error decompressing file contents for '{}': {e}", full_path.display())))?;
+                        return Ok(true);
+                    }
+                }
+            };
+
+            // Check if this is the continuation of an existing file.
+            // Note that we write directly into the destination file (like rsync's --inplace), rather than
+            // to a temporary file which is then renamed. This keeps hard links and the file's identity intact
+            // (including its permissions and owner, which may be managed separately to the contents, so we never
+            // change them for an existing file) and doesn't need any extra space, but means an interrupted transfer leaves a partially-written
+            // file (which will have a different modified time, so will be copied again on the next sync).
             let mut f = match context.as_mut().unwrap().in_progress_file_receive.take() {
                 Some((in_progress_path, f)) => {
                     if in_progress_path == path {
@@ -417,7 +629,7 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
                         return Ok(true);
                     }
                 },
-                None => match std::fs::File::create(&full_path) {
+                None => match create_file_clearing_flags(&full_path, context.as_ref().unwrap().file_flags) {
                     Ok(f) => f,
                     Err(e) => {
                         comms.send_response(Response::Error(format!("Error writing file contents to '{}': {e}", full_path.display())))?;
@@ -432,34 +644,69 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
                 return Ok(true);
             }
 
-            // If there is more data to follow, store the open file handle for next time
-            context.as_mut().unwrap().in_progress_file_receive = if more_to_follow {
-                Some((path, f))
-            } else {
-                None
-            };
-
             // After changing the content, we need to override the modified time of the file to that of the original,
             // otherwise it will immediately count as modified again if we do another sync.
+            // This is done through the handle we wrote the contents with, so that closing it afterwards can't
+            // update the modified time again (e.g. on Windows).
             if let Some(t) = set_modified_time {
                 trace!("Setting modifited time of '{}'", full_path.display());
-                let r =
-                    filetime::set_file_mtime(&full_path, filetime::FileTime::from_system_time(t));
-                if let Err(e) = r {
+                if let Err(e) = f.set_modified(t) {
                     comms.send_response(Response::Error(format!("Error setting modified time of '{}': {e}", full_path.display())))?;
                     return Ok(true);
                 }
             }
+
+            if more_to_follow {
+                // There is more data to follow, so store the open file handle for next time
+                context.as_mut().unwrap().in_progress_file_receive = Some((path, f));
+            } else if context.as_ref().unwrap().fsync {
+                // Make sure the whole file (including its modified time) is on disk, rather than just in the OS's cache
+                trace!("Flushing '{}' to disk", full_path.display());
+                if let Err(e) = f.sync_all() {
+                    comms.send_response(Response::Error(format!("Error flushing file '{}' to disk: {e}", full_path.display())))?;
+                    return Ok(true);
+                }
+                context.as_mut().unwrap().entry_changed(&full_path);
+            }
         }
-        Command::CreateFolder { path } => {
+        Command::CreateFolder { path, allow_existing } => {
+            if context.as_ref().unwrap().is_inside_failed_folder(&path) {
+                return Ok(true);
+            }
             let full_path =  path.get_full_path(&context.as_ref().unwrap().root);
             trace!("Creating folder '{}'", full_path.display());
             profile_this!(format!("CreateFolder {}", full_path.to_str().unwrap().to_string()));
-            if let Err(e) = std::fs::create_dir(&full_path) {
-                comms.send_response(Response::Error(format!("Error creating folder '{}': {e}", full_path.display())))?;
+            #[cfg(debug_assertions)]
+            if context.as_ref().unwrap().test_stop_before_folder.as_ref().is_some_and(|p| *p == path.to_string()) {
+                return Err("Stopped for testing".to_string());
+            }
+            let allow_existing = allow_existing || context.as_ref().unwrap().resuming;
+            match std::fs::create_dir(&full_path) {
+                Err(e) if allow_existing && e.kind() == ErrorKind::AlreadyExists && full_path.is_dir() => trace!("Folder already exists"),
+                Err(e) => {
+                    comms.send_response(Response::Error(format!(
+                        "Error creating folder '{}': {e}. Nothing inside it will be created.", full_path.display())))?;
+                    context.as_mut().unwrap().failed_folders.insert(path);
+                }
+                Ok(()) => context.as_mut().unwrap().entry_changed(&full_path),
+            }
+        }
+        Command::CreateHardLink { path, target } => {
+            if context.as_ref().unwrap().is_inside_failed_folder(&path) {
+                return Ok(true);
+            }
+            let full_path =  path.get_full_path(&context.as_ref().unwrap().root);
+            trace!("Creating hard link '{}' to '{}'", full_path.display(), target);
+            profile_this!(format!("CreateHardLink {}", path.to_string()));
+            match std::fs::hard_link(&target, &full_path) {
+                Err(e) => comms.send_response(Response::Error(format!("Error creating hard link '{}' to '{}': {e}", full_path.display(), target)))?,
+                Ok(()) => context.as_mut().unwrap().entry_changed(&full_path),
             }
         }
         Command::CreateSymlink { path, kind, target } => {
+            if context.as_ref().unwrap().is_inside_failed_folder(&path) {
+                return Ok(true);
+            }
             if let Err(e) = handle_create_symlink(path, context.as_mut().unwrap(), kind, target) {
                 comms.send_response(Response::Error(e))?;
             }
@@ -468,16 +715,44 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
             let full_path =  path.get_full_path(&context.as_ref().unwrap().root);
             trace!("Deleting file '{}'", full_path.display());
             profile_this!(format!("DeleteFile {}", path.to_string()));
-            if let Err(e) = std::fs::remove_file(&full_path) {
-                comms.send_response(Response::Error(format!("Error deleting file '{}': {e}", full_path.display())))?;
+            match remove_file_clearing_flags(&full_path, context.as_ref().unwrap().file_flags) {
+                // Might have already been deleted, when resuming a sync (see SetRoot)
+                Err(e) if context.as_ref().unwrap().resuming && e.kind() == ErrorKind::NotFound => trace!("File already deleted"),
+                Err(e) => comms.send_response(Response::Error(format!("Error deleting file '{}': {e}", full_path.display())))?,
+                Ok(()) => context.as_mut().unwrap().entry_changed(&full_path),
+            }
+        }
+        Command::SetFileFlags { path, flags } => {
+            if context.as_ref().unwrap().is_inside_failed_folder(&path) {
+                return Ok(true);
+            }
+            let full_path = path.get_full_path(&context.as_ref().unwrap().root);
+            trace!("Setting flags of '{}' to {:?}", full_path.display(), flags);
+            profile_this!(format!("SetFileFlags {}", path.to_string()));
+            if let Err(e) = set_file_flags(&full_path, flags) {
+                comms.send_response(Response::Error(e))?;
+            }
+        }
+        Command::PruneEmptyFolders { filters } => {
+            profile_this!("PruneEmptyFolders");
+            let root = context.as_ref().unwrap().root.clone();
+            let mut pruned = vec![];
+            // If the root isn't a folder (e.g. a single file was synced), then there's nothing to prune
+            let r = if root.is_dir() { prune_empty_folders(&root, &root, &filters, &mut pruned).map(|_| ()) } else { Ok(()) };
+            match r {
+                Ok(()) => comms.send_response(Response::EmptyFoldersPruned(pruned))?,
+                Err(e) => comms.send_response(Response::Error(e))?,
             }
         }
         Command::DeleteFolder { path } => {
             let full_path =  path.get_full_path(&context.as_ref().unwrap().root);
             trace!("Deleting folder '{}'", full_path.display());
             profile_this!(format!("DeleteFolder {}", path.to_string()));
-            if let Err(e) = std::fs::remove_dir(&full_path) {
-                comms.send_response(Response::Error(format!("Error deleting folder '{}': {e}", full_path.display())))?;
+            match std::fs::remove_dir(&full_path) {
+                // Might have already been deleted, when resuming a sync (see SetRoot)
+                Err(e) if context.as_ref().unwrap().resuming && e.kind() == ErrorKind::NotFound => trace!("Folder already deleted"),
+                Err(e) => comms.send_response(Response::Error(format!("Error deleting folder '{}': {e}", full_path.display())))?,
+                Ok(()) => context.as_mut().unwrap().entry_changed(&full_path),
             }
         }
         Command::DeleteSymlink { path, kind } => {
@@ -498,14 +773,52 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
                 // On Linux, any kind of symlink is removed with remove_file
                 std::fs::remove_file(&full_path)
             };
-            if let Err(e) = res {
-                comms.send_response(Response::Error(format!("Error deleting symlink '{}': {e}", full_path.display())))?;
+            match res {
+                // Might have already been deleted, when resuming a sync (see SetRoot)
+                Err(e) if context.as_ref().unwrap().resuming && e.kind() == ErrorKind::NotFound => trace!("Symlink already deleted"),
+                Err(e) => comms.send_response(Response::Error(format!("Error deleting symlink '{}': {e}", full_path.display())))?,
+                Ok(()) => context.as_mut().unwrap().entry_changed(&full_path),
             }
         },
+        Command::CreateTempFolder => {
+            // The folder is kept (rather than being deleted when the TempDir is dropped), as the boss will
+            // delete it once it's finished with it
+            match tempdir::TempDir::new("rjrssync-check") {
+                Ok(d) => comms.send_response(Response::TempFolderCreated(d.into_path().to_string_lossy().to_string()))?,
+                Err(e) => comms.send_response(Response::Error(format!("Error creating temporary folder: {e}")))?,
+            }
+        }
+        Command::GetBinaryDigest { challenge } => {
+            profile_this!("GetBinaryDigest");
+            match std::env::current_exe().and_then(std::fs::read) {
+                Ok(b) => comms.send_response(Response::BinaryDigest {
+                    target_triple: env!("TARGET").to_string(),
+                    digest: boss_doer_interface::compute_binary_digest(&challenge, &b),
+                })?,
+                Err(e) => comms.send_response(Response::Error(format!("Error reading current executable: {e}")))?,
+            }
+        }
+        Command::StartRelay { target_host, target_port, bind, ports, secret } => {
+            match bind_listener(&bind, ports.unwrap_or(0..=0)) {
+                Ok(listener) => {
+                    let port = listener.local_addr().map_err(|e| format!("Error getting relay port: {e}"))?.port();
+                    std::thread::spawn(move || relay_thread(listener, (target_host, target_port), secret));
+                    comms.send_response(Response::RelayStarted { port })?;
+                }
+                Err(e) => comms.send_response(Response::Error(format!("Error starting relay: {e}")))?,
+            }
+        }
         Command::ProfilingTimeSync => {
             comms.send_response(Response::ProfilingTimeSync(PROFILING_START.elapsed()))?;
         },
         Command::Marker(x) => {
+            // Everything has been written by now, so make sure the changes to folders are on disk too,
+            // before the boss is told that we're done (see --fsync)
+            if let (ProgressPhase::Done, Some(c)) = (&x.phase, context.as_mut()) {
+                for e in c.fsync_folders() {
+                    comms.send_response(Response::Error(e))?;
+                }
+            }
             comms.send_response(Response::Marker(x))?;
         }
         Command::Shutdown => {
@@ -515,16 +828,32 @@ fn exec_command(command: Command, comms: &mut Comms, context: &mut Option<DoerCo
     Ok(true)
 }
 
-fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: String) -> Result<(), String> {
+fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: String, file_flags: bool, fsync: bool,
+    bwlimit: Option<u64>, resuming: bool) -> Result<(), String>
+{
+    // Use our own separator throughout the root (e.g. if the user gave a Windows path with forward slashes), so that
+    // the full paths in our error messages (root + root-relative path) don't have a mix of slashes
+    #[cfg(windows)]
+    let root = root.replace('/', "\\");
+
     // Store the root path for future operations
     *context = Some(DoerContext {
         root: PathBuf::from(root),
         in_progress_file_receive: None,
+        failed_folders: HashSet::new(),
+        file_flags,
+        fsync,
+        folders_to_fsync: BTreeSet::new(),
+        bwlimit: bwlimit.map(BandwidthLimiter::new),
+        resuming,
+        #[cfg(debug_assertions)]
+        test_stop_before_folder: std::env::var("RJRSSYNC_TEST_STOP_BEFORE_FOLDER").ok(),
     });
     let context = context.as_ref().unwrap();
 
     let platform_differentiates_symlinks = cfg!(windows);
     let platform_dir_separator = std::path::MAIN_SEPARATOR;
+    let canonical_root = get_canonical_root(&context.root);
 
     // Respond to the boss with what type of file/folder the root is, as it makes some decisions
     // based on this.
@@ -532,12 +861,12 @@ fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: S
     let metadata = std::fs::symlink_metadata(&context.root);
     match metadata {
         Ok(m) => {
-            let entry_details = entry_details_from_metadata(m, &context.root)?;
-            comms.send_response(Response::RootDetails { root_details: Some(entry_details), platform_differentiates_symlinks, platform_dir_separator })?;
+            let entry_details = entry_details_from_metadata(m, &context.root, context.file_flags)?;
+            comms.send_response(Response::RootDetails { root_details: Some(entry_details), platform_differentiates_symlinks, platform_dir_separator, canonical_root })?;
         },
         Err(e) if e.kind() == ErrorKind::NotFound => {
             // Report this as a special error, as we handle it differently on the boss side
-            comms.send_response(Response::RootDetails { root_details: None, platform_differentiates_symlinks, platform_dir_separator })?;
+            comms.send_response(Response::RootDetails { root_details: None, platform_differentiates_symlinks, platform_dir_separator, canonical_root })?;
         }
         Err(e) => return Err(format!(
                     "root '{}' can't be read: {}", context.root.display(), e)),
@@ -546,13 +875,61 @@ fn handle_set_root(comms: &mut Comms, context: &mut Option<DoerContext>, root: S
     Ok(())
 }
 
+/// Creates (or truncates) a file. If this isn't allowed and we're managing file flags (see --flags),
+/// then the file might be immutable, so we clear its flags and try again. The correct flags are set
+/// again once the new contents have been written.
+fn create_file_clearing_flags(path: &Path, file_flags: bool) -> std::io::Result<std::fs::File> {
+    match std::fs::File::create(path) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied && file_flags && clear_file_flags(path) => std::fs::File::create(path),
+        r => r,
+    }
+}
+
+/// Deletes a file, clearing its flags first if needed (see create_file_clearing_flags).
+fn remove_file_clearing_flags(path: &Path, file_flags: bool) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() == ErrorKind::PermissionDenied && file_flags && clear_file_flags(path) => std::fs::remove_file(path),
+        r => r,
+    }
+}
+
+/// Clears the flags of a file, returning whether there were any to clear (and so it's worth trying again).
+fn clear_file_flags(path: &Path) -> bool {
+    match get_file_flags(path) {
+        Ok(f) if f != FileFlags::default() => {
+            trace!("Clearing flags {:?} of '{}'", f, path.display());
+            set_file_flags(path, FileFlags::default()).is_ok()
+        }
+        _ => false,
+    }
+}
+
+/// Resolves the root path to an absolute path with any symlinks and '..' components removed,
+/// so that the boss can compare the source and dest roots to see if one is inside the other.
+/// The root might not exist yet (e.g. a dest that will be created), in which case we resolve the
+/// parent folder instead and append the final component.
+fn get_canonical_root(root: &Path) -> Option<String> {
+    let canonical = match std::fs::canonicalize(root) {
+        Ok(c) => c,
+        Err(_) => {
+            let parent = match root.parent() {
+                Some(p) if p.as_os_str().is_empty() => Path::new("."),
+                Some(p) => p,
+                None => return None,
+            };
+            std::fs::canonicalize(parent).ok()?.join(root.file_name()?)
+        }
+    };
+    Some(canonical.to_string_lossy().to_string())
+}
+
 #[derive(PartialEq, Debug)]
-enum FilterResult {
+pub enum FilterResult {
     Include,
     Exclude
 }
 
-fn apply_filters(path: &RootRelativePath, filters: &Filters) -> FilterResult {
+pub fn apply_filters(path: &RootRelativePath, is_folder: bool, filters: &Filters) -> FilterResult {
     if path.is_root() {
         // The root is always included, otherwise it would be difficult to write filter lists that start with include,
         // because you'd need to include the root (empty string) explicitly
@@ -574,6 +951,9 @@ fn apply_filters(path: &RootRelativePath, filters: &Filters) -> FilterResult {
 
     // Now we go through the filters which matches, and work out the final include/exclude state
     for matched_filter_idx in matches {
+        if filters.folders_only[matched_filter_idx] && !is_folder {
+            continue;
+        }
         let filter_kind = filters.kinds[matched_filter_idx];
         match filter_kind {
             FilterKind::Include => result = FilterResult::Include,
@@ -585,7 +965,11 @@ fn apply_filters(path: &RootRelativePath, filters: &Filters) -> FilterResult {
 }
 
 /// Filter callback used when iterating over directory contents.
-fn filter_func(entry: &std::fs::DirEntry, root: &Path, filters: &Filters) -> Result<parallel_walk_dir::FilterResult<RootRelativePath>, String> {
+/// Entries excluded by the filters are counted in `num_filtered`, and rjrssync's own entries are collected in
+/// `internal_entries`, so that these can be reported to the boss.
+fn filter_func(entry: &std::fs::DirEntry, root: &Path, filters: &Filters, num_filtered: &AtomicU32,
+    internal_entries: &Mutex<Vec<RootRelativePath>>) -> Result<parallel_walk_dir::FilterResult<RootRelativePath>, String>
+{
     // First normalize the path to our platform-independent representation, so that the filters
     // apply equally well on both source and dest sides, if they are different platforms.
 
@@ -598,10 +982,23 @@ fn filter_func(entry: &std::fs::DirEntry, root: &Path, filters: &Filters) -> Res
         Err(e) => return Err(format!("normalize_path failed on '{}': {e}", path.display())),
     };
 
-    let skip = apply_filters(&path, &filters) == FilterResult::Exclude;
-    if skip {
+    // Note that file_type() doesn't follow symlinks, so folder-only filters don't apply to symlinks to folders
+    let is_folder = match entry.file_type() {
+        Ok(t) => t.is_dir(),
+        Err(e) => return Err(format!("Unable to get file type for '{}': {e}", entry.path().display())),
+    };
+    // rjrssync's own bookkeeping files are never reported as entries, regardless of the filters
+    let skip = if is_internal_entry_name(&entry.file_name().to_string_lossy()) {
+        trace!("Skipping '{}' as it is internal to rjrssync", path);
+        internal_entries.lock().expect("Mutex poisoned").push(path.clone());
+        true
+    } else if apply_filters(&path, is_folder, &filters) == FilterResult::Exclude {
         trace!("Skipping '{}' due to filter", path);
-    }
+        num_filtered.fetch_add(1, Ordering::Relaxed);
+        true
+    } else {
+        false
+    };
     // Store the normalized root-relative path so that we don't need to re-calculate this when we process
     // this entry
     Ok(parallel_walk_dir::FilterResult::<RootRelativePath> {
@@ -610,18 +1007,67 @@ fn filter_func(entry: &std::fs::DirEntry, root: &Path, filters: &Filters) -> Res
     })
 }
 
-fn handle_get_entries(comms: &mut Comms, context: &mut DoerContext, filters: Filters) -> Result<(), String> {
+/// Checks if the given entry is a symlink whose target points outside the root, and if so deals with it
+/// according to the --safe-links behaviour. Returns None if the entry should be skipped.
+fn apply_safe_links(path: &RootRelativePath, details: EntryDetails, behaviour: SafeLinksBehaviour) -> Result<Option<EntryDetails>, String> {
+    let (kind, target) = match details {
+        EntryDetails::Symlink { kind, target } => (kind, target),
+        d => return Ok(Some(d)),
+    };
+    let target_str = match &target {
+        SymlinkTarget::Normalized(t) if path.relative_path_stays_inside_root(t) => return Ok(Some(EntryDetails::Symlink { kind, target })),
+        SymlinkTarget::Normalized(t) => t,
+        // Targets we couldn't normalize are usually absolute, so we can't tell where they point. Assume it's outside.
+        // This includes targets that have already been munged, as they might have been crafted to get back out
+        // (e.g. "/rjrssync-munged/../etc"), so these get munged again.
+        SymlinkTarget::NotNormalized(t) => t,
+    };
+    match behaviour {
+        SafeLinksBehaviour::Skip => {
+            trace!("Skipping symlink '{path}' as its target '{target_str}' is outside the root");
+            Ok(None)
+        }
+        SafeLinksBehaviour::Munge => {
+            trace!("Munging symlink '{path}' as its target '{target_str}' is outside the root");
+            Ok(Some(EntryDetails::Symlink { kind, target: SymlinkTarget::NotNormalized(munge_symlink_target(target_str)) }))
+        }
+        SafeLinksBehaviour::Error => Err(format!("Symlink '{path}' has target '{target_str}' which is outside the root. See --safe-links.")),
+    }
+}
+
+/// Prefixes the given symlink target with MUNGED_SYMLINK_PREFIX so that it can't be followed.
+/// Any ".." components are removed first, as they could climb back out of the prefix
+/// (e.g. on Windows, ".." is resolved without checking that the folder before it exists).
+fn munge_symlink_target(target: &str) -> String {
+    let components: Vec<&str> = target.split(['/', '\\']).filter(|c| !c.is_empty() && *c != "." && *c != "..").collect();
+    format!("{MUNGED_SYMLINK_PREFIX}{}", components.join("/"))
+}
+
+fn handle_get_entries(comms: &mut Comms, context: &mut DoerContext, filters: Filters, skip_unreadable: bool,
+    safe_links: Option<SafeLinksBehaviour>) -> Result<(), String>
+{
     let start = Instant::now();
     // Note that we can't use this to get metadata for a single root entry when that entry is a symlink,
     // as the iteration will fail before we can get the metadata for the root. Therefore we only use this
     // when walking what's known to be a directory (discovered in SetRoot).
     let root = context.root.clone();
-    let entry_receiver = parallel_walk_dir(&context.root, move |e| filter_func(e, &root, &filters));
+    let num_filtered = Arc::new(AtomicU32::new(0));
+    let num_filtered_clone = num_filtered.clone();
+    let internal_entries = Arc::new(Mutex::new(vec![]));
+    let internal_entries_clone = internal_entries.clone();
+    let entry_receiver = parallel_walk_dir(&context.root, move |e| filter_func(e, &root, &filters, &num_filtered_clone, &internal_entries_clone));
     let mut count = 0;
+    let mut num_unsafe_links = 0;
     while let Ok(entry) = entry_receiver.recv() {
         count += 1;
         match entry {
-            Err(e) => return Err(format!("Error fetching entries of root '{}': {e}", context.root.display())),
+            Err(e) => {
+                let path = e.path.strip_prefix(&context.root).ok().and_then(|p| RootRelativePath::try_from(p).ok());
+                match path {
+                    Some(path) if skip_unreadable => comms.send_response(Response::UnreadableEntry { path, error: e.message })?,
+                    _ => return Err(format!("Error fetching entries of root '{}': {}", context.root.display(), e.message)),
+                }
+            }
             Ok(e) => {
                 trace!("Processing entry {:?}", e);
                 profile_this!("Processing entry");
@@ -630,20 +1076,33 @@ fn handle_get_entries(comms: &mut Comms, context: &mut DoerContext, filters: Fil
                 // so that we don't need to re-normalize it here.
                 let path = e.additional_data;
 
-                let metadata = match e.dir_entry.metadata() {
-                    Ok(m) => m,
-                    Err(err) => return Err(format!("Unable to get metadata for '{}': {err}", path)),
+                let d = match e.dir_entry.metadata() {
+                    Ok(m) => entry_details_from_metadata(m, &e.dir_entry.path(), context.file_flags),
+                    Err(err) => Err(format!("Unable to get metadata for '{}': {err}", path)),
                 };
-
-                let d = entry_details_from_metadata(metadata, &e.dir_entry.path())?;
-
-                comms.send_response(Response::Entry((path, d)))?;
+                let d = match (d, safe_links) {
+                    (Ok(d), Some(b)) => match apply_safe_links(&path, d, b)? {
+                        Some(d) => Ok(d),
+                        None => {
+                            num_unsafe_links += 1;
+                            continue;
+                        }
+                    },
+                    (d, _) => d,
+                };
+                match d {
+                    Ok(d) => comms.send_response(Response::Entry((path, d)))?,
+                    Err(error) if skip_unreadable => comms.send_response(Response::UnreadableEntry { path, error })?,
+                    Err(error) => return Err(error),
+                }
             }
         }
     }
 
     let elapsed = start.elapsed().as_millis();
-    comms.send_response(Response::EndOfEntries)?;
+    // The walk has finished once the receiver is exhausted, so all the filtered entries have been counted
+    let internal_entries = std::mem::take(&mut *internal_entries.lock().expect("Mutex poisoned"));
+    comms.send_response(Response::EndOfEntries { num_filtered: num_filtered.load(Ordering::Relaxed), num_unsafe_links, internal_entries })?;
     debug!(
         "Walked {} in {}ms ({}/s)",
         count,
@@ -654,7 +1113,75 @@ fn handle_get_entries(comms: &mut Comms, context: &mut DoerContext, filters: Fil
     Ok(())
 }
 
-fn handle_get_file_contents(comms: &mut Comms, full_path: &Path) -> Result<(), String> {
+/// The largest chunk of file contents that we send in one go.
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Compresses a chunk of file contents, if it's worth it, returning the data to send along with the original size
+/// if it was compressed. If the data doesn't compress well, then `compress` is cleared so that we don't waste time
+/// trying to compress the rest of the file (the start of a file is usually a good indication of the rest of it).
+fn compress_chunk(data: Vec<u8>, compress: &mut bool) -> (Vec<u8>, Option<u64>) {
+    if !*compress || data.is_empty() {
+        return (data, None);
+    }
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::fast());
+    match encoder.write_all(&data).and_then(|_| encoder.finish()) {
+        // Require a reasonable saving, otherwise it's not worth the time spent decompressing on the other side
+        Ok(c) if c.len() < data.len() * 9 / 10 => (c, Some(data.len() as u64)),
+        _ => {
+            *compress = false;
+            (data, None)
+        }
+    }
+}
+
+fn decompress_chunk(data: &[u8], uncompressed_size: u64) -> Result<Vec<u8>, String> {
+    // The size comes from the other side, so check it before using it to allocate memory
+    if uncompressed_size > MAX_CHUNK_SIZE as u64 {
+        return Err(format!("Chunk of {uncompressed_size} bytes is larger than the maximum of {MAX_CHUNK_SIZE} bytes"));
+    }
+    let mut result = Vec::with_capacity(uncompressed_size as usize);
+    // Stop one byte after the expected size, so that bad data can't make us decompress an unlimited amount
+    flate2::read::DeflateDecoder::new(data).take(uncompressed_size + 1).read_to_end(&mut result).map_err(|e| e.to_string())?;
+    if result.len() as u64 != uncompressed_size {
+        return Err(format!("Expected {uncompressed_size} bytes but got {}", result.len()));
+    }
+    Ok(result)
+}
+
+/// Removes the empty folders inside the given folder (but not the folder itself), in child-before-parent order
+/// so that folders containing only empty folders are removed too. Returns whether the given folder is now empty.
+/// Folders excluded by the filters, and rjrssync's own folders, are left alone (and so their parents aren't empty either).
+fn prune_empty_folders(root: &Path, folder: &Path, filters: &Filters, pruned: &mut Vec<RootRelativePath>) -> Result<bool, String> {
+    let mut empty = true;
+    let entries = std::fs::read_dir(folder).map_err(|e| format!("Error listing folder '{}': {e}", folder.display()))?;
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Error listing folder '{}': {e}", folder.display()))?;
+        let full_path = entry.path();
+        // Note that file_type() doesn't follow symlinks, so we never prune anything through a symlink
+        let is_folder = entry.file_type().map_err(|e| format!("Error getting type of '{}': {e}", full_path.display()))?.is_dir();
+        let path = RootRelativePath::try_from(full_path.strip_prefix(root).expect("Strip prefix failed"))
+            .map_err(|e| format!("normalize_path failed on '{}': {e}", full_path.display()))?;
+        let is_internal = is_internal_entry_name(&entry.file_name().to_string_lossy());
+        if is_folder && !is_internal && apply_filters(&path, true, filters) == FilterResult::Include && prune_empty_folders(root, &full_path, filters, pruned)? {
+            trace!("Removing empty folder '{}'", full_path.display());
+            std::fs::remove_dir(&full_path).map_err(|e| format!("Error deleting folder '{}': {e}", full_path.display()))?;
+            pruned.push(path);
+        } else {
+            empty = false;
+        }
+    }
+    Ok(empty)
+}
+
+/// Calculates the SHA-256 checksum of a file's contents, reading it in chunks so that large files
+/// don't need to fit in memory.
+fn compute_file_checksum(full_path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut std::fs::File::open(full_path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+fn handle_get_file_contents(comms: &mut Comms, full_path: &Path, mut compress: bool) -> Result<(), String> {
     trace!("Getting content of '{}'", full_path.display());
 
     let mut f = match std::fs::File::open(&full_path) {
@@ -680,14 +1207,16 @@ fn handle_get_file_contents(comms: &mut Comms, full_path: &Path) -> Result<(), S
             Ok(n) if n == 0 => {
                 // End of file - send the data that we got previously, and report that there is no more data to follow.
                 prev_buf.truncate(prev_buf_valid);
-                comms.send_response(Response::FileContent { data: prev_buf, more_to_follow: false })?;
+                let (data, uncompressed_size) = compress_chunk(prev_buf, &mut compress);
+                comms.send_response(Response::FileContent { data, uncompressed_size, more_to_follow: false })?;
                 return Ok(());
             },
             Ok(n) => {
                 // Some data read - send any previously retrieved data, and report that there is more data to follow
                 if prev_buf_valid > 0 {
                     prev_buf.truncate(prev_buf_valid);
-                    comms.send_response(Response::FileContent { data: prev_buf, more_to_follow: true })?;
+                    let (data, uncompressed_size) = compress_chunk(prev_buf, &mut compress);
+                    comms.send_response(Response::FileContent { data, uncompressed_size, more_to_follow: true })?;
                 }
 
                 // The data we just retrieved will be sent in the next iteration (once we know if there is more data to follow or not)
@@ -701,7 +1230,7 @@ fn handle_get_file_contents(comms: &mut Comms, full_path: &Path) -> Result<(), S
                 } else {
                     // There might be lots more data, so gradually increase the chunk size up to a practical limit
                     // 4 MB, chosen pretty arbitirarily. If this changes, will also need to update the fixed size pre-allocated buffers in encrypted_comms.rs!
-                    chunk_size = std::cmp::min(chunk_size * 2, 1024*1024*4);
+                    chunk_size = std::cmp::min(chunk_size * 2, MAX_CHUNK_SIZE);
 
                     next_buf = vec![0; chunk_size];
                 }
@@ -723,8 +1252,8 @@ fn handle_create_symlink(path: RootRelativePath, context: &mut DoerContext, #[al
 
     #[cfg(windows)]
     let res = match kind {
-        SymlinkKind::File => std::os::windows::fs::symlink_file(target, &full_path),
-        SymlinkKind::Folder => std::os::windows::fs::symlink_dir(target, &full_path),
+        SymlinkKind::File => std::os::windows::fs::symlink_file(&target, &full_path),
+        SymlinkKind::Folder => std::os::windows::fs::symlink_dir(&target, &full_path),
         SymlinkKind::Unknown => {
             // Windows can't create unknown symlinks - it needs to be either a file or folder symlink
             return Err(format!("Can't create symlink of unknown kind on this platform '{}'", full_path.display()));
@@ -733,12 +1262,20 @@ fn handle_create_symlink(path: RootRelativePath, context: &mut DoerContext, #[al
     #[cfg(not(windows))]
     // Non-windows platforms can't create explicit file/folder symlinks, but we can just create a generic
     // symlink, which will behave the same. All types of symlink are just generic ones.
-    let res = std::os::unix::fs::symlink(target, &full_path);
+    let res = std::os::unix::fs::symlink(&target, &full_path);
 
-    if let Err(e) = res {
-        return Err(format!("Failed to create symlink '{}': {e}", full_path.display()));
+    match res {
+        // Might have already been created, when resuming a sync (see SetRoot)
+        Err(e) if context.resuming && e.kind() == ErrorKind::AlreadyExists && std::fs::read_link(&full_path).is_ok_and(|t| t == Path::new(&target)) => {
+            trace!("Symlink already exists");
+            Ok(())
+        }
+        Err(e) => Err(format!("Failed to create symlink '{}': {e}", full_path.display())),
+        Ok(()) => {
+            context.entry_changed(&full_path);
+            Ok(())
+        }
     }
-    Ok(())
 }
 
 #[cfg(test)]
@@ -752,41 +1289,45 @@ mod tests {
         // Filters specify to exclude everything
         let filters = Filters {
             regex_set: RegexSet::new(&["^.*$"]).unwrap(),
-            kinds: vec![FilterKind::Exclude]
+            kinds: vec![FilterKind::Exclude],
+            folders_only: vec![false],
         };
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("will be excluded")).unwrap(), &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("will be excluded")).unwrap(), false, &filters), FilterResult::Exclude);
         // But the root is always included anyway
-        assert_eq!(apply_filters(&RootRelativePath::root(), &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::root(), true, &filters), FilterResult::Include);
     }
 
     #[test]
     fn test_apply_filters_no_filters() {
         let filters = Filters {
             regex_set: RegexSet::empty(),
-            kinds: vec![]
+            kinds: vec![],
+            folders_only: vec![],
         };
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("yes")).unwrap(), &filters), FilterResult::Include);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("no")).unwrap(), &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("yes")).unwrap(), false, &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("no")).unwrap(), false, &filters), FilterResult::Include);
     }
 
     #[test]
     fn test_apply_filters_single_include() {
         let filters = Filters {
             regex_set: RegexSet::new(&["^yes$"]).unwrap(),
-            kinds: vec![FilterKind::Include]
+            kinds: vec![FilterKind::Include],
+            folders_only: vec![false],
         };
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("yes")).unwrap(), &filters), FilterResult::Include);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("no")).unwrap(), &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("yes")).unwrap(), false, &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("no")).unwrap(), false, &filters), FilterResult::Exclude);
     }
 
     #[test]
     fn test_apply_filters_single_exclude() {
         let filters = Filters {
             regex_set: RegexSet::new(&["^no$"]).unwrap(),
-            kinds: vec![FilterKind::Exclude]
+            kinds: vec![FilterKind::Exclude],
+            folders_only: vec![false],
         };
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("yes")).unwrap(), &filters), FilterResult::Include);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("no")).unwrap(), &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("yes")).unwrap(), false, &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("no")).unwrap(), false, &filters), FilterResult::Exclude);
     }
 
     #[test]
@@ -805,14 +1346,110 @@ mod tests {
                 FilterKind::Exclude,
                 FilterKind::Include,
                 FilterKind::Exclude,
-            ]
+            ],
+            folders_only: vec![false; 5],
         };
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("README")).unwrap(), &filters), FilterResult::Include);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build/file.o")).unwrap(), &filters), FilterResult::Exclude);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("git/hash")).unwrap(), &filters), FilterResult::Exclude);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build/rob")).unwrap(), &filters), FilterResult::Exclude);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build/output.exe")).unwrap(), &filters), FilterResult::Include);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("src/build/file.o")).unwrap(), &filters), FilterResult::Exclude);
-        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("src/source.cpp")).unwrap(), &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("README")).unwrap(), false, &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build/file.o")).unwrap(), false, &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("git/hash")).unwrap(), false, &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build/rob")).unwrap(), false, &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build/output.exe")).unwrap(), false, &filters), FilterResult::Include);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("src/build/file.o")).unwrap(), false, &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("src/source.cpp")).unwrap(), false, &filters), FilterResult::Include);
+    }
+
+    #[test]
+    fn test_apply_filters_folders_only() {
+        let filters = Filters {
+            regex_set: RegexSet::new(["^build$"]).unwrap(),
+            kinds: vec![FilterKind::Exclude],
+            folders_only: vec![true],
+        };
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build")).unwrap(), true, &filters), FilterResult::Exclude);
+        assert_eq!(apply_filters(&RootRelativePath::try_from(Path::new("build")).unwrap(), false, &filters), FilterResult::Include);
+    }
+
+    /// Checks that data is forwarded in both directions by the relay, but only for a connection which
+    /// starts with the secret.
+    #[test]
+    fn test_relay_thread() {
+        // A target which sends back everything it receives, in upper case
+        let target = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let target_port = target.local_addr().unwrap().port();
+        std::thread::spawn(move || {
+            let (mut s, _) = target.accept().unwrap();
+            let mut buf = String::new();
+            s.read_to_string(&mut buf).unwrap();
+            s.write_all(buf.to_uppercase().as_bytes()).unwrap();
+        });
+
+        let relay = bind_listener("127.0.0.1", 0..=0).unwrap();
+        let relay_port = relay.local_addr().unwrap().port();
+        let secret = [7; 16];
+        std::thread::spawn(move || relay_thread(relay, ("127.0.0.1".to_string(), target_port), secret));
+
+        // Someone else connects first, without the secret. They get disconnected and nothing reaches the target.
+        let mut intruder = TcpStream::connect(("127.0.0.1", relay_port)).unwrap();
+        intruder.write_all(b"0123456789abcdef intruder").unwrap();
+        let mut response = String::new();
+        assert!(intruder.read_to_string(&mut response).is_err() || response.is_empty());
+
+        let mut client = TcpStream::connect(("127.0.0.1", relay_port)).unwrap();
+        client.write_all(&secret).unwrap();
+        client.write_all(b"hello relay").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert_eq!(response, "HELLO RELAY");
+    }
+
+    #[test]
+    fn test_parse_port_range() {
+        assert_eq!(parse_port_range("40000-40100"), Ok(40000..=40100));
+        assert_eq!(parse_port_range("40000-40000"), Ok(40000..=40000));
+        assert!(parse_port_range("40000").is_err());
+        assert!(parse_port_range("40100-40000").is_err());
+        assert!(parse_port_range("a-40000").is_err());
+        assert!(parse_port_range("40000-70000").is_err());
+    }
+
+    #[test]
+    fn test_munge_symlink_target() {
+        assert_eq!(munge_symlink_target("../outside.txt"), "/rjrssync-munged/outside.txt");
+        assert_eq!(munge_symlink_target("/etc/passwd"), "/rjrssync-munged/etc/passwd");
+        assert_eq!(munge_symlink_target(r"C:\Windows\..\System32"), "/rjrssync-munged/C:/Windows/System32");
+        // Already munged targets are munged again, so that they can't be used to get back out
+        assert_eq!(munge_symlink_target("/rjrssync-munged/../../etc"), "/rjrssync-munged/rjrssync-munged/etc");
+    }
+
+    #[test]
+    fn test_compress_chunk_round_trip() {
+        let mut compress = true;
+        let data = b"hello hello hello hello hello hello hello hello".to_vec();
+        let (compressed, size) = compress_chunk(data.clone(), &mut compress);
+        assert!(compress);
+        assert_eq!(size, Some(data.len() as u64));
+        assert!(compressed.len() < data.len());
+        assert_eq!(decompress_chunk(&compressed, data.len() as u64), Ok(data));
+    }
+
+    #[test]
+    fn test_compress_chunk_incompressible() {
+        // Data that doesn't get smaller is sent raw, and we stop trying for the rest of the file
+        let mut compress = true;
+        let data = b"abc".to_vec();
+        assert_eq!(compress_chunk(data.clone(), &mut compress), (data.clone(), None));
+        assert!(!compress);
+        let data = b"hello hello hello hello hello hello hello hello".to_vec();
+        assert_eq!(compress_chunk(data.clone(), &mut compress), (data, None));
+    }
+
+    #[test]
+    fn test_decompress_chunk_wrong_size() {
+        let mut compress = true;
+        let data = b"hello hello hello hello hello hello hello hello".to_vec();
+        let (compressed, _) = compress_chunk(data, &mut compress);
+        assert!(decompress_chunk(&compressed, 5).is_err());
+        assert!(decompress_chunk(&compressed, u64::MAX).is_err());
     }
 }
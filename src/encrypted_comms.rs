@@ -117,6 +117,11 @@ impl<S: Serialize + Send + 'static + Debug, R: for<'a> Deserialize<'a> + Seriali
         AsyncEncryptedComms { tcp_connection, sending_thread, sender, receiving_thread, receiver }
     }
 
+    /// The address of the other end of the network connection, for showing to the user.
+    pub fn peer_addr(&self) -> Option<std::net::SocketAddr> {
+        self.tcp_connection.peer_addr().ok()
+    }
+
     /// Clean shutdown which joins the background threads, making sure all messages are flushed etc.
     /// Prefer this to simply dropping the object, which will leave the threads to exit on their own.
     pub fn shutdown(self) {
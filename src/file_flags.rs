@@ -0,0 +1,178 @@
+//! Reading and writing file flags (see --flags), which are platform-specific.
+//! On platforms which don't support them, files are treated as never having any flags.
+
+use std::path::Path;
+
+use crate::boss_doer_interface::FileFlags;
+
+/// Gets the flags of the given file.
+/// Filesystems which don't support flags (e.g. some network filesystems) are treated as having no flags.
+pub fn get_file_flags(path: &Path) -> Result<FileFlags, String> {
+    imp::get(path).map_err(|e| format!("Error getting flags of '{}': {e}", path.display()))
+}
+
+/// Sets the flags of the given file. Any other flags which rjrssync doesn't know about are left unchanged.
+pub fn set_file_flags(path: &Path, flags: FileFlags) -> Result<(), String> {
+    imp::set(path, flags).map_err(|e| format!("Error setting flags of '{}': {e}", path.display()))
+}
+
+/// Linux has flags which are read and written using an ioctl on an open file (this is what lsattr/chattr do).
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{path::Path, os::unix::{io::AsRawFd, fs::OpenOptionsExt}};
+
+    use crate::boss_doer_interface::FileFlags;
+
+    // These aren't provided by the libc crate. They are defined in linux/fs.h as _IOR('f', 1, long) and _IOW('f', 2, long),
+    // although the kernel actually reads/writes an int.
+    const FS_IOC_GETFLAGS: u32 = (2 << 30) | ((std::mem::size_of::<libc::c_long>() as u32) << 16) | ((b'f' as u32) << 8) | 1;
+    const FS_IOC_SETFLAGS: u32 = (1 << 30) | ((std::mem::size_of::<libc::c_long>() as u32) << 16) | ((b'f' as u32) << 8) | 2;
+    const FS_IMMUTABLE_FL: libc::c_int = 0x00000010;
+    const FS_NODUMP_FL: libc::c_int = 0x00000040;
+
+    fn open(path: &Path) -> std::io::Result<std::fs::File> {
+        // Non-blocking, so that we don't hang on special files (although we only expect to be called for regular files)
+        std::fs::OpenOptions::new().read(true).custom_flags(libc::O_NONBLOCK | libc::O_NOFOLLOW).open(path)
+    }
+
+    fn get_raw(f: &std::fs::File) -> std::io::Result<libc::c_int> {
+        let mut raw: libc::c_int = 0;
+        if unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_GETFLAGS as _, &mut raw) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(raw)
+    }
+
+    fn is_unsupported(e: &std::io::Error) -> bool {
+        matches!(e.raw_os_error(), Some(libc::ENOTTY) | Some(libc::EOPNOTSUPP) | Some(libc::ENOSYS))
+    }
+
+    pub fn get(path: &Path) -> std::io::Result<FileFlags> {
+        let raw = match get_raw(&open(path)?) {
+            Ok(r) => r,
+            Err(e) if is_unsupported(&e) => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(FileFlags {
+            immutable: raw & FS_IMMUTABLE_FL != 0,
+            nodump: raw & FS_NODUMP_FL != 0,
+        })
+    }
+
+    pub fn set(path: &Path, flags: FileFlags) -> std::io::Result<()> {
+        let f = open(path)?;
+        let old_raw = match get_raw(&f) {
+            Ok(r) => r,
+            // If the filesystem doesn't support flags then there's nothing to clear, but we can't set any either
+            Err(e) if is_unsupported(&e) && flags == FileFlags::default() => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let mut raw = old_raw & !(FS_IMMUTABLE_FL | FS_NODUMP_FL);
+        if flags.immutable {
+            raw |= FS_IMMUTABLE_FL;
+        }
+        if flags.nodump {
+            raw |= FS_NODUMP_FL;
+        }
+        if raw != old_raw && unsafe { libc::ioctl(f.as_raw_fd(), FS_IOC_SETFLAGS as _, &raw) } != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// BSD and macOS have flags which are part of the file's metadata, and set with chflags.
+/// There are separate user and system versions of the immutable flag, which we treat the same.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+mod imp {
+    use std::{path::Path, os::unix::ffi::OsStrExt, ffi::CString};
+
+    use crate::boss_doer_interface::FileFlags;
+
+    fn get_raw(path: &Path) -> std::io::Result<u32> {
+        #[cfg(target_os = "macos")]
+        use std::os::macos::fs::MetadataExt;
+        #[cfg(target_os = "freebsd")]
+        use std::os::freebsd::fs::MetadataExt;
+        Ok(std::fs::symlink_metadata(path)?.st_flags())
+    }
+
+    pub fn get(path: &Path) -> std::io::Result<FileFlags> {
+        let raw = get_raw(path)?;
+        Ok(FileFlags {
+            immutable: raw & (libc::UF_IMMUTABLE | libc::SF_IMMUTABLE) as u32 != 0,
+            nodump: raw & libc::UF_NODUMP as u32 != 0,
+        })
+    }
+
+    pub fn set(path: &Path, flags: FileFlags) -> std::io::Result<()> {
+        let old_raw = get_raw(path)?;
+        let mut raw = old_raw & !((libc::UF_IMMUTABLE | libc::SF_IMMUTABLE | libc::UF_NODUMP) as u32);
+        if flags.immutable {
+            raw |= libc::UF_IMMUTABLE as u32;
+        }
+        if flags.nodump {
+            raw |= libc::UF_NODUMP as u32;
+        }
+        if raw != old_raw {
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            if unsafe { libc::chflags(c_path.as_ptr(), raw as _) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Other platforms (e.g. Windows) don't have any equivalent flags.
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+mod imp {
+    use std::path::Path;
+
+    use crate::boss_doer_interface::FileFlags;
+
+    pub fn get(_path: &Path) -> std::io::Result<FileFlags> {
+        Ok(FileFlags::default())
+    }
+
+    pub fn set(_path: &Path, flags: FileFlags) -> std::io::Result<()> {
+        if flags == FileFlags::default() {
+            Ok(())
+        } else {
+            Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "file flags aren't supported on this platform"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Setting and getting flags round-trips, where the filesystem supports them. Setting the immutable flag
+    /// needs extra privileges (e.g. root on Linux), so that is only tested if allowed.
+    #[test]
+    fn test_set_and_get() {
+        let temp_dir = tempdir::TempDir::new("rjrssync-test").unwrap();
+        let path = temp_dir.path().join("file");
+        std::fs::write(&path, "contents").unwrap();
+
+        assert_eq!(get_file_flags(&path), Ok(FileFlags::default()));
+        // Clearing flags always works, even if the platform or filesystem doesn't support them
+        assert_eq!(set_file_flags(&path, FileFlags::default()), Ok(()));
+
+        // The rest of the test needs a platform and filesystem that support flags
+        let nodump = FileFlags { immutable: false, nodump: true };
+        if set_file_flags(&path, nodump).is_err() {
+            return;
+        }
+        assert_eq!(get_file_flags(&path), Ok(nodump));
+
+        let both = FileFlags { immutable: true, nodump: true };
+        if set_file_flags(&path, both).is_ok() {
+            assert_eq!(get_file_flags(&path), Ok(both));
+            assert!(std::fs::write(&path, "new contents").is_err());
+        }
+        set_file_flags(&path, FileFlags::default()).unwrap(); // So that it can be cleaned up
+        assert_eq!(get_file_flags(&path), Ok(FileFlags::default()));
+    }
+}
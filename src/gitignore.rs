@@ -0,0 +1,188 @@
+/// Converts the contents of a file in .gitignore syntax (https://git-scm.com/docs/gitignore)
+/// into our filter strings (see --filter), appending them to the given filters (which they can override).
+///
+/// The semantics are mostly the same - the last matching pattern decides, and the contents of excluded folders
+/// are never looked at (so can't be re-included). The patterns are relative to the root of the sync,
+/// as if the .gitignore file was in the root folder.
+pub fn append_filters(text: &str, result: &mut Vec<String>) -> Result<(), String> {
+    for (line_idx, line) in text.lines().enumerate() {
+        let mut line = line.trim_end_matches('\r');
+        // Trailing spaces are ignored, unless escaped with a backslash
+        while line.ends_with(' ') && !line.ends_with("\\ ") {
+            line = &line[..line.len() - 1];
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (negate, pattern) = match line.strip_prefix('!') {
+            Some(p) => (true, p),
+            None => (false, line),
+        };
+        // A negation can only re-include something excluded by an earlier filter, so any before the first filter
+        // do nothing. We drop these, as an include at the start of our filters would change the default to exclude.
+        if negate && result.is_empty() {
+            continue;
+        }
+        let (folder_only, pattern) = match pattern.strip_suffix('/') {
+            Some(p) => (true, p),
+            None => (false, pattern),
+        };
+        if pattern.is_empty() {
+            continue;
+        }
+        // A slash at the start or middle of the pattern means it is relative to the root, otherwise it
+        // can match at any level
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+
+        let regex = glob_to_regex(pattern).map_err(|e| format!("Invalid gitignore pattern '{line}' on line {}: {e}", line_idx + 1))?;
+        result.push(format!("{}{}{}{}",
+            if negate { '+' } else { '-' },
+            if anchored { "" } else { "(.*/)?" },
+            regex,
+            if folder_only { "/" } else { "" }));
+    }
+    Ok(())
+}
+
+fn glob_to_regex(pattern: &str) -> Result<String, String> {
+    let components: Vec<&str> = pattern.split('/').collect();
+    let mut result = String::new();
+    let mut prev_was_double_star = false;
+    for (i, c) in components.iter().enumerate() {
+        let is_last = i == components.len() - 1;
+        if *c == "**" {
+            // A leading "**/" matches in all folders, a trailing "/**" matches everything inside,
+            // and "/**/" in the middle matches zero or more folders
+            result += match (i == 0, is_last) {
+                (true, true) => ".*",
+                (false, true) => "/.*",
+                (true, false) => "(.*/)?",
+                (false, false) => "/(.*/)?",
+            };
+            prev_was_double_star = true;
+        } else {
+            if i > 0 && !prev_was_double_star {
+                result.push('/');
+            }
+            result += &component_to_regex(c)?;
+            prev_was_double_star = false;
+        }
+    }
+    Ok(result)
+}
+
+/// Converts a single path component (which can't contain a slash) from glob syntax to a regex.
+fn component_to_regex(component: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = component.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => result += "[^/]*",
+            '?' => result += "[^/]",
+            '\\' => match chars.next() {
+                Some(e) => result += &regex::escape(&e.to_string()),
+                None => return Err("Trailing backslash".to_string()),
+            },
+            '[' => {
+                // Character class, e.g. [a-z] or [!0-9]. If there's no closing bracket (or nothing between the brackets)
+                // then it's a literal '['.
+                let class: String = chars.clone().take_while(|c| *c != ']').collect();
+                if class.chars().count() == chars.clone().count() || class.trim_start_matches(['!', '^']).is_empty() {
+                    result += "\\[";
+                    continue;
+                }
+                for _ in 0..=class.chars().count() {
+                    chars.next();
+                }
+                result.push('[');
+                let class = match class.strip_prefix(['!', '^']) {
+                    Some(negated) => { result.push('^'); negated.to_string() }
+                    None => class,
+                };
+                let class: Vec<char> = class.chars().collect();
+                for (i, cc) in class.iter().enumerate() {
+                    // A '-' between two characters is a range (e.g. a-z). Anything else is matched literally, so is
+                    // escaped if it would mean something else to the regex (e.g. '[', or '&&' for intersection).
+                    if *cc == '-' && i > 0 && i < class.len() - 1 {
+                        result.push('-');
+                    } else {
+                        result += &regex::escape(&cc.to_string());
+                    }
+                }
+                result.push(']');
+            }
+            c => result += &regex::escape(&c.to_string()),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gitignore_to_filters(text: &str) -> Result<Vec<String>, String> {
+        let mut result = vec![];
+        append_filters(text, &mut result).map(|_| result)
+    }
+
+    #[test]
+    fn test_gitignore_to_filters() {
+        let text = "\
+# Comment
+*.o
+
+build/
+/root_only
+docs/*.md
+!docs/README.md
+**/cache/**
+a/**/b
+\\#not_a_comment
+trailing   \n";
+        assert_eq!(gitignore_to_filters(text).unwrap(), vec![
+            "-(.*/)?[^/]*\\.o",
+            "-(.*/)?build/",
+            "-root_only",
+            "-docs/[^/]*\\.md",
+            "+docs/README\\.md",
+            "-(.*/)?cache/.*",
+            "-a/(.*/)?b",
+            "-(.*/)?\\#not_a_comment",
+            "-(.*/)?trailing",
+        ]);
+    }
+
+    #[test]
+    fn test_gitignore_leading_negation_ignored() {
+        assert_eq!(gitignore_to_filters("!keep\nfoo\n!bar").unwrap(), vec!["-(.*/)?foo", "+(.*/)?bar"]);
+    }
+
+    #[test]
+    fn test_gitignore_leading_negation_after_other_filters() {
+        // The negation can re-include something excluded by the filters before it
+        let mut filters = vec!["-.*\\.log".to_string()];
+        append_filters("!keep.log", &mut filters).unwrap();
+        assert_eq!(filters, vec!["-.*\\.log", "+(.*/)?keep\\.log"]);
+    }
+
+    #[test]
+    fn test_gitignore_character_classes() {
+        assert_eq!(gitignore_to_filters("file[0-9].txt").unwrap(), vec!["-(.*/)?file[0-9]\\.txt"]);
+        assert_eq!(gitignore_to_filters("file[!a].txt").unwrap(), vec!["-(.*/)?file[^a]\\.txt"]);
+        assert_eq!(gitignore_to_filters("unclosed[").unwrap(), vec!["-(.*/)?unclosed\\["]);
+        assert_eq!(gitignore_to_filters("file[_ .a-c-].txt").unwrap(), vec!["-(.*/)?file[_ \\.a-c\\-]\\.txt"]);
+        assert_eq!(gitignore_to_filters("file[a&&b~~c].txt").unwrap(), vec!["-(.*/)?file[a\\&\\&b\\~\\~c]\\.txt"]);
+        // The resulting regexes must be valid (e.g. not escaping characters that don't need it)
+        for f in gitignore_to_filters("[_ .a-c-]\n[a&&b~~c]\n[]\n[!^-]").unwrap() {
+            assert!(regex::Regex::new(&f[1..]).is_ok(), "{f}");
+        }
+    }
+
+    #[test]
+    fn test_gitignore_invalid() {
+        assert!(gitignore_to_filters("trailing\\").is_err());
+    }
+}
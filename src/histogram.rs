@@ -1,19 +1,51 @@
 use std::fmt::Display;
 use std::fmt::Write;
 
-#[derive(Default)]
+#[derive(Default, Clone, Debug)]
 pub struct FileSizeHistogram {
+    /// The boundaries between buckets, in bytes. Each bucket contains sizes from the previous edge
+    /// (inclusive) up to the next edge (exclusive), with the last bucket being unbounded.
+    /// If empty, power-of-ten buckets are used instead, which are added as needed.
+    edges: Vec<u64>,
     pub buckets: Vec<u32>,
 }
 
 impl FileSizeHistogram {
+    /// Creates an empty histogram with custom bucket boundaries (see `edges`), which must be
+    /// in ascending order.
+    pub fn with_edges(edges: Vec<u64>) -> Result<FileSizeHistogram, String> {
+        if edges.windows(2).any(|w| w[0] >= w[1]) {
+            return Err(format!("Histogram bucket edges must be in ascending order, but got {:?}", edges));
+        }
+        let buckets = if edges.is_empty() { vec![] } else { vec![0; edges.len() + 1] };
+        Ok(FileSizeHistogram { edges, buckets })
+    }
+
     pub fn add(&mut self, val: u64) {
-        let bucket = (val as f64).log10() as usize;
+        let bucket = if self.edges.is_empty() {
+            (val as f64).log10() as usize
+        } else {
+            self.edges.partition_point(|e| *e <= val)
+        };
         while self.buckets.len() <= bucket {
             self.buckets.push(0);
         }
         self.buckets[bucket] += 1;
     }
+
+    /// Gets the range of sizes covered by each bucket, as (min, max), with min being inclusive
+    /// and max being exclusive, or None if the bucket has no upper limit.
+    pub fn bucket_ranges(&self) -> Vec<(u64, Option<u64>)> {
+        (0..self.buckets.len()).map(|x| {
+            if self.edges.is_empty() {
+                let min = if x == 0 { 0 } else { 10u64.pow(x as u32) };
+                (min, 10u64.checked_pow(x as u32 + 1))
+            } else {
+                let min = if x == 0 { 0 } else { self.edges[x - 1] };
+                (min, self.edges.get(x).copied())
+            }
+        }).collect()
+    }
 }
 
 impl Display for FileSizeHistogram {
@@ -41,6 +73,11 @@ impl Display for FileSizeHistogram {
 
         let mut l = "".to_string();
         for x in 0..self.buckets.len() {
+            if !self.edges.is_empty() {
+                // Custom buckets don't have nice labels, so number them and show a key below
+                write!(&mut l, "{}", x % 10).unwrap();
+                continue;
+            }
             match x {
                 3 => l += "K",
                 6 => l += "M",
@@ -50,6 +87,46 @@ impl Display for FileSizeHistogram {
         }
         writeln!(f, "{}", l)?;
 
+        if !self.edges.is_empty() {
+            for (x, (min, max)) in self.bucket_ranges().iter().enumerate() {
+                match max {
+                    Some(max) => writeln!(f, "{x}: {min} - {max} bytes ({} files)", self.buckets[x])?,
+                    None => writeln!(f, "{x}: {min}+ bytes ({} files)", self.buckets[x])?,
+                }
+            }
+        }
+
         std::fmt::Result::Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_buckets() {
+        let mut h = FileSizeHistogram::default();
+        h.add(0);
+        h.add(5);
+        h.add(150);
+        assert_eq!(h.buckets, vec![2, 0, 1]);
+        assert_eq!(h.bucket_ranges(), vec![(0, Some(10)), (10, Some(100)), (100, Some(1000))]);
+    }
+
+    #[test]
+    fn test_custom_edges() {
+        let mut h = FileSizeHistogram::with_edges(vec![100, 1000]).unwrap();
+        h.add(0);
+        h.add(100);
+        h.add(999);
+        h.add(5000);
+        assert_eq!(h.buckets, vec![1, 2, 1]);
+        assert_eq!(h.bucket_ranges(), vec![(0, Some(100)), (100, Some(1000)), (1000, None)]);
+    }
+
+    #[test]
+    fn test_custom_edges_not_ascending() {
+        assert!(FileSizeHistogram::with_edges(vec![1000, 100]).is_err());
+    }
+}
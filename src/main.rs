@@ -3,11 +3,17 @@ use std::process::ExitCode;
 mod boss_frontend;
 mod boss_launch;
 mod boss_deploy;
+mod boss_check;
+mod boss_checkpoint;
+mod binary_delta;
 mod embedded_binaries;
 mod exe_utils;
 mod boss_sync;
+mod gitignore;
 mod ordered_map;
 mod histogram;
+mod bandwidth_limiter;
+mod file_flags;
 mod boss_progress;
 mod doer;
 mod boss_doer_interface;
@@ -28,8 +34,12 @@ fn main() -> ExitCode {
     // a transfer and then exits once complete ("boss"), or as a remote process on either the source
     // or destination computer which responds to commands from the boss (this is a "doer").
     // The boss (CLI) and doer modes have different command-line arguments, so handle them separately.
+    // There is also a special mode used when deploying to a remote target, which upgrades a
+    // previously deployed binary (see boss_deploy.rs).
     if std::env::args().any(|a| a == "--doer") {
         doer_main()
+    } else if std::env::args().nth(1).as_deref() == Some("--apply-delta") {
+        binary_delta::apply_delta_main()
     } else {
         boss_main()
     }
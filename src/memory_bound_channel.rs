@@ -92,4 +92,11 @@ pub fn select_ready<R> (r1: &Receiver<R>, r2: &Receiver<R>) -> usize {
     s.recv(&r1.inner);
     s.recv(&r2.inner);
     s.ready()
-}
\ No newline at end of file
+}
+/// Same as select_ready, but gives up after the given timeout, returning None.
+pub fn select_ready_timeout<R> (r1: &Receiver<R>, r2: &Receiver<R>, timeout: std::time::Duration) -> Option<usize> {
+    let mut s = crossbeam::channel::Select::new();
+    s.recv(&r1.inner);
+    s.recv(&r2.inner);
+    s.ready_timeout(timeout).ok()
+}
@@ -1,53 +1,402 @@
-use std::{collections::HashMap, hash::Hash};
+use std::{borrow::Cow, collections::{HashMap, hash_map::DefaultHasher}, hash::{Hash, Hasher}, cell::RefCell, fs::File, io::{Write, Read, Seek, SeekFrom}, marker::PhantomData};
+
+use log::debug;
+use serde::{Serialize, de::DeserializeOwned};
+use tempdir::TempDir;
 
 /// A map of K -> V which remembers the order in which things were added,
 /// and uses this order when iterating.
-/// Implemented simply as storing both a Vec and HashMap, and keeping these in sync.
-#[derive(Debug, Clone)]
+/// Normally implemented simply as storing both a Vec and HashMap, and keeping these in sync.
+/// If a spill threshold is set and the map grows larger than this, the entries are moved to a temporary
+/// file on disk instead (see SpilledStorage), so that huge numbers of entries don't need huge amounts of memory.
+/// Keys and values are borrowed from the map when it is in memory, but once spilled they have to be read back
+/// from the file, so are returned as Cows.
 pub struct OrderedMap<K, V> {
-    // Note that the vec doesn't store V. This means we don't have to keep V up to date here
-    // (e.g. in update()). We have to do a lookup in the map anyway when iterating, so we also
-    // fetch the V while we're there.
-    vec: Vec<K>,
-    map: HashMap<K, V>
+    storage: Storage<K, V>,
+    spill_threshold: Option<usize>,
+}
+
+enum Storage<K, V> {
+    Memory {
+        // Note that the vec doesn't store V. This means we don't have to keep V up to date here
+        // (e.g. in update()). We have to do a lookup in the map anyway when iterating, so we also
+        // fetch the V while we're there.
+        vec: Vec<K>,
+        map: HashMap<K, V>
+    },
+    Spilled(SpilledStorage<K, V>),
 }
-impl<K: Clone+ Eq + Hash, V: Clone> OrderedMap<K, V> {
+
+impl<K: Clone + Eq + Hash + Serialize + DeserializeOwned, V: Clone + Serialize + DeserializeOwned> OrderedMap<K, V> {
     pub fn new() -> OrderedMap<K, V> {
-        OrderedMap { vec: vec![], map: HashMap::new() }
+        OrderedMap { storage: Storage::Memory { vec: vec![], map: HashMap::new() }, spill_threshold: None }
     }
 
-    pub fn add(&mut self, k: K, v: V) {
-        self.vec.push(k.clone());
-        self.map.insert(k, v);
+    /// Once the map holds more than the given number of entries, they are moved to a temporary file on disk.
+    pub fn with_spill_threshold(spill_threshold: Option<usize>) -> OrderedMap<K, V> {
+        OrderedMap { spill_threshold, ..Self::new() }
+    }
+
+    pub fn add(&mut self, k: K, v: V) -> Result<(), String> {
+        match &mut self.storage {
+            Storage::Memory { vec, map } => {
+                vec.push(k.clone());
+                map.insert(k, v);
+                if self.spill_threshold.is_some_and(|t| map.len() > t) {
+                    self.spill()?;
+                }
+                Ok(())
+            }
+            Storage::Spilled(s) => s.add(k, v),
+        }
     }
 
     pub fn len(&self) -> usize {
-        // The vec len may be larger, if things have been removed, but the map len is always correct
-        self.map.len()
+        match &self.storage {
+            // The vec len may be larger, if things have been removed, but the map len is always correct
+            Storage::Memory { map, .. } => map.len(),
+            Storage::Spilled(s) => s.len,
+        }
     }
 
-    pub fn iter(&self) -> Box<dyn Iterator<Item = (&K, &V)> + '_> {
-        // Some entries may have been removed, so filter these out on the fly.
-        // Also grab the V from the map.
-        let iter = self.vec.iter().filter_map(|k| self.map.get(k).and_then(|v| Some((k, v))));
-        Box::new(iter)
+    /// Note that if the map has been spilled and reading from the file fails, the iteration stops early.
+    /// Check for this afterwards with check_read_error.
+    pub fn iter(&self) -> Box<dyn Iterator<Item = (Cow<'_, K>, Cow<'_, V>)> + '_> {
+        match &self.storage {
+            Storage::Memory { vec, map } => {
+                // Some entries may have been removed, so filter these out on the fly.
+                // Also grab the V from the map.
+                let iter = vec.iter().filter_map(|k| map.get(k).map(|v| (Cow::Borrowed(k), Cow::Borrowed(v))));
+                Box::new(iter)
+            }
+            Storage::Spilled(s) => {
+                let iter = s.order.iter().map_while(|&i| s.record_read_error(s.read_slot(i)))
+                    .flatten().map(|(k, v)| (Cow::Owned(k), Cow::Owned(v)));
+                Box::new(iter)
+            }
+        }
     }
 
-    pub fn lookup(&self, k: &K) -> Option<&V> {
-        self.map.get(k)
+    /// Note that if the map has been spilled and reading from the file fails, this returns None.
+    /// Check for this afterwards with check_read_error.
+    pub fn lookup(&self, k: &K) -> Option<Cow<'_, V>> {
+        match &self.storage {
+            Storage::Memory { map, .. } => map.get(k).map(Cow::Borrowed),
+            Storage::Spilled(s) => s.record_read_error(s.find_slot(k)).flatten().map(|(_, v)| Cow::Owned(v)),
+        }
     }
 
-    pub fn remove(&mut self, k: &K) {
-        self.map.remove(k);
-        // We don't remove from the vec, as that could be slow (shuffling data around).
-        // Instead we make sure to check when iterating that the entry hasn't been removed
+    pub fn remove(&mut self, k: &K) -> Result<(), String> {
+        match &mut self.storage {
+            Storage::Memory { map, .. } => {
+                map.remove(k);
+                // We don't remove from the vec, as that could be slow (shuffling data around).
+                // Instead we make sure to check when iterating that the entry hasn't been removed
+                Ok(())
+            }
+            Storage::Spilled(s) => s.remove(k),
+        }
     }
 
     pub fn reverse_order(&mut self) {
-        self.vec.reverse();
+        match &mut self.storage {
+            Storage::Memory { vec, .. } => vec.reverse(),
+            Storage::Spilled(s) => s.order.reverse(),
+        }
+    }
+
+    pub fn update(&mut self, k: &K, new_value: V) -> Result<(), String> {
+        match &mut self.storage {
+            Storage::Memory { map, .. } => {
+                *map.get_mut(k).unwrap() = new_value;
+                Ok(())
+            }
+            Storage::Spilled(s) => s.update(k, new_value),
+        }
+    }
+
+    /// iter() and lookup() can't return errors, so any error reading from the spill file is remembered and
+    /// returned from here instead (and from any later changes to the map). This never fails when the map is in memory.
+    pub fn check_read_error(&self) -> Result<(), String> {
+        match &self.storage {
+            Storage::Memory { .. } => Ok(()),
+            Storage::Spilled(s) => s.check_read_error(),
+        }
+    }
+
+    /// Moves all the entries from memory to a temporary file.
+    fn spill(&mut self) -> Result<(), String> {
+        let mut spilled = SpilledStorage::new()?;
+        for (k, v) in self.iter() {
+            spilled.add(k.into_owned(), v.into_owned())?;
+        }
+        debug!("Spilled {} entries to disk at '{}'", spilled.len, spilled.dir.path().display());
+        self.storage = Storage::Spilled(spilled);
+        Ok(())
+    }
+}
+
+/// Where an entry's (key, value) record is stored in the spill file, and a link to the next slot
+/// whose key has the same hash (if any).
+#[derive(Clone, Copy)]
+struct Slot {
+    /// None if this entry has been removed.
+    location: Option<(u64, u32)>,
+    next_with_same_hash: Option<u32>,
+}
+
+/// Storage for an OrderedMap which keeps the keys and values in a temporary file rather than in memory.
+/// Records are only ever appended to the file, with the in-memory index pointing at the latest record for
+/// each key. Only a small fixed amount of memory is needed for each entry (a Slot, its position in the order
+/// and the hash of its key), rather than the full key and value.
+struct SpilledStorage<K, V> {
+    /// Keeps the folder (and the file inside it) alive, and deletes it when we are dropped.
+    dir: TempDir,
+    file: RefCell<File>,
+    /// How much has been written to the file so far.
+    file_len: u64,
+    /// Records which haven't been written to the file yet, as writing each one individually would be slow.
+    /// These logically come after the end of the file.
+    pending: Vec<u8>,
+    slots: Vec<Slot>,
+    /// Indices into `slots`, in iteration order. May include removed slots, which are skipped when iterating.
+    order: Vec<u32>,
+    /// Hash of key -> index of the most recently added slot with a key with this hash.
+    /// Other slots with the same hash are found by following `next_with_same_hash`.
+    index: HashMap<u64, u32>,
+    len: usize,
+    /// The first error from reading the file when we weren't able to return it (see OrderedMap::check_read_error).
+    read_error: RefCell<Option<String>>,
+    _phantom: PhantomData<(K, V)>,
+}
+
+impl<K: Eq + Hash + Serialize + DeserializeOwned, V: Serialize + DeserializeOwned> SpilledStorage<K, V> {
+    fn new() -> Result<SpilledStorage<K, V>, String> {
+        let dir = TempDir::new("rjrssync-spill").map_err(|e| format!("Error creating temporary folder for spilled entries: {e}"))?;
+        let path = dir.path().join("entries");
+        let file = File::options().read(true).write(true).create_new(true).open(&path)
+            .map_err(|e| format!("Error creating temporary file '{}' for spilled entries: {e}", path.display()))?;
+        Ok(SpilledStorage {
+            dir,
+            file: RefCell::new(file),
+            file_len: 0,
+            pending: vec![],
+            slots: vec![],
+            order: vec![],
+            index: HashMap::new(),
+            len: 0,
+            read_error: RefCell::new(None),
+            _phantom: PhantomData,
+        })
+    }
+
+    fn hash(k: &K) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        k.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn spill_error(&self, e: impl std::fmt::Display) -> String {
+        format!("Error accessing temporary file for spilled entries in '{}': {e}", self.dir.path().display())
+    }
+
+    /// Converts an error into None, remembering it so that it can be returned later from check_read_error.
+    fn record_read_error<T>(&self, r: Result<T, String>) -> Option<T> {
+        match r {
+            Ok(x) => Some(x),
+            Err(e) => {
+                self.read_error.borrow_mut().get_or_insert(e);
+                None
+            }
+        }
+    }
+
+    fn check_read_error(&self) -> Result<(), String> {
+        match &*self.read_error.borrow() {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn write_record(&mut self, k: &K, v: &V) -> Result<(u64, u32), String> {
+        let offset = self.file_len + self.pending.len() as u64;
+        bincode::serialize_into(&mut self.pending, &(k, v)).map_err(|e| self.spill_error(e))?;
+        let len = (self.file_len + self.pending.len() as u64 - offset) as u32;
+        if self.pending.len() >= PENDING_WRITE_SIZE {
+            let file = self.file.get_mut();
+            file.seek(SeekFrom::End(0)).and_then(|_| file.write_all(&self.pending)).map_err(|e| self.spill_error(e))?;
+            self.file_len += self.pending.len() as u64;
+            self.pending.clear();
+        }
+        Ok((offset, len))
+    }
+
+    /// Reads the entry in the given slot, or None if it has been removed.
+    fn read_slot(&self, slot_idx: u32) -> Result<Option<(K, V)>, String> {
+        let (offset, len) = match self.slots[slot_idx as usize].location {
+            Some(l) => l,
+            None => return Ok(None),
+        };
+        let result = if offset >= self.file_len {
+            let start = (offset - self.file_len) as usize;
+            bincode::deserialize(&self.pending[start..start + len as usize])
+        } else {
+            let mut file = self.file.borrow_mut();
+            let mut bytes = vec![0; len as usize];
+            file.seek(SeekFrom::Start(offset)).and_then(|_| file.read_exact(&mut bytes)).map_err(|e| self.spill_error(e))?;
+            bincode::deserialize(&bytes)
+        };
+        result.map(Some).map_err(|e| self.spill_error(e))
+    }
+
+    /// Finds the slot index and value for the given key, if present.
+    fn find_slot(&self, k: &K) -> Result<Option<(u32, V)>, String> {
+        let mut next = self.index.get(&Self::hash(k)).copied();
+        while let Some(i) = next {
+            if let Some((slot_k, v)) = self.read_slot(i)? {
+                if &slot_k == k {
+                    return Ok(Some((i, v)));
+                }
+            }
+            next = self.slots[i as usize].next_with_same_hash;
+        }
+        Ok(None)
+    }
+
+    fn add(&mut self, k: K, v: V) -> Result<(), String> {
+        self.check_read_error()?;
+        // Adding an existing key replaces its previous entry
+        if let Some((i, _)) = self.find_slot(&k)? {
+            self.slots[i as usize].location = None;
+            self.len -= 1;
+        }
+        let location = self.write_record(&k, &v)?;
+        let new_idx = self.slots.len() as u32;
+        let prev = self.index.insert(Self::hash(&k), new_idx);
+        self.slots.push(Slot { location: Some(location), next_with_same_hash: prev });
+        self.order.push(new_idx);
+        self.len += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &K) -> Result<(), String> {
+        self.check_read_error()?;
+        if let Some((i, _)) = self.find_slot(k)? {
+            self.slots[i as usize].location = None;
+            self.len -= 1;
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, k: &K, new_value: V) -> Result<(), String> {
+        self.check_read_error()?;
+        let (i, _) = self.find_slot(k)?.expect("Key not found");
+        let location = self.write_record(k, &new_value)?;
+        self.slots[i as usize].location = Some(location);
+        Ok(())
+    }
+}
+
+const PENDING_WRITE_SIZE: usize = 1024 * 1024;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check_ops(spill_threshold: Option<usize>) {
+        let mut m = OrderedMap::<String, u32>::with_spill_threshold(spill_threshold);
+        for i in 0..10 {
+            m.add(format!("k{i}"), i).unwrap();
+        }
+        assert_eq!(m.len(), 10);
+        assert_eq!(m.lookup(&"k3".to_string()).as_deref(), Some(&3));
+        assert_eq!(m.lookup(&"missing".to_string()), None);
+
+        m.remove(&"k3".to_string()).unwrap();
+        m.remove(&"missing".to_string()).unwrap();
+        m.update(&"k5".to_string(), 50).unwrap();
+        assert_eq!(m.len(), 9);
+        assert_eq!(m.lookup(&"k3".to_string()), None);
+        assert_eq!(m.lookup(&"k5".to_string()).as_deref(), Some(&50));
+
+        m.reverse_order();
+        m.add("new".to_string(), 100).unwrap();
+        let expected = vec![("k9", 9), ("k8", 8), ("k7", 7), ("k6", 6), ("k5", 50), ("k4", 4), ("k2", 2), ("k1", 1), ("k0", 0), ("new", 100)];
+        assert_eq!(m.iter().map(|(k, v)| (k.into_owned(), v.into_owned())).collect::<Vec<_>>(),
+            expected.into_iter().map(|(k, v)| (k.to_string(), v)).collect::<Vec<_>>());
+        assert_eq!(m.check_read_error(), Ok(()));
+    }
+
+    #[test]
+    fn test_in_memory() {
+        check_ops(None);
+    }
+
+    #[test]
+    fn test_in_memory_borrows() {
+        let mut m = OrderedMap::<String, u32>::new();
+        m.add("k".to_string(), 1).unwrap();
+        assert!(matches!(m.lookup(&"k".to_string()), Some(Cow::Borrowed(_))));
+        assert!(m.iter().all(|(k, v)| matches!((k, v), (Cow::Borrowed(_), Cow::Borrowed(_)))));
+    }
+
+    #[test]
+    fn test_spilled() {
+        // Spills part way through adding the entries
+        check_ops(Some(4));
+    }
+
+    #[test]
+    fn test_spilled_large() {
+        // Enough data that some of it gets written to the file rather than staying pending
+        let mut m = OrderedMap::<String, String>::with_spill_threshold(Some(100));
+        let value = "x".repeat(100);
+        for i in 0..20000 {
+            m.add(format!("k{i}"), value.clone()).unwrap();
+        }
+        m.update(&"k0".to_string(), "updated".to_string()).unwrap();
+        assert_eq!(m.lookup(&"k0".to_string()).as_deref(), Some(&"updated".to_string()));
+        assert_eq!(m.lookup(&"k1".to_string()).as_deref(), Some(&value));
+        assert_eq!(m.lookup(&"k19999".to_string()).as_deref(), Some(&value));
+        assert_eq!(m.iter().count(), 20000);
+        assert_eq!(*m.iter().nth(5000).unwrap().0, "k5000");
+    }
+
+    #[test]
+    fn test_spilled_read_error() {
+        let mut m = OrderedMap::<String, String>::with_spill_threshold(Some(0));
+        let value = "x".repeat(100);
+        for i in 0..20000 {
+            m.add(format!("k{i}"), value.clone()).unwrap();
+        }
+        // Truncate the file behind the map's back, so that reading the records that were written fails
+        if let Storage::Spilled(s) = &m.storage {
+            s.file.borrow().set_len(0).unwrap();
+        }
+        assert_eq!(m.lookup(&"k0".to_string()), None);
+        assert!(m.check_read_error().unwrap_err().contains("Error accessing temporary file for spilled entries"));
+        // The error is also returned from any further changes
+        assert!(m.add("new".to_string(), value).is_err());
     }
 
-    pub fn update(&mut self, k: &K, new_value: V) {
-        *self.map.get_mut(k).unwrap() = new_value;
+    #[test]
+    fn test_spilled_hash_collisions() {
+        // Force all the keys to have the same hash, to check that we find the right one
+        #[derive(Serialize, serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+        struct K(u32);
+        impl Hash for K {
+            fn hash<H: Hasher>(&self, state: &mut H) { 0.hash(state) }
+        }
+        let mut m = OrderedMap::<K, u32>::with_spill_threshold(Some(0));
+        for i in 0..5 {
+            m.add(K(i), i * 10).unwrap();
+        }
+        m.remove(&K(2)).unwrap();
+        m.update(&K(4), 400).unwrap();
+        assert_eq!(m.lookup(&K(0)).as_deref(), Some(&0));
+        assert_eq!(m.lookup(&K(2)), None);
+        assert_eq!(m.lookup(&K(3)).as_deref(), Some(&30));
+        assert_eq!(m.lookup(&K(4)).as_deref(), Some(&400));
+        assert_eq!(m.len(), 4);
     }
 }
@@ -17,7 +17,7 @@ use crate::profiling;
 pub fn parallel_walk_dir<
     T: Send + 'static,
     F: Fn(&std::fs::DirEntry) -> Result<FilterResult<T>, String> + Send + Clone + 'static
-    >(root: &Path, filter_func: F) -> Receiver<Result<Entry<T>, String>>
+    >(root: &Path, filter_func: F) -> Receiver<Result<Entry<T>, WalkError>>
 {
     // A cross-thread queue of jobs to be executed by the worker threads (a 'job' is simply a directory to enumerate).
     // When encountering a sub-directory, worker threads will add those sub-directories as new jobs to the queue,
@@ -33,7 +33,7 @@ pub fn parallel_walk_dir<
     let num_unfinished_jobs = Arc::new(AtomicUsize::new(1));
 
     // The cross-thread queue of results, sent to the caller via their Receiver.
-    let (result_sender, result_receiver) = crossbeam::channel::bounded::<Result<Entry<T>, String>>(1000);  // Bounded arbitrarily to prevent too high memory usage
+    let (result_sender, result_receiver) = crossbeam::channel::bounded::<Result<Entry<T>, WalkError>>(1000);  // Bounded arbitrarily to prevent too high memory usage
 
     // The "best" number of threads to use depends on many things. This is a bit of a heuristic!
     #[cfg(windows)]
@@ -67,6 +67,14 @@ pub struct Entry<T> {
     pub additional_data: T,
 }
 
+/// An error encountered while iterating, along with the path of the file/folder that it relates to
+/// (e.g. a folder that couldn't be read), so that the caller can choose to skip it and carry on.
+#[derive(Debug)]
+pub struct WalkError {
+    pub path: PathBuf,
+    pub message: String,
+}
+
 /// Result of checking an entry against a filter.
 /// As well as determinining whether or not the entry should be skipped, this allows
 /// additional data to be provided with the entry, to avoid having to re-calculate
@@ -83,10 +91,10 @@ enum Job {
 
 fn worker_main<T, F: Fn(&std::fs::DirEntry) -> Result<FilterResult<T>, String>>(
     job_sender: Sender<Job>, job_receiver: Receiver<Job>,
-    result_sender: Sender<Result<Entry<T>, String>>, num_unfinished_jobs: Arc<AtomicUsize>,
+    result_sender: Sender<Result<Entry<T>, WalkError>>, num_unfinished_jobs: Arc<AtomicUsize>,
     num_threads: usize, filter_func: F)
     ->
-    Result<(), SendError<Result<Entry<T>, String>>>
+    Result<(), SendError<Result<Entry<T>, WalkError>>>
 {
     // Note that errors from sending to the _result_ channel are ignored and we silently stop this thread,
     // because this simply indicates that the user has dropped their receiver and so don't care about any
@@ -101,19 +109,21 @@ fn worker_main<T, F: Fn(&std::fs::DirEntry) -> Result<FilterResult<T>, String>>(
             Job::Dir(dir) => {
                 let timer = profiling::start_timer("read_dir");
                 let iter = match std::fs::read_dir(&dir) {
-                    Ok(x) => x,
+                    Ok(x) => Some(x),
                     Err(e) => {
-                        result_sender.send(Err(format!("Error reading dir '{}': {e}", dir.display())))?;
-                        continue;
+                        // Don't `continue` here, as this job still needs to be marked as finished (below),
+                        // otherwise the walk would never finish if the caller chooses to carry on after the error.
+                        result_sender.send(Err(WalkError { message: format!("Error reading dir '{}': {e}", dir.display()), path: dir.clone() }))?;
+                        None
                     }
                 };
                 profiling::stop_timer(timer);
 
-                for entry in iter {
+                for entry in iter.into_iter().flatten() {
                     let entry = match entry {
                         Ok(x) => x,
                         Err(e) => {
-                            result_sender.send(Err(format!("Error iterating dir '{}': {e}", dir.display())))?;
+                            result_sender.send(Err(WalkError { message: format!("Error iterating dir '{}': {e}", dir.display()), path: dir.clone() }))?;
                             continue;
                         }
                     };
@@ -129,7 +139,7 @@ fn worker_main<T, F: Fn(&std::fs::DirEntry) -> Result<FilterResult<T>, String>>(
                             f.additional_data
                         },
                         Err(e) => {
-                            result_sender.send(Err(format!("Error applying filter to '{}': {e}", entry.path().display())))?;
+                            result_sender.send(Err(WalkError { message: format!("Error applying filter to '{}': {e}", entry.path().display()), path: entry.path() }))?;
                             continue;
                         }
                     };
@@ -140,7 +150,7 @@ fn worker_main<T, F: Fn(&std::fs::DirEntry) -> Result<FilterResult<T>, String>>(
                     let file_type = match entry.file_type() {
                         Ok(x) => x,
                         Err(e) => {
-                            result_sender.send(Err(format!("Error checking file type of'{}': {e}", entry.path().display())))?;
+                            result_sender.send(Err(WalkError { message: format!("Error checking file type of'{}': {e}", entry.path().display()), path: entry.path() }))?;
                             continue;
                         }
                     };
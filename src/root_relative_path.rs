@@ -26,8 +26,9 @@ impl RootRelativePath {
     }
 
     /// Gets the full path consisting of the root and this root-relative path.
+    /// The slashes are converted to the local platform's separator, so that the path is displayed consistently.
     pub fn get_full_path(&self, root: &Path) -> PathBuf {
-        if self.is_root() { root.to_path_buf() } else { root.join(&self.inner) }
+        if self.is_root() { root.to_path_buf() } else { root.join(self.to_platform_path(std::path::MAIN_SEPARATOR)) }
     }
 
     /// Rather than exposing the inner string, expose just regex matching.
@@ -42,6 +43,75 @@ impl RootRelativePath {
     pub fn to_platform_path(&self, dir_separator: char) -> String {
         self.inner.replace('/', &dir_separator.to_string())
     }
+
+    /// The opposite of to_platform_path - converts a relative path for another platform, which uses
+    /// the given dir_separator, to our normalized representation.
+    /// This can't use the TryFrom<&Path> conversion because the path might not be for the local platform.
+    pub fn from_platform_path(s: &str, dir_separator: char) -> Result<RootRelativePath, String> {
+        let mut result = String::new();
+        for c in s.split(dir_separator).filter(|c| !c.is_empty()) {
+            if c.contains('/') || c.contains('\\') {
+                // Slashes in any component would mess things up, once we change which slash is significant
+                return Err("Illegal characters in path".to_string());
+            }
+            if !result.is_empty() {
+                result += "/";
+            }
+            result += c;
+        }
+        Ok(RootRelativePath { inner: result })
+    }
+
+    /// Gets the extension of the final component of this path (without the dot), if it has one.
+    /// Follows the same rules as std::path::Path::extension, e.g. ".bashrc" has no extension.
+    pub fn extension(&self) -> Option<&str> {
+        let file_name = self.inner.rsplit('/').next().unwrap_or("");
+        match file_name.rfind('.') {
+            Some(i) if i > 0 => Some(&file_name[i + 1..]),
+            _ => None,
+        }
+    }
+
+    /// Gets this path and all its ancestors (excluding the root), starting with the top-most ancestor.
+    pub fn self_and_ancestors(&self) -> Vec<RootRelativePath> {
+        let mut result = vec![];
+        if self.is_root() {
+            return result;
+        }
+        let mut prefix = String::new();
+        for c in self.inner.split('/') {
+            if !prefix.is_empty() {
+                prefix += "/";
+            }
+            prefix += c;
+            result.push(RootRelativePath { inner: prefix.clone() });
+        }
+        result
+    }
+
+    /// Is this path the same as, or inside of, the given path? Only whole components are considered,
+    /// e.g. "a/bc" doesn't start with "a/b". Every path starts with the root.
+    pub fn starts_with(&self, other: &RootRelativePath) -> bool {
+        other.is_root() || self.inner == other.inner
+            || (self.inner.starts_with(&other.inner) && self.inner[other.inner.len()..].starts_with('/'))
+    }
+
+    /// Checks if a relative path in our normalized format (e.g. a symlink target), when resolved from the folder
+    /// containing this path, stays inside the root. This is purely lexical, so any symlinks along the way
+    /// aren't followed.
+    pub fn relative_path_stays_inside_root(&self, relative: &str) -> bool {
+        // The number of folders between the root and the folder containing this path
+        let mut depth = if self.is_root() { 0 } else { self.inner.split('/').count() - 1 };
+        for c in relative.split('/') {
+            match c {
+                "" | "." => (),
+                ".." if depth == 0 => return false,
+                ".." => depth -= 1,
+                _ => depth += 1,
+            }
+        }
+        true
+    }
 }
 impl Display for RootRelativePath {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -156,4 +226,65 @@ mod tests {
     fn test_normalize_path_multiple_components() {
         assert_eq!(RootRelativePath::try_from(Path::new("one/two/three")), Ok(RootRelativePath { inner: "one/two/three".to_string() }));
     }
+
+    #[test]
+    fn test_from_platform_path() {
+        assert_eq!(RootRelativePath::from_platform_path("one\\two\\three", '\\'), Ok(RootRelativePath { inner: "one/two/three".to_string() }));
+        assert_eq!(RootRelativePath::from_platform_path("one/two/", '/'), Ok(RootRelativePath { inner: "one/two".to_string() }));
+        assert_eq!(RootRelativePath::from_platform_path("", '/'), Ok(RootRelativePath::root()));
+        assert_eq!(RootRelativePath::from_platform_path("one/two", '\\'), Err("Illegal characters in path".to_string()));
+    }
+
+    #[test]
+    fn test_get_full_path() {
+        let sep = std::path::MAIN_SEPARATOR;
+        assert_eq!(RootRelativePath::root().get_full_path(Path::new("root")), PathBuf::from("root"));
+        let full_path = RootRelativePath::from_platform_path("a/b", '/').unwrap().get_full_path(Path::new("root"));
+        assert_eq!(full_path.to_str(), Some(format!("root{sep}a{sep}b").as_str()));
+    }
+
+    #[test]
+    fn test_extension() {
+        assert_eq!(RootRelativePath::root().extension(), None);
+        assert_eq!(RootRelativePath::from_platform_path("a.b/c", '/').unwrap().extension(), None);
+        assert_eq!(RootRelativePath::from_platform_path("a/c.tar.gz", '/').unwrap().extension(), Some("gz"));
+        assert_eq!(RootRelativePath::from_platform_path("a/.bashrc", '/').unwrap().extension(), None);
+        assert_eq!(RootRelativePath::from_platform_path("a/b.", '/').unwrap().extension(), Some(""));
+    }
+
+    #[test]
+    fn test_self_and_ancestors() {
+        assert_eq!(RootRelativePath::root().self_and_ancestors(), vec![]);
+        assert_eq!(RootRelativePath::from_platform_path("a/b/c", '/').unwrap().self_and_ancestors(), vec![
+            RootRelativePath { inner: "a".to_string() },
+            RootRelativePath { inner: "a/b".to_string() },
+            RootRelativePath { inner: "a/b/c".to_string() },
+        ]);
+    }
+
+    #[test]
+    fn test_starts_with() {
+        let p = |s: &str| RootRelativePath::try_from(Path::new(s)).unwrap();
+        assert!(p("a/b/c").starts_with(&p("a/b")));
+        assert!(p("a/b").starts_with(&p("a/b")));
+        assert!(p("a/b").starts_with(&RootRelativePath::root()));
+        assert!(RootRelativePath::root().starts_with(&RootRelativePath::root()));
+        assert!(!p("a/bc").starts_with(&p("a/b")));
+        assert!(!p("a").starts_with(&p("a/b")));
+        assert!(!RootRelativePath::root().starts_with(&p("a")));
+    }
+
+    #[test]
+    fn test_relative_path_stays_inside_root() {
+        let p = |s: &str| RootRelativePath::try_from(Path::new(s)).unwrap();
+        assert!(p("link").relative_path_stays_inside_root("target"));
+        assert!(p("link").relative_path_stays_inside_root("./folder/target"));
+        assert!(!p("link").relative_path_stays_inside_root("../target"));
+        assert!(p("a/b/link").relative_path_stays_inside_root("../../target"));
+        assert!(!p("a/b/link").relative_path_stays_inside_root("../../../target"));
+        // Going back inside the root doesn't help, once it's gone outside
+        assert!(!p("a/link").relative_path_stays_inside_root("../../a/target"));
+        assert!(p("a/link").relative_path_stays_inside_root("b/../../target"));
+        assert!(!p("a/link").relative_path_stays_inside_root("b/../../../target"));
+    }
 }
\ No newline at end of file
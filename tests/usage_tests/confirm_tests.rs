@@ -0,0 +1,111 @@
+use std::time::SystemTime;
+
+use regex::Regex;
+
+use crate::{folder, test_framework::{run, TestDesc, NumActions}};
+use map_macro::map;
+use crate::filesystem_node::*;
+
+/// With --confirm, a single summary of the plan is shown before making any changes. Here we choose to see the
+/// full plan first, and then proceed.
+#[test]
+fn show_plan_then_proceed() {
+    let src = folder! {
+        "new" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "folder" => empty_folder(),
+    };
+    let dest = folder! {
+        "old" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--confirm".to_string(),
+        ],
+        prompt_responses: vec![
+            String::from("1:Will copy.*:Show plan"),
+            String::from("1:Will copy.*:Proceed"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (2, Regex::new(&regex::escape(
+                "Will copy 1 file(s) totalling 8 B, create 1 folder(s) and copy 0 symlink(s), and delete 1 entry(s) totalling 12 B. Proceed?"
+            )).unwrap()),
+            (1, Regex::new(r"Will delete \(not on source\) dest file .*old").unwrap()),
+            (1, Regex::new(r"Will copy \(8 B, not on dest\) source file .*new' => dest file .*new").unwrap()),
+            (1, Regex::new(r"Will create \(not on dest\) dest folder .*folder").unwrap()),
+            (1, Regex::new(&regex::escape("Deleted 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&src)), // Synced
+        ],
+        ..Default::default()
+    });
+}
+
+/// With --confirm, cancelling at the summary prompt means that nothing is changed.
+#[test]
+fn cancel() {
+    let src = folder! {
+        "new" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "old" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--confirm".to_string(),
+        ],
+        prompt_responses: vec![
+            String::from("1:Will copy.*:Cancel sync"),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Sync cancelled at the confirmation prompt")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&dest)), // Unchanged
+        ],
+        ..Default::default()
+    });
+}
+
+/// With --confirm, there is no prompt if there is nothing to do.
+#[test]
+fn nothing_to_do() {
+    let src = folder! {
+        "same" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--confirm".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: NumActions::default().into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&src)), // Unchanged
+        ],
+        ..Default::default()
+    });
+}
@@ -1,8 +1,8 @@
-use std::time::{SystemTime};
+use std::time::{SystemTime, Duration};
 
 use regex::Regex;
 
-use crate::{folder, test_framework::{run, TestDesc}};
+use crate::{folder, test_framework::{run, TestDesc, NumActions}};
 use map_macro::map;
 use crate::filesystem_node::*;
 
@@ -245,3 +245,143 @@ fn delete() {
         ..Default::default()
     });
 }
+
+/// Dest entries matching --no-delete-pattern aren't deleted when they aren't on the source, but are still
+/// overwritten when they are.
+#[test]
+fn no_delete_pattern() {
+    let src = folder! {
+        "c1" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    let dest = folder! {
+        "c1" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+        "keep" => folder! {
+            "notes.txt" => file_with_modified("notes", SystemTime::UNIX_EPOCH),
+        },
+        "other.txt" => file_with_modified("other", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--no-delete-pattern".to_string(),
+            "c1".to_string(),
+            "--no-delete-pattern".to_string(),
+            "keep".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: NumActions { copied_files: 1, deleted_files: 1, ..Default::default() }.into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&folder! {
+                "c1" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+                "keep" => folder! {
+                    "notes.txt" => file_with_modified("notes", SystemTime::UNIX_EPOCH),
+                },
+            })),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Dest has two files which need deleting and two files which are newer than the source, with both set to "prompt".
+/// We choose "delete all" on the first deletion prompt, which only applies to deletions,
+/// so we should still be prompted for each of the newer files.
+#[test]
+fn prompt_delete_all_keeps_prompting_other_categories() {
+    let src = folder! {
+        "n1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "n2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "d1" => file_with_modified("contents3", SystemTime::UNIX_EPOCH),
+        "d2" => file_with_modified("contents4", SystemTime::UNIX_EPOCH),
+        "n1" => file_with_modified("contents5", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "n2" => file_with_modified("contents6", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-entry-needs-deleting=prompt".to_string(),
+            "--dest-file-newer=prompt".to_string(),
+        ],
+        prompt_responses: vec![
+            String::from("1:.*needs deleting.*:Delete (all occurences)"),
+            String::from("2:.*is newer than.*:Skip (just this occurence)"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("dest file .*d\\d' needs deleting").unwrap()),
+            (2, Regex::new("dest file .*n\\d' is newer than").unwrap()),
+            (1, Regex::new(&regex::escape("Deleted 2 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&folder! { // Both deleted, the newer files skipped
+                "n1" => file_with_modified("contents5", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+                "n2" => file_with_modified("contents6", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+            })),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Dest has two files which need deleting and two files which are newer than the source, with both set to "prompt".
+/// We choose "skip all occurences and prompt categories" on the first deletion prompt, so we shouldn't be prompted again
+/// and everything should be skipped. An explicitly-set category (--dest-file-older) shouldn't be affected.
+#[test]
+fn prompt_skip_all_categories() {
+    let src = folder! {
+        "n1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "n2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        "o1" => file_with_modified("contents7", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    let dest = folder! {
+        "d1" => file_with_modified("contents3", SystemTime::UNIX_EPOCH),
+        "d2" => file_with_modified("contents4", SystemTime::UNIX_EPOCH),
+        "n1" => file_with_modified("contents5", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "n2" => file_with_modified("contents6", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "o1" => file_with_modified("contents8", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-entry-needs-deleting=prompt".to_string(),
+            "--dest-file-newer=prompt".to_string(),
+            "--dest-file-older=overwrite".to_string(),
+        ],
+        prompt_responses: vec![
+            String::from("1:.*needs deleting.*:Skip (all occurences and prompt categories)"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("dest file .*d\\d' needs deleting").unwrap()),
+            (0, Regex::new("is newer than").unwrap()),
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()), // Just the older file
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&folder! { // Everything skipped, apart from the older file which is overwritten
+                "d1" => file_with_modified("contents3", SystemTime::UNIX_EPOCH),
+                "d2" => file_with_modified("contents4", SystemTime::UNIX_EPOCH),
+                "n1" => file_with_modified("contents5", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+                "n2" => file_with_modified("contents6", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+                "o1" => file_with_modified("contents7", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+            })),
+        ],
+        ..Default::default()
+    });
+}
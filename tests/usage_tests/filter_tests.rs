@@ -191,6 +191,239 @@ fn root_always_included() {
     });
 }
 
+/// Checks that --src-filter and --dest-filter apply only to their own side. The source includes a build
+/// folder that the common filters exclude, and the dest excludes a cache folder so that it isn't deleted.
+#[test]
+fn test_per_side_filters() {
+    let src_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "build" => folder! {
+            "out" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        },
+        "junk" => file_with_modified("contents3", SystemTime::UNIX_EPOCH),
+    };
+    let dest_folder = folder! {
+        "cache" => folder! {
+            "c" => file_with_modified("cached", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let expected_dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "build" => folder! {
+            "out" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        },
+        "cache" => folder! {
+            "c" => file_with_modified("cached", SystemTime::UNIX_EPOCH),
+        },
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "-build".to_string(),
+            "--filter".to_string(),
+            "-junk".to_string(),
+            "--src-filter".to_string(),
+            "+build".to_string(),
+            "--dest-filter".to_string(),
+            "-cache".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(2, 1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that a source folder which is excluded on the dest (so we don't know if it exists there) can still
+/// be synced when it does already exist on the dest. The dest-only file inside it is left alone.
+#[test]
+fn test_dest_filter_excluded_folder_exists() {
+    let src_folder = folder! {
+        "cache" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest_folder = folder! {
+        "cache" => folder! {
+            "c2" => file_with_modified("dest only", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let expected_dest_folder = folder! {
+        "cache" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+            "c2" => file_with_modified("dest only", SystemTime::UNIX_EPOCH),
+        },
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-filter".to_string(),
+            "-cache".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(1, 1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --gitignore reads filters in .gitignore syntax, including negation, anchoring
+/// and folder-only patterns.
+#[test]
+fn test_gitignore() {
+    let gitignore = file("*.o\nlogs/\n!keep.o\n/top_only\n");
+    let src_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "a.o" => file_with_modified("object", SystemTime::UNIX_EPOCH),
+        "keep.o" => file_with_modified("re-included", SystemTime::UNIX_EPOCH),
+        "top_only" => file_with_modified("anchored", SystemTime::UNIX_EPOCH),
+        "logs" => folder! {
+            "log" => file_with_modified("folder excluded", SystemTime::UNIX_EPOCH),
+        },
+        "sub" => folder! {
+            "b.o" => file_with_modified("object", SystemTime::UNIX_EPOCH),
+            "logs" => file_with_modified("not a folder", SystemTime::UNIX_EPOCH),
+            "top_only" => file_with_modified("not at the top", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let expected_dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "keep.o" => file_with_modified("re-included", SystemTime::UNIX_EPOCH),
+        "sub" => folder! {
+            "logs" => file_with_modified("not a folder", SystemTime::UNIX_EPOCH),
+            "top_only" => file_with_modified("not at the top", SystemTime::UNIX_EPOCH),
+        },
+    };
+
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &empty_folder()),
+            ("$TEMP/ignore", &gitignore),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--gitignore".to_string(),
+            "$TEMP/ignore".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(4, 1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that rjrssync's own bookkeeping files (with the reserved name prefix) are never synced or deleted,
+/// even when no filters are given, and that the user is warned about them in case they aren't actually ours.
+#[test]
+fn internal_entries_always_excluded() {
+    let src_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        ".rjrssync-state" => file("source state"),
+    };
+    let dest_folder = folder! {
+        ".rjrssync-lock" => file_with_modified("dest lock", SystemTime::UNIX_EPOCH),
+        ".rjrssync-partial" => folder! {
+            "chunk" => file_with_modified("partial data", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let expected_dest_folder = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        ".rjrssync-lock" => file_with_modified("dest lock", SystemTime::UNIX_EPOCH),
+        ".rjrssync-partial" => folder! {
+            "chunk" => file_with_modified("partial data", SystemTime::UNIX_EPOCH),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-entry-needs-deleting".to_string(),
+            "delete".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: [&[
+            (1, Regex::new("Ignoring source entry '.*\\.rjrssync-state' as names starting with '\\.rjrssync-' are reserved").unwrap()),
+            (1, Regex::new("Ignoring dest entry '.*\\.rjrssync-lock' as names starting with '\\.rjrssync-' are reserved").unwrap()),
+            (1, Regex::new("Ignoring dest entry '.*\\.rjrssync-partial' as names starting with '\\.rjrssync-' are reserved").unwrap()),
+            (0, Regex::new("Ignoring .*chunk").unwrap()), // The contents of internal folders aren't walked
+        ], &<NumActions as Into<Vec<(usize, Regex)>>>::into(copied_files(1))[..]].concat(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest_folder)), // Internal entries are neither copied nor deleted
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --prune-empty-dirs doesn't remove rjrssync's own folders, or the folders containing them,
+/// even though they look empty to the boss.
+#[test]
+fn internal_entries_not_pruned() {
+    let src_folder = folder! {
+        "a" => empty_folder(),
+    };
+    let dest_folder = folder! {
+        "a" => folder! {
+            ".rjrssync-partial" => empty_folder(),
+        },
+    };
+    for dry_run in [true, false] {
+        let mut args = vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--prune-empty-dirs".to_string(),
+        ];
+        if dry_run {
+            args.push("--dry-run".to_string());
+        }
+        run(TestDesc {
+            setup_filesystem_nodes: vec![
+                ("$TEMP/src", &src_folder),
+                ("$TEMP/dest", &dest_folder),
+            ],
+            args,
+            expected_exit_code: 0,
+            expected_output_messages: vec![
+                (1, Regex::new("Ignoring dest entry '.*\\.rjrssync-partial'").unwrap()),
+                (0, Regex::new("[Rr]emove").unwrap()),
+            ],
+            expected_filesystem_nodes: vec![
+                ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+                ("$TEMP/dest", Some(&dest_folder)), // Nothing is pruned
+            ],
+            ..Default::default()
+        });
+    }
+}
+
 // "Tag" these tests as they require remote platforms (GitHub Actions differentiates these)
 mod remote {
 
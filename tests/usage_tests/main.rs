@@ -16,4 +16,6 @@ mod dest_file_older_tests;
 mod files_same_time_tests;
 mod dest_entry_needs_deleting_tests;
 mod dest_root_needs_deleting_tests;
+mod confirm_tests;
+mod merge_root_tests;
 mod misc_tests;
@@ -0,0 +1,171 @@
+use std::time::{Duration, SystemTime};
+
+use regex::Regex;
+
+use crate::{folder, test_framework::{run, TestDesc}};
+use map_macro::map;
+use crate::filesystem_node::*;
+
+/// Runs rjrssync with --merge-root, expecting success and the given dest contents afterwards.
+fn run_merge_root_expect_success(src: &FilesystemNode, dest: &FilesystemNode, expected_message: &str,
+    expected_dest: &FilesystemNode)
+{
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", src),
+            ("$TEMP/dest", dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--merge-root".to_string(),
+            // Make sure that nothing is silently deleted
+            "--dest-root-needs-deleting=error".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape(expected_message)).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(src)), // Unchanged
+            ("$TEMP/dest", Some(expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// A source file is synced into an existing dest folder, keeping the folder's other contents.
+#[test]
+fn file_into_folder() {
+    let dest = folder! {
+        "existing" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run_merge_root_expect_success(&file_with_modified("src contents", SystemTime::UNIX_EPOCH), &dest, "Copied 1 file(s)",
+        &folder! {
+            "existing" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+            "src" => file_with_modified("src contents", SystemTime::UNIX_EPOCH),
+        });
+}
+
+/// A source symlink is synced into an existing dest folder, like a file.
+#[test]
+fn symlink_into_folder() {
+    let dest = folder! {
+        "existing" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run_merge_root_expect_success(&symlink_file("target"), &dest, "copied 1 symlink(s)",
+        &folder! {
+            "existing" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+            "src" => symlink_file("target"),
+        });
+}
+
+/// A source folder is synced into the folder that a dest symlink points to (as for --keep-dest-dirlink).
+#[test]
+fn folder_into_symlinked_folder() {
+    let src = folder! {
+        "file1.txt" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let dest = symlink_folder("target-folder");
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+            ("$TEMP/target-folder", &empty_folder()),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--merge-root".to_string(),
+            "--dest-root-needs-deleting=error".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&dest)), // Symlink should still be there
+            ("$TEMP/target-folder", Some(&src)), // Target should be the same as the source
+        ],
+        ..Default::default()
+    });
+}
+
+/// Compatible roots are unaffected by --merge-root: folders are merged as normal...
+#[test]
+fn folder_into_folder() {
+    let src = folder! {
+        "file1.txt" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "file2.txt" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+    };
+    run_merge_root_expect_success(&src, &dest, "Copied 1 file(s)", &src);
+}
+
+/// ...and files are updated as normal.
+#[test]
+fn file_onto_file() {
+    let src = file_with_modified("src contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+    run_merge_root_expect_success(&src, &file_with_modified("dest contents", SystemTime::UNIX_EPOCH), "Copied 1 file(s)", &src);
+}
+
+/// A source folder can't be merged into a dest file, so the dest root still needs deleting.
+#[test]
+fn folder_onto_file_still_needs_deleting() {
+    let src = folder! {
+        "file1.txt" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let dest = file_with_modified("dest contents", SystemTime::UNIX_EPOCH);
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--merge-root".to_string(),
+            "--dest-root-needs-deleting=error".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("dest root file .* needs deleting as it is incompatible with source root folder").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&dest)), // Unchanged
+        ],
+        ..Default::default()
+    });
+}
+
+/// Without --merge-root, the error for a source file and a dest folder mentions the option.
+#[test]
+fn merge_root_hint() {
+    let src = file_with_modified("src contents", SystemTime::UNIX_EPOCH);
+    let dest = folder! {
+        "existing" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-root-needs-deleting=error".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("--merge-root").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$TEMP/dest", Some(&dest)), // Unchanged
+        ],
+        ..Default::default()
+    });
+}
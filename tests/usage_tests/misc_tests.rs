@@ -15,3 +15,24 @@ fn generate_auto_complete_script() {
         ..Default::default()
     });
 }
+
+/// Checks that --check connects to the target and reports latency and throughput, without needing a SRC or DEST.
+/// An empty target means the local computer.
+#[test]
+fn check() {
+    run(TestDesc {
+        args: vec![
+            "--check=".to_string(),
+            "--check-transfer-size=100000".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("Connected in .* using transport: local thread").unwrap()),
+            (1, Regex::new("Round-trip latency: min .*ms, average .*ms").unwrap()),
+            (1, Regex::new("Upload throughput: .*/s \\(97.66 KiB in").unwrap()),
+            (1, Regex::new("Download throughput: .*/s \\(97.66 KiB in").unwrap()),
+            (1, Regex::new("Check successful!").unwrap()),
+        ],
+        ..Default::default()
+    });
+}
@@ -3,7 +3,7 @@ use std::time::SystemTime;
 use regex::Regex;
 
 use map_macro::map;
-use crate::{test_framework::{run, TestDesc, NumActions, copied_files}, folder, test_utils::{RemotePlatforms, run_process_with_live_output, RemotePlatform, self}};
+use crate::{test_framework::{run, TestDesc, NumActions, copied_files, copied_files_and_folders}, folder, test_utils::{RemotePlatforms, run_process_with_live_output, RemotePlatform, self}};
 use crate::filesystem_node::*;
 
 /// Tests that rjrssync can be launched on a remote platform, and communication is estabilished.
@@ -312,6 +312,163 @@ fn needs_deploy_ok() {
     });
 }
 
+/// Tests that with --ssh-tunnel, the remote doer only listens on loopback and we connect to it through
+/// a local port forwarded by ssh.
+#[test]
+fn ssh_tunnel() {
+    let src = file_with_modified("something to sync", SystemTime::UNIX_EPOCH);
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$REMOTE_LINUX_TEMP/dest".to_string(),
+            "--deploy=ok".to_string(),
+            "--verbose".to_string(), // So that we can check the addresses in the logs
+            "--ssh-tunnel".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: [&[
+            (1, Regex::new("Listening on Ok\\(127.0.0.1:").unwrap()),
+            (1, Regex::new("Connecting to doer over network at \\(\"127.0.0.1\"").unwrap()),
+        ], &<NumActions as Into<Vec<(usize, Regex)>>>::into(copied_files(1))[..]].concat(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that with --relay, the network connection to the remote doer goes through a doer on another host,
+/// rather than directly. We relay through the Windows remote to the Linux remote.
+#[test]
+fn relay() {
+    let (linux_user_and_host, windows_user_and_host) = {
+        let remote_platforms = RemotePlatforms::lock();
+        (remote_platforms.linux.user_and_host.clone(), remote_platforms.windows.user_and_host.clone())
+    };
+    // The relay is configured for the hostname part of the target only
+    let linux_host = linux_user_and_host.split_once('@').map_or(linux_user_and_host.as_str(), |(_, h)| h);
+    let src = file_with_modified("something to sync", SystemTime::UNIX_EPOCH);
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$REMOTE_LINUX_TEMP/dest".to_string(),
+            "--deploy=ok".to_string(),
+            "--verbose".to_string(), // So that we can check the relaying in the logs
+            format!("--relay={linux_host}={windows_user_and_host}"),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: [&[
+            (1, Regex::new("Relaying to").unwrap()),
+            (1, Regex::new("transport: .* \\(a relay").unwrap()),
+        ], &<NumActions as Into<Vec<(usize, Regex)>>>::into(copied_files(1))[..]].concat(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that with --verify-remote-binary, the remote binary is checked against the one we would deploy.
+#[test]
+fn verify_remote_binary() {
+    let src = file_with_modified("something to sync", SystemTime::UNIX_EPOCH);
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$REMOTE_LINUX_TEMP/dest".to_string(),
+            "--deploy=ok".to_string(),
+            "--verbose".to_string(), // So that we can check the verification in the logs
+            "--verify-remote-binary".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: [&[
+            (1, Regex::new("Verified .* binary on Remote dest doer").unwrap()),
+        ], &<NumActions as Into<Vec<(usize, Regex)>>>::into(copied_files(1))[..]].concat(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+            ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --relative preserves the folders from the source path on a remote dest, which might use
+/// different slashes to the source.
+#[test]
+fn relative() {
+    let subdir = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let src = folder! {
+        "deep" => folder! {
+            "subdir" => subdir.clone(),
+        },
+    };
+    for dest in ["$REMOTE_WINDOWS_TEMP/dest", "$REMOTE_LINUX_TEMP/dest"] {
+        run(TestDesc {
+            setup_filesystem_nodes: vec![
+                ("$TEMP/src", &src),
+            ],
+            args: vec![
+                "$TEMP/src/./deep/subdir".to_string(),
+                dest.to_string(),
+                "--relative".to_string(),
+                "--deploy=ok".to_string(),
+            ],
+            expected_exit_code: 0,
+            expected_output_messages: copied_files_and_folders(1, 1).into(),
+            expected_filesystem_nodes: vec![
+                ("$TEMP/src", Some(&src)), // Unchanged
+                (dest, Some(&src)), // The "deep" folder is preserved
+            ],
+            ..Default::default()
+        });
+    }
+}
+
+/// Tests that --stats shows the transport used for a remote doer, including whether file contents are compressed.
+#[test]
+fn stats_transport() {
+    let src = file_with_modified("something to sync", SystemTime::UNIX_EPOCH);
+    for (compress_arg, compression) in [(None, "not compressed"), (Some("--compress"), "compressed with deflate")] {
+        run(TestDesc {
+            setup_filesystem_nodes: vec![
+                ("$TEMP/src", &src),
+            ],
+            args: [
+                vec![
+                    "$TEMP/src".to_string(),
+                    "$REMOTE_LINUX_TEMP/dest".to_string(),
+                    "--deploy=ok".to_string(),
+                    "--stats".to_string(),
+                ],
+                compress_arg.map(|a| a.to_string()).into_iter().collect(),
+            ].concat(),
+            expected_exit_code: 0,
+            expected_output_messages: vec![
+                (1, Regex::new(&format!("Local src doer transport: local thread \\(in-process channel, not encrypted\\), file contents {compression}")).unwrap()),
+                (1, Regex::new(&format!("Remote dest transport: remote doer launched via ssh, over TCP to .* \\(encrypted with AES-128-GCM\\), file contents {compression}")).unwrap()),
+            ],
+            expected_filesystem_nodes: vec![
+                ("$TEMP/src", Some(&src)), // Unchanged
+                ("$REMOTE_LINUX_TEMP/dest", Some(&src)), // Src copied to dest
+            ],
+            ..Default::default()
+        });
+    }
+}
+
 /// Tests that the --remote-port option works.
 #[test]
 fn remote_port() {
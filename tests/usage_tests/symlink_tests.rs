@@ -332,6 +332,178 @@ fn test_file_to_symlink_folder_dest_root() {
     });
 }
 
+/// Tests that with --keep-dest-dirlink, a dest root which is a symlink to a folder is synced into,
+/// rather than being replaced.
+#[test]
+fn test_keep_dest_dirlink() {
+    let src = folder! {
+        "file1.txt" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let target = folder! {
+        "old.txt" => file_with_modified("delete me", SystemTime::UNIX_EPOCH),
+    };
+    let dest = symlink_folder("target-folder");
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+            ("$TEMP/target-folder", &target),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--keep-dest-dirlink".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Deleted 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Symlink should still be there
+            ("$TEMP/target-folder", Some(&src)), // Target should be the same as the source
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that without --keep-dest-dirlink, the error for a dest root which is a symlink to a folder
+/// mentions the option.
+#[test]
+fn test_keep_dest_dirlink_hint() {
+    let src = folder! {
+        "file1.txt" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let target = empty_folder();
+    let dest = symlink_folder("target-folder");
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+            ("$TEMP/target-folder", &target),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-root-needs-deleting=error".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("--keep-dest-dirlink").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&dest)), // Unchanged
+            ("$TEMP/target-folder", Some(&target)), // Unchanged
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --safe-links skips source symlinks whose targets point outside the source root,
+/// but still copies those that stay inside it (even if they go up a level first).
+#[test]
+fn test_safe_links_skip() {
+    let src = folder! {
+        "file.txt" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "inside" => symlink_file("file.txt"),
+        "escape" => symlink_file("../outside.txt"),
+        "sub" => folder! {
+            "up" => symlink_file("../file.txt"),
+            "escape" => symlink_file("../../outside.txt"),
+        },
+        "fake munged" => symlink_file("/rjrssync-munged/../outside.txt"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/outside.txt", &file_with_modified("secret", SystemTime::UNIX_EPOCH)),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--safe-links".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("skipped 3 because symlink points outside root")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&folder! {
+                "file.txt" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+                "inside" => symlink_file("file.txt"),
+                "sub" => folder! {
+                    "up" => symlink_file("../file.txt"),
+                },
+            })),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --safe-links=munge copies source symlinks which point outside the source root, but with their
+/// targets changed so that they don't point anywhere. Targets that look like they're already munged are
+/// munged again, as they could still point outside (here using "..").
+#[test]
+fn test_safe_links_munge() {
+    let src = folder! {
+        "inside" => symlink_file("file.txt"),
+        "escape" => symlink_file("../outside.txt"),
+        "fake munged" => symlink_file("/rjrssync-munged/../outside.txt"),
+    };
+    let expected_dest = folder! {
+        "inside" => symlink_file("file.txt"),
+        "escape" => symlink_file("/rjrssync-munged/outside.txt"),
+        "fake munged" => symlink_file("/rjrssync-munged/rjrssync-munged/outside.txt"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--safe-links=munge".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_folders_and_symlinks(0, 1, 3).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --safe-links=error stops the sync if there is a source symlink which points outside the source root.
+#[test]
+fn test_safe_links_error() {
+    let src = folder! {
+        "file.txt" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "escape" => symlink_file("../outside.txt"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--safe-links=error".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Symlink 'escape' has target '../outside.txt' which is outside the root. See --safe-links.")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", None),
+        ],
+        ..Default::default()
+    });
+}
+
 // "Tag" these tests as they require remote platforms (GitHub Actions differentiates these)
 mod remote {
 
@@ -401,6 +573,59 @@ fn test_symlink_target_slashes() {
     }
 }
 
+/// Tests that a symlink whose kind can't be determined on Unix (because its target has been removed) isn't
+/// deleted and recreated on Windows, where it was previously created with a known kind.
+#[test]
+#[cfg(unix)]
+fn test_unknown_symlink_kind_unix_to_windows_unchanged() {
+    let remote_platforms = RemotePlatforms::lock();
+    // The remote part of the test framework doesn't handle symlinks so well (see above), so use our own temp folder
+    // which persists between the two syncs
+    let remote_dest = get_unique_remote_temp_folder(&remote_platforms.windows);
+    let dest_arg = format!("{}:{}", remote_platforms.windows.user_and_host, remote_dest.folder);
+
+    // First sync while the target exists, so the symlink is created as a folder symlink
+    let src = folder! {
+        "symlink" => symlink_folder("target"),
+        "target" => empty_folder(),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            dest_arg.clone(),
+            "--deploy=ok".to_string(),  // Skip the confirmation prompt for deploying
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_folders_and_symlinks(0, 1, 1).into(),
+        ..Default::default()
+    });
+
+    // Then sync after the target has gone, so the source symlink's kind is unknown. The dest symlink
+    // should be left alone, rather than being deleted and then failing to be recreated.
+    let src = folder! {
+        "symlink" => symlink_generic("target"),
+    };
+    // The first of these syncs deletes the target folder, and then the second should have nothing to do.
+    for expected_actions in [NumActions { deleted_folders: 1, ..Default::default() }, NumActions::default()] {
+        run(TestDesc {
+            setup_filesystem_nodes: vec![
+                ("$TEMP/src", &src),
+            ],
+            args: vec![
+                "$TEMP/src".to_string(),
+                dest_arg.clone(),
+                "--deploy=ok".to_string(),
+            ],
+            expected_exit_code: 0,
+            expected_output_messages: expected_actions.into(),
+            ..Default::default()
+        });
+    }
+}
+
 /// Tests that syncing a broken/unknown symlink from Unix to Windows raises an error as expected.
 #[test]
 #[cfg(unix)]
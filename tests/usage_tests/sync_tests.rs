@@ -164,184 +164,1690 @@ fn test_spec_file() {
     });
 }
 
-/// Syncing a large file that therefore needs splitting into chunks
+/// Tests that --spec - reads the spec from stdin.
+/// The test framework doesn't support providing stdin, so this runs rjrssync directly.
 #[test]
-fn test_large_file() {
+fn test_spec_from_stdin() {
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src1 = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    save_filesystem_node_to_disk_local(&src1, &temp_folder.path().join("src1"));
+
+    let mut child = std::process::Command::new(env!("CARGO_BIN_EXE_rjrssync"))
+        .arg("--spec").arg("-")
+        .current_dir(temp_folder.path())
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn().unwrap();
+    child.stdin.take().unwrap().write_all(b"
+        syncs:
+        - src: src1/
+          dest: dest1/
+    ").unwrap();
+    let output = child.wait_with_output().unwrap();
+    println!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+
+    assert!(output.status.success());
+    assert_eq!(load_filesystem_node_from_disk_local(&temp_folder.path().join("dest1")), Some(src1));
+}
+
+/// Tests that --backup-snapshots creates a new snapshot alongside the existing one, hard-linking unchanged files to it.
+/// The name of the new snapshot depends on the current time, so this runs rjrssync directly rather than using
+/// the test framework.
+#[test]
+fn backup_snapshots() {
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "same" => file_with_modified("unchanged", SystemTime::UNIX_EPOCH),
+        "sub" => folder! {
+            "changed" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        },
+    };
+    let prev_snapshot = folder! {
+        "same" => file_with_modified("unchanged", SystemTime::UNIX_EPOCH),
+        "sub" => folder! {
+            "changed" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let snapshots = folder! {
+        "2000-01-01_000000.000" => prev_snapshot.clone(),
+        "not a snapshot" => empty_folder(),
+    };
+    save_filesystem_node_to_disk_local(&src, &temp_folder.path().join("src"));
+    save_filesystem_node_to_disk_local(&snapshots, &temp_folder.path().join("snapshots"));
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rjrssync"))
+        .arg("src").arg("snapshots").arg("--backup-snapshots")
+        .current_dir(temp_folder.path())
+        .output().unwrap();
+    let output_text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    println!("{output_text}");
+
+    assert!(output.status.success());
+    assert!(output_text.contains("Copied 1 file(s)"));
+    assert!(output_text.contains("Hard linked 1 unchanged file(s)"));
+
+    // The previous snapshot should be untouched, and the new one should be a complete copy of the source
+    let prev_path = temp_folder.path().join("snapshots").join("2000-01-01_000000.000");
+    assert_eq!(load_filesystem_node_from_disk_local(&prev_path), Some(prev_snapshot));
+    let new_snapshots: Vec<_> = std::fs::read_dir(temp_folder.path().join("snapshots")).unwrap()
+        .map(|e| e.unwrap().path())
+        .filter(|p| p != &prev_path && !p.ends_with("not a snapshot"))
+        .collect();
+    assert_eq!(new_snapshots.len(), 1);
+    assert_eq!(load_filesystem_node_from_disk_local(&new_snapshots[0]), Some(src));
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let ino = |p: &std::path::Path| std::fs::metadata(p).unwrap().ino();
+        assert_eq!(ino(&new_snapshots[0].join("same")), ino(&prev_path.join("same")));
+        assert_ne!(ino(&new_snapshots[0].join("sub/changed")), ino(&prev_path.join("sub/changed")));
+    }
+
+    // Syncing again straight away (within the same second) should make another snapshot, rather than failing
+    // because one with the same name already exists
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rjrssync"))
+        .arg("src").arg("snapshots").arg("--backup-snapshots")
+        .current_dir(temp_folder.path())
+        .output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(std::fs::read_dir(temp_folder.path().join("snapshots")).unwrap().count(), 4);
+}
+
+/// Updating the contents of an existing dest file keeps the dest file's permissions, rather than
+/// replacing them with those of the source file (or the defaults for a new file).
+/// The permissions need setting up before running rjrssync, so the filesystem is set up here rather than by the test framework.
+#[cfg(unix)]
+#[test]
+fn update_preserves_dest_permissions() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "config" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    let dest = folder! {
+        "config" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+    };
+    let src_path = temp_folder.path().join("src");
+    let dest_path = temp_folder.path().join("dest");
+    save_filesystem_node_to_disk_local(&src, &src_path);
+    save_filesystem_node_to_disk_local(&dest, &dest_path);
+    let set_mode = |p: &std::path::Path, m: u32| std::fs::set_permissions(p, std::fs::Permissions::from_mode(m)).unwrap();
+    set_mode(&src_path.join("config"), 0o644);
+    set_mode(&dest_path.join("config"), 0o600);
+
+    run(TestDesc {
+        args: vec![
+            src_path.to_string_lossy().to_string(),
+            dest_path.to_string_lossy().to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files(1).into(),
+        expected_filesystem_nodes: vec![
+            (&dest_path.to_string_lossy(), Some(&src)),
+        ],
+        ..Default::default()
+    });
+
+    let mode = std::fs::metadata(dest_path.join("config")).unwrap().permissions().mode() & 0o777;
+    assert_eq!(mode, 0o600);
+}
+
+/// When a folder can't be created on the dest (here due to permissions), a single error is reported for that folder
+/// rather than one for every entry inside it too.
+#[cfg(unix)]
+#[test]
+fn folder_creation_failure_reported_once() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "locked" => folder! {
+            "new" => folder! {
+                "c1" => file("contents1"),
+                "c2" => file("contents2"),
+                "sub" => folder! {
+                    "c3" => file("contents3"),
+                },
+            },
+        },
+    };
+    let dest = folder! {
+        "locked" => empty_folder(),
+    };
+    save_filesystem_node_to_disk_local(&src, &temp_folder.path().join("src"));
+    save_filesystem_node_to_disk_local(&dest, &temp_folder.path().join("dest"));
+    let locked = temp_folder.path().join("dest").join("locked");
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o555)).unwrap();
+    // Permissions aren't enforced for some users (e.g. root), in which case there's nothing to test
+    if std::fs::create_dir(locked.join("probe")).is_ok() {
+        println!("Permissions not enforced - skipping test");
+        return;
+    }
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rjrssync"))
+        .arg("src").arg("dest")
+        .current_dir(temp_folder.path())
+        .output().unwrap();
+    std::fs::set_permissions(&locked, std::fs::Permissions::from_mode(0o755)).unwrap(); // So that it can be cleaned up
+    let output_text = format!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    println!("{output_text}");
+
+    assert!(!output.status.success());
+    assert_eq!(output_text.matches("Error creating folder").count(), 1);
+    assert!(output_text.contains("new':"));
+    assert!(!output_text.contains("c1") && !output_text.contains("c3") && !output_text.contains("sub"));
+    // The entries inside weren't created, so mustn't be reported as copied
+    assert!(!output_text.contains("Copied") && !output_text.contains("copied"));
+    assert_eq!(load_filesystem_node_from_disk_local(&temp_folder.path().join("dest")), Some(dest));
+}
+
+/// Source entries that can't be read (here due to permissions) fail the sync by default, but with --ignore-read-errors
+/// they are skipped and listed at the end, distinguishing folders that can't be listed from files that can't be read.
+/// Anything on the dest at those paths is left alone.
+/// The permissions need setting up before running rjrssync, so the filesystem is set up here rather than by the test framework.
+#[cfg(unix)]
+#[test]
+fn ignore_read_errors() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "locked_folder" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        },
+        "locked_file" => file_with_modified("secret", SystemTime::UNIX_EPOCH),
+        "ok" => file_with_modified("fine", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "locked_folder" => folder! {
+            "old" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let src_path = temp_folder.path().join("src");
+    let dest_path = temp_folder.path().join("dest");
+    save_filesystem_node_to_disk_local(&src, &src_path);
+    save_filesystem_node_to_disk_local(&dest, &dest_path);
+    let locked_folder = src_path.join("locked_folder");
+    let locked_file = src_path.join("locked_file");
+    // So that the temp folder can be cleaned up, even if the test fails
+    let _cleanup = Cleanup(|| std::fs::set_permissions(&locked_folder, std::fs::Permissions::from_mode(0o755)).unwrap());
+    std::fs::set_permissions(&locked_folder, std::fs::Permissions::from_mode(0o000)).unwrap();
+    std::fs::set_permissions(&locked_file, std::fs::Permissions::from_mode(0o000)).unwrap();
+    // Permissions aren't enforced for some users (e.g. root), in which case there's nothing to test
+    if std::fs::read_dir(&locked_folder).is_ok() {
+        println!("Permissions not enforced - skipping test");
+        return;
+    }
+
+    // Without the flag, the sync fails and nothing is changed
+    run(TestDesc {
+        args: vec![
+            src_path.to_string_lossy().to_string(),
+            dest_path.to_string_lossy().to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_filesystem_nodes: vec![
+            (&dest_path.to_string_lossy(), Some(&dest)),
+        ],
+        ..Default::default()
+    });
+
+    // With the flag, the unreadable entries are skipped and reported, and everything else is synced
+    let expected_dest = folder! {
+        "locked_folder" => folder! {
+            "old" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+        },
+        "ok" => file_with_modified("fine", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        args: vec![
+            src_path.to_string_lossy().to_string(),
+            dest_path.to_string_lossy().to_string(),
+            "--ignore-read-errors".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("1 whose details couldn't be read and 1 file(s) whose contents couldn't be read")).unwrap()),
+            (1, Regex::new("Couldn't read details of .*locked_folder").unwrap()),
+            (1, Regex::new("Couldn't read contents of .*locked_file").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&dest_path.to_string_lossy(), Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Runs the given function when dropped, to undo setup that would stop a test's temporary folder from being
+/// cleaned up (e.g. immutable files), even if the test fails.
+#[cfg(unix)]
+struct Cleanup<F: FnMut()>(F);
+#[cfg(unix)]
+impl<F: FnMut()> Drop for Cleanup<F> {
+    fn drop(&mut self) {
+        (self.0)();
+    }
+}
+
+/// Runs chattr with the given arguments on the given path, returning false if it fails (e.g. not supported).
+#[cfg(target_os = "linux")]
+fn chattr(args: &str, path: &std::path::Path) -> bool {
+    std::process::Command::new("chattr").arg(args).arg(path).output().is_ok_and(|o| o.status.success())
+}
+
+/// Gets the flags of the given path, as listed by lsattr.
+#[cfg(target_os = "linux")]
+fn lsattr(path: &std::path::Path) -> String {
+    let output = std::process::Command::new("lsattr").arg("-d").arg(path).output().unwrap();
+    String::from_utf8_lossy(&output.stdout).split_whitespace().next().unwrap().to_string()
+}
+
+/// --flags transfers file flags, updating just the flags of files which are otherwise up-to-date, and clearing
+/// the immutable flag from dest files that need overwriting. Flags are set using chattr, so this is Linux-only,
+/// and is skipped if flags aren't supported (e.g. by the filesystem, or for the immutable flag, if not root).
+/// The flags need setting up before running rjrssync, so the filesystem is set up here rather than by the test framework.
+#[cfg(target_os = "linux")]
+#[test]
+fn flags() {
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "new" => file_with_modified("new", SystemTime::UNIX_EPOCH),
+        "flags only" => file_with_modified("src contents", SystemTime::UNIX_EPOCH),
+        "was immutable" => file_with_modified("updated", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    let dest = folder! {
+        "flags only" => file_with_modified("dest contents", SystemTime::UNIX_EPOCH),
+        "was immutable" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+        "deleted immutable" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+    };
+    let src_path = temp_folder.path().join("src");
+    let dest_path = temp_folder.path().join("dest");
+    save_filesystem_node_to_disk_local(&src, &src_path);
+    save_filesystem_node_to_disk_local(&dest, &dest_path);
+
+    // In case the test fails before the immutable files are updated/deleted
+    let _cleanup = Cleanup(|| {
+        chattr("-i", &dest_path.join("was immutable"));
+        chattr("-i", &dest_path.join("deleted immutable"));
+    });
+    if !chattr("+d", &src_path.join("new")) || !chattr("+i", &dest_path.join("was immutable")) {
+        println!("File flags not supported - skipping test");
+        return;
+    }
+    assert!(chattr("+d", &src_path.join("flags only")));
+    assert!(chattr("+i", &dest_path.join("deleted immutable")));
+
+    // The file with just different flags isn't copied
+    let expected_dest = folder! {
+        "new" => file_with_modified("new", SystemTime::UNIX_EPOCH),
+        "flags only" => file_with_modified("dest contents", SystemTime::UNIX_EPOCH),
+        "was immutable" => file_with_modified("updated", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    run(TestDesc {
+        args: vec![
+            src_path.to_string_lossy().to_string(),
+            dest_path.to_string_lossy().to_string(),
+            "--flags".to_string(),
+            "--dest-entry-needs-deleting=delete".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 2 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Updated flags of 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&dest_path.to_string_lossy(), Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+
+    let new_flags = lsattr(&dest_path.join("new"));
+    let was_immutable_flags = lsattr(&dest_path.join("was immutable"));
+    assert!(new_flags.contains('d') && !new_flags.contains('i'));
+    assert!(lsattr(&dest_path.join("flags only")).contains('d'));
+    assert!(!was_immutable_flags.contains('i') && !was_immutable_flags.contains('d'));
+}
+
+/// With --backup-snapshots, a file is only hard-linked to the previous snapshot if its flags are the same too
+/// (see flags()), and the flags of hard-linked files aren't set again, as that would change the previous snapshot too.
+/// The name of the new snapshot depends on the current time, so it is found after running rjrssync.
+#[cfg(target_os = "linux")]
+#[test]
+fn flags_backup_snapshots() {
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "same flags" => file_with_modified("same", SystemTime::UNIX_EPOCH),
+        "different flags" => file_with_modified("different", SystemTime::UNIX_EPOCH),
+    };
+    let snapshots = folder! {
+        "2000-01-01_000000.000" => src.clone(),
+    };
+    let src_path = temp_folder.path().join("src");
+    let snapshots_path = temp_folder.path().join("snapshots");
+    let prev_path = snapshots_path.join("2000-01-01_000000.000");
+    save_filesystem_node_to_disk_local(&src, &src_path);
+    save_filesystem_node_to_disk_local(&snapshots, &snapshots_path);
+    if !chattr("+d", &src_path.join("same flags")) {
+        println!("File flags not supported - skipping test");
+        return;
+    }
+    assert!(chattr("+d", &prev_path.join("same flags")));
+    assert!(chattr("+d", &prev_path.join("different flags")));
+
+    run(TestDesc {
+        args: vec![
+            src_path.to_string_lossy().to_string(),
+            snapshots_path.to_string_lossy().to_string(),
+            "--backup-snapshots".to_string(),
+            "--flags".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Hard linked 1 unchanged file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&prev_path.to_string_lossy(), Some(&src)),
+        ],
+        ..Default::default()
+    });
+
+    let new_path = std::fs::read_dir(&snapshots_path).unwrap().map(|e| e.unwrap().path())
+        .find(|p| p != &prev_path).unwrap();
+    assert_eq!(load_filesystem_node_from_disk_local(&new_path), Some(src));
+    assert!(lsattr(&new_path.join("same flags")).contains('d'));
+    assert!(!lsattr(&new_path.join("different flags")).contains('d'));
+    // The previous snapshot keeps its flags
+    assert!(lsattr(&prev_path.join("same flags")).contains('d'));
+    assert!(lsattr(&prev_path.join("different flags")).contains('d'));
+}
+
+/// --reverse syncs from the second path to the first.
+#[test]
+fn reverse() {
+    let src = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "--reverse".to_string(),
+            "$TEMP/dest".to_string(),
+            "$TEMP/src".to_string(),
+            "--dest-entry-needs-deleting=delete".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("Copied 1 file").unwrap()),
+            (1, Regex::new("Deleted 1 file").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source unchanged
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// --fsync doesn't change the result of the sync (we can't easily check that it's actually on disk).
+/// The file is large enough to be sent in several chunks, to check that it's flushed once it's finished.
+/// Relative paths are used so that the folder containing the dest root is the current folder.
+#[test]
+fn fsync() {
+    let src = folder! {
+        "big" => file_with_modified(&"x".repeat(5 * 1024 * 1024), SystemTime::UNIX_EPOCH),
+        "folder" => folder! {
+            "small" => file_with_modified("small", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest = folder! {
+        "old" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "src".to_string(),
+            "dest".to_string(),
+            "--fsync".to_string(),
+            "--dest-entry-needs-deleting=delete".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 2 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Deleted 1 file(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// --resume with no checkpoint file does a normal sync, and the checkpoint file is removed once the sync is done.
+#[test]
+fn resume_without_checkpoint() {
+    let src = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--checkpoint".to_string(),
+            "$TEMP/checkpoint".to_string(),
+            "--resume".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(2, 1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&src)),
+            ("$TEMP/checkpoint", None),
+        ],
+        ..Default::default()
+    });
+}
+
+/// A sync which fails partway through can be resumed using the plan recorded in the checkpoint file,
+/// without querying the source again (so new source files aren't noticed).
+/// The first sync is stopped just before creating a folder, using a test hook in the doer (which is only in debug builds).
+/// The checkpoint can be a little behind what was actually done, so this also creates that folder before resuming,
+/// to check that this is allowed.
+/// The same checkpoint file is used for both syncs, so the filesystem is set up here rather than by the test framework.
+#[cfg(debug_assertions)]
+#[test]
+fn resume_from_checkpoint() {
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "a" => folder! {
+            "b" => folder! {
+                "c" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+            },
+        },
+    };
+    let dest = folder! {
+        "old" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+    };
+    let src_path = temp_folder.path().join("src");
+    let dest_path = temp_folder.path().join("dest");
+    let checkpoint_path = temp_folder.path().join("checkpoint");
+    save_filesystem_node_to_disk_local(&src, &src_path);
+    save_filesystem_node_to_disk_local(&dest, &dest_path);
+    let args = vec![
+        src_path.to_string_lossy().to_string(),
+        dest_path.to_string_lossy().to_string(),
+        "--checkpoint".to_string(),
+        checkpoint_path.to_string_lossy().to_string(),
+        "--resume".to_string(), // Nothing to resume for the first sync, so this is a normal sync
+        "--dest-entry-needs-deleting=delete".to_string(),
+    ];
+
+    // Everything before creating 'a/b' gets done
+    run(TestDesc {
+        args: args.clone(),
+        env_vars: vec![("RJRSSYNC_TEST_STOP_BEFORE_FOLDER", "a/b")],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("Lost communication with Local dest doer").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            (&dest_path.to_string_lossy(), Some(&folder! {
+                "a" => empty_folder(),
+            })),
+        ],
+        ..Default::default()
+    });
+    assert!(checkpoint_path.exists());
+
+    std::fs::create_dir(dest_path.join("a").join("b")).unwrap();
+    std::fs::write(src_path.join("new"), "new").unwrap();
+    run(TestDesc {
+        args,
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("Resuming from checkpoint").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            // Everything from the original plan has been done, but the new file wasn't part of the plan
+            (&dest_path.to_string_lossy(), Some(&src)),
+            (&checkpoint_path.to_string_lossy(), None),
+        ],
+        ..Default::default()
+    });
+}
+
+/// --bwlimit-down and --bwlimit-up each slow down their leg of the transfer, so syncing a file that's
+/// twice the size of each limit should take at least two seconds. The legs run at the same time, so it
+/// shouldn't take much longer than that (the dest is only behind the source by one chunk of the file).
+#[test]
+fn bwlimit() {
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "file" => file(&"x".repeat(40_000_000)),
+    };
+    save_filesystem_node_to_disk_local(&src, &temp_folder.path().join("src"));
+
+    let start = std::time::Instant::now();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rjrssync"))
+        .arg("src").arg("dest").arg("--bwlimit-down=20000000").arg("--bwlimit-up=20000000")
+        .current_dir(temp_folder.path())
+        .output().unwrap();
+    let elapsed = start.elapsed();
+    println!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+
+    assert!(output.status.success());
+    assert!(elapsed >= Duration::from_secs(2), "Took {elapsed:?}");
+    assert!(elapsed < Duration::from_millis(3500), "Took {elapsed:?}");
+    assert_eq!(load_filesystem_node_from_disk_local(&temp_folder.path().join("dest")), Some(src));
+}
+
+/// With a tiny --max-entries-in-memory, all the lists of entries are moved to disk part way through querying.
+/// The sync should still make exactly the same decisions, including ones that are changed after seeing
+/// the entry on the other side (e.g. an incompatible entry, or one that turns out to be up to date).
+#[test]
+fn max_entries_in_memory() {
+    let t0 = SystemTime::UNIX_EPOCH;
+    let t1 = SystemTime::UNIX_EPOCH + Duration::from_secs(1);
     let src_folder = folder! {
-        "file" => file(&"so much big!".repeat(1000*1000*10)), // Roughly 100MB
+        "new" => file_with_modified("new", t0),
+        "updated" => file_with_modified("updated", t1),
+        "same" => file_with_modified("same", t0),
+        "folder" => folder! {
+            "nested" => file_with_modified("nested", t0),
+        },
+        "incompatible" => file_with_modified("now a file", t0),
     };
-    run_expect_success(&src_folder, &empty_folder(), copied_files(1));
+    let dest_folder = folder! {
+        "updated" => file_with_modified("old", t0),
+        "same" => file_with_modified("same", t0),
+        "old" => file_with_modified("old", t0),
+        "incompatible" => folder! {
+            "inside" => file_with_modified("inside", t0),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--max-entries-in-memory=1".to_string(),
+            "--dest-entry-needs-deleting=delete".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: NumActions {
+            copied_files: 4, created_folders: 1, deleted_files: 2, deleted_folders: 1, ..Default::default()
+        }.into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&src_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Syncing a large file that therefore needs splitting into chunks
+#[test]
+fn test_large_file() {
+    let src_folder = folder! {
+        "file" => file(&"so much big!".repeat(1000*1000*10)), // Roughly 100MB
+    };
+    run_expect_success(&src_folder, &empty_folder(), copied_files(1));
+}
+
+/// Zero-byte files (e.g. marker/placeholder files) and empty folders are replicated exactly,
+/// including when nested inside other folders.
+#[test]
+fn zero_byte_files() {
+    let src_folder = folder! {
+        "marker" => file_with_modified("", SystemTime::UNIX_EPOCH),
+        "empty folder" => empty_folder(),
+        "nested" => folder! {
+            "placeholder" => file_with_modified("", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+            "nested empty folder" => empty_folder(),
+            ".keep" => file_with_modified("", SystemTime::UNIX_EPOCH),
+        },
+    };
+    run_expect_success(&src_folder, &empty_folder(), copied_files_and_folders(3, 3));
+}
+
+/// Files which change to or from being empty are updated correctly (there's no data to send for an empty file,
+/// so this checks that the chunking doesn't get confused and that the size check doesn't misfire).
+#[test]
+fn zero_byte_file_updates() {
+    let src_folder = folder! {
+        "now empty" => file_with_modified("", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "now not empty" => file_with_modified("contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "still empty" => file_with_modified("", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+    };
+    let dest_folder = folder! {
+        "now empty" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "now not empty" => file_with_modified("", SystemTime::UNIX_EPOCH),
+        "still empty" => file_with_modified("", SystemTime::UNIX_EPOCH),
+    };
+    run_expect_success(&src_folder, &dest_folder, copied_files(3));
+}
+
+/// As zero_byte_file_updates, but where the dest files are newer or have the same modified time, so are only
+/// overwritten because of --dest-file-newer and --files-same-time. The sizes should play no part in this.
+#[test]
+fn zero_byte_file_updates_dest_newer_or_same_time() {
+    let src_folder = folder! {
+        "newer now empty" => file_with_modified("", SystemTime::UNIX_EPOCH),
+        "newer now not empty" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "same now empty" => file_with_modified("", SystemTime::UNIX_EPOCH),
+        "same now not empty" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let dest_folder = folder! {
+        "newer now empty" => file_with_modified("contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "newer now not empty" => file_with_modified("", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "same now empty" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "same now not empty" => file_with_modified("", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src_folder),
+            ("$TEMP/dest", &dest_folder),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dest-file-newer=overwrite".to_string(),
+            "--files-same-time=overwrite".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files(4).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src_folder)),
+            ("$TEMP/dest", Some(&src_folder)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// A zero-byte file can replace a non-empty file as the root, and vice versa.
+#[test]
+fn zero_byte_file_root_updates() {
+    for (src_contents, dest_contents) in [("", "contents"), ("contents", "")] {
+        let src = file_with_modified(src_contents, SystemTime::UNIX_EPOCH + Duration::from_secs(1));
+        let dest = file_with_modified(dest_contents, SystemTime::UNIX_EPOCH);
+        run(TestDesc {
+            setup_filesystem_nodes: vec![
+                ("$TEMP/src", &src),
+                ("$TEMP/dest", &dest),
+            ],
+            args: vec![
+                "$TEMP/src".to_string(),
+                "$TEMP/dest".to_string(),
+            ],
+            expected_exit_code: 0,
+            expected_output_messages: copied_files(1).into(),
+            expected_filesystem_nodes: vec![
+                ("$TEMP/dest", Some(&src)),
+            ],
+            ..Default::default()
+        });
+    }
+}
+
+/// A zero-byte file can be synced on its own (as the root), including with --compress
+/// (where an empty chunk doesn't compress well, so is sent raw).
+#[test]
+fn zero_byte_file_root() {
+    let src = file_with_modified("", SystemTime::UNIX_EPOCH);
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--compress".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files(1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that files are transferred correctly with --compress, including large files that need splitting
+/// into chunks and files which are skipped because of their extension, and that less data is actually sent.
+#[test]
+fn compress() {
+    let src = folder! {
+        "big" => file_with_modified(&"so much big!".repeat(1000*1000), SystemTime::UNIX_EPOCH),
+        "small" => file_with_modified("hello", SystemTime::UNIX_EPOCH),
+        "skipped.ZIP" => file_with_modified(&"not really a zip".repeat(1000), SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--compress".to_string(),
+            "--stats".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Copied 3 file(s) totalling 11.46 MiB")).unwrap()),
+            // The big file compresses well, so much less than that is actually sent
+            (1, Regex::new("Transferred [0-9.]+ (B|KiB) of file contents after compression").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that the --dry-run flag means that no changes are made, and that information about
+/// what _would_ happen is printed.
+#[test]
+fn dry_run() {
+    let slash = regex::escape(&std::path::MAIN_SEPARATOR.to_string());
+    let src = folder! {
+        "file" => file("contents"),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+        "symlink" => symlink_file("bob")
+    };
+    let dest = folder! {
+        "file2" => file("contents"),
+        "folder2" => folder! {
+            "c12" => file("contents1"),
+        },
+        "symlink2" => symlink_file("bob")
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dry-run".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&format!(r"Would delete dest file .*/dest{slash}folder2{slash}c12")).unwrap()),
+            (1, Regex::new(&format!(r"Would delete dest symlink .*/dest{slash}symlink2")).unwrap()),
+            (1, Regex::new(&format!(r"Would delete dest folder .*/dest{slash}folder2")).unwrap()),
+            (1, Regex::new(&format!(r"Would delete dest file .*/dest{slash}file2")).unwrap()),
+            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}file' => dest file .*/dest{slash}file")).unwrap()),
+            (1, Regex::new(&format!(r"Would create dest folder .*/dest{slash}folder")).unwrap()),
+            (1, Regex::new(&format!(r"Would create dest symlink .*/dest{slash}symlink")).unwrap()),
+            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}folder{slash}c1' => dest file .*/dest{slash}folder{slash}c1")).unwrap()),
+            (1, Regex::new(&regex::escape("Would delete 2 file(s) totalling 17B, 1 folder(s) and 1 symlink(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Would copy 2 file(s) totalling 17B, would create 1 folder(s) and would copy 1 symlink(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Dest should be unchanged too
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that the --dry-run flag means that no changes are made, and that information about
+/// what _would_ happen is printed. Also checks when dest ancestor folders are missing, that
+/// they are not created.
+#[test]
+fn dry_run_root_ancestors() {
+    let slash = regex::escape(&std::path::MAIN_SEPARATOR.to_string());
+    let src = folder! {
+        "file" => file("contents"),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+        "symlink" => symlink_file("bob")
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            // Place the dest inside some non-existent folders, to check that root ancestors are not
+            // created in dry-run mode
+            "$TEMP/dest1/dest2/dest3/dest".to_string(),
+            "--dry-run".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&format!(r"Would create dest root folder .*/dest1/dest2/dest3/dest")).unwrap()),
+            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}file' => dest file .*/dest{slash}file")).unwrap()),
+            (1, Regex::new(&format!(r"Would create dest folder .*/dest{slash}folder")).unwrap()),
+            (1, Regex::new(&format!(r"Would create dest symlink .*/dest{slash}symlink")).unwrap()),
+            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}folder{slash}c1' => dest file .*/dest{slash}folder{slash}c1")).unwrap()),
+            (1, Regex::new(&regex::escape("Would copy 2 file(s) totalling 17B, would create 2 folder(s) and would copy 1 symlink(s)")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest1", None), // Dest should be unchanged, with no ancestors created
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --dry-run=detailed also shows the size of each file and the reason for each copy/delete.
+#[test]
+fn dry_run_detailed() {
+    let slash = regex::escape(std::path::MAIN_SEPARATOR_STR);
+    let src = folder! {
+        "new" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "updated" => file_with_modified("new contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "folder" => empty_folder(),
+        "type" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "updated" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+        "old" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+        "type" => empty_folder(),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--dry-run=detailed".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&format!(r"Would copy \(8 B, not on dest\) source file .*{slash}new' => dest file .*/dest{slash}new")).unwrap()),
+            (1, Regex::new(&format!(r"Would copy \(12 B, dest older\) source file .*{slash}updated' => dest file .*/dest{slash}updated")).unwrap()),
+            (1, Regex::new(&format!(r"Would create \(not on dest\) dest folder .*/dest{slash}folder")).unwrap()),
+            (1, Regex::new(&format!(r"Would delete \(12 B, not on source\) dest file .*/dest{slash}old")).unwrap()),
+            (1, Regex::new(&format!(r"Would delete \(different type on source\) dest folder .*/dest{slash}type")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Dest should be unchanged too
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --diff reports the differences between the source and dest, without making any changes.
+#[test]
+fn diff() {
+    let slash = regex::escape(std::path::MAIN_SEPARATOR_STR);
+    let src = folder! {
+        "same" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "newer" => file_with_modified("contents", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "type" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "src only" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest = folder! {
+        "same" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "newer" => file_with_modified("old contents", SystemTime::UNIX_EPOCH),
+        "type" => empty_folder(),
+        "dest only" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--diff".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&format!(r"Only on source: source folder .*/src{slash}src only'")).unwrap()),
+            (1, Regex::new(&format!(r"Only on source: source file .*/src{slash}src only{slash}c1'")).unwrap()),
+            (1, Regex::new(&format!(r"Only on dest: dest file .*/dest{slash}dest only'")).unwrap()),
+            (1, Regex::new(&format!(r"Different \(dest is older\): source file .*/src{slash}newer'")).unwrap()),
+            (1, Regex::new(&format!(r"Different \(different types\): source file .*/src{slash}type'")).unwrap()),
+            (0, Regex::new("same'").unwrap()),
+            // The identical entries are the "same" file and the root folder
+            (1, Regex::new(&regex::escape("2 only on source, 1 only on dest, 2 different, 2 identical")).unwrap()),
+            (0, Regex::new("Copied|Deleted|Nothing to do").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Dest should be unchanged too
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks what happens when a file's size changes between the querying phase and the actual sync.
+#[test]
+fn file_size_change_during_sync() {
+    let src_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    // Make the file quite large, so that it takes a little while to copy it, giving the background
+    // thread plenty of time to update it (to minimize race conditions)
+    src_file.as_file().write_all(&"so much big!".as_bytes().repeat(1000*1000)).expect("Failed to write file");
+
+    // Launch a background thread that constantly changes the file's size
+    let mut second_handle = std::fs::File::options().append(true).open(src_file.path()).expect("Failed to reopen temp file");
+    let stop_signal = Arc::new(AtomicBool::new(false));
+    let stop_signal2 = stop_signal.clone();
+    let thread = std::thread::spawn(move || {
+        while !stop_signal2.load(Ordering::Relaxed) {
+            writeln!(second_handle, "some more stuff").expect("Failed to write to temp file");
+        }
+    });
+
+    run(TestDesc {
+        args: vec![
+            src_file.path().to_string_lossy().to_string(),
+            "$TEMP/dest_file.txt".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("Size of .* changed during the sync").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            // Source file is constantly changing, so nothing we can really check here
+            // Dest file might have been changed, but it depends at what point the error is caught, so there's nothing we can really check here
+        ],
+        ..Default::default()
+    });
+
+    stop_signal.store(true, Ordering::Relaxed);
+    thread.join().expect("Failed to join thread");
+}
+
+/// Checks that --stats prints some stats
+#[test]
+fn stats() {
+    let src = folder! {
+        "file" => file("contents"),
+        "folder" => folder! {
+            "c1" => file("contents1"),
+        },
+        "symlink" => symlink_file("bob")
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--stats".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Source: 2 file(s) totalling 17B, 2 folder(s) and 1 symlink(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Dest: 0 file(s) totalling 0B, 0 folder(s) and 0 symlink(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("Local src doer transport: local thread (in-process channel, not encrypted), file contents not compressed")).unwrap()),
+            (1, Regex::new(&regex::escape("Local dest doer transport: local thread (in-process channel, not encrypted), file contents not compressed")).unwrap()),
+            (1, Regex::new("Queried in [0-9.]+s").unwrap()),
+            (1, Regex::new("Deleted .* in [0-9.]+s").unwrap()),
+            (1, Regex::new("Copied .* in [0-9.]+s \\(.*/s\\)").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)),
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that the transport shown by --stats says when file contents are compressed (see --compress).
+#[test]
+fn stats_transport_compressed() {
+    let src = file_with_modified("contents", SystemTime::UNIX_EPOCH);
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--stats".to_string(),
+            "--compress".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Local src doer transport: local thread (in-process channel, not encrypted), file contents compressed with deflate")).unwrap()),
+            (1, Regex::new(&regex::escape("Local dest doer transport: local thread (in-process channel, not encrypted), file contents compressed with deflate")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --query-timeout gives up when the doers don't send any entries for too long,
+/// reporting which doers stopped responding. The doers are stalled using a test hook which is only in debug builds.
+#[cfg(debug_assertions)]
+#[test]
+fn query_timeout() {
+    let src = folder! {
+        "file" => file("contents"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--query-timeout=1".to_string(),
+        ],
+        env_vars: vec![
+            ("RJRSSYNC_TEST_QUERY_STALL_SECS", "5"),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("Timed out querying entries: nothing received for 1 seconds from \
+                Local src doer \\(last entry received was source root entry '.*src'\\) or \
+                Local dest doer \\(last entry received was dest root entry '.*dest'\\)").unwrap()),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --histogram-out writes the source and copied file size histograms, using the buckets
+/// from --histogram-buckets, as CSV or JSON depending on the file extension.
+#[test]
+fn histogram_out() {
+    let src = folder! {
+        "small" => file_with_modified("1", SystemTime::UNIX_EPOCH),
+        "medium" => file_with_modified(&"2".repeat(50), SystemTime::UNIX_EPOCH),
+        "large" => file_with_modified(&"3".repeat(2000), SystemTime::UNIX_EPOCH),
+    };
+    // The output files are outside of the test framework's temporary folder, so that we can check them afterwards
+    let out_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    for (filename, expected) in [
+        ("hist.csv", "src,dest,histogram,min_bytes,max_bytes,count\n\
+            src,dest,source,0,10,1\nsrc,dest,source,10,1000,1\nsrc,dest,source,1000,,1\n\
+            src,dest,copied,0,10,1\nsrc,dest,copied,10,1000,1\nsrc,dest,copied,1000,,1\n"),
+        ("hist.json", "{\"copied\":[{\"count\":1,\"max_bytes\":10,\"min_bytes\":0},{\"count\":1,\"max_bytes\":1000,\"min_bytes\":10},\
+            {\"count\":1,\"max_bytes\":null,\"min_bytes\":1000}],\"dest\":\"dest\",\
+            \"source\":[{\"count\":1,\"max_bytes\":10,\"min_bytes\":0},{\"count\":1,\"max_bytes\":1000,\"min_bytes\":10},\
+            {\"count\":1,\"max_bytes\":null,\"min_bytes\":1000}],\"src\":\"src\"}\n"),
+    ] {
+        let out_path = out_folder.path().join(filename);
+        run(TestDesc {
+            setup_filesystem_nodes: vec![
+                ("$TEMP/src", &src),
+            ],
+            args: vec![
+                "src".to_string(),
+                "dest".to_string(),
+                "--histogram-buckets=10,1000".to_string(),
+                "--histogram-out".to_string(),
+                out_path.to_str().unwrap().to_string(),
+            ],
+            expected_exit_code: 0,
+            expected_output_messages: copied_files_and_folders(3, 1).into(),
+            expected_filesystem_nodes: vec![
+                ("$TEMP/dest", Some(&src)),
+            ],
+            ..Default::default()
+        });
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), expected);
+    }
+}
+
+/// Checks that --manifest-out writes the checksum of each source file, sorted by path, in each --manifest-format.
+#[test]
+fn manifest_out() {
+    let src = folder! {
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH + Duration::from_secs(2)),
+        "folder" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH + Duration::from_millis(1500)),
+        },
+        "empty" => empty_folder(),
+    };
+    // The output files are outside of the test framework's temporary folder, so that we can check them afterwards
+    let out_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let out_path = out_folder.path().join("manifest.txt");
+    for (format, expected) in [
+        ("sha256sum", "869ed4d9645d8f65f6650ff3e987e335183c02ebed99deccea2917c6fd7be006  c2\n\
+            809da78733fb34d7548ff1a8abe962ec865f8db07820e00f7a61ba79e2b6ff9f  folder/c1\n"),
+        ("detailed", "c2  9  2.000000000  869ed4d9645d8f65f6650ff3e987e335183c02ebed99deccea2917c6fd7be006\n\
+            folder/c1  9  1.500000000  809da78733fb34d7548ff1a8abe962ec865f8db07820e00f7a61ba79e2b6ff9f\n"),
+    ] {
+        run(TestDesc {
+            setup_filesystem_nodes: vec![
+                ("$TEMP/src", &src),
+            ],
+            args: vec![
+                "src".to_string(),
+                "dest".to_string(),
+                "--manifest-out".to_string(),
+                out_path.to_str().unwrap().to_string(),
+                format!("--manifest-format={format}"),
+            ],
+            expected_exit_code: 0,
+            expected_output_messages: copied_files_and_folders(2, 3).into(),
+            expected_filesystem_nodes: vec![
+                ("$TEMP/dest", Some(&src)),
+            ],
+            ..Default::default()
+        });
+        assert_eq!(std::fs::read_to_string(&out_path).unwrap(), expected);
+    }
+
+    // When syncing a single file, the line is named after the dest file
+    let src = file("contents1");
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src.txt", &src),
+        ],
+        args: vec![
+            "src.txt".to_string(),
+            "dest.txt".to_string(),
+            "--manifest-out".to_string(),
+            out_path.to_str().unwrap().to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files(1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest.txt", Some(&src)),
+        ],
+        ..Default::default()
+    });
+    assert_eq!(std::fs::read_to_string(&out_path).unwrap(),
+        "809da78733fb34d7548ff1a8abe962ec865f8db07820e00f7a61ba79e2b6ff9f  dest.txt\n");
+}
+
+/// Checks that the dest can be verified with `sha256sum -c` using the manifest from --manifest-out,
+/// including for names which sha256sum needs to escape (backslashes aren't allowed in names, but line breaks are).
+#[cfg(target_os = "linux")]
+#[test]
+fn manifest_out_sha256sum_check() {
+    let temp_folder = tempdir::TempDir::new("rjrssync-test").unwrap();
+    let src = folder! {
+        "c1" => file("contents1"),
+        "c2" => file("contents2"),
+        "folder" => folder! {
+            "new\nline" => file("contents3"),
+        },
+    };
+    save_filesystem_node_to_disk_local(&src, &temp_folder.path().join("src"));
+
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_rjrssync"))
+        .arg("src").arg("dest").arg("--manifest-out").arg("manifest.txt")
+        .current_dir(temp_folder.path())
+        .output().unwrap();
+    println!("{}{}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+
+    let output = std::process::Command::new("sha256sum")
+        .arg("-c").arg(temp_folder.path().join("manifest.txt"))
+        .current_dir(temp_folder.path().join("dest"))
+        .output().unwrap();
+    let output_text = String::from_utf8_lossy(&output.stdout);
+    println!("{output_text}{}", String::from_utf8_lossy(&output.stderr));
+    assert!(output.status.success());
+    assert_eq!(output_text.matches(": OK").count(), 3);
+}
+
+/// Checks that invalid --histogram-buckets are reported when parsing the command-line, before doing anything.
+#[test]
+fn histogram_buckets_invalid() {
+    run(TestDesc {
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--histogram-buckets=1000,10".to_string(),
+        ],
+        expected_exit_code: 2,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Histogram bucket edges must be in ascending order, but got [1000, 10]")).unwrap()),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that a one-line summary is always shown at the end of a sync, even without --stats and
+/// when there was nothing to do (the --quiet test checks that it's hidden for --quiet).
+#[test]
+fn summary() {
+    let src = folder! {
+        "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "folder" => folder! {
+            "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest = folder! {
+        "old" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(r"Synced in [0-9.]+s: copied 3 entry\(s\) \(17 B\), deleted 1 entry\(s\)").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)),
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+
+    // Nothing to do, but the summary is still shown
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape("Nothing to do")).unwrap()),
+            (1, Regex::new(r"Synced in [0-9.]+s: already up to date").unwrap()),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that the summary at the end of the sync lists the entries that were skipped, grouped by the reason.
+#[test]
+fn summary_skipped() {
+    let src = folder! {
+        "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        "excluded.tmp" => file_with_modified("temp", SystemTime::UNIX_EPOCH),
+        "build" => folder! { // Counts as only one filtered entry, as its contents aren't looked at
+            "a.o" => file_with_modified("a", SystemTime::UNIX_EPOCH),
+            "b.o" => file_with_modified("b", SystemTime::UNIX_EPOCH),
+        },
+        "newer" => file_with_modified("src", SystemTime::UNIX_EPOCH),
+    };
+    let dest = folder! {
+        "newer" => file_with_modified("dest", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "keep" => folder! {
+            "notes.txt" => file_with_modified("notes", SystemTime::UNIX_EPOCH),
+        },
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--filter".to_string(),
+            "-.*\\.tmp".to_string(),
+            "--filter".to_string(),
+            "-build".to_string(),
+            "--dest-file-newer=skip".to_string(),
+            "--no-delete-pattern".to_string(),
+            "keep".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new(&regex::escape(
+                "copied 1 entry(s) (8 B), skipped 2 by filter; 1 because dest newer; 2 by --no-delete-pattern")).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest", Some(&folder! {
+                "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+                "newer" => file_with_modified("dest", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+                "keep" => folder! {
+                    "notes.txt" => file_with_modified("notes", SystemTime::UNIX_EPOCH),
+                },
+            })),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --quiet doesn't print anything, but does show errors
+#[test]
+fn quiet() {
+    let src = folder! {
+        "file" => file("contents"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--quiet".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (0, Regex::new(".+").unwrap()), // No output at all
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)),
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+
+    run(TestDesc {
+        args: vec![
+            "$TEMP/src-that-doesnt-exist".to_string(),
+            "$TEMP/dest".to_string(),
+            "--quiet".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("src path .* doesn't exist").unwrap()), // Just an error message
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that --verbose prints additional messages
+#[test]
+fn verbose() {
+    let src = folder! {
+        "file" => file("contents"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--verbose".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (2, Regex::new("setup_comms").unwrap()),
+            (1, Regex::new("Copying source file").unwrap()),
+            (2, Regex::new("Shutdown command received").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)),
+            ("$TEMP/dest", Some(&src)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks what happens when a file on the dest needs to be overwritten but it is read-only.
+/// This should result in an error that gracefully stops the sync, saying which side (and platform) the error came from.
+#[test]
+fn read_only_dest_file() {
+    let dest_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
+    let mut perm = dest_file.as_file().metadata().expect("Failed to query metadata").permissions();
+    perm.set_readonly(true);
+    dest_file.as_file().set_permissions(perm).expect("Failed to make read-only");
+
+    let src = file("contents");
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            dest_file.path().to_string_lossy().to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new(&format!(r"dest doer \({}\): Error writing file contents to", if cfg!(windows) { "Windows" } else { "Unix" })).unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source unchanged
+            (&dest_file.path().to_string_lossy(), // Dest unchanged (as sync failed)
+                Some(&file_with_modified("", dest_file.as_file().metadata().unwrap().modified().unwrap()))),
+        ],
+        ..Default::default()
+    });
+}
+
+
+/// Checks that syncing a folder into a subfolder of itself is an error, as it would copy the dest into itself.
+#[test]
+fn dest_inside_src() {
+    let src = folder! {
+        "c1" => file("contents1"),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/src/backup".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_output_messages: vec![
+            (1, Regex::new("is inside src path").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Unchanged
+        ],
+        ..Default::default()
+    });
+}
+
+/// Checks that syncing a folder into a subfolder of itself is allowed, if that subfolder is excluded by the filters.
+#[test]
+fn dest_inside_src_excluded() {
+    let src = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "backups" => empty_folder(),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/src/backups/latest".to_string(),
+            "--filter".to_string(),
+            "-backups".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(1, 1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src/backups/latest", Some(&folder! {
+                "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+            })),
+        ],
+        ..Default::default()
+    });
 }
 
-/// Checks that the --dry-run flag means that no changes are made, and that information about
-/// what _would_ happen is printed.
+/// Checks that --explain shows the reason that each entry doesn't need copying.
 #[test]
-fn dry_run() {
-    let slash = regex::escape(&std::path::MAIN_SEPARATOR.to_string());
+fn explain() {
     let src = folder! {
-        "file" => file("contents"),
-        "folder" => folder! {
-            "c1" => file("contents1"),
-        },
-        "symlink" => symlink_file("bob")
-    };
-    let dest = folder! {
-        "file2" => file("contents"),
-        "folder2" => folder! {
-            "c12" => file("contents1"),
-        },
-        "symlink2" => symlink_file("bob")
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
     };
     run(TestDesc {
         setup_filesystem_nodes: vec![
             ("$TEMP/src", &src),
-            ("$TEMP/dest", &dest),
+            ("$TEMP/dest", &src),
         ],
         args: vec![
             "$TEMP/src".to_string(),
             "$TEMP/dest".to_string(),
-            "--dry-run".to_string(),
+            "--explain".to_string(),
         ],
         expected_exit_code: 0,
         expected_output_messages: vec![
-            (1, Regex::new(&format!(r"Would delete dest file .*/dest{slash}folder2{slash}c12")).unwrap()),
-            (1, Regex::new(&format!(r"Would delete dest symlink .*/dest{slash}symlink2")).unwrap()),
-            (1, Regex::new(&format!(r"Would delete dest folder .*/dest{slash}folder2")).unwrap()),
-            (1, Regex::new(&format!(r"Would delete dest file .*/dest{slash}file2")).unwrap()),
-            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}file' => dest file .*/dest{slash}file")).unwrap()),
-            (1, Regex::new(&format!(r"Would create dest folder .*/dest{slash}folder")).unwrap()),
-            (1, Regex::new(&format!(r"Would create dest symlink .*/dest{slash}symlink")).unwrap()),
-            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}folder{slash}c1' => dest file .*/dest{slash}folder{slash}c1")).unwrap()),
-            (1, Regex::new(&regex::escape("Would delete 2 file(s) totalling 17B, 1 folder(s) and 1 symlink(s)")).unwrap()),
-            (1, Regex::new(&regex::escape("Would copy 2 file(s) totalling 17B, would create 1 folder(s) and would copy 1 symlink(s)")).unwrap()),
+            (1, Regex::new("source root folder .* already exists at dest root folder .* - nothing to do").unwrap()),
+            (2, Regex::new("has same modified time as .*Will not update").unwrap()),
+            (0, Regex::new("further explanations omitted").unwrap()),
+            (1, Regex::new("Nothing to do").unwrap()),
         ],
         expected_filesystem_nodes: vec![
-            ("$TEMP/src", Some(&src)), // Source should always be unchanged
-            ("$TEMP/dest", Some(&dest)), // Dest should be unchanged too
+            ("$TEMP/dest", Some(&src)), // Unchanged
         ],
         ..Default::default()
     });
 }
 
-/// Checks that the --dry-run flag means that no changes are made, and that information about
-/// what _would_ happen is printed. Also checks when dest ancestor folders are missing, that
-/// they are not created.
+/// Checks that --explain stops explaining after the given limit.
 #[test]
-fn dry_run_root_ancestors() {
-    let slash = regex::escape(&std::path::MAIN_SEPARATOR.to_string());
+fn explain_limit() {
     let src = folder! {
-        "file" => file("contents"),
-        "folder" => folder! {
-            "c1" => file("contents1"),
-        },
-        "symlink" => symlink_file("bob")
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        "c3" => file_with_modified("contents3", SystemTime::UNIX_EPOCH),
     };
     run(TestDesc {
         setup_filesystem_nodes: vec![
             ("$TEMP/src", &src),
+            ("$TEMP/dest", &src),
         ],
         args: vec![
             "$TEMP/src".to_string(),
-            // Place the dest inside some non-existent folders, to check that root ancestors are not
-            // created in dry-run mode
-            "$TEMP/dest1/dest2/dest3/dest".to_string(),
-            "--dry-run".to_string(),
+            "$TEMP/dest".to_string(),
+            "--explain=2".to_string(),
         ],
         expected_exit_code: 0,
         expected_output_messages: vec![
-            (1, Regex::new(&format!(r"Would create dest root folder .*/dest1/dest2/dest3/dest")).unwrap()),
-            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}file' => dest file .*/dest{slash}file")).unwrap()),
-            (1, Regex::new(&format!(r"Would create dest folder .*/dest{slash}folder")).unwrap()),
-            (1, Regex::new(&format!(r"Would create dest symlink .*/dest{slash}symlink")).unwrap()),
-            (1, Regex::new(&format!(r"Would copy source file .*/src{slash}folder{slash}c1' => dest file .*/dest{slash}folder{slash}c1")).unwrap()),
-            (1, Regex::new(&regex::escape("Would copy 2 file(s) totalling 17B, would create 2 folder(s) and would copy 1 symlink(s)")).unwrap()),
+            (1, Regex::new("further explanations omitted - limit of 2 reached").unwrap()),
         ],
         expected_filesystem_nodes: vec![
-            ("$TEMP/src", Some(&src)), // Source should always be unchanged
-            ("$TEMP/dest1", None), // Dest should be unchanged, with no ancestors created
+            ("$TEMP/dest", Some(&src)), // Unchanged
         ],
         ..Default::default()
     });
 }
 
-/// Checks what happens when a file's size changes between the querying phase and the actual sync.
+/// Tests that --keep-going carries on with the remaining syncs in a spec file after one fails,
+/// and reports the partial failure.
 #[test]
-fn file_size_change_during_sync() {
-    let src_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
-    // Make the file quite large, so that it takes a little while to copy it, giving the background
-    // thread plenty of time to update it (to minimize race conditions)
-    src_file.as_file().write_all(&"so much big!".as_bytes().repeat(1000*1000)).expect("Failed to write file");
-
-    // Launch a background thread that constantly changes the file's size
-    let mut second_handle = std::fs::File::options().append(true).open(src_file.path()).expect("Failed to reopen temp file");
-    let stop_signal = Arc::new(AtomicBool::new(false));
-    let stop_signal2 = stop_signal.clone();
-    let thread = std::thread::spawn(move || {
-        while !stop_signal2.load(Ordering::Relaxed) {
-            writeln!(second_handle, "some more stuff").expect("Failed to write to temp file");
-        }
-    });
-
+fn keep_going() {
+    let spec_file = file(r#"
+        syncs:
+        - src: src1/
+          dest: dest1/
+        - src: missing/
+          dest: dest2/
+        - src: src3/
+          dest: dest3/
+    "#);
+    let src1 = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let src3 = folder! {
+        "c3" => file_with_modified("contents3", SystemTime::UNIX_EPOCH),
+    };
     run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/spec.yaml", &spec_file),
+            ("$TEMP/src1", &src1),
+            ("$TEMP/src3", &src3),
+        ],
         args: vec![
-            src_file.path().to_string_lossy().to_string(),
-            "$TEMP/dest_file.txt".to_string(),
+            "--spec".to_string(),
+            "$TEMP/spec.yaml".to_string(),
+            "--keep-going".to_string(),
         ],
-        expected_exit_code: 12,
+        expected_exit_code: 13,
         expected_output_messages: vec![
-            (1, Regex::new("Size of .* changed during the sync").unwrap()),
+            (2, Regex::new(&regex::escape("Copied 1 file(s)")).unwrap()),
+            (1, Regex::new(&regex::escape("1 of 3 sync(s) failed")).unwrap()),
+            (1, Regex::new(&regex::escape("missing/ => dest2/: ")).unwrap()),
         ],
         expected_filesystem_nodes: vec![
-            // Source file is constantly changing, so nothing we can really check here
-            // Dest file might have been changed, but it depends at what point the error is caught, so there's nothing we can really check here
+            ("$TEMP/dest1", Some(&src1)),
+            ("$TEMP/dest2", None),
+            ("$TEMP/dest3", Some(&src3)),
         ],
         ..Default::default()
     });
-
-    stop_signal.store(true, Ordering::Relaxed);
-    thread.join().expect("Failed to join thread");
 }
 
-/// Checks that --stats prints some stats
+/// Tests that --extra-dest syncs the same source to several dests, each of which may need
+/// different things doing to it.
 #[test]
-fn stats() {
+fn extra_dest() {
     let src = folder! {
-        "file" => file("contents"),
-        "folder" => folder! {
-            "c1" => file("contents1"),
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "sub" => folder! {
+            "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
         },
-        "symlink" => symlink_file("bob")
+    };
+    let dest2 = folder! {
+        "c1" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+        "extra" => file_with_modified("delete me", SystemTime::UNIX_EPOCH),
     };
     run(TestDesc {
         setup_filesystem_nodes: vec![
             ("$TEMP/src", &src),
+            ("$TEMP/dest2", &dest2),
+            ("$TEMP/dest3", &src),
         ],
         args: vec![
             "$TEMP/src".to_string(),
-            "$TEMP/dest".to_string(),
-            "--stats".to_string(),
+            "$TEMP/dest1".to_string(),
+            "--extra-dest".to_string(),
+            "$TEMP/dest2".to_string(),
+            "--extra-dest".to_string(),
+            "$TEMP/dest3".to_string(),
         ],
         expected_exit_code: 0,
         expected_output_messages: vec![
-            (1, Regex::new(&regex::escape("Source: 2 file(s) totalling 17B, 2 folder(s) and 1 symlink(s)")).unwrap()),
-            (1, Regex::new(&regex::escape("Dest: 0 file(s) totalling 0B, 0 folder(s) and 0 symlink(s)")).unwrap()),
-            (1, Regex::new("Queried in .* seconds").unwrap()),
-            (1, Regex::new("Deleted .* in .* seconds").unwrap()),
-            (1, Regex::new("Copied .* in .* seconds").unwrap()),
+            (1, Regex::new("=> .*dest1:").unwrap()),
+            (1, Regex::new("=> .*dest2:").unwrap()),
+            (1, Regex::new("=> .*dest3:").unwrap()),
+            (1, Regex::new("Copied 2 file\\(s\\).*created 2 folder\\(s\\)").unwrap()), // dest1
+            (1, Regex::new("Deleted 1 file\\(s\\)").unwrap()), // dest2
+            (1, Regex::new("Copied 2 file\\(s\\).*created 1 folder\\(s\\)").unwrap()), // dest2
+            (1, Regex::new("Nothing to do").unwrap()), // dest3
         ],
         expected_filesystem_nodes: vec![
-            ("$TEMP/src", Some(&src)),
-            ("$TEMP/dest", Some(&src)),
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest1", Some(&src)),
+            ("$TEMP/dest2", Some(&src)),
+            ("$TEMP/dest3", Some(&src)),
         ],
         ..Default::default()
     });
 }
 
-/// Checks that --quiet doesn't print anything, but does show errors
+/// Tests that connecting to several doers at once (--max-connections) still syncs correctly.
+/// All the targets here are local, so the src and dest doers are set up on separate threads at the same time.
 #[test]
-fn quiet() {
+fn max_connections() {
     let src = folder! {
-        "file" => file("contents"),
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
     };
     run(TestDesc {
         setup_filesystem_nodes: vec![
@@ -349,91 +1855,238 @@ fn quiet() {
         ],
         args: vec![
             "$TEMP/src".to_string(),
-            "$TEMP/dest".to_string(),
-            "--quiet".to_string(),
+            "$TEMP/dest1".to_string(),
+            "--extra-dest".to_string(),
+            "$TEMP/dest2".to_string(),
+            "--max-connections=4".to_string(),
         ],
         expected_exit_code: 0,
         expected_output_messages: vec![
-            (0, Regex::new(".+").unwrap()), // No output at all
+            (2, Regex::new("Copied 1 file\\(s\\)").unwrap()),
         ],
         expected_filesystem_nodes: vec![
             ("$TEMP/src", Some(&src)),
-            ("$TEMP/dest", Some(&src)),
+            ("$TEMP/dest1", Some(&src)),
+            ("$TEMP/dest2", Some(&src)),
         ],
         ..Default::default()
     });
+}
 
+/// Tests that --relative preserves the folders from the source path on the dest, starting from
+/// the "." marker if there is one.
+#[test]
+fn relative() {
+    let subdir = folder! {
+        "c1" => file_with_modified("contents1", SystemTime::UNIX_EPOCH),
+    };
+    let src = folder! {
+        "deep" => folder! {
+            "subdir" => subdir.clone(),
+            "not synced" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let expected_dest = folder! {
+        "deep" => folder! {
+            "subdir" => subdir.clone(),
+        },
+    };
     run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
         args: vec![
-            "$TEMP/src-that-doesnt-exist".to_string(),
+            "$TEMP/src/./deep/subdir".to_string(),
             "$TEMP/dest".to_string(),
-            "--quiet".to_string(),
+            "--relative".to_string(),
         ],
-        expected_exit_code: 12,
-        expected_output_messages: vec![
-            (1, Regex::new("src path .* doesn't exist").unwrap()), // Just an error message
+        expected_exit_code: 0,
+        expected_output_messages: copied_files_and_folders(1, 1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest)),
         ],
         ..Default::default()
     });
 }
 
-/// Checks that --verbose prints additional messages
+/// Tests that --existing only updates entries which are already on the dest, and doesn't create new ones.
 #[test]
-fn verbose() {
+fn existing() {
     let src = folder! {
-        "file" => file("contents"),
+        "c1" => file_with_modified("new", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
+        "c2" => file_with_modified("not on dest", SystemTime::UNIX_EPOCH),
+        "sub" => folder! {
+            "c3" => file_with_modified("not on dest either", SystemTime::UNIX_EPOCH),
+        },
+    };
+    let dest = folder! {
+        "c1" => file_with_modified("old", SystemTime::UNIX_EPOCH),
+    };
+    let expected_dest = folder! {
+        "c1" => file_with_modified("new", SystemTime::UNIX_EPOCH + Duration::from_secs(1)),
     };
     run(TestDesc {
         setup_filesystem_nodes: vec![
             ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
         ],
         args: vec![
             "$TEMP/src".to_string(),
             "$TEMP/dest".to_string(),
-            "--verbose".to_string(),
+            "--existing".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: copied_files(1).into(),
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
+
+/// Tests that --existing doesn't create the dest root (or its parent) if it doesn't exist.
+#[test]
+fn existing_missing_dest_root() {
+    let src = folder! {
+        "c1" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/parent/dest".to_string(),
+            "--existing".to_string(),
         ],
         expected_exit_code: 0,
         expected_output_messages: vec![
-            (2, Regex::new("setup_comms").unwrap()),
-            (1, Regex::new("Copying source file").unwrap()),
-            (2, Regex::new("Shutdown command received").unwrap()),
+            (1, Regex::new("Nothing to do").unwrap()),
         ],
         expected_filesystem_nodes: vec![
-            ("$TEMP/src", Some(&src)),
-            ("$TEMP/dest", Some(&src)),
+            ("$TEMP/parent", None),
         ],
         ..Default::default()
     });
 }
 
-/// Checks what happens when a file on the dest needs to be overwritten but it is read-only.
-/// This should result in an error that gracefully stops the sync.
+/// Tests that --prune-empty-dirs removes folders on the dest which are left empty after the sync,
+/// including folders which only contain other empty folders, but not those excluded by a filter.
+/// Folders which are empty on the source aren't created in the first place.
 #[test]
-fn read_only_dest_file() {
-    let dest_file = tempfile::NamedTempFile::new().expect("Failed to create temp file");
-    let mut perm = dest_file.as_file().metadata().expect("Failed to query metadata").permissions();
-    perm.set_readonly(true);
-    dest_file.as_file().set_permissions(perm).expect("Failed to make read-only");
+fn prune_empty_dirs() {
+    let src = folder! {
+        "keep" => folder! {
+            "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "a" => empty_folder(),
+        "b" => folder! {
+            "c" => empty_folder(),
+        },
+    };
+    let dest = folder! {
+        "a" => folder! {
+            "deleted" => file_with_modified("not on source", SystemTime::UNIX_EPOCH),
+        },
+        "excluded" => empty_folder(),
+    };
+    let expected_dest = folder! {
+        "keep" => folder! {
+            "file" => file_with_modified("contents", SystemTime::UNIX_EPOCH),
+        },
+        "excluded" => empty_folder(),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
+        ],
+        args: vec![
+            "$TEMP/src".to_string(),
+            "$TEMP/dest".to_string(),
+            "--prune-empty-dirs".to_string(),
+            "--filter".to_string(),
+            "-excluded".to_string(),
+        ],
+        expected_exit_code: 0,
+        expected_output_messages: vec![
+            (1, Regex::new("Removed 1 empty folder\\(s\\)").unwrap()),
+            (1, Regex::new("skipped 2 because would be empty \\(--prune-empty-dirs\\)").unwrap()),
+        ],
+        expected_filesystem_nodes: vec![
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&expected_dest)),
+        ],
+        ..Default::default()
+    });
+}
 
-    let src = file("contents");
+/// Tests that --prune-empty-dirs with --dry-run lists the folders which would be removed, without removing them.
+#[test]
+fn prune_empty_dirs_dry_run() {
+    let src = folder! {
+        "a" => empty_folder(),
+        "b" => empty_folder(),
+    };
+    let dest = folder! {
+        "a" => folder! {
+            "deleted" => file_with_modified("not on source", SystemTime::UNIX_EPOCH),
+        },
+    };
     run(TestDesc {
         setup_filesystem_nodes: vec![
             ("$TEMP/src", &src),
+            ("$TEMP/dest", &dest),
         ],
         args: vec![
             "$TEMP/src".to_string(),
-            dest_file.path().to_string_lossy().to_string(),
+            "$TEMP/dest".to_string(),
+            "--prune-empty-dirs".to_string(),
+            "--dry-run".to_string(),
         ],
-        expected_exit_code: 12,
+        expected_exit_code: 0,
         expected_output_messages: vec![
-            (1, Regex::new("Error writing file contents to").unwrap()),
+            (1, Regex::new("Would delete dest file '.*deleted'").unwrap()),
+            (1, Regex::new("Would remove empty dest folder '.*a'").unwrap()),
+            (0, Regex::new("Would create").unwrap()),
+            (1, Regex::new("Would remove 1 empty folder\\(s\\)").unwrap()),
         ],
         expected_filesystem_nodes: vec![
-            ("$TEMP/src", Some(&src)), // Source unchanged
-            (&dest_file.path().to_string_lossy(), // Dest unchanged (as sync failed)
-                Some(&file_with_modified("", dest_file.as_file().metadata().unwrap().modified().unwrap()))),
+            ("$TEMP/src", Some(&src)), // Source should always be unchanged
+            ("$TEMP/dest", Some(&dest)), // Dest should be unchanged too
         ],
         ..Default::default()
     });
 }
 
+/// Tests that without --keep-going, the remaining syncs in a spec file are abandoned after one fails.
+#[test]
+fn no_keep_going() {
+    let spec_file = file(r#"
+        syncs:
+        - src: missing/
+          dest: dest1/
+        - src: src2/
+          dest: dest2/
+    "#);
+    let src2 = folder! {
+        "c2" => file_with_modified("contents2", SystemTime::UNIX_EPOCH),
+    };
+    run(TestDesc {
+        setup_filesystem_nodes: vec![
+            ("$TEMP/spec.yaml", &spec_file),
+            ("$TEMP/src2", &src2),
+        ],
+        args: vec![
+            "--spec".to_string(),
+            "$TEMP/spec.yaml".to_string(),
+        ],
+        expected_exit_code: 12,
+        expected_filesystem_nodes: vec![
+            ("$TEMP/dest2", None),
+        ],
+        ..Default::default()
+    });
+}
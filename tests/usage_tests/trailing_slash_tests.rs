@@ -145,12 +145,31 @@ fn test_file_no_trailing_slash_to_folder_no_trailing_slash() {
     run_trailing_slashes_test_expect_success(Some(&file("contents1")), "", Some(&empty_folder()), "", 1);
 }
 
+/// Tries syncing a file to a folder, without saying what to do with the dest root. Replacing the whole folder is
+/// probably a mistake, so this should fail (as we can't prompt) and suggest how to sync into the folder instead.
+#[test]
+fn test_file_no_trailing_slash_to_folder_no_trailing_slash_default_behaviour() {
+    let dest = folder! {
+        "c1" => file("contents2"),
+    };
+    run_trailing_slashes_test_expected_failure(Some(&file("contents1")), "", Some(&dest), "",
+        Regex::new("needs deleting .* To sync into the folder instead, add a trailing slash to the dest path or use --merge-root").unwrap());
+}
+
 /// Tries syncing a file to a folder/. This should place the file inside the folder
 #[test]
 fn test_file_no_trailing_slash_to_folder_trailing_slash() {
     run_trailing_slashes_test_expect_success_override_dest(Some(&file("contents1")), "", Some(&empty_folder()), "/", 1, "$TEMP/dest/src");
 }
 
+/// Tries syncing a file to a folder\. On Windows, a backslash is a trailing slash too, so this should place the file
+/// inside the folder.
+#[cfg(windows)]
+#[test]
+fn test_file_no_trailing_slash_to_folder_trailing_backslash() {
+    run_trailing_slashes_test_expect_success_override_dest(Some(&file("contents1")), "", Some(&empty_folder()), "\\", 1, "$TEMP/dest/src");
+}
+
 /// Tries syncing a file/ to a folder. This should fail because trailing slashes on files are not allowed.
 #[test]
 fn test_file_trailing_slash_to_folder_no_trailing_slash() {